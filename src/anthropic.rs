@@ -0,0 +1,495 @@
+use crate::ChatRequest;
+use crate::error::ApiError;
+use crate::qwen3::StopReason;
+use crate::segment::{BlockParser, Segment};
+use serde_json::{Value, json};
+
+// Anthropic Messages API 兼容层: 请求转换成 ChatRequest, 输出按 <think> 和 <tool_call> 标签切分成 content block
+// thinking 与 Anthropic 一致默认关闭, budget_tokens 不生效; tool_choice 为 any/tool 时不强制调用工具
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MessagesRequest {
+    pub model: Option<String>,
+    pub max_tokens: usize,
+    pub messages: Vec<AnthropicMessage>,
+    pub system: Option<SystemPrompt>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub stream: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<i64>,
+    pub tools: Option<Vec<AnthropicTool>>,
+    pub tool_choice: Option<ToolChoice>,
+    pub thinking: Option<ThinkingConfig>,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: BlockContent,
+}
+
+// content 既可以是字符串, 也可以是 content block 数组
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum BlockContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {},
+    Document {},
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: Option<BlockContent>,
+        is_error: Option<bool>,
+    },
+    Thinking {
+        thinking: String,
+    },
+    // 加密的思考内容无法还原, 直接忽略
+    RedactedThinking {},
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SystemPrompt {
+    Text(String),
+    Blocks(Vec<SystemBlock>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SystemBlock {
+    pub text: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AnthropicTool {
+    pub name: String,
+    pub description: Option<String>,
+    pub input_schema: Value,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolChoice {
+    Auto {},
+    Any {},
+    Tool { name: String },
+    None {},
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ThinkingConfig {
+    #[serde(rename = "type")]
+    pub thinking_type: String,
+    pub budget_tokens: Option<usize>,
+}
+
+impl ContentBlock {
+    fn block_type(&self) -> &'static str {
+        match self {
+            ContentBlock::Text { .. } => "text",
+            ContentBlock::Image {} => "image",
+            ContentBlock::Document {} => "document",
+            ContentBlock::ToolUse { .. } => "tool_use",
+            ContentBlock::ToolResult { .. } => "tool_result",
+            ContentBlock::Thinking { .. } => "thinking",
+            ContentBlock::RedactedThinking {} => "redacted_thinking",
+        }
+    }
+}
+
+impl BlockContent {
+    // tool_result 的内容只支持文本
+    fn text(&self, param: &str) -> Result<String, ApiError> {
+        match self {
+            BlockContent::Text(text) => Ok(text.clone()),
+            BlockContent::Blocks(blocks) => {
+                let mut texts = Vec::new();
+                for block in blocks {
+                    match block {
+                        ContentBlock::Text { text } => texts.push(text.as_str()),
+                        block => return Err(unsupported_block(block, param)),
+                    }
+                }
+                Ok(texts.join("\n"))
+            }
+        }
+    }
+}
+
+fn unsupported_block(block: &ContentBlock, param: &str) -> ApiError {
+    ApiError::invalid_request(
+        format!("{} content blocks are not supported here", block.block_type()),
+        Some(param),
+    )
+}
+
+impl MessagesRequest {
+    pub fn is_stream(&self) -> bool {
+        self.stream == Some(true)
+    }
+
+    pub fn stop(&self) -> Vec<String> {
+        self.stop_sequences.clone().unwrap_or_default()
+    }
+
+    fn enable_thinking(&self) -> Result<bool, ApiError> {
+        match self.thinking.as_ref().map(|thinking| thinking.thinking_type.as_str()) {
+            None | Some("disabled") => Ok(false),
+            Some("enabled") => Ok(true),
+            Some(other) => Err(ApiError::invalid_request(
+                format!("thinking.type must be enabled or disabled, got {}", other),
+                Some("thinking.type"),
+            )),
+        }
+    }
+
+    // 转换成 OpenAI 格式的请求, 剩下的校验交给 ChatRequest::validate
+    pub fn to_chat_request(&self) -> Result<ChatRequest, ApiError> {
+        if self.max_tokens == 0 {
+            return Err(ApiError::invalid_request("max_tokens must be at least 1", Some("max_tokens")));
+        }
+        if self.messages.is_empty() {
+            return Err(ApiError::invalid_request(
+                "messages must contain at least one message",
+                Some("messages"),
+            ));
+        }
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system {
+            let system = match system {
+                SystemPrompt::Text(text) => text.clone(),
+                SystemPrompt::Blocks(blocks) => blocks
+                    .iter()
+                    .map(|block| block.text.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+            };
+            messages.push(json!({"role": "system", "content": system}));
+        }
+        for (index, message) in self.messages.iter().enumerate() {
+            let param = format!("messages[{}].content", index);
+            match message.role.as_str() {
+                "user" => messages.extend(user_messages(&message.content, &param)?),
+                "assistant" => messages.push(assistant_message(&message.content, &param)?),
+                role => {
+                    return Err(ApiError::invalid_request(
+                        format!("role must be user or assistant, got {}", role),
+                        Some(&format!("messages[{}].role", index)),
+                    ));
+                }
+            }
+        }
+        let tools = match (&self.tools, &self.tool_choice) {
+            (Some(_), Some(ToolChoice::None {})) | (None, _) => None,
+            (Some(tools), _) => Some(
+                tools
+                    .iter()
+                    .map(|tool| {
+                        json!({
+                            "type": "function",
+                            "function": {
+                                "name": tool.name,
+                                "description": tool.description.clone().unwrap_or_default(),
+                                "parameters": tool.input_schema,
+                            }
+                        })
+                    })
+                    .collect::<Vec<Value>>(),
+            ),
+        };
+        let request = json!({
+            "messages": messages,
+            "tools": tools,
+            "max_tokens": self.max_tokens,
+            "temperature": self.temperature,
+            "top_p": self.top_p,
+            "top_k": self.top_k,
+            "stop": self.stop_sequences,
+            "chat_template_kwargs": {"enable_thinking": self.enable_thinking()?},
+        });
+        serde_json::from_value(request).map_err(|e| ApiError::invalid_request(e.to_string(), None))
+    }
+}
+
+// tool_result 转换成 tool 消息, 与文本按原来的顺序排列
+fn user_messages(content: &BlockContent, param: &str) -> Result<Vec<Value>, ApiError> {
+    let blocks = match content {
+        BlockContent::Text(text) => return Ok(vec![json!({"role": "user", "content": text})]),
+        BlockContent::Blocks(blocks) => blocks,
+    };
+    let mut messages = Vec::new();
+    let mut texts = Vec::new();
+    for block in blocks {
+        match block {
+            ContentBlock::Text { text } => texts.push(text.as_str()),
+            ContentBlock::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => {
+                if !texts.is_empty() {
+                    messages.push(json!({"role": "user", "content": texts.join("\n")}));
+                    texts.clear();
+                }
+                let mut result = match content {
+                    Some(content) => content.text(param)?,
+                    None => String::new(),
+                };
+                if *is_error == Some(true) {
+                    result = format!("Error: {}", result);
+                }
+                messages.push(json!({"role": "tool", "tool_call_id": tool_use_id, "content": result}));
+            }
+            block => return Err(unsupported_block(block, param)),
+        }
+    }
+    if !texts.is_empty() {
+        messages.push(json!({"role": "user", "content": texts.join("\n")}));
+    }
+    Ok(messages)
+}
+
+// 思考内容按模型的输出格式放回 <think> 标签中, tool_use 转换成 tool_calls
+fn assistant_message(content: &BlockContent, param: &str) -> Result<Value, ApiError> {
+    let blocks = match content {
+        BlockContent::Text(text) => return Ok(json!({"role": "assistant", "content": text})),
+        BlockContent::Blocks(blocks) => blocks,
+    };
+    let mut thinking = Vec::new();
+    let mut texts = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block {
+            ContentBlock::Text { text } => texts.push(text.as_str()),
+            ContentBlock::Thinking { thinking: text } => thinking.push(text.as_str()),
+            ContentBlock::ToolUse { id, name, input } => tool_calls.push(json!({
+                "id": id,
+                "type": "function",
+                "function": {"name": name, "arguments": input},
+            })),
+            ContentBlock::RedactedThinking {} => {}
+            block => return Err(unsupported_block(block, param)),
+        }
+    }
+    let text = texts.join("\n");
+    let content = if thinking.is_empty() {
+        text
+    } else {
+        format!("<think>\n{}\n</think>\n\n{}", thinking.join("\n"), text)
+    };
+    let mut message = json!({"role": "assistant", "content": content});
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    Ok(message)
+}
+
+pub fn new_message_id() -> String {
+    format!("msg_{}", uuid::Uuid::new_v4().simple())
+}
+
+fn new_tool_use_id() -> String {
+    format!("toolu_{}", uuid::Uuid::new_v4().simple())
+}
+
+// 调用了工具时为 tool_use, 遇到停止字符串时同时返回该字符串
+pub fn stop_reason(finish_reason: StopReason, tool_use: bool, stop: &[String]) -> (&'static str, Option<String>) {
+    match finish_reason {
+        StopReason::Length => ("max_tokens", None),
+        StopReason::StopSequence(index) => ("stop_sequence", stop.get(index).cloned()),
+        StopReason::Stop if tool_use => ("tool_use", None),
+        StopReason::Stop => ("end_turn", None),
+    }
+}
+
+pub fn content_blocks(text: &str) -> Vec<Value> {
+    let mut parser = BlockParser::default();
+    let mut segments = parser.push(text);
+    segments.extend(parser.finish());
+    let mut blocks: Vec<Value> = Vec::new();
+    for segment in segments {
+        let last_type = blocks.last().and_then(|block| block["type"].as_str()).unwrap_or_default();
+        match segment {
+            Segment::Thinking(text) if last_type == "thinking" => {
+                let thinking = blocks.last().unwrap()["thinking"].as_str().unwrap_or_default().to_string() + &text;
+                blocks.last_mut().unwrap()["thinking"] = Value::String(thinking);
+            }
+            Segment::Text(text) if last_type == "text" => {
+                let merged = blocks.last().unwrap()["text"].as_str().unwrap_or_default().to_string() + &text;
+                blocks.last_mut().unwrap()["text"] = Value::String(merged);
+            }
+            Segment::Thinking(text) => blocks.push(json!({"type": "thinking", "thinking": text, "signature": ""})),
+            Segment::Text(text) => blocks.push(json!({"type": "text", "text": text})),
+            Segment::ToolCall { name, input } => {
+                blocks.push(json!({"type": "tool_use", "id": new_tool_use_id(), "name": name, "input": input}))
+            }
+        }
+    }
+    blocks
+}
+
+pub fn message_response(
+    model: &str,
+    text: &str,
+    finish_reason: StopReason,
+    stop: &[String],
+    input_tokens: usize,
+    output_tokens: usize,
+) -> Value {
+    let content = content_blocks(text);
+    let tool_use = content.iter().any(|block| block["type"] == "tool_use");
+    let (stop_reason, stop_sequence) = stop_reason(finish_reason, tool_use, stop);
+    json!({
+        "id": new_message_id(),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop_reason,
+        "stop_sequence": stop_sequence,
+        "usage": {"input_tokens": input_tokens, "output_tokens": output_tokens},
+    })
+}
+
+// 流式输出的事件, 每个事件的 type 同时作为 SSE 的 event 名
+#[derive(Debug)]
+pub struct MessageStream {
+    model: String,
+    input_tokens: usize,
+    parser: BlockParser,
+    // 当前打开的 text 或 thinking 块
+    open: Option<&'static str>,
+    index: usize,
+    tool_use: bool,
+}
+
+impl MessageStream {
+    pub fn new(model: &str, input_tokens: usize) -> Self {
+        MessageStream {
+            model: model.to_string(),
+            input_tokens,
+            parser: BlockParser::default(),
+            open: None,
+            index: 0,
+            tool_use: false,
+        }
+    }
+
+    pub fn start(&self) -> Value {
+        json!({
+            "type": "message_start",
+            "message": {
+                "id": new_message_id(),
+                "type": "message",
+                "role": "assistant",
+                "model": self.model,
+                "content": [],
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": {"input_tokens": self.input_tokens, "output_tokens": 0},
+            }
+        })
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<Value> {
+        let segments = self.parser.push(text);
+        self.events(segments)
+    }
+
+    pub fn finish(&mut self, finish_reason: StopReason, stop: &[String], output_tokens: usize) -> Vec<Value> {
+        let segments = self.parser.finish();
+        let mut events = self.events(segments);
+        events.extend(self.close());
+        let (stop_reason, stop_sequence) = stop_reason(finish_reason, self.tool_use, stop);
+        events.push(json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": stop_reason, "stop_sequence": stop_sequence},
+            "usage": {"output_tokens": output_tokens},
+        }));
+        events.push(json!({"type": "message_stop"}));
+        events
+    }
+
+    fn events(&mut self, segments: Vec<Segment>) -> Vec<Value> {
+        let mut events = Vec::new();
+        for segment in segments {
+            let (block_type, block, delta) = match segment {
+                Segment::Thinking(text) => (
+                    "thinking",
+                    json!({"type": "thinking", "thinking": "", "signature": ""}),
+                    json!({"type": "thinking_delta", "thinking": text}),
+                ),
+                Segment::Text(text) => (
+                    "text",
+                    json!({"type": "text", "text": ""}),
+                    json!({"type": "text_delta", "text": text}),
+                ),
+                // 工具调用解析完整后一次输出参数
+                Segment::ToolCall { name, input } => {
+                    events.extend(self.close());
+                    self.tool_use = true;
+                    events.push(json!({
+                        "type": "content_block_start",
+                        "index": self.index,
+                        "content_block": {"type": "tool_use", "id": new_tool_use_id(), "name": name, "input": {}},
+                    }));
+                    events.push(json!({
+                        "type": "content_block_delta",
+                        "index": self.index,
+                        "delta": {"type": "input_json_delta", "partial_json": input.to_string()},
+                    }));
+                    events.push(json!({"type": "content_block_stop", "index": self.index}));
+                    self.index += 1;
+                    continue;
+                }
+            };
+            if self.open != Some(block_type) {
+                events.extend(self.close());
+                events.push(json!({"type": "content_block_start", "index": self.index, "content_block": block}));
+                self.open = Some(block_type);
+            }
+            events.push(json!({"type": "content_block_delta", "index": self.index, "delta": delta}));
+        }
+        events
+    }
+
+    fn close(&mut self) -> Option<Value> {
+        self.open.take().map(|_| {
+            let event = json!({"type": "content_block_stop", "index": self.index});
+            self.index += 1;
+            event
+        })
+    }
+}
+
+// Anthropic 风格的错误: {"type": "error", "error": {"type", "message"}}
+// 流式输出中途出错时作为 error 事件输出
+pub fn error_event(e: &ApiError) -> Value {
+    let error_type = match e.status {
+        400 | 413 | 422 => "invalid_request_error",
+        401 => "authentication_error",
+        404 => "not_found_error",
+        503 => "overloaded_error",
+        _ => "api_error",
+    };
+    json!({"type": "error", "error": {"type": error_type, "message": e.message}})
+}
+
+pub fn error_json(e: &ApiError) -> String {
+    error_event(e).to_string()
+}
@@ -1,15 +1,29 @@
-use qwen3_deploy::{chat_stream, chat_sync, ChatRequest};
+use qwen3_deploy::anthropic::{MessagesRequest, error_json};
+use qwen3_deploy::batch::{self, BatchRequest, BatchStore};
+use qwen3_deploy::completion::CompletionRequest;
+use qwen3_deploy::embedding::EmbeddingRequest;
+use qwen3_deploy::error::ApiError;
+use qwen3_deploy::ollama::{self, OllamaChatRequest, OllamaGenerateRequest};
+use qwen3_deploy::rerank::RerankRequest;
+use qwen3_deploy::responses::ResponsesRequest;
+use qwen3_deploy::score::ScoreRequest;
+use qwen3_deploy::{batch_store, cancel_batch, chat_stream, create_batch, chat_sync, completions_stream, completions_sync, delete_response, embeddings, get_response, messages_stream, messages_sync, ollama_chat_stream, ollama_chat_sync, ollama_generate_stream, ollama_generate_sync, ollama_show, ollama_tags, rerank, responses_stream, responses_sync, score, session_action, speculative_stats, ChatRequest, SessionRequest};
 use rocket::Request;
+use rocket::form::{Errors, Form};
+use rocket::fs::TempFile;
 use rocket::futures::{Stream, StreamExt};
 use rocket::http::{ContentType, Status};
 use rocket::response::Responder;
+use rocket::response::status::Custom;
 use rocket::response::stream::TextStream;
-use rocket::serde::json::Json;
+use rocket::serde::json::{Error as JsonError, Json};
 
-enum Response<R: Stream<Item = String> + Send> {
+pub(crate) enum Response<R: Stream<Item = String> + Send> {
     Stream(TextStream<R>),
     Text(String),
-    Error(String),
+    Error(ApiError),
+    // 已经按其它 API 的格式序列化的错误
+    ErrorBody(u16, String),
 }
 
 impl<'r, 'o: 'r, R> Responder<'r, 'o> for Response<R>
@@ -21,11 +35,12 @@ where
         match self {
             Response::Stream(stream) => stream.respond_to(req),
             Response::Text(text) => text.respond_to(req),
-            Response::Error(e) => {
+            Response::Error(e) => Response::<R>::ErrorBody(e.status, e.to_json()).respond_to(req),
+            Response::ErrorBody(status, body) => {
                 let mut res = rocket::response::Response::new();
-                res.set_status(Status::InternalServerError);
+                res.set_status(Status::from_code(status).unwrap_or(Status::InternalServerError));
                 res.set_header(ContentType::JSON);
-                res.set_sized_body(e.len(), std::io::Cursor::new(e));
+                res.set_sized_body(body.len(), std::io::Cursor::new(body));
                 Ok(res)
             }
         }
@@ -33,35 +48,397 @@ where
 }
 
 #[post("/completions", data = "<req>")]
-pub(crate) async fn chat(req: Json<ChatRequest>) -> (ContentType, Response<impl Stream<Item = String>>) {
+pub(crate) async fn chat(
+    req: Result<Json<ChatRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, Response::Error(json_error(e))),
+    };
     match req.stream {
         Some(false) => {
-            match chat_sync(&req.into_inner()).await {
+            match chat_sync(&req).await {
                 Ok(response) => {
-                    (ContentType::Text, Response::Text(response))
+                    (ContentType::JSON, Response::Text(response))
                 }
                 Err(e) => {
-                     (ContentType::Text, Response::Error(e.to_string()))
+                     (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e)))
                 }
             }
         },
         _ => {
-            let stream = TextStream! {
-                match chat_stream(&req.into_inner()) {
-                    Ok(stream) => {
+            match chat_stream(&req).await {
+                Ok(stream) => {
+                    let stream = TextStream! {
                         let mut boxed_stream = Box::pin(stream);
                         while let Some(resp) = boxed_stream.next().await {
-                            yield format!("event: message\ndata: {}\n\n", resp);
+                            match resp {
+                                Ok(resp) => yield format!("event: message\ndata: {}\n\n", resp),
+                                // 生成过程中出错时输出错误后结束, 不再输出 [DONE]
+                                Err(e) => {
+                                    yield format!("event: message\ndata: {}\n\n", e.to_json());
+                                    return;
+                                }
+                            }
                         }
                         yield format!("event: message\ndata: {}\n\n", "[DONE]");
+                    };
+                    (ContentType::EventStream, Response::Stream(stream))
+                }
+                Err(e) => (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e))),
+            }
+        }
+    }
+}
+
+// 与 OpenAI 一致, 默认不使用流式输出
+#[post("/completions", data = "<req>")]
+pub(crate) async fn completion(
+    req: Result<Json<CompletionRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, Response::Error(json_error(e))),
+    };
+    if req.stream != Some(true) {
+        return match completions_sync(&req).await {
+            Ok(response) => (ContentType::JSON, Response::Text(response)),
+            Err(e) => (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e))),
+        };
+    }
+    match completions_stream(&req).await {
+        Ok(stream) => {
+            let stream = TextStream! {
+                let mut boxed_stream = Box::pin(stream);
+                while let Some(resp) = boxed_stream.next().await {
+                    match resp {
+                        Ok(resp) => yield format!("data: {}\n\n", resp),
+                        Err(e) => {
+                            yield format!("data: {}\n\n", e.to_json());
+                            return;
+                        }
                     }
-                    Err(e) => {
-                        yield format!("event: error\ndata: {}\n\n", e.to_string());
-                    }
+                }
+                yield format!("data: {}\n\n", "[DONE]");
+            };
+            (ContentType::EventStream, Response::Stream(stream))
+        }
+        Err(e) => (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e))),
+    }
+}
+
+// Anthropic 的客户端默认不使用流式输出, 错误也按 Anthropic 的格式返回
+#[post("/messages", data = "<req>")]
+pub(crate) async fn messages(
+    req: Result<Json<MessagesRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let anthropic_error = |e: ApiError| Response::ErrorBody(e.status, error_json(&e));
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, anthropic_error(json_error(e))),
+    };
+    if !req.is_stream() {
+        return match messages_sync(&req).await {
+            Ok(response) => (ContentType::JSON, Response::Text(response)),
+            Err(e) => (ContentType::JSON, anthropic_error(ApiError::from_anyhow(&e))),
+        };
+    }
+    match messages_stream(&req).await {
+        Ok(stream) => {
+            let stream = TextStream! {
+                let mut boxed_stream = Box::pin(stream);
+                while let Some(event) = boxed_stream.next().await {
+                    yield format!("event: {}\ndata: {}\n\n", event["type"].as_str().unwrap_or_default(), event);
+                }
+            };
+            (ContentType::EventStream, Response::Stream(stream))
+        }
+        Err(e) => (ContentType::JSON, anthropic_error(ApiError::from_anyhow(&e))),
+    }
+}
+
+// OpenAI Responses API, 流式输出的每个事件带 event 名
+#[post("/responses", data = "<req>")]
+pub(crate) async fn responses(
+    req: Result<Json<ResponsesRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, Response::Error(json_error(e))),
+    };
+    if !req.is_stream() {
+        return match responses_sync(&req).await {
+            Ok(response) => (ContentType::JSON, Response::Text(response)),
+            Err(e) => (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e))),
+        };
+    }
+    match responses_stream(&req).await {
+        Ok(stream) => {
+            let stream = TextStream! {
+                let mut boxed_stream = Box::pin(stream);
+                while let Some(event) = boxed_stream.next().await {
+                    yield format!("event: {}\ndata: {}\n\n", event["type"].as_str().unwrap_or_default(), event);
                 }
             };
             (ContentType::EventStream, Response::Stream(stream))
         }
+        Err(e) => (ContentType::JSON, Response::Error(ApiError::from_anyhow(&e))),
+    }
+}
+
+#[get("/responses/<id>")]
+pub(crate) async fn response_info(id: &str) -> Custom<(ContentType, String)> {
+    json_response(get_response(id).await)
+}
+
+#[delete("/responses/<id>")]
+pub(crate) async fn response_delete(id: &str) -> Custom<(ContentType, String)> {
+    json_response(delete_response(id).await)
+}
+
+// Ollama 的流式输出为每行一个 JSON, 错误格式为 {"error": message}
+#[post("/chat", data = "<req>")]
+pub(crate) async fn ollama_chat(
+    req: Result<Json<OllamaChatRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, ollama_error(json_error(e))),
+    };
+    if !ollama::is_stream(req.stream) {
+        return match ollama_chat_sync(&req).await {
+            Ok(response) => (ContentType::JSON, Response::Text(response)),
+            Err(e) => (ContentType::JSON, ollama_error(ApiError::from_anyhow(&e))),
+        };
+    }
+    match ollama_chat_stream(&req).await {
+        Ok(stream) => (ndjson(), Response::Stream(ndjson_stream(stream))),
+        Err(e) => (ContentType::JSON, ollama_error(ApiError::from_anyhow(&e))),
+    }
+}
+
+#[post("/generate", data = "<req>")]
+pub(crate) async fn ollama_generate(
+    req: Result<Json<OllamaGenerateRequest>, JsonError<'_>>,
+) -> (ContentType, Response<impl Stream<Item = String>>) {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return (ContentType::JSON, ollama_error(json_error(e))),
+    };
+    if !ollama::is_stream(req.stream) || req.is_load() {
+        return match ollama_generate_sync(&req).await {
+            Ok(response) => (ContentType::JSON, Response::Text(response)),
+            Err(e) => (ContentType::JSON, ollama_error(ApiError::from_anyhow(&e))),
+        };
+    }
+    match ollama_generate_stream(&req).await {
+        Ok(stream) => (ndjson(), Response::Stream(ndjson_stream(stream))),
+        Err(e) => (ContentType::JSON, ollama_error(ApiError::from_anyhow(&e))),
+    }
+}
+
+#[get("/tags")]
+pub(crate) async fn ollama_models() -> Custom<(ContentType, String)> {
+    ollama_response(ollama_tags().await)
+}
+
+// 只有一个模型, 忽略请求中的模型名
+#[post("/show")]
+pub(crate) async fn ollama_model_info() -> Custom<(ContentType, String)> {
+    ollama_response(ollama_show().await)
+}
+
+fn ndjson() -> ContentType {
+    ContentType::new("application", "x-ndjson")
+}
+
+fn ndjson_stream(stream: impl Stream<Item = serde_json::Value> + Send) -> TextStream<impl Stream<Item = String> + Send> {
+    TextStream! {
+        let mut boxed_stream = Box::pin(stream);
+        while let Some(chunk) = boxed_stream.next().await {
+            yield format!("{}\n", chunk);
+        }
+    }
+}
+
+fn ollama_error<R: Stream<Item = String> + Send>(e: ApiError) -> Response<R> {
+    Response::ErrorBody(e.status, ollama::error_json(&e))
+}
+
+fn ollama_response(result: Result<String, ApiError>) -> Custom<(ContentType, String)> {
+    match result {
+        Ok(body) => Custom(Status::Ok, (ContentType::JSON, body)),
+        Err(e) => Custom(
+            Status::from_code(e.status).unwrap_or(Status::InternalServerError),
+            (ContentType::JSON, ollama::error_json(&e)),
+        ),
+    }
+}
+
+#[get("/speculative_stats")]
+pub(crate) async fn stats() -> Custom<(ContentType, String)> {
+    json_response(speculative_stats().await)
+}
+
+#[post("/embeddings", data = "<req>")]
+pub(crate) async fn embedding(req: Result<Json<EmbeddingRequest>, JsonError<'_>>) -> Custom<(ContentType, String)> {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return json_response(Err(json_error(e))),
+    };
+    json_response(embeddings(&req).await.map_err(|e| ApiError::from_anyhow(&e)))
+}
+
+#[post("/rerank", data = "<req>")]
+pub(crate) async fn rerank_documents(req: Result<Json<RerankRequest>, JsonError<'_>>) -> Custom<(ContentType, String)> {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return json_response(Err(json_error(e))),
+    };
+    json_response(rerank(&req).await.map_err(|e| ApiError::from_anyhow(&e)))
+}
+
+#[post("/score", data = "<req>")]
+pub(crate) async fn score_continuations(req: Result<Json<ScoreRequest>, JsonError<'_>>) -> Custom<(ContentType, String)> {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return json_response(Err(json_error(e))),
+    };
+    json_response(score(&req).await.map_err(|e| ApiError::from_anyhow(&e)))
+}
+
+#[derive(FromForm)]
+pub(crate) struct FileUpload<'r> {
+    file: TempFile<'r>,
+    purpose: String,
+}
+
+// 只支持 purpose 为 batch 的文件, 上传时检查每行的格式
+#[post("/files", data = "<form>")]
+pub(crate) async fn upload_file(form: Result<Form<FileUpload<'_>>, Errors<'_>>) -> Custom<(ContentType, String)> {
+    let mut form = match form {
+        Ok(form) => form.into_inner(),
+        Err(e) => return json_response(Err(ApiError::invalid_request(format!("invalid upload: {}", e), None))),
+    };
+    json_response(save_upload(&mut form).await)
+}
+
+async fn save_upload(form: &mut FileUpload<'_>) -> Result<String, ApiError> {
+    if form.purpose != "batch" {
+        return Err(ApiError::invalid_request("purpose must be batch", Some("purpose")));
+    }
+    let store = batch_store()?;
+    let id = BatchStore::new_file_id();
+    let path = store.file_path(&id)?;
+    form.file.copy_to(&path).await.map_err(|e| ApiError::server_error(e.to_string()))?;
+    let content = std::fs::read_to_string(&path).map_err(|_| ApiError::invalid_request("file must be UTF-8 JSONL", Some("file")));
+    if let Err(e) = content.and_then(|content| batch::parse_input(&content)) {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    let filename = form
+        .file
+        .raw_name()
+        .map(|name| name.dangerous_unsafe_unsanitized_raw().as_str().to_string())
+        .unwrap_or_else(|| format!("{}.jsonl", id));
+    let file = store
+        .register_file(&id, &filename, &form.purpose)
+        .map_err(|e| ApiError::server_error(e.to_string()))?;
+    Ok(file.to_string())
+}
+
+#[get("/files")]
+pub(crate) async fn list_files() -> Custom<(ContentType, String)> {
+    json_response(batch_store().and_then(|store| {
+        store
+            .list_files()
+            .map(|files| files.to_string())
+            .map_err(|e| ApiError::server_error(e.to_string()))
+    }))
+}
+
+#[get("/files/<id>")]
+pub(crate) async fn file_info(id: &str) -> Custom<(ContentType, String)> {
+    json_response(batch_store().and_then(|store| store.file(id)).map(|file| file.to_string()))
+}
+
+#[get("/files/<id>/content")]
+pub(crate) async fn file_content(id: &str) -> Custom<(ContentType, String)> {
+    match batch_store().and_then(|store| store.file_content(id)) {
+        Ok(content) => Custom(Status::Ok, (ndjson(), content)),
+        Err(e) => json_response(Err(e)),
     }
-    
+}
+
+#[delete("/files/<id>")]
+pub(crate) async fn file_delete(id: &str) -> Custom<(ContentType, String)> {
+    json_response(batch_store().and_then(|store| store.delete_file(id)).map(|file| file.to_string()))
+}
+
+#[post("/batches", data = "<req>")]
+pub(crate) async fn batch_create(req: Result<Json<BatchRequest>, JsonError<'_>>) -> Custom<(ContentType, String)> {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(e) => return json_response(Err(json_error(e))),
+    };
+    json_response(create_batch(&req))
+}
+
+#[get("/batches")]
+pub(crate) async fn batch_list() -> Custom<(ContentType, String)> {
+    json_response(batch_store().and_then(|store| {
+        store
+            .list_batches()
+            .map(|batches| batches.to_string())
+            .map_err(|e| ApiError::server_error(e.to_string()))
+    }))
+}
+
+#[get("/batches/<id>")]
+pub(crate) async fn batch_info(id: &str) -> Custom<(ContentType, String)> {
+    json_response(batch_store().and_then(|store| store.batch(id)).map(|batch| batch.to_string()))
+}
+
+#[post("/batches/<id>/cancel")]
+pub(crate) async fn batch_cancel(id: &str) -> Custom<(ContentType, String)> {
+    json_response(cancel_batch(id))
+}
+
+// 保存和恢复时可以不带请求体, 使用默认的文件名
+#[post("/sessions/<id>?<action>", data = "<req>")]
+pub(crate) async fn session(
+    id: &str,
+    action: &str,
+    req: Result<Json<SessionRequest>, JsonError<'_>>,
+) -> Custom<(ContentType, String)> {
+    let req = match req {
+        Ok(req) => req.into_inner(),
+        Err(JsonError::Parse(body, _)) if body.trim().is_empty() => SessionRequest::default(),
+        Err(e) => return json_response(Err(json_error(e))),
+    };
+    json_response(session_action(id, action, &req).await)
+}
+
+fn json_response(result: Result<String, ApiError>) -> Custom<(ContentType, String)> {
+    match result {
+        Ok(body) => Custom(Status::Ok, (ContentType::JSON, body)),
+        Err(e) => Custom(
+            Status::from_code(e.status).unwrap_or(Status::InternalServerError),
+            (ContentType::JSON, e.to_json()),
+        ),
+    }
+}
+
+pub(crate) fn json_error(e: JsonError<'_>) -> ApiError {
+    match e {
+        JsonError::Io(e) => ApiError::invalid_request(format!("failed to read request body: {}", e), None),
+        JsonError::Parse(_, e) => ApiError::invalid_request(format!("invalid JSON body: {}", e), None),
+    }
+}
+
+// 未匹配的路由、超出大小限制等由 rocket 直接返回的错误也统一成 JSON
+#[catch(default)]
+pub(crate) fn default_catcher(status: Status, _req: &Request<'_>) -> Custom<(ContentType, String)> {
+    let message = status.reason().unwrap_or("unknown error");
+    Custom(status, (ContentType::JSON, ApiError::new(status.code, message).to_json()))
 }
@@ -0,0 +1,378 @@
+use crate::error::ApiError;
+use rocket::futures::{StreamExt, stream};
+use serde_json::{Value, json};
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// OpenAI Batch API: 输入为每行一个请求的 JSONL, 输出为每行一个结果
+// 每完成一个请求就追加写入结果文件, 中断后重新运行时跳过结果文件中已有的 custom_id
+
+pub const BATCH_ENDPOINTS: [&str; 3] = ["/v1/chat/completions", "/v1/completions", "/v1/embeddings"];
+
+// 同时执行的请求数, 各请求的 prefill 和解码在模型上交替执行
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct BatchLine {
+    pub custom_id: String,
+    pub method: String,
+    pub url: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BatchCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BatchRequest {
+    pub input_file_id: String,
+    pub endpoint: String,
+    pub completion_window: String,
+    pub metadata: Option<Value>,
+}
+
+// 逐行解析输入, 空行跳过; custom_id 用于断点续跑, 必须唯一
+pub fn parse_input(text: &str) -> Result<Vec<BatchLine>, ApiError> {
+    let mut lines = Vec::new();
+    let mut custom_ids = HashSet::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let param = format!("line {}", index + 1);
+        let line: BatchLine = serde_json::from_str(line)
+            .map_err(|e| ApiError::invalid_request(format!("invalid batch request: {}", e), Some(&param)))?;
+        if !custom_ids.insert(line.custom_id.clone()) {
+            return Err(ApiError::invalid_request(
+                format!("duplicate custom_id {}", line.custom_id),
+                Some(&param),
+            ));
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        return Err(ApiError::invalid_request("batch input is empty", None));
+    }
+    Ok(lines)
+}
+
+// 请求失败时 response 中为错误码和错误内容, 与直接调用接口的返回相同
+pub fn result_line(custom_id: &str, result: Result<String, ApiError>) -> Value {
+    let (status_code, body) = match result {
+        Ok(body) => (200, serde_json::from_str(&body).unwrap_or(Value::String(body))),
+        Err(e) => (e.status, json!({ "error": e })),
+    };
+    json!({
+        "id": format!("batch_req_{}", uuid::Uuid::new_v4().simple()),
+        "custom_id": custom_id,
+        "response": {
+            "status_code": status_code,
+            "request_id": uuid::Uuid::new_v4().to_string(),
+            "body": body,
+        },
+        "error": null,
+    })
+}
+
+pub fn is_failed(result: &Value) -> bool {
+    !result["error"].is_null() || result["response"]["status_code"] != 200
+}
+
+// 读取已经写入的结果, 崩溃时写了一半的最后一行被截掉
+pub fn read_results(path: &Path) -> anyhow::Result<Vec<Value>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read(path)?;
+    let complete = content.iter().rposition(|&b| b == b'\n').map(|i| i + 1).unwrap_or(0);
+    if complete < content.len() {
+        OpenOptions::new().write(true).open(path)?.set_len(complete as u64)?;
+    }
+    let mut results = Vec::new();
+    for line in String::from_utf8_lossy(&content[..complete]).lines() {
+        if !line.trim().is_empty() {
+            results.push(serde_json::from_str(line)?);
+        }
+    }
+    Ok(results)
+}
+
+fn check_line(line: &BatchLine, endpoint: Option<&str>) -> Result<(), ApiError> {
+    if line.method != "POST" {
+        return Err(ApiError::invalid_request("only POST is supported", Some("method")));
+    }
+    if let Some(endpoint) = endpoint
+        && line.url != endpoint
+    {
+        return Err(ApiError::invalid_request(
+            format!("url {} does not match the batch endpoint {}", line.url, endpoint),
+            Some("url"),
+        ));
+    }
+    Ok(())
+}
+
+// 失败的结果写入 errors, 不设置时与成功的结果写入同一个文件
+// progress 在每写入一行后调用, 返回 false 时停止, 正在执行的请求被丢弃, 下次重新执行
+pub async fn run_batch(
+    lines: Vec<BatchLine>,
+    endpoint: Option<&str>,
+    output: &Path,
+    errors: Option<&Path>,
+    concurrency: usize,
+    mut progress: impl FnMut(&BatchCounts) -> bool,
+) -> anyhow::Result<BatchCounts> {
+    let input_ids: HashSet<String> = lines.iter().map(|line| line.custom_id.clone()).collect();
+    let mut counts = BatchCounts {
+        total: lines.len(),
+        ..Default::default()
+    };
+    let mut done = HashSet::new();
+    let mut previous = read_results(output)?;
+    if let Some(errors) = errors {
+        previous.extend(read_results(errors)?);
+    }
+    for result in previous {
+        let Some(custom_id) = result["custom_id"].as_str() else {
+            continue;
+        };
+        if input_ids.contains(custom_id) && done.insert(custom_id.to_string()) {
+            if is_failed(&result) {
+                counts.failed += 1;
+            } else {
+                counts.completed += 1;
+            }
+        }
+    }
+    if !progress(&counts) {
+        return Ok(counts);
+    }
+
+    let mut output_file = OpenOptions::new().create(true).append(true).open(output)?;
+    let mut error_file = match errors {
+        Some(errors) => Some(OpenOptions::new().create(true).append(true).open(errors)?),
+        None => None,
+    };
+    let pending = lines.into_iter().filter(|line| !done.contains(&line.custom_id));
+    let mut results = stream::iter(pending)
+        .map(|line| async move {
+            let result = match check_line(&line, endpoint) {
+                Ok(()) => crate::batch_request(&line.url, &line.body).await,
+                Err(e) => Err(e),
+            };
+            result_line(&line.custom_id, result)
+        })
+        .buffer_unordered(concurrency.max(1));
+    while let Some(result) = results.next().await {
+        let failed = is_failed(&result);
+        let file = match (&mut error_file, failed) {
+            (Some(error_file), true) => error_file,
+            _ => &mut output_file,
+        };
+        writeln!(file, "{}", result)?;
+        file.flush()?;
+        if failed {
+            counts.failed += 1;
+        } else {
+            counts.completed += 1;
+        }
+        if !progress(&counts) {
+            break;
+        }
+    }
+    Ok(counts)
+}
+
+// /v1/files 和 /v1/batches 的数据保存在目录中, files/ 下为文件内容和元数据, batches/ 下为 batch 对象
+// 结果文件的 id 由 batch id 得到, 重启后继续写入同一个文件
+#[derive(Debug)]
+pub struct BatchStore {
+    dir: PathBuf,
+    // 运行中的 batch 和取消请求同时修改 batch 对象
+    lock: Mutex<()>,
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl BatchStore {
+    pub fn new(dir: &str) -> anyhow::Result<Self> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(dir.join("files"))?;
+        std::fs::create_dir_all(dir.join("batches"))?;
+        Ok(BatchStore {
+            dir,
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn new_file_id() -> String {
+        format!("file-{}", uuid::Uuid::new_v4().simple())
+    }
+
+    // 输出和错误文件的 id
+    pub fn result_file_ids(batch_id: &str) -> (String, String) {
+        let suffix = batch_id.trim_start_matches("batch_");
+        (format!("file-{}-output", suffix), format!("file-{}-errors", suffix))
+    }
+
+    pub fn file_path(&self, id: &str) -> Result<PathBuf, ApiError> {
+        if !valid_id(id) {
+            return Err(ApiError::new(404, format!("No such File object: {}", id)));
+        }
+        Ok(self.dir.join("files").join(id))
+    }
+
+    fn file_meta_path(&self, id: &str) -> Result<PathBuf, ApiError> {
+        Ok(self.file_path(id)?.with_extension("json"))
+    }
+
+    // 内容写入 file_path 之后登记文件, 大小从内容文件读取
+    pub fn register_file(&self, id: &str, filename: &str, purpose: &str) -> anyhow::Result<Value> {
+        let bytes = std::fs::metadata(self.file_path(id)?)?.len();
+        let file = json!({
+            "id": id,
+            "object": "file",
+            "bytes": bytes,
+            "created_at": chrono::Utc::now().timestamp(),
+            "filename": filename,
+            "purpose": purpose,
+        });
+        std::fs::write(self.file_meta_path(id)?, file.to_string())?;
+        Ok(file)
+    }
+
+    pub fn file(&self, id: &str) -> Result<Value, ApiError> {
+        let path = self.file_meta_path(id)?;
+        let content = std::fs::read_to_string(path).map_err(|_| ApiError::new(404, format!("No such File object: {}", id)))?;
+        serde_json::from_str(&content).map_err(|e| ApiError::server_error(e.to_string()))
+    }
+
+    pub fn file_content(&self, id: &str) -> Result<String, ApiError> {
+        self.file(id)?;
+        std::fs::read_to_string(self.file_path(id)?).map_err(|e| ApiError::server_error(e.to_string()))
+    }
+
+    pub fn delete_file(&self, id: &str) -> Result<Value, ApiError> {
+        self.file(id)?;
+        std::fs::remove_file(self.file_meta_path(id)?).map_err(|e| ApiError::server_error(e.to_string()))?;
+        let _ = std::fs::remove_file(self.file_path(id)?);
+        Ok(json!({"id": id, "object": "file", "deleted": true}))
+    }
+
+    pub fn list_files(&self) -> anyhow::Result<Value> {
+        let mut files = self.list("files")?;
+        files.sort_by_key(|file| std::cmp::Reverse(file["created_at"].as_i64()));
+        Ok(json!({"object": "list", "data": files}))
+    }
+
+    fn list(&self, sub_dir: &str) -> anyhow::Result<Vec<Value>> {
+        let mut items = Vec::new();
+        for entry in std::fs::read_dir(self.dir.join(sub_dir))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                items.push(serde_json::from_str(&std::fs::read_to_string(path)?)?);
+            }
+        }
+        Ok(items)
+    }
+
+    fn batch_path(&self, id: &str) -> Result<PathBuf, ApiError> {
+        if !valid_id(id) {
+            return Err(ApiError::new(404, format!("No such Batch object: {}", id)));
+        }
+        Ok(self.dir.join("batches").join(format!("{}.json", id)))
+    }
+
+    pub fn create_batch(&self, request: &BatchRequest) -> Result<Value, ApiError> {
+        if !BATCH_ENDPOINTS.contains(&request.endpoint.as_str()) {
+            return Err(ApiError::invalid_request(
+                format!("endpoint must be one of {}", BATCH_ENDPOINTS.join(", ")),
+                Some("endpoint"),
+            ));
+        }
+        if request.completion_window != "24h" {
+            return Err(ApiError::invalid_request("completion_window must be 24h", Some("completion_window")));
+        }
+        self.file(&request.input_file_id).map_err(|_| {
+            ApiError::invalid_request(
+                format!("input file {} not found", request.input_file_id),
+                Some("input_file_id"),
+            )
+        })?;
+        let now = chrono::Utc::now().timestamp();
+        let batch = json!({
+            "id": format!("batch_{}", uuid::Uuid::new_v4().simple()),
+            "object": "batch",
+            "endpoint": request.endpoint,
+            "errors": null,
+            "input_file_id": request.input_file_id,
+            "completion_window": request.completion_window,
+            "status": "validating",
+            "output_file_id": null,
+            "error_file_id": null,
+            "created_at": now,
+            "in_progress_at": null,
+            "expires_at": now + 24 * 3600,
+            "finalizing_at": null,
+            "completed_at": null,
+            "failed_at": null,
+            "expired_at": null,
+            "cancelling_at": null,
+            "cancelled_at": null,
+            "request_counts": BatchCounts::default(),
+            "metadata": request.metadata,
+        });
+        self.save_batch(&batch).map_err(|e| ApiError::server_error(e.to_string()))?;
+        Ok(batch)
+    }
+
+    pub fn batch(&self, id: &str) -> Result<Value, ApiError> {
+        let content = std::fs::read_to_string(self.batch_path(id)?)
+            .map_err(|_| ApiError::new(404, format!("No such Batch object: {}", id)))?;
+        serde_json::from_str(&content).map_err(|e| ApiError::server_error(e.to_string()))
+    }
+
+    fn save_batch(&self, batch: &Value) -> anyhow::Result<()> {
+        let id = batch["id"].as_str().unwrap_or_default();
+        let path = self.batch_path(id)?;
+        // 先写临时文件再改名, 崩溃时不会留下写了一半的 batch 对象
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, batch.to_string())?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    // 加锁读取、修改并保存 batch 对象
+    pub fn update_batch(&self, id: &str, update: impl FnOnce(&mut Value)) -> Result<Value, ApiError> {
+        let _guard = self.lock.lock().unwrap();
+        let mut batch = self.batch(id)?;
+        update(&mut batch);
+        self.save_batch(&batch).map_err(|e| ApiError::server_error(e.to_string()))?;
+        Ok(batch)
+    }
+
+    pub fn list_batches(&self) -> anyhow::Result<Value> {
+        let mut batches = self.list("batches")?;
+        batches.sort_by_key(|batch| std::cmp::Reverse(batch["created_at"].as_i64()));
+        Ok(json!({"object": "list", "data": batches}))
+    }
+
+    // 服务重启时需要继续执行的 batch
+    pub fn unfinished_batches(&self) -> anyhow::Result<Vec<String>> {
+        let batches = self.list("batches")?;
+        Ok(batches
+            .iter()
+            .filter(|batch| matches!(batch["status"].as_str(), Some("validating" | "in_progress" | "finalizing" | "cancelling")))
+            .filter_map(|batch| batch["id"].as_str().map(|id| id.to_string()))
+            .collect())
+    }
+}
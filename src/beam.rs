@@ -0,0 +1,135 @@
+// beam search 的参数和候选管理, 与 HF transformers 的 BeamHypotheses 行为一致
+
+use crate::qwen3::StopReason;
+
+#[derive(Debug, Clone)]
+pub struct BeamSearchParam {
+    pub beam_width: usize,
+    // 得分 = 累计 logprob / 生成长度^length_penalty, 大于 0 时偏向更长的结果
+    pub length_penalty: f32,
+    // true: 得到 beam_width 个结束的候选后立即停止
+    // false: 直到剩余的 beam 不可能超过已结束的候选时才停止
+    pub early_stopping: bool,
+}
+
+impl Default for BeamSearchParam {
+    fn default() -> Self {
+        BeamSearchParam {
+            beam_width: 4,
+            length_penalty: 1.0,
+            early_stopping: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    // 生成的 token, 不含 prompt, 以 eos 结尾或者达到长度上限
+    pub tokens: Vec<u32>,
+    pub cumulative_logprob: f32,
+    pub score: f32,
+    // 以 eos 结尾为 Stop, 达到长度上限为 Length
+    pub finish_reason: StopReason,
+}
+
+// 已结束的候选, 最多保留 beam_width 个得分最高的
+pub struct BeamHypotheses {
+    param: BeamSearchParam,
+    hypotheses: Vec<Hypothesis>,
+}
+
+impl BeamHypotheses {
+    pub fn new(param: BeamSearchParam) -> Self {
+        BeamHypotheses {
+            param,
+            hypotheses: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hypotheses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hypotheses.is_empty()
+    }
+
+    pub fn score(&self, cumulative_logprob: f32, len: usize) -> f32 {
+        cumulative_logprob / (len.max(1) as f32).powf(self.param.length_penalty)
+    }
+
+    pub fn add(&mut self, tokens: Vec<u32>, cumulative_logprob: f32, finish_reason: StopReason) {
+        let score = self.score(cumulative_logprob, tokens.len());
+        if self.hypotheses.len() >= self.param.beam_width
+            && score <= self.worst_score().unwrap_or(f32::NEG_INFINITY)
+        {
+            return;
+        }
+        self.hypotheses.push(Hypothesis {
+            tokens,
+            cumulative_logprob,
+            score,
+            finish_reason,
+        });
+        self.hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.hypotheses.truncate(self.param.beam_width);
+    }
+
+    fn worst_score(&self) -> Option<f32> {
+        self.hypotheses.last().map(|hypothesis| hypothesis.score)
+    }
+
+    // best_logprob 为仍在搜索的 beam 中最高的累计 logprob, len 为当前生成长度
+    pub fn is_done(&self, best_logprob: f32, len: usize) -> bool {
+        if self.hypotheses.len() < self.param.beam_width {
+            return false;
+        }
+        if self.param.early_stopping {
+            return true;
+        }
+        let best_attainable = self.score(best_logprob, len);
+        self.worst_score().unwrap_or(f32::NEG_INFINITY) >= best_attainable
+    }
+
+    // 按得分从高到低排列
+    pub fn into_sorted(self) -> Vec<Hypothesis> {
+        self.hypotheses
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub beam: usize,
+    pub token: u32,
+    // 加上该 token 后的累计 logprob
+    pub cumulative_logprob: f32,
+}
+
+// beams 为每个 beam 的 (累计 logprob, 下一个 token 的 log_softmax)
+// 所有 beam 的扩展合并后按累计 logprob 取前 k 个
+pub fn top_candidates(beams: &[(f32, Vec<f32>)], k: usize) -> Vec<Candidate> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (beam, (cumulative_logprob, log_probs)) in beams.iter().enumerate() {
+        let mut tokens: Vec<(u32, f32)> = log_probs
+            .iter()
+            .enumerate()
+            .filter(|(_, logprob)| logprob.is_finite())
+            .map(|(token, &logprob)| (token as u32, logprob))
+            .collect();
+        if tokens.len() > k {
+            tokens.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+            tokens.truncate(k);
+        }
+        candidates.extend(tokens.into_iter().map(|(token, logprob)| Candidate {
+            beam,
+            token,
+            cumulative_logprob: cumulative_logprob + logprob,
+        }));
+    }
+    candidates.sort_by(|a, b| b.cumulative_logprob.total_cmp(&a.cumulative_logprob));
+    candidates.truncate(k);
+    candidates
+}
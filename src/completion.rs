@@ -0,0 +1,197 @@
+use crate::error::ApiError;
+use crate::qwen3::{MAX_TOP_LOGPROBS, TokenLogprob};
+use crate::sampling::SamplingParam;
+use crate::stop::StopSequences;
+use crate::{MAX_CHOICES, parse_logit_bias, validate_sampling_param};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// OpenAI 旧版 /v1/completions: 不套用对话模板, 直接续写 prompt
+
+// 与 OpenAI 一致, 未指定时只生成 16 个 token
+pub const DEFAULT_COMPLETION_TOKENS: usize = 16;
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    pub prompt: CompletionPrompt,
+    // 不支持填充中间的 suffix, 设置时报错
+    pub suffix: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<i64>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub logit_bias: Option<HashMap<String, f32>>,
+    pub n: Option<usize>,
+    pub best_of: Option<usize>,
+    pub stream: Option<bool>,
+    // 旧版 logprobs 为 top_logprobs 的个数
+    pub logprobs: Option<usize>,
+    // 在输出前面带上 prompt, 同时带上 prompt 的 logprobs
+    pub echo: Option<bool>,
+    pub stop: Option<StopSequences>,
+    pub user: Option<String>,
+}
+
+// 字符串、字符串数组、token 数组或多个 token 数组, 每个 prompt 各自生成 n 个结果
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Text(String),
+    Tokens(Vec<u32>),
+    Texts(Vec<String>),
+    TokenBatch(Vec<Vec<u32>>),
+}
+
+pub enum PromptInput {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+impl CompletionRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.suffix.is_some() {
+            return Err(ApiError::invalid_request("suffix is not supported", Some("suffix")));
+        }
+        let (count, has_empty) = match &self.prompt {
+            CompletionPrompt::Text(text) => (1, text.is_empty()),
+            CompletionPrompt::Tokens(tokens) => (1, tokens.is_empty()),
+            CompletionPrompt::Texts(texts) => (texts.len(), texts.iter().any(String::is_empty)),
+            CompletionPrompt::TokenBatch(batch) => (batch.len(), batch.iter().any(Vec::is_empty)),
+        };
+        if count == 0 || has_empty {
+            return Err(ApiError::invalid_request("prompt cannot be empty", Some("prompt")));
+        }
+        let n = self.n();
+        if n == 0 || count * n > MAX_CHOICES {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "n times the number of prompts must be in [1, {}], got {}",
+                    MAX_CHOICES,
+                    count * n
+                ),
+                Some("n"),
+            ));
+        }
+        if let Some(best_of) = self.best_of
+            && best_of != n
+        {
+            return Err(ApiError::invalid_request("best_of must be equal to n", Some("best_of")));
+        }
+        if let Some(logprobs) = self.logprobs
+            && logprobs > MAX_TOP_LOGPROBS
+        {
+            return Err(ApiError::invalid_request(
+                format!("logprobs must be in [0, {}], got {}", MAX_TOP_LOGPROBS, logprobs),
+                Some("logprobs"),
+            ));
+        }
+        let stop = self.stop();
+        if stop.len() > MAX_STOP_SEQUENCES || stop.iter().any(String::is_empty) {
+            return Err(ApiError::invalid_request(
+                format!("stop must contain at most {} non-empty sequences", MAX_STOP_SEQUENCES),
+                Some("stop"),
+            ));
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            parse_logit_bias(logit_bias)?;
+        }
+        validate_sampling_param(&self.sampling_param())
+    }
+
+    pub fn inputs(&self) -> Vec<PromptInput> {
+        match &self.prompt {
+            CompletionPrompt::Text(text) => vec![PromptInput::Text(text.clone())],
+            CompletionPrompt::Tokens(tokens) => vec![PromptInput::Tokens(tokens.clone())],
+            CompletionPrompt::Texts(texts) => texts.iter().map(|text| PromptInput::Text(text.clone())).collect(),
+            CompletionPrompt::TokenBatch(batch) => {
+                batch.iter().map(|tokens| PromptInput::Tokens(tokens.clone())).collect()
+            }
+        }
+    }
+
+    pub fn n(&self) -> usize {
+        self.n.unwrap_or(1)
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo == Some(true)
+    }
+
+    pub fn stop(&self) -> Vec<String> {
+        self.stop.as_ref().map(StopSequences::to_vec).unwrap_or_default()
+    }
+
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens.unwrap_or(DEFAULT_COMPLETION_TOKENS)
+    }
+
+    pub fn sampling_param(&self) -> SamplingParam {
+        SamplingParam {
+            temperature: self.temperature,
+            top_k: self.top_k.filter(|&top_k| top_k > 0).map(|top_k| top_k as usize),
+            top_p: self.top_p,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            seed: self.seed,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            repetition_penalty: self.repetition_penalty,
+            logit_bias: self.logit_bias.as_ref().and_then(|logit_bias| parse_logit_bias(logit_bias).ok()),
+        }
+    }
+}
+
+// 旧版 logprobs 格式, text_offset 为 token 在返回的 text 中的字符偏移
+// echo 时 prompt 的第一个 token 没有 logprob, 对应位置为 null
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct LegacyLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Vec<Option<Map<String, Value>>>,
+    pub text_offset: Vec<usize>,
+    #[serde(skip)]
+    offset: usize,
+}
+
+impl LegacyLogprobs {
+    pub fn push_first(&mut self, token: String) {
+        self.push_token(token, None, None);
+    }
+
+    pub fn push(&mut self, logprob: &TokenLogprob) {
+        let top_logprobs = logprob
+            .top_logprobs
+            .iter()
+            .map(|top| (top.token.clone(), top.logprob.into()))
+            .collect();
+        self.push_token(logprob.token.clone(), Some(logprob.logprob), Some(top_logprobs));
+    }
+
+    pub fn extend(&mut self, logprobs: &[TokenLogprob]) {
+        logprobs.iter().for_each(|logprob| self.push(logprob));
+    }
+
+    // 流式输出时每个 chunk 只带自己的 token, 偏移接着上一个 chunk
+    pub fn take(&mut self) -> LegacyLogprobs {
+        let offset = self.offset;
+        let taken = std::mem::take(self);
+        self.offset = offset;
+        taken
+    }
+
+    fn push_token(&mut self, token: String, logprob: Option<f32>, top_logprobs: Option<Map<String, Value>>) {
+        self.text_offset.push(self.offset);
+        self.offset += token.chars().count();
+        self.tokens.push(token);
+        self.token_logprobs.push(logprob);
+        self.top_logprobs.push(top_logprobs);
+    }
+}
@@ -0,0 +1,64 @@
+use tokenizers::Tokenizer;
+
+// 流式输出的增量解码, 做法与 HF text-generation-inference 相同
+// 每次解码 prefix_offset 之后的 token, prefix_offset..read_offset 是上一段已经输出的文本,
+// 作为上下文保证空格和合并的结果与整体解码一致, 新文本为两次解码结果的差
+// 新文本以 U+FFFD 结尾时可能是不完整的 utf-8, 先不输出, 等后面的 token 补全或者 flush
+#[derive(Debug, Clone)]
+pub struct IncrementalDecoder {
+    prefix_offset: usize,
+    read_offset: usize,
+}
+
+impl IncrementalDecoder {
+    // start 为第一个需要输出的 token 的位置, 之前的 prompt 不参与解码
+    pub fn new(start: usize) -> Self {
+        IncrementalDecoder {
+            prefix_offset: start,
+            read_offset: start,
+        }
+    }
+
+    // 已经输出的 token 数(从序列开头算起)
+    pub fn read_offset(&self) -> usize {
+        self.read_offset
+    }
+
+    // tokens 为完整的序列, 返回新增的文本, 暂时不能输出时返回 None
+    pub fn step(&mut self, tokenizer: &Tokenizer, tokens: &[u32]) -> anyhow::Result<Option<String>> {
+        let (prefix_text, new_text) = self.decode_window(tokenizer, tokens)?;
+        match new_text.get(prefix_text.len()..) {
+            Some(text) if !text.is_empty() && !new_text.ends_with('\u{FFFD}') => {
+                let text = text.to_string();
+                self.advance(tokens.len());
+                Ok(Some(text))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // 序列结束时输出剩下的全部文本, 包括结尾真实的 U+FFFD
+    pub fn flush(&mut self, tokenizer: &Tokenizer, tokens: &[u32]) -> anyhow::Result<String> {
+        let (prefix_text, new_text) = self.decode_window(tokenizer, tokens)?;
+        let start = prefix_text.len().min(new_text.len());
+        let text = String::from_utf8_lossy(&new_text.as_bytes()[start..]).to_string();
+        self.advance(tokens.len());
+        Ok(text)
+    }
+
+    fn decode_window(&self, tokenizer: &Tokenizer, tokens: &[u32]) -> anyhow::Result<(String, String)> {
+        let decode = |ids: &[u32]| {
+            tokenizer
+                .decode(ids, true)
+                .map_err(|e| anyhow::anyhow!(format!("stream decode error{}", e)))
+        };
+        let prefix_text = decode(&tokens[self.prefix_offset..self.read_offset])?;
+        let new_text = decode(&tokens[self.prefix_offset..])?;
+        Ok((prefix_text, new_text))
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.prefix_offset = self.read_offset;
+        self.read_offset = len;
+    }
+}
@@ -0,0 +1,170 @@
+use crate::error::ApiError;
+use crate::model::Model;
+use crate::utils::base64_encode;
+use tokenizers::Tokenizer;
+
+// Qwen3-Embedding: 输入末尾为 <|endoftext|>, 取最后一个 token 的 hidden state 作为向量, 再做 L2 归一化
+// 模型用 MRL 训练, 截取前 dimensions 维后重新归一化仍然可用
+
+pub enum EmbeddingInput {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+// 单独加载的 embedding 模型, 与对话模型分开
+pub struct EmbeddingModel {
+    pub tokenizer: Tokenizer,
+    pub model: Model,
+    pub eos_token: Option<u32>,
+    pub max_position_embeddings: usize,
+}
+
+// 查询需要带上任务说明, 文档不需要
+pub fn with_instruction(instruction: &str, query: &str) -> String {
+    format!("Instruct: {}\nQuery:{}", instruction, query)
+}
+
+// 编码输入并做 last-token pooling, 返回未归一化的向量和输入的 token 数
+pub fn embed(
+    tokenizer: &Tokenizer,
+    model: &Model,
+    eos_token: Option<u32>,
+    max_len: usize,
+    input: &EmbeddingInput,
+) -> anyhow::Result<(Vec<f32>, usize)> {
+    let mut tokens = match input {
+        EmbeddingInput::Text(text) => tokenizer
+            .encode(text.as_str(), true)
+            .map_err(|e| anyhow::anyhow!(format!("tokenizer encode error{}", e)))?
+            .get_ids()
+            .to_vec(),
+        EmbeddingInput::Tokens(tokens) => tokens.clone(),
+    };
+    // tokenizer 没有自动在末尾添加 <|endoftext|> 时补上
+    if let Some(eos_token) = eos_token
+        && tokens.last() != Some(&eos_token)
+    {
+        tokens.push(eos_token);
+    }
+    let vocab_size = tokenizer.get_vocab_size(true);
+    if let Some(token) = tokens.iter().find(|&&token| token as usize >= vocab_size) {
+        return Err(ApiError::invalid_request(format!("invalid token id {} in input", token), Some("input")).into());
+    }
+    if tokens.len() > max_len {
+        return Err(ApiError::invalid_request(
+            format!(
+                "This model's maximum context length is {} tokens, however you requested {} tokens in the input.",
+                max_len,
+                tokens.len()
+            ),
+            Some("input"),
+        )
+        .with_code("context_length_exceeded")
+        .into());
+    }
+    let hidden = model.last_hidden_state(&tokens)?.to_vec1::<f32>()?;
+    Ok((hidden, tokens.len()))
+}
+
+// 截取前 dimensions 维后做 L2 归一化
+pub fn postprocess(mut embedding: Vec<f32>, dimensions: Option<usize>) -> Vec<f32> {
+    if let Some(dimensions) = dimensions {
+        embedding.truncate(dimensions);
+    }
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+// 与 OpenAI 一致, base64 为小端 f32 的字节
+pub fn encode_base64(embedding: &[f32]) -> String {
+    let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+    base64_encode(&bytes)
+}
+
+// OpenAI 一次最多 2048 个输入
+pub const MAX_EMBEDDING_INPUTS: usize = 2048;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct EmbeddingRequest {
+    pub input: EmbeddingRequestInput,
+    pub model: Option<String>,
+    // "float"(默认) 或 "base64"
+    pub encoding_format: Option<String>,
+    pub dimensions: Option<usize>,
+    // Qwen3-Embedding 的任务说明扩展字段, 只加在文本输入前面
+    pub instruction: Option<String>,
+    pub user: Option<String>,
+}
+
+// 字符串、字符串数组、token 数组或多个 token 数组
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingRequestInput {
+    Text(String),
+    Tokens(Vec<u32>),
+    Texts(Vec<String>),
+    TokenBatch(Vec<Vec<u32>>),
+}
+
+impl EmbeddingRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if let Some(format) = &self.encoding_format
+            && format != "float"
+            && format != "base64"
+        {
+            return Err(ApiError::invalid_request(
+                format!("encoding_format must be float or base64, got {:?}", format),
+                Some("encoding_format"),
+            ));
+        }
+        if self.dimensions == Some(0) {
+            return Err(ApiError::invalid_request("dimensions must be at least 1", Some("dimensions")));
+        }
+        let (count, has_empty) = match &self.input {
+            EmbeddingRequestInput::Text(text) => (1, text.is_empty()),
+            EmbeddingRequestInput::Tokens(tokens) => (1, tokens.is_empty()),
+            EmbeddingRequestInput::Texts(texts) => (texts.len(), texts.iter().any(String::is_empty)),
+            EmbeddingRequestInput::TokenBatch(batch) => (batch.len(), batch.iter().any(Vec::is_empty)),
+        };
+        if count == 0 || has_empty {
+            return Err(ApiError::invalid_request("input cannot be empty", Some("input")));
+        }
+        if count > MAX_EMBEDDING_INPUTS {
+            return Err(ApiError::invalid_request(
+                format!("input can contain at most {} items, got {}", MAX_EMBEDDING_INPUTS, count),
+                Some("input"),
+            ));
+        }
+        if self.instruction.is_some()
+            && matches!(self.input, EmbeddingRequestInput::Tokens(_) | EmbeddingRequestInput::TokenBatch(_))
+        {
+            return Err(ApiError::invalid_request(
+                "instruction can only be used with text input",
+                Some("instruction"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn inputs(&self) -> Vec<EmbeddingInput> {
+        let text = |text: &String| match &self.instruction {
+            Some(instruction) => EmbeddingInput::Text(with_instruction(instruction, text)),
+            None => EmbeddingInput::Text(text.clone()),
+        };
+        match &self.input {
+            EmbeddingRequestInput::Text(input) => vec![text(input)],
+            EmbeddingRequestInput::Tokens(tokens) => vec![EmbeddingInput::Tokens(tokens.clone())],
+            EmbeddingRequestInput::Texts(texts) => texts.iter().map(text).collect(),
+            EmbeddingRequestInput::TokenBatch(batch) => {
+                batch.iter().map(|tokens| EmbeddingInput::Tokens(tokens.clone())).collect()
+            }
+        }
+    }
+
+    pub fn is_base64(&self) -> bool {
+        self.encoding_format.as_deref() == Some("base64")
+    }
+}
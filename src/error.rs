@@ -0,0 +1,89 @@
+use std::fmt;
+
+// OpenAI 风格的错误: {"error": {"message", "type", "param", "code"}}
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        let error_type = match status {
+            400 | 413 | 422 => "invalid_request_error",
+            401 => "authentication_error",
+            404 => "not_found_error",
+            503 => "service_unavailable_error",
+            _ => "server_error",
+        };
+        ApiError {
+            status,
+            message: message.into(),
+            error_type: error_type.to_string(),
+            param: None,
+            code: None,
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>, param: Option<&str>) -> Self {
+        ApiError {
+            param: param.map(|param| param.to_string()),
+            ..ApiError::new(400, message)
+        }
+    }
+
+    pub fn context_length_exceeded(max_len: usize, prompt_len: usize, max_tokens: Option<usize>) -> Self {
+        let message = match max_tokens {
+            Some(max_tokens) => format!(
+                "This model's maximum context length is {} tokens. However, you requested {} tokens ({} in the messages, {} in the completion). Please reduce the length of the messages or completion.",
+                max_len,
+                prompt_len + max_tokens,
+                prompt_len,
+                max_tokens
+            ),
+            None => format!(
+                "This model's maximum context length is {} tokens. However, your messages resulted in {} tokens. Please reduce the length of the messages.",
+                max_len, prompt_len
+            ),
+        };
+        ApiError {
+            param: Some("messages".to_string()),
+            code: Some("context_length_exceeded".to_string()),
+            ..ApiError::new(400, message)
+        }
+    }
+
+    pub fn server_error(message: impl Into<String>) -> Self {
+        ApiError::new(500, message)
+    }
+
+    pub fn with_code(mut self, code: &str) -> Self {
+        self.code = Some(code.to_string());
+        self
+    }
+
+    // 没有显式标记为 ApiError 的错误都视为服务端错误
+    pub fn from_anyhow(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<ApiError>() {
+            Some(api_error) => api_error.clone(),
+            None => ApiError::server_error(e.to_string()),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "error": self }).to_string()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
@@ -0,0 +1,954 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// guided decoding: 与 vLLM 同名的 guided_regex / guided_choice / guided_grammar 请求扩展
+// 三种约束统一编译为字符级文法, 生成时再结合词表前缀树得到 token 级的可选集合
+
+// 输出一个 JSON 对象, 用于 Ollama 的 format 和 Responses 的 json_object
+pub const JSON_GRAMMAR: &str = r#"
+root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+object ::= "{" ws (string ":" ws value ("," ws string ":" ws value)*)? "}" ws
+array  ::= "[" ws (value ("," ws value)*)? "]" ws
+string ::= "\"" ([^"\\\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4}))* "\"" ws
+number ::= "-"? ("0" | [1-9] [0-9]{0,15}) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws
+ws     ::= [ \t\n]{0,20}
+"#;
+
+#[derive(Debug, Clone)]
+pub enum GuidedDecoding {
+    Regex(String),
+    Choice(Vec<String>),
+    Grammar(String),
+}
+
+impl GuidedDecoding {
+    pub fn compile(&self) -> anyhow::Result<Grammar> {
+        match self {
+            GuidedDecoding::Regex(pattern) => parse_regex(pattern),
+            GuidedDecoding::Choice(choices) => {
+                if choices.is_empty() {
+                    return Err(anyhow::anyhow!("guided_choice must not be empty"));
+                }
+                let mut builder = GrammarBuilder::default();
+                let alts = choices
+                    .iter()
+                    .map(|choice| choice.chars().map(|c| Element::Char(CharClass::single(c))).collect())
+                    .collect();
+                let root = builder.new_rule(alts);
+                builder.build(root)
+            }
+            GuidedDecoding::Grammar(text) => parse_gbnf(text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    fn single(c: char) -> Self {
+        CharClass {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    // `.` 不匹配换行, 与正则的默认行为一致
+    fn any() -> Self {
+        CharClass {
+            ranges: vec![('\n', '\n')],
+            negated: true,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let hit = self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+        hit != self.negated
+    }
+
+    // [lo, hi] 中可能有字符匹配, 取反的字符类只在整段被一个区间覆盖时排除
+    fn may_match(&self, lo: u32, hi: u32) -> bool {
+        if self.negated {
+            !self.ranges.iter().any(|&(a, b)| a as u32 <= lo && hi <= b as u32)
+        } else {
+            self.ranges.iter().any(|&(a, b)| a as u32 <= hi && lo <= b as u32)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Element {
+    Char(CharClass),
+    Rule(usize),
+}
+
+type Sequence = Vec<Element>;
+
+// 规则 -> 候选分支 -> 元素序列
+#[derive(Debug)]
+pub struct Grammar {
+    rules: Vec<Vec<Sequence>>,
+    root: usize,
+}
+
+// 栈中每一项指向某条规则分支里下一个待匹配的元素
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    idx: usize,
+}
+
+type Stack = Vec<Pos>;
+
+// 计数量词 {m,n} 的上限, 以及展开后文法的元素总数上限, 避免请求构造出巨大的文法
+const MAX_REPEAT: usize = 1000;
+const MAX_GRAMMAR_SIZE: usize = 100_000;
+
+impl Grammar {
+    fn element(&self, pos: Pos) -> &Element {
+        &self.rules[pos.rule][pos.alt][pos.idx]
+    }
+
+    fn seq_len(&self, pos: Pos) -> usize {
+        self.rules[pos.rule][pos.alt].len()
+    }
+
+    // 把栈展开到栈顶是字符元素(或栈为空, 即已完整匹配)
+    // 左递归在编译时已经拒绝, 剩下的空串循环(如 (a?)*)回到同一规则时栈不变, path 记录本次展开经过的状态
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>, path: &mut Vec<(usize, Stack)>) {
+        let Some(top) = stack.last().copied() else {
+            out.push(stack);
+            return;
+        };
+        match self.element(top) {
+            Element::Char(_) => out.push(stack),
+            Element::Rule(rule) => {
+                let rule = *rule;
+                stack.pop();
+                if top.idx + 1 < self.seq_len(top) {
+                    stack.push(Pos {
+                        idx: top.idx + 1,
+                        ..top
+                    });
+                }
+                self.enter_rule(rule, stack, out, path);
+            }
+        }
+    }
+
+    fn enter_rule(&self, rule: usize, stack: Stack, out: &mut Vec<Stack>, path: &mut Vec<(usize, Stack)>) {
+        if path.iter().any(|(visited, visited_stack)| *visited == rule && *visited_stack == stack) {
+            return;
+        }
+        path.push((rule, stack.clone()));
+        for (alt, seq) in self.rules[rule].iter().enumerate() {
+            let mut next = stack.clone();
+            if !seq.is_empty() {
+                next.push(Pos { rule, alt, idx: 0 });
+            }
+            self.expand(next, out, path);
+        }
+        path.pop();
+    }
+
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut out = Vec::new();
+        self.enter_rule(self.root, Vec::new(), &mut out, &mut Vec::new());
+        dedup_stacks(out)
+    }
+
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if let Element::Char(class) = self.element(top)
+                && class.matches(c)
+            {
+                let mut next = stack.clone();
+                next.pop();
+                if top.idx + 1 < self.seq_len(top) {
+                    next.push(Pos {
+                        idx: top.idx + 1,
+                        ..top
+                    });
+                }
+                self.expand(next, &mut out, &mut Vec::new());
+            }
+        }
+        dedup_stacks(out)
+    }
+
+    // 以 [lo, hi] 中某个字符继续时是否可能被接受, 用于还没凑成完整字符的 utf-8 片段
+    fn may_advance(&self, stacks: &[Stack], lo: u32, hi: u32) -> bool {
+        stacks.iter().any(|stack| {
+            matches!(stack.last().map(|&top| self.element(top)), Some(Element::Char(class)) if class.may_match(lo, hi))
+        })
+    }
+}
+
+fn dedup_stacks(mut stacks: Vec<Stack>) -> Vec<Stack> {
+    stacks.sort();
+    stacks.dedup();
+    stacks
+}
+
+#[derive(Default)]
+struct GrammarBuilder {
+    rules: Vec<Vec<Sequence>>,
+    names: HashMap<String, usize>,
+    defined: Vec<bool>,
+    // 量词展开产生的元素数
+    size: usize,
+}
+
+impl GrammarBuilder {
+    fn named_rule(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        let id = self.rules.len();
+        self.rules.push(Vec::new());
+        self.defined.push(false);
+        self.names.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, alts: Vec<Sequence>) -> usize {
+        let id = self.rules.len();
+        self.rules.push(alts);
+        self.defined.push(true);
+        id
+    }
+
+    // item{min,max}, max 为 None 表示不设上限
+    fn repeat(&mut self, item: Sequence, min: usize, max: Option<usize>) -> anyhow::Result<Sequence> {
+        let count = max.unwrap_or(min).max(min);
+        if count > MAX_REPEAT {
+            return Err(anyhow::anyhow!("repetition count {} exceeds the limit of {}", count, MAX_REPEAT));
+        }
+        // 嵌套的量词会成倍放大, 按展开后的元素数再检查一次
+        self.size += item.len().max(1) * (count + 1);
+        if self.size > MAX_GRAMMAR_SIZE {
+            return Err(anyhow::anyhow!(
+                "repetitions expand to more than {} grammar elements",
+                MAX_GRAMMAR_SIZE
+            ));
+        }
+        let mut seq = Vec::new();
+        for _ in 0..min {
+            seq.extend(item.iter().cloned());
+        }
+        match max {
+            None => {
+                // R ::= item R | ε
+                let rule = self.new_rule(Vec::new());
+                let mut alt = item.clone();
+                alt.push(Element::Rule(rule));
+                self.rules[rule] = vec![alt, Vec::new()];
+                seq.push(Element::Rule(rule));
+            }
+            Some(max) => {
+                // R_k ::= item R_{k-1} | ε
+                let mut tail: Option<usize> = None;
+                for _ in min..max {
+                    let mut alt = item.clone();
+                    if let Some(tail) = tail {
+                        alt.push(Element::Rule(tail));
+                    }
+                    tail = Some(self.new_rule(vec![alt, Vec::new()]));
+                }
+                if let Some(tail) = tail {
+                    seq.push(Element::Rule(tail));
+                }
+            }
+        }
+        Ok(seq)
+    }
+
+    // 可以匹配空串的规则
+    fn nullable(&self) -> Vec<bool> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if nullable[rule] {
+                    continue;
+                }
+                let empty = alts.iter().any(|seq| {
+                    seq.iter()
+                        .all(|element| matches!(element, Element::Rule(r) if nullable[*r]))
+                });
+                if empty {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+        nullable
+    }
+
+    // 不消耗字符就能从规则 a 进入规则 b 时有一条边, b 不在分支末尾时栈会变深
+    // 经过这种边回到自身的环就是左递归, 展开永远不会结束
+    fn check_left_recursion(&self) -> anyhow::Result<()> {
+        let nullable = self.nullable();
+        let mut edges = vec![Vec::new(); self.rules.len()];
+        for (rule, alts) in self.rules.iter().enumerate() {
+            for seq in alts {
+                for (idx, element) in seq.iter().enumerate() {
+                    let Element::Rule(next) = element else {
+                        break;
+                    };
+                    edges[rule].push((*next, idx + 1 < seq.len()));
+                    if !nullable[*next] {
+                        break;
+                    }
+                }
+            }
+        }
+        let reaches = |from: usize, to: usize| {
+            let mut seen = vec![false; edges.len()];
+            let mut queue = vec![from];
+            while let Some(rule) = queue.pop() {
+                if rule == to {
+                    return true;
+                }
+                if !std::mem::replace(&mut seen[rule], true) {
+                    queue.extend(edges[rule].iter().map(|&(next, _)| next));
+                }
+            }
+            false
+        };
+        for (rule, rule_edges) in edges.iter().enumerate() {
+            for &(next, grows) in rule_edges {
+                if grows && reaches(next, rule) {
+                    let name = |id: usize| self.names.iter().find(|&(_, &named)| named == id).map(|(name, _)| name);
+                    return Err(match name(rule).or_else(|| name(next)) {
+                        Some(name) => anyhow::anyhow!("grammar rule `{}` is left-recursive", name),
+                        None => anyhow::anyhow!("grammar is left-recursive"),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn build(self, root: usize) -> anyhow::Result<Grammar> {
+        for (name, &id) in &self.names {
+            if !self.defined[id] {
+                return Err(anyhow::anyhow!("grammar rule `{}` is not defined", name));
+            }
+        }
+        self.check_left_recursion()?;
+        Ok(Grammar {
+            rules: self.rules,
+            root,
+        })
+    }
+}
+
+// 生成过程中的约束状态
+// token 按字节匹配, 一个字符可能被拆到多个 token 中, 没凑完整的字节留到下一个 token
+#[derive(Debug, Clone)]
+pub struct GuidedMatcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+    partial: Vec<u8>,
+    // 每个约束状态下可选的 token, 同一状态反复出现时(如 JSON 字符串内部)不必重新遍历前缀树
+    allowed_cache: HashMap<(Vec<Stack>, Vec<u8>), Vec<u32>>,
+}
+
+// 缓存的约束状态数上限, 超过时清空
+const MAX_CACHED_STATES: usize = 1024;
+
+impl GuidedMatcher {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let stacks = grammar.initial_stacks();
+        GuidedMatcher {
+            grammar,
+            stacks,
+            partial: Vec::new(),
+            allowed_cache: HashMap::new(),
+        }
+    }
+
+    // 当前输出已经是一个完整匹配, 可以结束
+    pub fn is_accepting(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(|stack| stack.is_empty())
+    }
+
+    pub fn accept_str(&mut self, text: &str) -> bool {
+        self.accept_bytes(text.as_bytes())
+    }
+
+    // 不能接受时不修改状态
+    pub fn accept_bytes(&mut self, bytes: &[u8]) -> bool {
+        let mut stacks = self.stacks.clone();
+        let mut partial = self.partial.clone();
+        for &byte in bytes {
+            partial.push(byte);
+            match decode_partial(&partial) {
+                Utf8Prefix::Char(c) => {
+                    stacks = self.grammar.advance(&stacks, c);
+                    partial.clear();
+                    if stacks.is_empty() {
+                        return false;
+                    }
+                }
+                Utf8Prefix::Partial(lo, hi) => {
+                    if !self.grammar.may_advance(&stacks, lo, hi) {
+                        return false;
+                    }
+                }
+                Utf8Prefix::Invalid => return false,
+            }
+        }
+        self.stacks = stacks;
+        self.partial = partial;
+        true
+    }
+
+    // 同一个 matcher 只配合一个词表使用
+    pub fn allowed_tokens(&mut self, trie: &TokenTrie) -> Vec<u32> {
+        let key = (self.stacks.clone(), self.partial.clone());
+        if let Some(allowed) = self.allowed_cache.get(&key) {
+            return allowed.clone();
+        }
+        let mut out = Vec::new();
+        let mut partial = self.partial.clone();
+        self.collect(trie, 0, &self.stacks, &mut partial, &mut out);
+        if self.allowed_cache.len() >= MAX_CACHED_STATES {
+            self.allowed_cache.clear();
+        }
+        self.allowed_cache.insert(key, out.clone());
+        out
+    }
+
+    // 文法拒绝某个前缀时不再进入它的子树
+    fn collect(&self, trie: &TokenTrie, node: usize, stacks: &[Stack], partial: &mut Vec<u8>, out: &mut Vec<u32>) {
+        for (&byte, &child) in &trie.nodes[node].children {
+            partial.push(byte);
+            match decode_partial(partial) {
+                Utf8Prefix::Char(c) => {
+                    let next = self.grammar.advance(stacks, c);
+                    if !next.is_empty() {
+                        out.extend_from_slice(&trie.nodes[child].tokens);
+                        self.collect(trie, child, &next, &mut Vec::new(), out);
+                    }
+                }
+                Utf8Prefix::Partial(lo, hi) => {
+                    if self.grammar.may_advance(stacks, lo, hi) {
+                        out.extend_from_slice(&trie.nodes[child].tokens);
+                        self.collect(trie, child, stacks, partial, out);
+                    }
+                }
+                Utf8Prefix::Invalid => {}
+            }
+            partial.pop();
+        }
+    }
+}
+
+enum Utf8Prefix {
+    Char(char),
+    // 还缺后续字节, 可能组成的码点范围
+    Partial(u32, u32),
+    Invalid,
+}
+
+fn decode_partial(bytes: &[u8]) -> Utf8Prefix {
+    let Some(&lead) = bytes.first() else {
+        return Utf8Prefix::Invalid;
+    };
+    let width = match lead {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => return Utf8Prefix::Invalid,
+    };
+    if bytes.len() > width || bytes[1..].iter().any(|&byte| !(0x80..=0xBF).contains(&byte)) {
+        return Utf8Prefix::Invalid;
+    }
+    if bytes.len() == width {
+        return match std::str::from_utf8(bytes).ok().and_then(|text| text.chars().next()) {
+            Some(c) => Utf8Prefix::Char(c),
+            None => Utf8Prefix::Invalid,
+        };
+    }
+    let code_point = |fill: u8| {
+        let lead_bits = (lead as u32) & (0x7F >> width);
+        (1..width).fold(lead_bits, |code, index| {
+            let byte = bytes.get(index).copied().unwrap_or(fill);
+            (code << 6) | (byte as u32 & 0x3F)
+        })
+    };
+    Utf8Prefix::Partial(code_point(0x80), code_point(0xBF))
+}
+
+// 词表前缀树, 按 token 的原始字节建立, 共享前缀的 token 只需要推进一次文法状态
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<u8, usize>,
+    tokens: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl TokenTrie {
+    pub fn new(tokens: impl IntoIterator<Item = (u32, String)>) -> Self {
+        Self::from_bytes(tokens.into_iter().map(|(id, text)| (id, text.into_bytes())))
+    }
+
+    // 字节级 BPE 中单个 token 可能只是一个字符的部分 utf-8 字节
+    pub fn from_bytes(tokens: impl IntoIterator<Item = (u32, Vec<u8>)>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in tokens {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for byte in bytes {
+                node = match nodes[node].children.get(&byte) {
+                    Some(&child) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(byte, child);
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id);
+        }
+        TokenTrie { nodes }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    builder: &'a mut GrammarBuilder,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &str, builder: &'a mut GrammarBuilder) -> Self {
+        Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            builder,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> anyhow::Result<char> {
+        let c = self
+            .peek()
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of pattern"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        let c = self.next()?;
+        if c != expected {
+            return Err(anyhow::anyhow!(
+                "expected `{}` at {}, found `{}`",
+                expected,
+                self.pos - 1,
+                c
+            ));
+        }
+        Ok(())
+    }
+
+    fn parse_number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return None;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    // {m} {m,} {m,n}, 不是合法的计数量词时回退并返回 None
+    fn parse_braces(&mut self) -> anyhow::Result<Option<(usize, Option<usize>)>> {
+        let start = self.pos;
+        self.pos += 1;
+        let Some(min) = self.parse_number() else {
+            self.pos = start;
+            return Ok(None);
+        };
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.parse_number()
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some('}') {
+            self.pos = start;
+            return Ok(None);
+        }
+        self.pos += 1;
+        if matches!(max, Some(max) if max < min) {
+            return Err(anyhow::anyhow!("invalid repetition {{{},{:?}}}", min, max));
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn parse_quantifier(&mut self, item: Sequence, allow_lazy: bool) -> anyhow::Result<Sequence> {
+        let (min, max) = match self.peek() {
+            Some('*') => {
+                self.pos += 1;
+                (0, None)
+            }
+            Some('+') => {
+                self.pos += 1;
+                (1, None)
+            }
+            Some('?') => {
+                self.pos += 1;
+                (0, Some(1))
+            }
+            Some('{') => match self.parse_braces()? {
+                Some(range) => range,
+                None => return Ok(item),
+            },
+            _ => return Ok(item),
+        };
+        // 非贪婪标记对整串匹配没有意义, 直接忽略
+        if allow_lazy && self.peek() == Some('?') {
+            self.pos += 1;
+        }
+        self.builder.repeat(item, min, max)
+    }
+
+    fn parse_escape(&mut self) -> anyhow::Result<CharClass> {
+        let c = self.next()?;
+        let class = match c {
+            'd' => ranges(&[('0', '9')], false),
+            'D' => ranges(&[('0', '9')], true),
+            'w' => ranges(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')], false),
+            'W' => ranges(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')], true),
+            's' => ranges(&[(' ', ' '), ('\t', '\r')], false),
+            'S' => ranges(&[(' ', ' '), ('\t', '\r')], true),
+            'n' => CharClass::single('\n'),
+            't' => CharClass::single('\t'),
+            'r' => CharClass::single('\r'),
+            'f' => CharClass::single('\x0c'),
+            'v' => CharClass::single('\x0b'),
+            '0' => CharClass::single('\0'),
+            'x' => CharClass::single(self.parse_hex(2)?),
+            'u' => CharClass::single(self.parse_hex(4)?),
+            other => CharClass::single(other),
+        };
+        Ok(class)
+    }
+
+    fn parse_hex(&mut self, len: usize) -> anyhow::Result<char> {
+        let mut value = 0u32;
+        for _ in 0..len {
+            let c = self.next()?;
+            let digit = c
+                .to_digit(16)
+                .ok_or_else(|| anyhow::anyhow!("invalid hex escape digit `{}`", c))?;
+            value = value * 16 + digit;
+        }
+        char::from_u32(value).ok_or_else(|| anyhow::anyhow!("invalid unicode escape {:x}", value))
+    }
+
+    // [...] 字符类, 调用时 pos 指向 `[`
+    fn parse_class(&mut self) -> anyhow::Result<CharClass> {
+        self.expect('[')?;
+        let negated = if self.peek() == Some('^') {
+            self.pos += 1;
+            true
+        } else {
+            false
+        };
+        let mut class_ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next()?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let escaped = self.parse_escape()?;
+                if escaped.negated {
+                    return Err(anyhow::anyhow!("negated escapes are not supported inside []"));
+                }
+                if escaped.ranges.len() != 1 || escaped.ranges[0].0 != escaped.ranges[0].1 {
+                    class_ranges.extend(escaped.ranges);
+                    continue;
+                }
+                escaped.ranges[0].0
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && !matches!(self.peek_at(1), Some(']') | None) {
+                self.pos += 1;
+                let c = self.next()?;
+                let hi = if c == '\\' {
+                    let escaped = self.parse_escape()?;
+                    escaped.ranges[0].0
+                } else {
+                    c
+                };
+                if hi < lo {
+                    return Err(anyhow::anyhow!("invalid character range {}-{}", lo, hi));
+                }
+                class_ranges.push((lo, hi));
+            } else {
+                class_ranges.push((lo, lo));
+            }
+        }
+        Ok(CharClass {
+            ranges: class_ranges,
+            negated,
+        })
+    }
+
+    // ---------------- 正则 ----------------
+
+    fn parse_regex_alternation(&mut self) -> anyhow::Result<Vec<Sequence>> {
+        let mut alts = vec![self.parse_regex_sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_regex_sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_regex_sequence(&mut self) -> anyhow::Result<Sequence> {
+        let mut seq = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_regex_atom()?;
+            let atom = self.parse_quantifier(atom, true)?;
+            seq.extend(atom);
+        }
+        Ok(seq)
+    }
+
+    fn parse_regex_atom(&mut self) -> anyhow::Result<Sequence> {
+        let c = self.peek().ok_or_else(|| anyhow::anyhow!("unexpected end of pattern"))?;
+        let atom = match c {
+            '(' => {
+                self.pos += 1;
+                if self.peek() == Some('?') {
+                    if self.peek_at(1) != Some(':') {
+                        return Err(anyhow::anyhow!("only (?:...) groups are supported"));
+                    }
+                    self.pos += 2;
+                }
+                let alts = self.parse_regex_alternation()?;
+                self.expect(')')?;
+                vec![Element::Rule(self.builder.new_rule(alts))]
+            }
+            '[' => vec![Element::Char(self.parse_class()?)],
+            '.' => {
+                self.pos += 1;
+                vec![Element::Char(CharClass::any())]
+            }
+            '\\' => {
+                self.pos += 1;
+                vec![Element::Char(self.parse_escape()?)]
+            }
+            // 约束本身就是整串匹配, 锚点不需要额外处理
+            '^' | '$' => {
+                self.pos += 1;
+                Vec::new()
+            }
+            '*' | '+' | '?' => {
+                return Err(anyhow::anyhow!("nothing to repeat at {}", self.pos));
+            }
+            _ => {
+                self.pos += 1;
+                vec![Element::Char(CharClass::single(c))]
+            }
+        };
+        Ok(atom)
+    }
+
+    // ---------------- GBNF ----------------
+
+    // 跳过空白和 # 注释, 顶层规则里换行表示规则结束
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while matches!(self.peek(), Some(c) if c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == ' ' || c == '\t' || c == '\r' || (newline_ok && c == '\n') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> anyhow::Result<String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(anyhow::anyhow!("expected rule name at {}", self.pos));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_gbnf_rules(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect(':')?;
+            self.expect(':')?;
+            self.expect('=')?;
+            let alts = self.parse_gbnf_alternation(false)?;
+            let id = self.builder.named_rule(&name);
+            if self.builder.defined[id] {
+                return Err(anyhow::anyhow!("grammar rule `{}` is defined twice", name));
+            }
+            self.builder.rules[id] = alts;
+            self.builder.defined[id] = true;
+        }
+    }
+
+    fn parse_gbnf_alternation(&mut self, nested: bool) -> anyhow::Result<Vec<Sequence>> {
+        let mut alts = vec![self.parse_gbnf_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_gbnf_sequence(nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_gbnf_sequence(&mut self, nested: bool) -> anyhow::Result<Sequence> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space(true);
+            let Some(c) = self.peek() else {
+                break;
+            };
+            let atom = match c {
+                '|' => break,
+                ')' if nested => break,
+                '"' => {
+                    self.pos += 1;
+                    let mut literal = Vec::new();
+                    loop {
+                        let c = self.next()?;
+                        match c {
+                            '"' => break,
+                            '\\' => literal.push(Element::Char(self.parse_escape()?)),
+                            _ => literal.push(Element::Char(CharClass::single(c))),
+                        }
+                    }
+                    literal
+                }
+                '[' => vec![Element::Char(self.parse_class()?)],
+                '.' => {
+                    self.pos += 1;
+                    vec![Element::Char(CharClass::any())]
+                }
+                '(' => {
+                    self.pos += 1;
+                    let alts = self.parse_gbnf_alternation(true)?;
+                    self.skip_space(true);
+                    self.expect(')')?;
+                    vec![Element::Rule(self.builder.new_rule(alts))]
+                }
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    // 下一行的 `name ::=` 是新规则的开始
+                    let start = self.pos;
+                    let name = self.parse_name()?;
+                    self.skip_space(false);
+                    if !nested && self.peek() == Some(':') {
+                        self.pos = start;
+                        break;
+                    }
+                    vec![Element::Rule(self.builder.named_rule(&name))]
+                }
+                other => {
+                    return Err(anyhow::anyhow!(
+                        "unexpected `{}` in grammar at {}",
+                        other,
+                        self.pos
+                    ));
+                }
+            };
+            let atom = self.parse_quantifier(atom, false)?;
+            seq.extend(atom);
+        }
+        Ok(seq)
+    }
+}
+
+fn ranges(class_ranges: &[(char, char)], negated: bool) -> CharClass {
+    CharClass {
+        ranges: class_ranges.to_vec(),
+        negated,
+    }
+}
+
+pub fn parse_regex(pattern: &str) -> anyhow::Result<Grammar> {
+    let mut builder = GrammarBuilder::default();
+    let mut parser = Parser::new(pattern, &mut builder);
+    let alts = parser
+        .parse_regex_alternation()
+        .map_err(|e| anyhow::anyhow!("invalid guided_regex: {}", e))?;
+    if parser.peek().is_some() {
+        return Err(anyhow::anyhow!(
+            "invalid guided_regex: unbalanced `)` at {}",
+            parser.pos
+        ));
+    }
+    let root = builder.new_rule(alts);
+    builder.build(root)
+}
+
+pub fn parse_gbnf(text: &str) -> anyhow::Result<Grammar> {
+    let mut builder = GrammarBuilder::default();
+    Parser::new(text, &mut builder)
+        .parse_gbnf_rules()
+        .map_err(|e| anyhow::anyhow!("invalid guided_grammar: {}", e))?;
+    let root = *builder
+        .names
+        .get("root")
+        .ok_or_else(|| anyhow::anyhow!("invalid guided_grammar: missing `root` rule"))?;
+    builder
+        .build(root)
+        .map_err(|e| anyhow::anyhow!("invalid guided_grammar: {}", e))
+}
@@ -0,0 +1,229 @@
+use candle_core::{DType, Device, Result, Tensor};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+// 分页的 KV cache 显存池, 按块分配给序列, 类似 vLLM 的块管理
+// 每层一对 (k, v), shape 为 (num_blocks * block_size, num_kv_heads, head_dim), 块 i 占第 i * block_size 行开始的 block_size 行
+// 设置了显存预算时池的块数固定, 启动时一次分配, 块用完时由调度器淘汰会话、抢占序列或拒绝请求
+// 不设置预算时池按需扩容
+
+// 默认每块的 token 数
+pub const KV_BLOCK_SIZE: usize = 16;
+
+// KV cache 的形状, 一个池只服务于一种形状的模型
+#[derive(Debug, Clone)]
+pub struct KvLayout {
+    pub num_layers: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+    pub dtype: DType,
+    pub device: Device,
+}
+
+impl KvLayout {
+    // 一个 token 在所有层的 K 和 V 占用的字节数
+    pub fn bytes_per_token(&self) -> u64 {
+        (2 * self.num_layers * self.num_kv_heads * self.head_dim * self.dtype.size_in_bytes()) as u64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KvPool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    layout: KvLayout,
+    block_size: usize,
+    // None 表示按需扩容
+    max_blocks: Option<usize>,
+    state: Mutex<PoolState>,
+    // 每次归还块时唤醒等待的请求
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    layers: Vec<(Tensor, Tensor)>,
+    num_blocks: usize,
+    free: Vec<u32>,
+}
+
+// 池中的一个块, 共享前缀的序列持有同一个 Arc<Block>, 最后一个引用 drop 时归还到池中
+#[derive(Debug)]
+pub struct Block {
+    id: u32,
+    pool: KvPool,
+}
+
+impl Block {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.pool.inner.state.lock().unwrap().free.push(self.id);
+        self.pool.inner.released.notify_waiters();
+    }
+}
+
+impl KvPool {
+    // 固定 num_blocks 个块, 显存在这里一次分配
+    pub fn new(layout: KvLayout, block_size: usize, num_blocks: usize) -> Result<Self> {
+        let pool = Self::with_limit(layout, block_size, Some(num_blocks));
+        pool.inner.state.lock().unwrap().grow(&pool.inner.layout, block_size, num_blocks)?;
+        Ok(pool)
+    }
+
+    // 不限制块数, 块不够时扩容, 已有的块原样复制到新的池中
+    pub fn growable(layout: KvLayout, block_size: usize) -> Self {
+        Self::with_limit(layout, block_size, None)
+    }
+
+    fn with_limit(layout: KvLayout, block_size: usize, max_blocks: Option<usize>) -> Self {
+        KvPool {
+            inner: Arc::new(PoolInner {
+                layout,
+                block_size: block_size.max(1),
+                max_blocks,
+                state: Mutex::new(PoolState {
+                    layers: Vec::new(),
+                    num_blocks: 0,
+                    free: Vec::new(),
+                }),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn layout(&self) -> &KvLayout {
+        &self.inner.layout
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.inner.block_size
+    }
+
+    pub fn block_bytes(&self) -> u64 {
+        self.inner.layout.bytes_per_token() * self.inner.block_size as u64
+    }
+
+    // 固定大小的池的块数, 按需扩容的池返回 None
+    pub fn max_blocks(&self) -> Option<usize> {
+        self.inner.max_blocks
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.inner.state.lock().unwrap().num_blocks
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.inner.state.lock().unwrap().free.len()
+    }
+
+    pub fn used_blocks(&self) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.num_blocks - state.free.len()
+    }
+
+    // 能否再分配 blocks 个块, 按需扩容的池总是可以
+    pub fn has_free(&self, blocks: usize) -> bool {
+        self.inner.max_blocks.is_none() || self.free_blocks() >= blocks
+    }
+
+    // 一次分配 count 个块, 不够时不分配任何块并返回错误
+    pub fn allocate(&self, count: usize) -> Result<Vec<Arc<Block>>> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.free.len() < count {
+            match self.inner.max_blocks {
+                Some(_) => candle_core::bail!(
+                    "kv cache pool exhausted: need {} blocks, {} free",
+                    count,
+                    state.free.len()
+                ),
+                None => {
+                    let extra = (count - state.free.len()).max(state.num_blocks);
+                    state.grow(&self.inner.layout, self.inner.block_size, extra)?;
+                }
+            }
+        }
+        let start = state.free.len() - count;
+        let ids = state.free.split_off(start);
+        drop(state);
+        Ok(ids
+            .into_iter()
+            .rev()
+            .map(|id| {
+                Arc::new(Block {
+                    id,
+                    pool: self.clone(),
+                })
+            })
+            .collect())
+    }
+
+    // 把 rows 个 token 的 (k, v) 写入第 layer 层、块 block 的第 offset 行开始的位置, shape 为 (rows, num_kv_heads, head_dim)
+    pub fn write(&self, layer: usize, block: &Block, offset: usize, k: &Tensor, v: &Tensor) -> Result<()> {
+        let state = self.inner.state.lock().unwrap();
+        let (cache_k, cache_v) = &state.layers[layer];
+        let row = block.id as usize * self.inner.block_size + offset;
+        cache_k.slice_set(k, 0, row)?;
+        cache_v.slice_set(v, 0, row)
+    }
+
+    // 按行号取出第 layer 层的 (k, v), shape 为 (slots.len(), num_kv_heads, head_dim)
+    pub fn gather(&self, layer: usize, slots: &Tensor) -> Result<(Tensor, Tensor)> {
+        let state = self.inner.state.lock().unwrap();
+        let (k, v) = &state.layers[layer];
+        Ok((k.index_select(slots, 0)?, v.index_select(slots, 0)?))
+    }
+
+    // 写时复制: 把 src 的前 rows 行复制到 dst
+    pub fn copy_block(&self, src: &Block, dst: &Block, rows: usize) -> Result<()> {
+        if rows == 0 {
+            return Ok(());
+        }
+        let state = self.inner.state.lock().unwrap();
+        let block_size = self.inner.block_size;
+        for (k, v) in state.layers.iter() {
+            let from = src.id as usize * block_size;
+            let to = dst.id as usize * block_size;
+            k.slice_set(&k.narrow(0, from, rows)?.copy()?, 0, to)?;
+            v.slice_set(&v.narrow(0, from, rows)?.copy()?, 0, to)?;
+        }
+        Ok(())
+    }
+
+    // 等待下一次归还, 最多等 timeout, 之后由调用方重新检查空闲的块
+    // 检查和开始等待之间的归还会错过, 所以总是带超时
+    pub async fn released(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.inner.released.notified()).await;
+    }
+}
+
+impl PoolState {
+    // 增加 extra 个块, 新的块放在空闲列表的底部, 已有的空闲块先分配
+    fn grow(&mut self, layout: &KvLayout, block_size: usize, extra: usize) -> Result<()> {
+        let num_blocks = self.num_blocks + extra;
+        let shape = (num_blocks * block_size, layout.num_kv_heads, layout.head_dim);
+        let mut layers = Vec::with_capacity(layout.num_layers);
+        for layer in 0..layout.num_layers {
+            let k = Tensor::zeros(shape, layout.dtype, &layout.device)?;
+            let v = Tensor::zeros(shape, layout.dtype, &layout.device)?;
+            if let Some((old_k, old_v)) = self.layers.get(layer) {
+                k.slice_set(old_k, 0, 0)?;
+                v.slice_set(old_v, 0, 0)?;
+            }
+            layers.push((k, v));
+        }
+        self.layers = layers;
+        let new_ids = (self.num_blocks as u32..num_blocks as u32).rev();
+        self.free.splice(0..0, new_ids);
+        self.num_blocks = num_blocks;
+        Ok(())
+    }
+}
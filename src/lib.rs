@@ -1,22 +1,72 @@
-use crate::qwen3::Qwen3;
+use crate::anthropic::{MessageStream, MessagesRequest, message_response};
+use crate::batch::{BatchRequest, BatchStore, DEFAULT_BATCH_CONCURRENCY};
+use crate::beam::BeamSearchParam;
+use crate::completion::{CompletionRequest, LegacyLogprobs, PromptInput};
+use crate::embedding::{EmbeddingRequest, encode_base64, postprocess};
+use crate::error::ApiError;
+use crate::guided::GuidedDecoding;
+use crate::ollama::{OllamaChatRequest, OllamaGenerateRequest, OllamaReply};
+use crate::qwen3::{
+    GenerateParam, GenerateTask, GenerateToken, Generation, MAX_TOP_LOGPROBS, Qwen3, StopReason, TokenLogprob,
+};
+use crate::rerank::{RerankRequest, ranked};
+use crate::responses::{ResponseStore, ResponseStream, ResponsesRequest, StoredResponse};
+use crate::sampling::SamplingParam;
+use crate::score::{ScoreRequest, log_likelihood};
+use crate::segment::{BlockParser, Segment};
+use crate::stop::StopSequences;
 use openai_dive::v1::resources::chat::{
     ChatCompletionChoice, ChatCompletionChunkChoice, ChatCompletionChunkResponse,
     ChatCompletionResponse, ChatMessage, ChatMessageContent, DeltaChatMessage, DeltaFunction,
-    DeltaToolCall, Function as ChatFunction, ToolCall,
+    DeltaToolCall, Function as ChatFunction, LogProbs, LogProbsContent, ToolCall, TopLogProbs,
 };
 use openai_dive::v1::resources::shared::FinishReason;
 use rocket::async_stream::stream;
+use rocket::futures::future::try_join_all;
+use rocket::futures::stream::select_all;
 use rocket::futures::{Stream, StreamExt};
 use serde_json::Value;
-use std::sync::{Arc, OnceLock};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tokio::sync::RwLock;
 
+pub mod anthropic;
+pub mod batch;
+pub mod beam;
+pub mod completion;
+pub mod detokenizer;
+pub mod embedding;
+pub mod error;
+pub mod guided;
+pub mod kv_pool;
+pub mod model;
+pub mod ollama;
 pub mod qwen3;
+pub mod repl;
+pub mod rerank;
+pub mod responses;
+pub mod sampling;
+pub mod score;
+pub mod segment;
+pub mod session;
+pub mod speculative;
+pub mod stop;
 pub mod utils;
 
 const MODEL_NAME: &str = "qwen3-0.6b";
+// KV cache 预算不够时每次最多等待的时间, 之后重新检查
+const KV_BUDGET_WAIT: Duration = Duration::from_millis(50);
+
+// 单个请求最多生成的 choice 数, 与 OpenAI 一致
+pub const MAX_CHOICES: usize = 128;
+pub const MAX_BEAM_WIDTH: usize = 32;
 
 static MODEL: OnceLock<Arc<RwLock<Qwen3>>> = OnceLock::new();
+static SESSION_DIR: OnceLock<PathBuf> = OnceLock::new();
+static RESPONSES: OnceLock<Mutex<ResponseStore>> = OnceLock::new();
+static BATCHES: OnceLock<BatchStore> = OnceLock::new();
 
 // 主请求结构体
 #[derive(Debug, serde::Deserialize)]
@@ -24,18 +74,457 @@ pub struct ChatRequest {
     pub messages: Vec<Message>,
     pub tools: Option<Vec<Tool>>,
     pub stream: Option<bool>,
+    // vLLM 风格的约束解码扩展字段
+    pub guided_regex: Option<String>,
+    pub guided_choice: Option<Vec<String>>,
+    pub guided_grammar: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub max_completion_tokens: Option<usize>,
+    // "disabled"(默认): 超长直接报错; "auto": 从最早的轮次开始丢弃历史消息
+    pub truncation: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    // 以下为 vLLM 风格的采样扩展字段, top_k <= 0 表示不限制
+    pub top_k: Option<i64>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    // 与 OpenAI 一致, key 为字符串形式的 token id
+    pub logit_bias: Option<HashMap<String, f32>>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+    pub n: Option<usize>,
+    // beam search 扩展字段, 开启后返回 n 个(默认 beam_width 个)得分最高的 beam
+    pub use_beam_search: Option<bool>,
+    pub beam_width: Option<usize>,
+    pub length_penalty: Option<f32>,
+    pub early_stopping: Option<bool>,
+    // 会话扩展字段, 生成后保留 KV cache, 同一会话的下一次请求复用相同的前缀
+    pub session_id: Option<String>,
+    // 停止字符串, 输出中不包含
+    pub stop: Option<StopSequences>,
+    // vLLM 风格的模板参数
+    pub chat_template_kwargs: Option<ChatTemplateKwargs>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct ChatTemplateKwargs {
+    // 默认开启思考
+    pub enable_thinking: Option<bool>,
+}
+
+impl ChatRequest {
+    pub fn guided_decoding(&self) -> Result<Option<(GuidedDecoding, &'static str)>, ApiError> {
+        let mut guided = Vec::new();
+        if let Some(regex) = &self.guided_regex {
+            guided.push((GuidedDecoding::Regex(regex.clone()), "guided_regex"));
+        }
+        if let Some(choice) = &self.guided_choice {
+            guided.push((GuidedDecoding::Choice(choice.clone()), "guided_choice"));
+        }
+        if let Some(grammar) = &self.guided_grammar {
+            guided.push((GuidedDecoding::Grammar(grammar.clone()), "guided_grammar"));
+        }
+        if guided.len() > 1 {
+            return Err(ApiError::invalid_request(
+                "only one of guided_regex, guided_choice and guided_grammar can be set",
+                Some(guided[1].1),
+            ));
+        }
+        Ok(guided.pop())
+    }
+
+    pub fn is_guided(&self) -> bool {
+        self.guided_regex.is_some() || self.guided_choice.is_some() || self.guided_grammar.is_some()
+    }
+
+    // 约束解码时关闭思考, 让约束作用于整个输出
+    pub fn enable_thinking(&self) -> bool {
+        let enable_thinking = self
+            .chat_template_kwargs
+            .as_ref()
+            .and_then(|kwargs| kwargs.enable_thinking)
+            .unwrap_or(true);
+        enable_thinking && !self.is_guided()
+    }
+
+    pub fn stop(&self) -> Vec<String> {
+        self.stop.as_ref().map(StopSequences::to_vec).unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.messages.is_empty() {
+            return Err(ApiError::invalid_request(
+                "messages must contain at least one message",
+                Some("messages"),
+            ));
+        }
+        for (index, message) in self.messages.iter().enumerate() {
+            if !matches!(message.role.as_str(), "system" | "user" | "assistant" | "tool") {
+                return Err(ApiError::invalid_request(
+                    format!(
+                        "messages[{}]: unknown role `{}`, expected one of system, user, assistant, tool",
+                        index, message.role
+                    ),
+                    Some(&format!("messages[{}].role", index)),
+                ));
+            }
+        }
+        if let Some(truncation) = &self.truncation
+            && truncation != "auto"
+            && truncation != "disabled"
+        {
+            return Err(ApiError::invalid_request(
+                format!("truncation must be `auto` or `disabled`, got `{}`", truncation),
+                Some("truncation"),
+            ));
+        }
+        if self.max_tokens() == Some(0) {
+            return Err(ApiError::invalid_request(
+                "max_tokens must be at least 1",
+                Some("max_tokens"),
+            ));
+        }
+        if let Some(n) = self.n
+            && !(1..=MAX_CHOICES).contains(&n)
+        {
+            return Err(ApiError::invalid_request(
+                format!("n must be in [1, {}], got {}", MAX_CHOICES, n),
+                Some("n"),
+            ));
+        }
+        if self.stop().iter().any(String::is_empty) {
+            return Err(ApiError::invalid_request("stop sequences cannot be empty", Some("stop")));
+        }
+        self.validate_beam_search()?;
+        self.validate_sampling()?;
+        self.validate_content_parts()?;
+        self.validate_tool_call_ids()
+    }
+
+    pub fn validate_sampling(&self) -> Result<(), ApiError> {
+        validate_sampling_param(&self.sampling_param())?;
+        self.logit_bias()?;
+        self.validate_logprobs()
+    }
+
+    pub fn validate_beam_search(&self) -> Result<(), ApiError> {
+        let Some(beam) = self.beam_search() else {
+            return Ok(());
+        };
+        if !(1..=MAX_BEAM_WIDTH).contains(&beam.beam_width) {
+            return Err(ApiError::invalid_request(
+                format!("beam_width must be in [1, {}], got {}", MAX_BEAM_WIDTH, beam.beam_width),
+                Some("beam_width"),
+            ));
+        }
+        if let Some(n) = self.n
+            && n > beam.beam_width
+        {
+            return Err(ApiError::invalid_request(
+                format!("n ({}) must not exceed beam_width ({}) with beam search", n, beam.beam_width),
+                Some("n"),
+            ));
+        }
+        // 与 /chat/completions 一致, 没有指定 stream 时按流式处理
+        let unsupported = [
+            ("stream", self.stream != Some(false)),
+            ("logprobs", self.logprobs == Some(true)),
+            ("guided decoding", self.is_guided()),
+            ("stop", !self.stop().is_empty()),
+        ];
+        for (feature, enabled) in unsupported {
+            if enabled {
+                return Err(ApiError::invalid_request(
+                    format!("{} is not supported with beam search", feature),
+                    Some("use_beam_search"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // 未指定 beam_width 时使用 n, 都没有时为 4
+    pub fn beam_search(&self) -> Option<BeamSearchParam> {
+        if self.use_beam_search != Some(true) {
+            return None;
+        }
+        let default = BeamSearchParam::default();
+        Some(BeamSearchParam {
+            beam_width: self.beam_width.or(self.n).unwrap_or(default.beam_width),
+            length_penalty: self.length_penalty.unwrap_or(default.length_penalty),
+            early_stopping: self.early_stopping.unwrap_or(default.early_stopping),
+        })
+    }
+
+    pub fn validate_logprobs(&self) -> Result<(), ApiError> {
+        let Some(top_logprobs) = self.top_logprobs else {
+            return Ok(());
+        };
+        if top_logprobs > MAX_TOP_LOGPROBS {
+            return Err(ApiError::invalid_request(
+                format!("top_logprobs must be in [0, {}], got {}", MAX_TOP_LOGPROBS, top_logprobs),
+                Some("top_logprobs"),
+            ));
+        }
+        if self.logprobs != Some(true) {
+            return Err(ApiError::invalid_request(
+                "logprobs must be set to true if top_logprobs is used",
+                Some("top_logprobs"),
+            ));
+        }
+        Ok(())
+    }
+
+    // Some(n) 表示需要返回 logprobs, n 为 top_logprobs 个数
+    pub fn logprobs(&self) -> Option<usize> {
+        match self.logprobs {
+            Some(true) => Some(self.top_logprobs.unwrap_or(0)),
+            _ => None,
+        }
+    }
+
+    pub fn logit_bias(&self) -> Result<Option<HashMap<u32, f32>>, ApiError> {
+        self.logit_bias.as_ref().map(parse_logit_bias).transpose()
+    }
+
+    pub fn sampling_param(&self) -> SamplingParam {
+        SamplingParam {
+            temperature: self.temperature,
+            top_k: self.top_k.filter(|&top_k| top_k > 0).map(|top_k| top_k as usize),
+            top_p: self.top_p,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            seed: self.seed,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            repetition_penalty: self.repetition_penalty,
+            logit_bias: self.logit_bias().ok().flatten(),
+        }
+    }
+
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_completion_tokens.or(self.max_tokens)
+    }
+
+    pub fn truncation_auto(&self) -> bool {
+        self.truncation.as_deref() == Some("auto")
+    }
+
+    // 开头连续的 system 消息在截断时始终保留
+    pub fn system_prefix_len(&self) -> usize {
+        self.messages
+            .iter()
+            .take_while(|message| message.role == "system")
+            .count()
+    }
+
+    // 截断后历史可以开始的位置: 不截断, 或从之后的某条 user 消息开始
+    // 一轮对话里的 assistant tool_calls 和对应的 tool 结果总是一起保留或丢弃
+    pub fn truncation_starts(&self) -> Vec<usize> {
+        let system_len = self.system_prefix_len();
+        let mut starts = vec![system_len];
+        starts.extend(
+            self.messages
+                .iter()
+                .enumerate()
+                .skip(system_len + 1)
+                .filter(|(_, message)| message.role == "user")
+                .map(|(index, _)| index),
+        );
+        starts
+    }
+
+    // 目前只支持文本, image_url / input_audio 等 part 直接拒绝
+    pub fn validate_content_parts(&self) -> Result<(), ApiError> {
+        for (index, message) in self.messages.iter().enumerate() {
+            let Some(MessageContent::Parts(parts)) = &message.content else {
+                continue;
+            };
+            for (part_index, part) in parts.iter().enumerate() {
+                let param = format!("messages[{}].content[{}]", index, part_index);
+                if part.part_type != "text" {
+                    return Err(ApiError::invalid_request(
+                        format!(
+                            "{}: unsupported content part type `{}`, only `text` is supported",
+                            param, part.part_type
+                        ),
+                        Some(&param),
+                    ));
+                }
+                if part.text.is_none() {
+                    return Err(ApiError::invalid_request(
+                        format!("{}: text part is missing `text`", param),
+                        Some(&param),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // tool 消息引用的 tool_call_id 必须出现在之前 assistant 消息的 tool_calls 中
+    pub fn validate_tool_call_ids(&self) -> Result<(), ApiError> {
+        let mut tool_call_ids = HashSet::new();
+        for (index, message) in self.messages.iter().enumerate() {
+            if message.role == "assistant"
+                && let Some(Value::Array(tool_calls)) = &message.tool_calls
+            {
+                for tool_call in tool_calls {
+                    if let Some(id) = tool_call.get("id").and_then(|id| id.as_str()) {
+                        tool_call_ids.insert(id);
+                    }
+                }
+            } else if message.role == "tool" {
+                let param = format!("messages[{}].tool_call_id", index);
+                match &message.tool_call_id {
+                    Some(id) if tool_call_ids.contains(id.as_str()) => {}
+                    Some(id) => {
+                        return Err(ApiError::invalid_request(
+                            format!(
+                                "messages[{}]: tool_call_id `{}` does not match any tool call of a previous assistant message",
+                                index, id
+                            ),
+                            Some(&param),
+                        ));
+                    }
+                    None => {
+                        return Err(ApiError::invalid_request(
+                            format!("messages[{}]: tool message is missing tool_call_id", index),
+                            Some(&param),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn generate_param(&self) -> Result<GenerateParam, ApiError> {
+        let guided = match self.guided_decoding()? {
+            Some((guided, param)) => Some(Arc::new(
+                guided
+                    .compile()
+                    .map_err(|e| ApiError::invalid_request(e.to_string(), Some(param)))?,
+            )),
+            None => None,
+        };
+        Ok(GenerateParam {
+            guided,
+            max_tokens: self.max_tokens(),
+            sampling: self.sampling_param(),
+            logprobs: self.logprobs(),
+            n: self.n,
+            beam: self.beam_search(),
+            session_id: self.session_id.clone(),
+            stop: self.stop(),
+        })
+    }
+}
+
+// chat 和 completions 共用的采样参数范围检查
+pub fn validate_sampling_param(sampling: &SamplingParam) -> Result<(), ApiError> {
+    let ranges = [
+        ("temperature", sampling.temperature, 0.0, 2.0, true),
+        ("top_p", sampling.top_p, 0.0, 1.0, false),
+        ("min_p", sampling.min_p, 0.0, 1.0, true),
+        ("typical_p", sampling.typical_p, 0.0, 1.0, false),
+    ];
+    let penalties = [
+        ("presence_penalty", sampling.presence_penalty, -2.0, 2.0, true),
+        ("frequency_penalty", sampling.frequency_penalty, -2.0, 2.0, true),
+        ("repetition_penalty", sampling.repetition_penalty, 0.0, 2.0, false),
+    ];
+    let penalties = penalties
+        .into_iter()
+        .map(|(param, value, min, max, min_inclusive)| {
+            (param, value.map(|value| value as f64), min, max, min_inclusive)
+        });
+    for (param, value, min, max, min_inclusive) in ranges.into_iter().chain(penalties) {
+        let Some(value) = value else {
+            continue;
+        };
+        let above_min = if min_inclusive { value >= min } else { value > min };
+        if !above_min || value > max {
+            let open = if min_inclusive { "[" } else { "(" };
+            return Err(ApiError::invalid_request(
+                format!("{} must be in {}{}, {}], got {}", param, open, min, max, value),
+                Some(param),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// 与 OpenAI 一致, key 为字符串形式的 token id, 偏置范围为 [-100, 100]
+pub fn parse_logit_bias(logit_bias: &HashMap<String, f32>) -> Result<HashMap<u32, f32>, ApiError> {
+    let mut biases = HashMap::new();
+    for (token, &bias) in logit_bias {
+        let token_id = token.parse::<u32>().map_err(|_| {
+            ApiError::invalid_request(
+                format!("logit_bias key `{}` is not a token id", token),
+                Some("logit_bias"),
+            )
+        })?;
+        if !(-100.0..=100.0).contains(&bias) {
+            return Err(ApiError::invalid_request(
+                format!("logit_bias value for token {} must be in [-100, 100], got {}", token, bias),
+                Some("logit_bias"),
+            ));
+        }
+        biases.insert(token_id, bias);
+    }
+    Ok(biases)
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Message {
     role: String,
-    content: Option<Value>,
+    content: Option<MessageContent>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_calls: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tool_call_id: Option<String>,
 }
 
+// OpenAI 的 content 既可以是字符串, 也可以是 content part 数组
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    part_type: String,
+    text: Option<String>,
+}
+
+impl MessageContent {
+    pub fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+}
+
+// 模板里只认字符串, text part 拼接后再交给模板
+impl serde::Serialize for MessageContent {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text())
+    }
+}
+
 // 工具定义结构体
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Tool {
@@ -59,11 +548,158 @@ pub fn init(path: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn chat_stream(message: &ChatRequest) -> anyhow::Result<impl Stream<Item = String>> {
-    let model_ref = MODEL
+// 加载模型后由 configure 设置投机解码等可选功能
+pub fn init_with(
+    path: &str,
+    configure: impl FnOnce(&mut Qwen3<'static>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut model = Qwen3::new(path.to_string(), false)?;
+    configure(&mut model)?;
+    MODEL.get_or_init(|| Arc::new(RwLock::new(model)));
+    Ok(())
+}
+
+// 投机解码的累计统计, 没有开启投机解码时返回 404
+pub async fn speculative_stats() -> Result<String, ApiError> {
+    let model = model_ref()?;
+    let model = model.read().await;
+    let stats = model
+        .speculative_stats()
+        .ok_or_else(|| ApiError::new(404, "speculative decoding is not enabled"))?;
+    let body = serde_json::json!({
+        "steps": stats.steps,
+        "proposed_tokens": stats.proposed_tokens,
+        "accepted_tokens": stats.accepted_tokens,
+        "acceptance_rate": stats.acceptance_rate(),
+        "tokens_per_step": stats.tokens_per_step(),
+    });
+    Ok(body.to_string())
+}
+
+// 会话文件保存的目录, 不设置时不能保存和恢复会话
+pub fn set_session_dir(path: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path)?;
+    let _ = SESSION_DIR.set(PathBuf::from(path));
+    Ok(())
+}
+
+// /v1/responses 保存响应的目录, 不设置时保存在内存中
+pub fn set_response_store_dir(path: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path)?;
+    let _ = RESPONSES.set(Mutex::new(ResponseStore::new(Some(PathBuf::from(path)))));
+    Ok(())
+}
+
+fn response_store() -> &'static Mutex<ResponseStore> {
+    RESPONSES.get_or_init(|| Mutex::new(ResponseStore::new(None)))
+}
+
+// /v1/files 和 /v1/batches 的数据目录, 不设置时不能使用这两个接口
+pub fn set_batch_dir(path: &str) -> anyhow::Result<()> {
+    let _ = BATCHES.set(BatchStore::new(path)?);
+    Ok(())
+}
+
+pub fn batch_store() -> Result<&'static BatchStore, ApiError> {
+    BATCHES.get().ok_or_else(|| {
+        ApiError::invalid_request("files and batches are disabled, start the server with --batch-dir", None)
+    })
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct SessionRequest {
+    // 会话目录下的文件名, 默认为 <session_id>.safetensors
+    pub filename: Option<String>,
+}
+
+// 对应 llama.cpp 的 /slots/{id}?action=, action 为 save、restore 或 erase
+pub async fn session_action(id: &str, action: &str, request: &SessionRequest) -> Result<String, ApiError> {
+    let model = model_ref()?;
+    if action == "erase" {
+        if !model.write().await.erase_session(id) {
+            return Err(ApiError::new(404, format!("session {} not found", id)));
+        }
+        return Ok(serde_json::json!({ "session_id": id, "erased": true }).to_string());
+    }
+    let dir = SESSION_DIR.get().ok_or_else(|| {
+        ApiError::invalid_request("session files are disabled, start the server with --session-dir", None)
+    })?;
+    let filename = request
+        .filename
+        .clone()
+        .unwrap_or_else(|| format!("{}.safetensors", id));
+    session::validate_filename(&filename)?;
+    let path = dir.join(&filename);
+    let body = match action {
+        // 模型锁内只复制 cache, 写文件放到阻塞线程中, 不阻塞生成
+        "save" => {
+            let (n_saved, tensors) = model
+                .read()
+                .await
+                .snapshot_session(id)
+                .map_err(|e| ApiError::from_anyhow(&e))?;
+            let write_path = path.clone();
+            rocket::tokio::task::spawn_blocking(move || session::write(&write_path, &tensors))
+                .await
+                .map_err(|e| ApiError::server_error(e.to_string()))?
+                .map_err(|e| ApiError::from_anyhow(&e))?;
+            let n_written = std::fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+            serde_json::json!({
+                "session_id": id,
+                "filename": filename,
+                "n_saved": n_saved,
+                "n_written": n_written,
+            })
+        }
+        "restore" => {
+            let n_restored = model
+                .write()
+                .await
+                .restore_session(id, &path)
+                .map_err(|e| ApiError::from_anyhow(&e))?;
+            serde_json::json!({
+                "session_id": id,
+                "filename": filename,
+                "n_restored": n_restored,
+            })
+        }
+        _ => {
+            return Err(ApiError::invalid_request(
+                format!("unknown session action {:?}, expected save, restore or erase", action),
+                Some("action"),
+            ));
+        }
+    };
+    Ok(body.to_string())
+}
+
+pub fn new_tool_call_id() -> String {
+    format!("call_{}", uuid::Uuid::new_v4().simple())
+}
+
+fn model_ref() -> Result<Arc<RwLock<Qwen3<'static>>>, ApiError> {
+    MODEL
         .get()
         .cloned()
-        .ok_or_else(|| anyhow::anyhow!("model not init"))?;
+        .ok_or_else(|| ApiError::new(503, "model not init"))
+}
+
+// 校验、渲染模板和 prompt 长度检查都在返回 stream 之前完成, 这样调用方能拿到对应的错误码
+// 生成过程中出错时输出 Err 并结束, 由调用方输出错误事件
+pub async fn chat_stream(
+    message: &ChatRequest,
+) -> anyhow::Result<impl Stream<Item = Result<String, ApiError>> + use<>> {
+    message.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(message)?;
+    let extra = ResponseExtra {
+        truncated_messages: prompt.truncated_messages,
+        ..Default::default()
+    };
+    let with_logprobs = prompt.param.logprobs.is_some();
+    let task = model.new_task(prompt.tokens, prompt.param);
+    drop(model);
 
     let id = uuid::Uuid::new_v4().to_string();
     let response = ChatCompletionChunkResponse {
@@ -77,95 +713,179 @@ pub fn chat_stream(message: &ChatRequest) -> anyhow::Result<impl Stream<Item = S
     };
 
     Ok(stream! {
-        match model_ref.write().await.generate_stream(message) {
-            Ok(inner_stream) => {
-                let mut pinned_stream = Box::pin(inner_stream);
-                let mut tool_call_id = None;
-                let mut tool_call_content = String::new();
-                while let Some(token) = pinned_stream.next().await {
-                    let choice = if token.as_str() == "<tool_call>"{
-                        tool_call_id = Some(uuid::Uuid::new_v4().to_string());
-                        continue;
-                    }else{
-                        if token.as_str() == "</tool_call>"{
-                            let choice = build_chunk_choice(token,tool_call_id.clone(),Some(tool_call_content.clone()));
-                            tool_call_id = None;
-                            choice
-                        }else{
-                            if tool_call_id.is_some(){
-                                tool_call_content.push_str(&token);
-                                continue;
-                            }else{
-                                build_chunk_choice(token,None,None)
-                            }
-                        }
-                    };
-                    let mut resp = response.clone();
-                    resp.choices.push(choice);
-                    match serde_json::to_string(&resp) {
-                        Ok(json) => yield json,
-                        Err(e) => {
-                            yield format!("Serialization error: {}", e);
-                            break;
-                        }
+        let mut pinned_stream = Box::pin(task_results(model_ref, task));
+        let mut chunks = ChatChunkStream::new(with_logprobs);
+        while let Some(result) = pinned_stream.next().await {
+            let generated = match result {
+                Ok(generated) => generated,
+                Err(e) => {
+                    yield Err(stream_error(&e));
+                    return;
+                }
+            };
+            for choice in chunks.push(generated) {
+                let mut resp = response.clone();
+                resp.choices.push(choice);
+                match to_json(&resp, &extra) {
+                    Ok(json) => yield Ok(json),
+                    Err(e) => {
+                        yield Err(stream_error(&e.into()));
+                        return;
                     }
                 }
             }
-            Err(e) => {
-                yield format!("Error: {}", e);
-            }
         }
     })
 }
 
-pub fn build_chunk_choice(
-    token: String,
-    tool_call_id: Option<String>,
-    tool_call_content: Option<String>,
-) -> ChatCompletionChunkChoice {
-    if tool_call_id.is_some() {
-        let tool_call_id = tool_call_id.unwrap();
-        let function = if let Some(content) = tool_call_content {
-            match serde_json::from_str::<serde_json::Value>(&content) {
-                Ok(json_value) => {
-                    let name = json_value
-                        .get("name")
-                        .and_then(|v| v.as_str())
-                        .map(|s| s.to_string());
+// 直接输出生成的原始文本, 用于终端对话; 出错时输出 Err, 不混在生成的文本中
+pub async fn chat_text_stream(
+    message: &ChatRequest,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<GenerateToken>> + use<>> {
+    message.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(message)?;
+    let task = model.new_task(prompt.tokens, prompt.param);
+    drop(model);
+    Ok(task_results(model_ref, task))
+}
 
-                    let arguments = json_value.get("arguments").map(|v| v.to_string());
+// 流式输出中途出错时记录日志, 各接口按自己的格式输出错误事件后结束, 不混在生成的文本中
+fn stream_error(e: &anyhow::Error) -> ApiError {
+    log::error!("model error: {}", e);
+    ApiError::from_anyhow(e)
+}
 
-                    DeltaFunction { name, arguments }
+// 每一步单独获取模型的写锁, tokio 的 RwLock 按先来先得排队
+// 多个请求的 prefill 块和解码步骤因此交替执行, 长 prompt 不会阻塞其它请求
+fn task_results(
+    model: Arc<RwLock<Qwen3<'static>>>,
+    mut task: GenerateTask,
+) -> impl Stream<Item = anyhow::Result<GenerateToken>> {
+    stream! {
+        while !task.is_finished() {
+            let result = advance_task(&model, &mut task).await;
+            match result {
+                Ok(tokens) => {
+                    for token in tokens {
+                        yield Ok(token);
+                    }
                 }
-                Err(_) => DeltaFunction {
-                    name: None,
-                    arguments: Some(content),
-                },
+                Err(e) => yield Err(e),
             }
-        } else {
-            DeltaFunction {
-                name: None,
-                arguments: None,
+        }
+    }
+}
+
+async fn run_task(model: &Arc<RwLock<Qwen3<'static>>>, mut task: GenerateTask) -> anyhow::Result<Vec<Generation>> {
+    finish_task(model, &mut task).await?;
+    model.read().await.task_generations(task)
+}
+
+// 不输出中间结果, 一直推进到任务结束
+async fn finish_task(model: &Arc<RwLock<Qwen3<'static>>>, task: &mut GenerateTask) -> anyhow::Result<()> {
+    while !task.is_finished() {
+        advance_task(model, task).await?;
+    }
+    Ok(())
+}
+
+// 推进一步, 因为 KV cache 预算不够没有推进时释放模型锁, 等其它请求归还预算后再返回
+async fn advance_task(
+    model: &Arc<RwLock<Qwen3<'static>>>,
+    task: &mut GenerateTask,
+) -> anyhow::Result<Vec<GenerateToken>> {
+    let mut guard = model.write().await;
+    let result = guard.advance(task);
+    let pool = guard.kv_pool().cloned();
+    drop(guard);
+    if task.is_waiting()
+        && let Some(pool) = pool
+    {
+        pool.released(KV_BUDGET_WAIT).await;
+    }
+    result
+}
+
+// 流式输出时每个 choice 各自的 tool_call 解析状态
+#[derive(Default)]
+struct ChunkState {
+    parser: BlockParser,
+    // 已经输出的 tool_call 个数, 作为 tool_calls 增量的 index
+    tool_calls: usize,
+    pending_logprobs: Vec<TokenLogprob>,
+}
+
+// chat 流式输出的 chunk, 每个 choice 单独按 <tool_call> 标签切分
+// 投机解码一次接受多个 token, 停止字符串也会合并暂缓输出的文本, 标签不一定单独出现在一段输出中
+pub struct ChatChunkStream {
+    with_logprobs: bool,
+    choices: HashMap<usize, ChunkState>,
+}
+
+impl ChatChunkStream {
+    pub fn new(with_logprobs: bool) -> Self {
+        ChatChunkStream {
+            with_logprobs,
+            choices: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, generated: GenerateToken) -> Vec<ChatCompletionChunkChoice> {
+        let state = self.choices.entry(generated.index).or_insert_with(|| ChunkState {
+            parser: BlockParser::tool_calls_only(),
+            ..Default::default()
+        });
+        // tool_call 内部的 token 不单独输出, 其 logprobs 合并到下一个输出的 chunk
+        state.pending_logprobs.extend(generated.logprobs);
+        let mut segments = state.parser.push(&generated.text);
+        if generated.finish_reason.is_some() {
+            segments.extend(state.parser.finish());
+        }
+        let mut choices = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let mut choice = match segment {
+                Segment::Text(text) | Segment::Thinking(text) => build_chunk_choice(text),
+                Segment::ToolCall { name, input } => {
+                    state.tool_calls += 1;
+                    build_tool_call_chunk_choice(state.tool_calls - 1, name, &input)
+                }
+            };
+            choice.index = Some(generated.index as u32);
+            if self.with_logprobs && choices.is_empty() {
+                choice.logprobs = Some(to_logprobs(&std::mem::take(&mut state.pending_logprobs)));
             }
-        };
-        return ChatCompletionChunkChoice {
-            index: Some(0),
-            delta: DeltaChatMessage::Assistant {
-                content: None,
-                reasoning_content: None,
-                refusal: None,
-                name: None,
-                tool_calls: Some(vec![DeltaToolCall {
-                    index: Some(0),
-                    id: Some(tool_call_id),
-                    r#type: Some("function".to_string()),
-                    function,
-                }]),
-            },
-            finish_reason: None,
-            logprobs: None,
-        };
+            choices.push(choice);
+        }
+        choices
     }
+}
+
+pub fn build_tool_call_chunk_choice(index: usize, name: String, input: &Value) -> ChatCompletionChunkChoice {
+    ChatCompletionChunkChoice {
+        index: Some(0),
+        delta: DeltaChatMessage::Assistant {
+            content: None,
+            reasoning_content: None,
+            refusal: None,
+            name: None,
+            tool_calls: Some(vec![DeltaToolCall {
+                index: Some(index as u32),
+                id: Some(new_tool_call_id()),
+                r#type: Some("function".to_string()),
+                function: DeltaFunction {
+                    name: Some(name),
+                    arguments: Some(input.to_string()),
+                },
+            }]),
+        },
+        finish_reason: None,
+        logprobs: None,
+    }
+}
+
+pub fn build_chunk_choice(token: String) -> ChatCompletionChunkChoice {
     ChatCompletionChunkChoice {
         index: Some(0),
         delta: DeltaChatMessage::Assistant {
@@ -181,10 +901,8 @@ pub fn build_chunk_choice(
 }
 
 pub async fn chat_sync(message: &ChatRequest) -> anyhow::Result<String> {
-    let model_ref = MODEL
-        .get()
-        .cloned()
-        .ok_or_else(|| anyhow::anyhow!("model not init"))?;
+    message.validate()?;
+    let model_ref = model_ref()?;
 
     let id = uuid::Uuid::new_v4().to_string();
     let mut response = ChatCompletionResponse {
@@ -198,12 +916,867 @@ pub async fn chat_sync(message: &ChatRequest) -> anyhow::Result<String> {
         usage: None,
     };
 
-    let generate_str = model_ref.write().await.generate(message)?;
-    let choice: ChatCompletionChoice = build_choice(generate_str);
-    response.choices.push(choice);
-    let response_str = serde_json::to_string(&response).unwrap();
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(message)?;
+    let with_logprobs = prompt.param.logprobs.is_some();
+    let task = model.new_task(prompt.tokens, prompt.param);
+    drop(model);
+    let generations = run_task(&model_ref, task).await?;
+    let mut extra = ResponseExtra {
+        truncated_messages: prompt.truncated_messages,
+        ..Default::default()
+    };
+    for (index, generation) in generations.into_iter().enumerate() {
+        if let (Some(cumulative_logprob), Some(score)) = (generation.cumulative_logprob, generation.score) {
+            extra.beam_scores.push((cumulative_logprob, score));
+        }
+        let mut choice: ChatCompletionChoice = build_choice(generation.text);
+        choice.index = index as u32;
+        if with_logprobs {
+            choice.logprobs = Some(to_logprobs(&generation.logprobs));
+        }
+        response.choices.push(choice);
+    }
+    let response_str = to_json(&response, &extra)?;
     Ok(response_str)
 }
+
+// Anthropic Messages API, 转换成 ChatRequest 后与 chat 走相同的生成流程
+pub async fn messages_sync(request: &MessagesRequest) -> anyhow::Result<String> {
+    let chat = request.to_chat_request()?;
+    chat.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(&chat)?;
+    let input_tokens = prompt.tokens.len();
+    let task = model.new_task(prompt.tokens, prompt.param);
+    drop(model);
+    let generation = run_task(&model_ref, task).await?.remove(0);
+    let response = message_response(
+        MODEL_NAME,
+        &generation.text,
+        generation.finish_reason,
+        &chat.stop(),
+        input_tokens,
+        generation.completion_tokens,
+    );
+    Ok(response.to_string())
+}
+
+// 每个事件的 type 即 SSE 的 event 名
+pub async fn messages_stream(request: &MessagesRequest) -> anyhow::Result<impl Stream<Item = Value> + use<>> {
+    let chat = request.to_chat_request()?;
+    chat.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(&chat)?;
+    let mut message = MessageStream::new(MODEL_NAME, prompt.tokens.len());
+    let task = model.new_task(prompt.tokens, prompt.param);
+    drop(model);
+    let stop = chat.stop();
+
+    Ok(stream! {
+        yield message.start();
+        let mut pinned_stream = Box::pin(task_results(model_ref, task));
+        while let Some(result) = pinned_stream.next().await {
+            let generated = match result {
+                Ok(generated) => generated,
+                Err(e) => {
+                    yield anthropic::error_event(&stream_error(&e));
+                    return;
+                }
+            };
+            for event in message.push(&generated.text) {
+                yield event;
+            }
+            if let Some(finish_reason) = generated.finish_reason {
+                for event in message.finish(finish_reason, &stop, generated.completion_tokens) {
+                    yield event;
+                }
+            }
+        }
+    })
+}
+
+// Ollama 的 /api/chat 和 /api/generate 共用生成和输出的流程
+struct OllamaTask {
+    task: GenerateTask,
+    reply: OllamaReply,
+    // 非 raw 的 /api/generate 返回 prompt 和输出的 token, 下次请求传回来接着对话
+    context: Option<Vec<u32>>,
+}
+
+async fn prepare_ollama_chat(request: &OllamaChatRequest) -> anyhow::Result<OllamaTask> {
+    let chat = request.to_chat_request()?;
+    chat.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(&chat)?;
+    let name = request.model.as_deref().unwrap_or(MODEL_NAME);
+    let reply = OllamaReply::chat(name, prompt.tokens.len());
+    let task = model.new_task(prompt.tokens, prompt.param);
+    Ok(OllamaTask {
+        task,
+        reply,
+        context: None,
+    })
+}
+
+async fn prepare_ollama_generate(request: &OllamaGenerateRequest) -> anyhow::Result<OllamaTask> {
+    let chat = request.to_chat_request()?;
+    chat.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let text = if request.is_raw() {
+        request.prompt.clone()
+    } else {
+        let messages: Vec<&Message> = chat.messages.iter().collect();
+        model.render_messages(&messages, None, chat.enable_thinking())?
+    };
+    let mut tokens = request.context.clone().unwrap_or_default();
+    tokens.extend(model.encode(text)?);
+    let prompt = model.prepare_raw(tokens, chat.generate_param()?)?;
+    let name = request.model.as_deref().unwrap_or(MODEL_NAME);
+    let reply = OllamaReply::generate(name, prompt.tokens.len(), request.is_raw());
+    let context = (!request.is_raw()).then(|| prompt.tokens.clone());
+    let task = model.new_task(prompt.tokens, prompt.param);
+    Ok(OllamaTask { task, reply, context })
+}
+
+// 遇到 eos 或停止字符串结束时补上 <|im_end|>, 下一轮的模板从新的用户消息开始
+async fn ollama_context(
+    model_ref: &Arc<RwLock<Qwen3<'static>>>,
+    prompt_tokens: Option<Vec<u32>>,
+    text: &str,
+    finish_reason: StopReason,
+) -> anyhow::Result<Option<Vec<u32>>> {
+    let Some(mut context) = prompt_tokens else {
+        return Ok(None);
+    };
+    let mut text = text.to_string();
+    if matches!(finish_reason, StopReason::Stop | StopReason::StopSequence(_)) {
+        text.push_str("<|im_end|>");
+    }
+    context.extend(model_ref.read().await.encode(text)?);
+    Ok(Some(context))
+}
+
+async fn ollama_sync(mut prepared: OllamaTask) -> anyhow::Result<String> {
+    let model_ref = model_ref()?;
+    finish_task(&model_ref, &mut prepared.task).await?;
+    if let Some(at) = prepared.task.prefilled_at() {
+        prepared.reply.prompt_evaluated_at(at);
+    }
+    let generation = model_ref.read().await.task_generations(prepared.task)?.remove(0);
+    let context = ollama_context(&model_ref, prepared.context, &generation.text, generation.finish_reason).await?;
+    let response = prepared.reply.response(
+        &generation.text,
+        generation.finish_reason,
+        generation.completion_tokens,
+        context,
+    );
+    Ok(response.to_string())
+}
+
+fn ollama_stream(mut prepared: OllamaTask) -> anyhow::Result<impl Stream<Item = Value>> {
+    let model_ref = model_ref()?;
+    Ok(stream! {
+        let mut text = String::new();
+        let mut pinned_stream = Box::pin(task_results(model_ref.clone(), prepared.task));
+        while let Some(result) = pinned_stream.next().await {
+            let generated = match result {
+                Ok(generated) => generated,
+                Err(e) => {
+                    yield ollama::error_value(&stream_error(&e));
+                    return;
+                }
+            };
+            text.push_str(&generated.text);
+            if let Some(chunk) = prepared.reply.push(&generated.text) {
+                yield chunk;
+            }
+            if let Some(finish_reason) = generated.finish_reason {
+                match ollama_context(&model_ref, prepared.context.take(), &text, finish_reason).await {
+                    Ok(context) => {
+                        for chunk in prepared.reply.finish(finish_reason, generated.completion_tokens, context) {
+                            yield chunk;
+                        }
+                    }
+                    Err(e) => yield ollama::error_value(&stream_error(&e)),
+                }
+            }
+        }
+    })
+}
+
+pub async fn ollama_chat_sync(request: &OllamaChatRequest) -> anyhow::Result<String> {
+    ollama_sync(prepare_ollama_chat(request).await?).await
+}
+
+pub async fn ollama_chat_stream(request: &OllamaChatRequest) -> anyhow::Result<impl Stream<Item = Value> + use<>> {
+    ollama_stream(prepare_ollama_chat(request).await?)
+}
+
+// prompt 为空时只返回加载的结果, 不生成
+pub async fn ollama_generate_sync(request: &OllamaGenerateRequest) -> anyhow::Result<String> {
+    if request.is_load() {
+        model_ref()?;
+        return Ok(OllamaReply::load(request.model.as_deref().unwrap_or(MODEL_NAME)).to_string());
+    }
+    ollama_sync(prepare_ollama_generate(request).await?).await
+}
+
+pub async fn ollama_generate_stream(request: &OllamaGenerateRequest) -> anyhow::Result<impl Stream<Item = Value> + use<>> {
+    ollama_stream(prepare_ollama_generate(request).await?)
+}
+
+// OpenAI Responses API, previous_response_id 对应的对话历史从保存的响应中恢复
+struct ResponseTask {
+    stream: ResponseStream,
+    // 包括之前响应在内的完整输入, 保存后下次接着对话
+    input: Vec<Value>,
+    input_tokens: usize,
+    store: bool,
+}
+
+async fn prepare_response(request: &ResponsesRequest) -> anyhow::Result<(GenerateTask, ResponseTask)> {
+    let mut input = match &request.previous_response_id {
+        Some(id) => {
+            let stored = response_store().lock().unwrap().get(id)?;
+            stored
+                .ok_or_else(|| responses::not_found(id, Some("previous_response_id")))?
+                .history()
+        }
+        None => Vec::new(),
+    };
+    input.extend(request.input_items()?);
+    let chat = request.to_chat_request(&input)?;
+    chat.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let prompt = model.prepare(&chat)?;
+    let input_tokens = prompt.tokens.len();
+    let stream = ResponseStream::new(request.response_object(request.model.as_deref().unwrap_or(MODEL_NAME)));
+    let task = model.new_task(prompt.tokens, prompt.param);
+    let prepared = ResponseTask {
+        stream,
+        input,
+        input_tokens,
+        store: request.store(),
+    };
+    Ok((task, prepared))
+}
+
+// 返回 response.completed 或 response.incomplete 事件, 需要保存时先保存
+async fn complete_response(
+    model_ref: &Arc<RwLock<Qwen3<'static>>>,
+    prepared: &mut ResponseTask,
+    finish_reason: StopReason,
+    output_tokens: usize,
+) -> anyhow::Result<Value> {
+    let reasoning_tokens = match prepared.stream.reasoning() {
+        "" => 0,
+        reasoning => model_ref.read().await.encode(reasoning.to_string())?.len(),
+    };
+    let event = prepared
+        .stream
+        .complete(finish_reason, prepared.input_tokens, output_tokens, reasoning_tokens);
+    if prepared.store {
+        let stored = StoredResponse {
+            input: std::mem::take(&mut prepared.input),
+            response: prepared.stream.response().clone(),
+        };
+        response_store().lock().unwrap().insert(prepared.stream.id(), stored)?;
+    }
+    Ok(event)
+}
+
+pub async fn responses_sync(request: &ResponsesRequest) -> anyhow::Result<String> {
+    let (task, mut prepared) = prepare_response(request).await?;
+    let model_ref = model_ref()?;
+    let generation = run_task(&model_ref, task).await?.remove(0);
+    prepared.stream.push(&generation.text);
+    prepared.stream.flush();
+    complete_response(&model_ref, &mut prepared, generation.finish_reason, generation.completion_tokens).await?;
+    Ok(prepared.stream.response().to_string())
+}
+
+// 每个事件的 type 即 SSE 的 event 名, 保存失败时最后输出 error 事件
+pub async fn responses_stream(request: &ResponsesRequest) -> anyhow::Result<impl Stream<Item = Value> + use<>> {
+    let (task, mut prepared) = prepare_response(request).await?;
+    let model_ref = model_ref()?;
+    Ok(stream! {
+        for event in prepared.stream.start() {
+            yield event;
+        }
+        let mut pinned_stream = Box::pin(task_results(model_ref.clone(), task));
+        while let Some(result) = pinned_stream.next().await {
+            let generated = match result {
+                Ok(generated) => generated,
+                Err(e) => {
+                    for event in prepared.stream.fail(&stream_error(&e)) {
+                        yield event;
+                    }
+                    return;
+                }
+            };
+            for event in prepared.stream.push(&generated.text) {
+                yield event;
+            }
+            if let Some(finish_reason) = generated.finish_reason {
+                for event in prepared.stream.flush() {
+                    yield event;
+                }
+                match complete_response(&model_ref, &mut prepared, finish_reason, generated.completion_tokens).await {
+                    Ok(event) => yield event,
+                    Err(e) => {
+                        for event in prepared.stream.fail(&stream_error(&e)) {
+                            yield event;
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub async fn get_response(id: &str) -> Result<String, ApiError> {
+    let stored = response_store()
+        .lock()
+        .unwrap()
+        .get(id)
+        .map_err(|e| ApiError::server_error(e.to_string()))?;
+    let stored = stored.ok_or_else(|| responses::not_found(id, None))?;
+    Ok(stored.response.to_string())
+}
+
+pub async fn delete_response(id: &str) -> Result<String, ApiError> {
+    let deleted = response_store()
+        .lock()
+        .unwrap()
+        .remove(id)
+        .map_err(|e| ApiError::server_error(e.to_string()))?;
+    if !deleted {
+        return Err(responses::not_found(id, None));
+    }
+    Ok(serde_json::json!({"id": id, "object": "response.deleted", "deleted": true}).to_string())
+}
+
+// Batch 中的一行请求, 与调用对应的接口相同, 但不支持流式输出
+pub async fn batch_request(url: &str, body: &Value) -> Result<String, ApiError> {
+    if body.get("stream").and_then(Value::as_bool) == Some(true) {
+        return Err(ApiError::invalid_request("stream is not supported in batch requests", Some("stream")));
+    }
+    let result = match url {
+        "/v1/chat/completions" => {
+            let mut req: ChatRequest = batch_body(body)?;
+            req.stream = Some(false);
+            chat_sync(&req).await
+        }
+        "/v1/completions" => completions_sync(&batch_body(body)?).await,
+        "/v1/embeddings" => embeddings(&batch_body(body)?).await,
+        _ => {
+            return Err(ApiError::invalid_request(
+                format!("url must be one of {}", batch::BATCH_ENDPOINTS.join(", ")),
+                Some("url"),
+            ));
+        }
+    };
+    result.map_err(|e| ApiError::from_anyhow(&e))
+}
+
+fn batch_body<T: serde::de::DeserializeOwned>(body: &Value) -> Result<T, ApiError> {
+    serde_json::from_value(body.clone()).map_err(|e| ApiError::invalid_request(e.to_string(), Some("body")))
+}
+
+// 创建后在后台执行, 输入文件在执行时才解析
+pub fn create_batch(request: &BatchRequest) -> Result<String, ApiError> {
+    let store = batch_store()?;
+    let batch = store.create_batch(request)?;
+    let id = batch["id"].as_str().unwrap_or_default().to_string();
+    rocket::tokio::spawn(run_stored_batch(store, id));
+    Ok(batch.to_string())
+}
+
+// 服务启动时继续执行上次没有完成的 batch, 已经写入的结果不会重新执行
+pub fn resume_batches() -> anyhow::Result<()> {
+    let store = batch_store()?;
+    for id in store.unfinished_batches()? {
+        rocket::tokio::spawn(run_stored_batch(store, id));
+    }
+    Ok(())
+}
+
+// 正在执行的请求被丢弃, 已经完成的结果保留在输出文件中
+pub fn cancel_batch(id: &str) -> Result<String, ApiError> {
+    let mut status = String::new();
+    let batch = batch_store()?.update_batch(id, |batch| {
+        status = batch["status"].as_str().unwrap_or_default().to_string();
+        if matches!(status.as_str(), "validating" | "in_progress") {
+            batch["status"] = serde_json::json!("cancelling");
+            batch["cancelling_at"] = serde_json::json!(chrono::Utc::now().timestamp());
+        }
+    })?;
+    if !matches!(status.as_str(), "validating" | "in_progress" | "cancelling") {
+        return Err(ApiError::new(409, format!("Cannot cancel a batch with status '{}'.", status)));
+    }
+    Ok(batch.to_string())
+}
+
+async fn run_stored_batch(store: &'static BatchStore, id: String) {
+    if let Err(e) = execute_stored_batch(store, &id).await {
+        log::error!("batch {} failed: {}", id, e);
+        let error = serde_json::json!({"code": "server_error", "message": e.to_string(), "param": null, "line": null});
+        let _ = store.update_batch(&id, |batch| {
+            batch["status"] = serde_json::json!("failed");
+            batch["failed_at"] = serde_json::json!(chrono::Utc::now().timestamp());
+            batch["errors"] = serde_json::json!({"object": "list", "data": [error]});
+        });
+    }
+}
+
+async fn execute_stored_batch(store: &'static BatchStore, id: &str) -> anyhow::Result<()> {
+    let batch = store.batch(id)?;
+    let now = || serde_json::json!(chrono::Utc::now().timestamp());
+    let input_file_id = batch["input_file_id"].as_str().unwrap_or_default();
+    let parsed = store
+        .file_content(input_file_id)
+        .and_then(|content| batch::parse_input(&content));
+    let lines = match parsed {
+        Ok(lines) => lines,
+        Err(e) => {
+            // 输入文件有错误时整个 batch 失败, 错误中带上行号
+            let line = e
+                .param
+                .as_deref()
+                .and_then(|param| param.strip_prefix("line "))
+                .and_then(|line| line.parse::<usize>().ok());
+            let error = serde_json::json!({"code": "invalid_request", "message": e.message, "param": null, "line": line});
+            store.update_batch(id, |batch| {
+                batch["status"] = serde_json::json!("failed");
+                batch["failed_at"] = now();
+                batch["errors"] = serde_json::json!({"object": "list", "data": [error]});
+            })?;
+            return Ok(());
+        }
+    };
+    store.update_batch(id, |batch| {
+        if batch["status"] == "validating" {
+            batch["status"] = serde_json::json!("in_progress");
+            batch["in_progress_at"] = now();
+        }
+    })?;
+
+    let (output_id, error_id) = BatchStore::result_file_ids(id);
+    let output = store.file_path(&output_id)?;
+    let errors = store.file_path(&error_id)?;
+    let endpoint = batch["endpoint"].as_str().unwrap_or_default();
+    let counts = batch::run_batch(lines, Some(endpoint), &output, Some(&errors), DEFAULT_BATCH_CONCURRENCY, |counts| {
+        let updated = store.update_batch(id, |batch| batch["request_counts"] = serde_json::json!(counts));
+        !matches!(updated, Ok(batch) if batch["status"] == "cancelling")
+    })
+    .await?;
+
+    store.update_batch(id, |batch| {
+        if batch["status"] == "in_progress" {
+            batch["status"] = serde_json::json!("finalizing");
+            batch["finalizing_at"] = now();
+        }
+    })?;
+    let mut file_ids = Vec::new();
+    for (file_id, suffix) in [(&output_id, "output"), (&error_id, "errors")] {
+        let path = store.file_path(file_id)?;
+        if std::fs::metadata(&path).map(|meta| meta.len() > 0).unwrap_or(false) {
+            store.register_file(file_id, &format!("{}_{}.jsonl", id, suffix), "batch_output")?;
+            file_ids.push(Some(file_id.clone()));
+        } else {
+            let _ = std::fs::remove_file(path);
+            file_ids.push(None);
+        }
+    }
+    store.update_batch(id, |batch| {
+        if batch["status"] == "cancelling" {
+            batch["status"] = serde_json::json!("cancelled");
+            batch["cancelled_at"] = now();
+        } else {
+            batch["status"] = serde_json::json!("completed");
+            batch["completed_at"] = now();
+        }
+        batch["output_file_id"] = serde_json::json!(file_ids[0]);
+        batch["error_file_id"] = serde_json::json!(file_ids[1]);
+        batch["request_counts"] = serde_json::json!(counts);
+    })?;
+    Ok(())
+}
+
+pub async fn ollama_tags() -> Result<String, ApiError> {
+    let model_ref = model_ref()?;
+    let model = model_ref.read().await;
+    let entry = ollama::model_entry(MODEL_NAME, model.model_path(), model.fingerprint())
+        .map_err(|e| ApiError::server_error(e.to_string()))?;
+    Ok(serde_json::json!({ "models": [entry] }).to_string())
+}
+
+pub async fn ollama_show() -> Result<String, ApiError> {
+    let model_ref = model_ref()?;
+    let model = model_ref.read().await;
+    let show = ollama::model_show(model.model_path()).map_err(|e| ApiError::server_error(e.to_string()))?;
+    Ok(show.to_string())
+}
+
+// 多个输入逐个计算, 长度不同的输入不需要补齐
+// 每个输入单独持有读锁, 输入很多时生成任务也能在两次 forward 之间推进
+pub async fn embeddings(request: &EmbeddingRequest) -> anyhow::Result<String> {
+    request.validate()?;
+    let model_ref = model_ref()?;
+    let mut data = Vec::new();
+    let mut prompt_tokens = 0;
+    for (index, input) in request.inputs().iter().enumerate() {
+        let (embedding, num_tokens) = model_ref.read().await.embed(input)?;
+        if let Some(dimensions) = request.dimensions
+            && dimensions > embedding.len()
+        {
+            return Err(ApiError::invalid_request(
+                format!("dimensions must be at most {}, got {}", embedding.len(), dimensions),
+                Some("dimensions"),
+            )
+            .into());
+        }
+        prompt_tokens += num_tokens;
+        let embedding = postprocess(embedding, request.dimensions);
+        let embedding = if request.is_base64() {
+            Value::String(encode_base64(&embedding))
+        } else {
+            serde_json::json!(embedding)
+        };
+        data.push(serde_json::json!({
+            "object": "embedding",
+            "index": index,
+            "embedding": embedding,
+        }));
+    }
+    let response = serde_json::json!({
+        "object": "list",
+        "data": data,
+        "model": MODEL_NAME,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "total_tokens": prompt_tokens,
+        },
+    });
+    Ok(response.to_string())
+}
+
+// 所有文档共享 query 部分的 prefill, 结果按相关性从高到低排列
+pub async fn rerank(request: &RerankRequest) -> anyhow::Result<String> {
+    request.validate()?;
+    let model_ref = model_ref()?;
+    let model = model_ref.read().await;
+    let (scores, total_tokens) = model.rerank(request.instruction(), &request.query, &request.documents())?;
+    let return_documents = request.return_documents.unwrap_or(false);
+    let results: Vec<Value> = ranked(&scores, request.top_n)
+        .into_iter()
+        .map(|(index, score)| {
+            let mut result = serde_json::json!({
+                "index": index,
+                "relevance_score": score,
+            });
+            if return_documents {
+                result["document"] = serde_json::json!({"text": request.documents[index].text()});
+            }
+            result
+        })
+        .collect();
+    let response = serde_json::json!({
+        "id": uuid::Uuid::new_v4().to_string(),
+        "model": MODEL_NAME,
+        "results": results,
+        "usage": {
+            "total_tokens": total_tokens,
+        },
+    });
+    Ok(response.to_string())
+}
+
+// 一个 prompt 的生成任务, echo 时先输出 prompt 的文本和 logprobs
+struct PreparedCompletion {
+    task: GenerateTask,
+    prompt_tokens: usize,
+    echo: Option<(String, Option<LegacyLogprobs>)>,
+}
+
+// 所有 prompt 都在返回之前完成编码和长度检查
+async fn prepare_completions(
+    request: &CompletionRequest,
+) -> anyhow::Result<(Arc<RwLock<Qwen3<'static>>>, Vec<PreparedCompletion>)> {
+    request.validate()?;
+    let model_ref = model_ref()?;
+    let mut model = model_ref.write().await;
+    let mut prepared = Vec::new();
+    for input in request.inputs() {
+        let (tokens, text) = match input {
+            PromptInput::Text(text) => (model.encode(text.clone())?, Some(text)),
+            PromptInput::Tokens(tokens) => (tokens, None),
+        };
+        let param = GenerateParam {
+            max_tokens: Some(request.max_tokens()),
+            sampling: request.sampling_param(),
+            logprobs: request.logprobs,
+            n: request.n,
+            stop: request.stop(),
+            ..Default::default()
+        };
+        let prompt = model.prepare_raw(tokens, param)?;
+        let echo = if request.echo() {
+            let text = match text {
+                Some(text) => text,
+                None => model.decode(&prompt.tokens)?,
+            };
+            let logprobs = match request.logprobs {
+                Some(top_n) => {
+                    let mut logprobs = LegacyLogprobs::default();
+                    logprobs.push_first(model.token_text(prompt.tokens[0]).0);
+                    logprobs.extend(&model.prompt_logprobs(&prompt.tokens, top_n)?);
+                    Some(logprobs)
+                }
+                None => None,
+            };
+            Some((text, logprobs))
+        } else {
+            None
+        };
+        prepared.push(PreparedCompletion {
+            prompt_tokens: prompt.tokens.len(),
+            task: model.new_task(prompt.tokens, prompt.param),
+            echo,
+        });
+    }
+    drop(model);
+    Ok((model_ref, prepared))
+}
+
+fn completion_choice(
+    index: usize,
+    text: String,
+    logprobs: Option<LegacyLogprobs>,
+    finish_reason: Option<StopReason>,
+) -> Value {
+    serde_json::json!({
+        "index": index,
+        "text": text,
+        "logprobs": logprobs,
+        "finish_reason": finish_reason.map(|reason| reason.as_str()),
+    })
+}
+
+// 第 i 个 prompt 的第 j 个结果的 index 为 i * n + j, 多个 prompt 的任务同时推进
+pub async fn completions_sync(request: &CompletionRequest) -> anyhow::Result<String> {
+    let (model_ref, prepared) = prepare_completions(request).await?;
+    let n = request.n();
+    let results = try_join_all(prepared.into_iter().map(|prepared| {
+        let model_ref = &model_ref;
+        async move {
+            let generations = run_task(model_ref, prepared.task).await?;
+            anyhow::Ok((prepared.prompt_tokens, prepared.echo, generations))
+        }
+    }))
+    .await?;
+    let mut choices = Vec::new();
+    let mut prompt_tokens = 0;
+    let mut completion_tokens = 0;
+    for (prompt_index, (num_tokens, echo, generations)) in results.into_iter().enumerate() {
+        prompt_tokens += num_tokens;
+        let (echo_text, echo_logprobs) = echo.unwrap_or_default();
+        for (index, generation) in generations.into_iter().enumerate() {
+            completion_tokens += generation.completion_tokens;
+            let logprobs = request.logprobs.map(|_| {
+                let mut logprobs = echo_logprobs.clone().unwrap_or_default();
+                logprobs.extend(&generation.logprobs);
+                logprobs
+            });
+            choices.push(completion_choice(
+                prompt_index * n + index,
+                echo_text.clone() + &generation.text,
+                logprobs,
+                Some(generation.finish_reason),
+            ));
+        }
+    }
+    let response = serde_json::json!({
+        "id": format!("cmpl-{}", uuid::Uuid::new_v4().simple()),
+        "object": "text_completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": MODEL_NAME,
+        "choices": choices,
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    });
+    Ok(response.to_string())
+}
+
+// echo 的 prompt 作为每个结果的第一个 chunk, 最后一个 chunk 带 finish_reason
+// 与 chat_stream 一样, 出错时输出 Err 并结束
+pub async fn completions_stream(
+    request: &CompletionRequest,
+) -> anyhow::Result<impl Stream<Item = Result<String, ApiError>> + use<>> {
+    let (model_ref, prepared) = prepare_completions(request).await?;
+    let n = request.n();
+    let with_logprobs = request.logprobs.is_some();
+    let response = serde_json::json!({
+        "id": format!("cmpl-{}", uuid::Uuid::new_v4().simple()),
+        "object": "text_completion",
+        "created": chrono::Utc::now().timestamp(),
+        "model": MODEL_NAME,
+    });
+    let mut echoes = Vec::new();
+    let mut streams = Vec::new();
+    for (prompt_index, prepared) in prepared.into_iter().enumerate() {
+        echoes.push(prepared.echo);
+        let stream = task_results(model_ref.clone(), prepared.task).map(move |result| (prompt_index, result));
+        streams.push(Box::pin(stream));
+    }
+
+    Ok(stream! {
+        let chunk = |choice: Value| {
+            let mut chunk = response.clone();
+            chunk["choices"] = serde_json::json!([choice]);
+            chunk.to_string()
+        };
+        // 每个结果各自累计 text_offset
+        let mut choice_logprobs: HashMap<usize, LegacyLogprobs> = HashMap::new();
+        for (prompt_index, echo) in echoes.into_iter().enumerate() {
+            let Some((text, logprobs)) = echo else {
+                continue;
+            };
+            for index in prompt_index * n..(prompt_index + 1) * n {
+                let state = choice_logprobs.entry(index).or_insert(logprobs.clone().unwrap_or_default());
+                let logprobs = with_logprobs.then(|| state.take());
+                yield Ok(chunk(completion_choice(index, text.clone(), logprobs, None)));
+            }
+        }
+        let mut merged = select_all(streams);
+        while let Some((prompt_index, result)) = merged.next().await {
+            let generated = match result {
+                Ok(generated) => generated,
+                Err(e) => {
+                    yield Err(stream_error(&e));
+                    return;
+                }
+            };
+            let index = prompt_index * n + generated.index;
+            let state = choice_logprobs.entry(index).or_default();
+            state.extend(&generated.logprobs);
+            let logprobs = with_logprobs.then(|| state.take());
+            yield Ok(chunk(completion_choice(index, generated.text, logprobs, generated.finish_reason)));
+        }
+    })
+}
+
+// prompt 只编码和 prefill 一次, 所有候选共享
+pub async fn score(request: &ScoreRequest) -> anyhow::Result<String> {
+    request.validate()?;
+    let model_ref = model_ref()?;
+    let model = model_ref.read().await;
+    let prompt = match &request.messages {
+        Some(messages) => {
+            let messages: Vec<&Message> = messages.iter().collect();
+            model.render_messages(&messages, request.tools.as_ref(), false)?
+        }
+        None => request.prompt.clone().unwrap_or_default(),
+    };
+    let context = model.encode(prompt)?;
+    let continuations = request
+        .continuations()
+        .into_iter()
+        .map(|continuation| model.encode(continuation))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let scores = model.score(&context, &continuations, request.top_logprobs.unwrap_or(0))?;
+    drop(model);
+    let continuation_tokens: usize = continuations.iter().map(Vec::len).sum();
+    let data: Vec<Value> = scores
+        .iter()
+        .enumerate()
+        .map(|(index, logprobs)| {
+            let (total, perplexity) = log_likelihood(logprobs);
+            serde_json::json!({
+                "object": "score",
+                "index": index,
+                "logprob": total,
+                "perplexity": perplexity,
+                "num_tokens": logprobs.len(),
+                "logprobs": to_logprobs(logprobs),
+            })
+        })
+        .collect();
+    let response = serde_json::json!({
+        "object": "list",
+        "data": data,
+        "model": MODEL_NAME,
+        "usage": {
+            "prompt_tokens": context.len(),
+            "continuation_tokens": continuation_tokens,
+            "total_tokens": context.len() + continuation_tokens,
+        },
+    });
+    Ok(response.to_string())
+}
+
+pub fn to_logprobs(logprobs: &[TokenLogprob]) -> LogProbs {
+    let content = logprobs
+        .iter()
+        .map(|logprob| LogProbsContent {
+            token: logprob.token.clone(),
+            logprob: logprob.logprob,
+            bytes: Some(logprob.bytes.clone()),
+            top_logprobs: logprob
+                .top_logprobs
+                .iter()
+                .map(|top| TopLogProbs {
+                    token: top.token.clone(),
+                    logprob: top.logprob,
+                    bytes: Some(top.bytes.clone()),
+                })
+                .collect(),
+        })
+        .collect();
+    LogProbs {
+        content: Some(content),
+        refusal: None,
+    }
+}
+
+// OpenAI 响应结构之外的扩展字段
+#[derive(Default)]
+struct ResponseExtra {
+    // 自动截断时在响应顶层附加 truncated_messages 字段, 说明丢弃了多少条历史消息
+    truncated_messages: Option<usize>,
+    // beam search 时每个 choice 的 (cumulative_logprob, score)
+    beam_scores: Vec<(f32, f32)>,
+}
+
+fn to_json<T: serde::Serialize>(response: &T, extra: &ResponseExtra) -> serde_json::Result<String> {
+    if extra.truncated_messages.is_none() && extra.beam_scores.is_empty() {
+        return serde_json::to_string(response);
+    }
+    let mut value = serde_json::to_value(response)?;
+    if let Some(truncated_messages) = extra.truncated_messages
+        && let Some(object) = value.as_object_mut()
+    {
+        object.insert("truncated_messages".to_string(), truncated_messages.into());
+    }
+    if let Some(choices) = value.get_mut("choices").and_then(|choices| choices.as_array_mut()) {
+        for (choice, (cumulative_logprob, score)) in choices.iter_mut().zip(&extra.beam_scores) {
+            if let Some(object) = choice.as_object_mut() {
+                object.insert("cumulative_logprob".to_string(), (*cumulative_logprob).into());
+                object.insert("score".to_string(), (*score).into());
+            }
+        }
+    }
+    serde_json::to_string(&value)
+}
 pub fn build_choice(token: String) -> ChatCompletionChoice {
     if token.contains("<tool_call>") {
         let mes: Vec<&str> = token.split("<tool_call>").collect();
@@ -232,7 +1805,7 @@ pub fn build_choice(token: String) -> ChatCompletionChoice {
                 },
             };
             let tool_call = ToolCall {
-                id: (i-1).to_string(),
+                id: new_tool_call_id(),
                 r#type: "function".to_string(),
                 function: function,
             };
@@ -1,13 +1,19 @@
 #[macro_use]
 extern crate rocket;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use rocket::Config;
 use rocket::data::{ByteUnit, Limits};
-use std::io::Write;
+use rocket::futures::StreamExt;
+use std::io::{BufRead, Write};
+use std::path::Path;
 use std::{env, fs};
 
-use qwen3_deploy::init;
+use qwen3_deploy::batch::{self, DEFAULT_BATCH_CONCURRENCY};
+use qwen3_deploy::repl::{ChatSession, CommandResult, Renderer};
+use qwen3_deploy::kv_pool::KV_BLOCK_SIZE;
+use qwen3_deploy::qwen3::DEFAULT_MAX_SESSIONS;
+use qwen3_deploy::{chat_text_stream, init_with, resume_batches, set_batch_dir, set_response_store_dir, set_session_dir};
 
 mod api;
 
@@ -19,19 +25,116 @@ struct Args {
 
     #[arg(short, long)]
     model_path: String,
+
+    // 与目标模型共用 tokenizer 的小模型, 设置后开启投机解码
+    #[arg(long)]
+    draft_model_path: Option<String>,
+
+    // 不使用草稿模型, 从 prompt 中查找与末尾 n-gram 匹配的片段作为草稿
+    #[arg(long, conflicts_with = "draft_model_path")]
+    prompt_lookup: bool,
+
+    // prompt lookup 匹配的最长 n-gram
+    #[arg(long, default_value_t = 3)]
+    prompt_lookup_max_ngram: usize,
+
+    // 每次提出的草稿 token 数
+    #[arg(long, default_value_t = 4)]
+    num_speculative_tokens: usize,
+
+    // prefill 每块的 token 数, 块之间可以穿插其它请求的解码, 0 表示不分块
+    #[arg(long, default_value_t = 512)]
+    prefill_chunk_size: usize,
+
+    // KV cache 的显存预算(MiB), 不设置时不限制
+    #[arg(long)]
+    kv_cache_memory_mb: Option<u64>,
+
+    // KV cache 池每块的 token 数
+    #[arg(long, default_value_t = KV_BLOCK_SIZE)]
+    kv_cache_block_size: usize,
+
+    // 会话文件保存的目录, 不设置时不能保存和恢复会话
+    #[arg(long)]
+    session_dir: Option<String>,
+
+    // 内存中最多保留的会话数, 超过时淘汰最久未使用的会话
+    #[arg(long, default_value_t = DEFAULT_MAX_SESSIONS)]
+    max_sessions: usize,
+
+    // /v1/responses 保存响应的目录, 不设置时只在内存中保存最近的响应
+    #[arg(long)]
+    response_store_dir: Option<String>,
+
+    // /v1/files 和 /v1/batches 的数据目录, 启动时继续执行没有完成的 batch
+    #[arg(long)]
+    batch_dir: Option<String>,
+
+    // Qwen3-Embedding 模型路径, 不设置时用对话模型计算 embedding
+    #[arg(long)]
+    embedding_model_path: Option<String>,
+
+    // Qwen3-Reranker 模型路径, 不设置时用对话模型打分
+    #[arg(long)]
+    reranker_model_path: Option<String>,
+
+    // 不设置时启动 HTTP 服务
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // 离线执行 JSONL 中的请求, 格式与 OpenAI Batch 相同, 中断后重新运行时跳过已经完成的请求
+    Batch {
+        #[arg(long)]
+        input: String,
+
+        #[arg(long)]
+        output: String,
+
+        // 失败的请求单独写入的文件, 不设置时与成功的结果写入 output
+        #[arg(long)]
+        error_output: Option<String>,
+
+        #[arg(long, default_value_t = DEFAULT_BATCH_CONCURRENCY)]
+        concurrency: usize,
+    },
+    // 终端中多轮对话, 输入 /help 查看命令
+    Chat {
+        // 系统提示词文件
+        #[arg(long)]
+        system: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    write_pid()?;
-    start_http_server(args.port, args.model_path).await?;
-    Ok(())
+    match &args.command {
+        Some(Command::Batch {
+            input,
+            output,
+            error_output,
+            concurrency,
+        }) => {
+            init_model(&args)?;
+            run_batch_file(input, output, error_output.as_deref(), *concurrency).await
+        }
+        Some(Command::Chat { system }) => {
+            init_model(&args)?;
+            run_chat(system.as_deref()).await
+        }
+        None => {
+            write_pid()?;
+            start_http_server(args).await
+        }
+    }
 }
 
-pub async fn start_http_server(port: u16, model_path: String) -> anyhow::Result<()> {
+async fn start_http_server(args: Args) -> anyhow::Result<()> {
     let mut builder = rocket::build().configure(Config {
-        port,
+        port: args.port,
         limits: Limits::default()
             .limit("string", ByteUnit::Mebibyte(5))
             .limit("json", ByteUnit::Mebibyte(5))
@@ -40,14 +143,134 @@ pub async fn start_http_server(port: u16, model_path: String) -> anyhow::Result<
         ..Config::default()
     });
 
-    builder = builder.mount("/chat", routes![api::chat]);
+    builder = builder
+        .mount("/chat", routes![api::chat, api::stats, api::session])
+        .mount("/v1", routes![api::completion, api::embedding, api::rerank_documents, api::score_continuations, api::messages, api::responses, api::response_info, api::response_delete])
+        .mount("/v1", routes![api::upload_file, api::list_files, api::file_info, api::file_content, api::file_delete, api::batch_create, api::batch_list, api::batch_info, api::batch_cancel])
+        .mount("/api", routes![api::ollama_chat, api::ollama_generate, api::ollama_models, api::ollama_model_info])
+        .register("/", catchers![api::default_catcher]);
 
-    init(&model_path)?;
+    init_model(&args)?;
+    if let Some(session_dir) = &args.session_dir {
+        set_session_dir(session_dir)?;
+    }
+    if let Some(response_store_dir) = &args.response_store_dir {
+        set_response_store_dir(response_store_dir)?;
+    }
+    if let Some(batch_dir) = &args.batch_dir {
+        set_batch_dir(batch_dir)?;
+        resume_batches()?;
+    }
 
     builder.launch().await?;
     Ok(())
 }
 
+fn init_model(args: &Args) -> anyhow::Result<()> {
+    init_with(&args.model_path, |model| {
+        model.set_prefill_chunk_size(Some(args.prefill_chunk_size));
+        model.set_max_sessions(args.max_sessions);
+        if let Some(memory_mb) = args.kv_cache_memory_mb {
+            model.set_kv_cache_budget(memory_mb * 1024 * 1024, args.kv_cache_block_size)?;
+        }
+        if let Some(draft_path) = &args.draft_model_path {
+            model.load_draft_model(draft_path, args.num_speculative_tokens)?;
+        }
+        if let Some(embedding_path) = &args.embedding_model_path {
+            model.load_embedding_model(embedding_path)?;
+        }
+        if let Some(reranker_path) = &args.reranker_model_path {
+            model.load_rerank_model(reranker_path)?;
+        }
+        if args.prompt_lookup {
+            model.enable_prompt_lookup(args.prompt_lookup_max_ngram, args.num_speculative_tokens);
+        }
+        Ok(())
+    })
+}
+
+async fn run_batch_file(input: &str, output: &str, error_output: Option<&str>, concurrency: usize) -> anyhow::Result<()> {
+    let lines = batch::parse_input(&fs::read_to_string(input)?)?;
+    let error_output = error_output.map(Path::new);
+    let counts = batch::run_batch(lines, None, Path::new(output), error_output, concurrency, |counts| {
+        eprint!("\r{}/{} completed, {} failed", counts.completed + counts.failed, counts.total, counts.failed);
+        true
+    })
+    .await?;
+    eprintln!();
+    println!("{} requests: {} completed, {} failed", counts.total, counts.completed, counts.failed);
+    Ok(())
+}
+
+async fn run_chat(system: Option<&str>) -> anyhow::Result<()> {
+    let mut session = ChatSession::default();
+    if let Some(system) = system {
+        session.set_system(fs::read_to_string(system)?.trim());
+    }
+    println!("type /help for commands, /exit to quit");
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!(">>> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('/') {
+            match session.command(line) {
+                Ok(CommandResult::Reply(reply)) => println!("{}", reply),
+                Ok(CommandResult::Exit) => break,
+                Err(e) => println!("error: {}", e),
+            }
+            continue;
+        }
+        session.push_user(line);
+        match chat_reply(&session).await {
+            Ok(text) => session.push_assistant(&text),
+            Err(e) => {
+                session.pop();
+                println!("error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// 边生成边输出, 返回生成的原始文本
+async fn chat_reply(session: &ChatSession) -> anyhow::Result<String> {
+    let request = session.chat_request()?;
+    let mut stream = Box::pin(chat_text_stream(&request).await?);
+    let mut renderer = Renderer::default();
+    let mut text = String::new();
+    let start = std::time::Instant::now();
+    let mut completion_tokens = 0;
+    while let Some(generated) = stream.next().await {
+        let generated = match generated {
+            Ok(generated) => generated,
+            Err(e) => {
+                println!("{}", renderer.finish());
+                return Err(e);
+            }
+        };
+        text.push_str(&generated.text);
+        print!("{}", renderer.push(&generated.text));
+        std::io::stdout().flush()?;
+        completion_tokens = generated.completion_tokens;
+    }
+    println!("{}", renderer.finish());
+    let seconds = start.elapsed().as_secs_f64();
+    println!(
+        "\x1b[2m[{} tokens, {:.1} tokens/s]\x1b[0m",
+        completion_tokens,
+        completion_tokens as f64 / seconds.max(1e-6)
+    );
+    Ok(text)
+}
+
 fn write_pid() -> anyhow::Result<()> {
     let pid = std::process::id();
     fs::File::create(&env::current_exe()?.parent().unwrap().join(".pid"))?
@@ -0,0 +1,575 @@
+use candle_core::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, VarBuilder};
+use candle_transformers::models::qwen3::Config;
+use candle_transformers::models::with_tracing::{Linear, RmsNorm, linear_b, linear_no_bias};
+use std::sync::Arc;
+
+use crate::kv_pool::{Block, KV_BLOCK_SIZE, KvLayout, KvPool};
+
+// 基于 candle-transformers 的 qwen3 实现
+// KV cache 不放在模型内部, 由每个序列自己持有, 这样 prompt 的 prefill 结果可以在多个序列间共享
+
+// 序列的 KV cache: 块表记录每 block_size 个 token 存在池中的哪个块
+// fork 出的序列共享已有的块, 写入共享的块之前先复制一份 (copy-on-write), 所以共享前缀不需要复制
+#[derive(Debug)]
+pub struct KvCache {
+    pool: KvPool,
+    blocks: Vec<Arc<Block>>,
+    len: usize,
+    // 本次前向所有位置在池中的行号, 每层共用
+    slots: Option<Tensor>,
+}
+
+impl KvCache {
+    pub fn new(pool: KvPool) -> Self {
+        KvCache {
+            pool,
+            blocks: Vec::new(),
+            len: 0,
+            slots: None,
+        }
+    }
+
+    pub fn pool(&self) -> &KvPool {
+        &self.pool
+    }
+
+    pub fn bytes_per_token(&self) -> u64 {
+        self.pool.layout().bytes_per_token()
+    }
+
+    // 已缓存的 token 数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // 块表中的块数, 包括预先分配还没写入的块
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // 写到 len 个 token 需要从池中新分配的块数, 包括写入共享块前复制的块
+    pub fn growth_blocks(&self, len: usize) -> usize {
+        if len <= self.len {
+            return 0;
+        }
+        let block_size = self.pool.block_size();
+        let needed = len.div_ceil(block_size).saturating_sub(self.blocks.len());
+        needed + self.shared_blocks(len)
+    }
+
+    // 写入 [self.len, len) 会碰到的已有块中被共享的块数
+    fn shared_blocks(&self, len: usize) -> usize {
+        let block_size = self.pool.block_size();
+        let end = len.div_ceil(block_size).min(self.blocks.len());
+        let start = (self.len / block_size).min(end);
+        self.blocks[start..end]
+            .iter()
+            .filter(|block| Arc::strong_count(block) > 1)
+            .count()
+    }
+
+    // 只保留前 len 个 token 的缓存, 之后不再用到的块归还给池
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        self.len = len;
+        self.blocks.truncate(len.div_ceil(self.pool.block_size()));
+    }
+
+    // 前 len 个 token 的副本, 直接共享块表中的块, 不复制数据
+    pub fn fork(&self, len: usize) -> KvCache {
+        let len = len.min(self.len);
+        KvCache {
+            pool: self.pool.clone(),
+            blocks: self.blocks[..len.div_ceil(self.pool.block_size())].to_vec(),
+            len,
+            slots: None,
+        }
+    }
+
+    // 每层已缓存的 (k, v), shape 为 (1, num_kv_heads, len, head_dim), 用于把会话保存到文件
+    pub fn layers(&self) -> Result<Vec<(Tensor, Tensor)>> {
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let slots = self.slots(self.len)?;
+        (0..self.pool.layout().num_layers)
+            .map(|layer| {
+                let (k, v) = self.pool.gather(layer, &slots)?;
+                Ok((heads_first(&k)?, heads_first(&v)?))
+            })
+            .collect()
+    }
+
+    // 从文件恢复的每层 (k, v) 写入新分配的块, 逐层复制到池所在的设备
+    pub fn restore(&mut self, layers: Vec<(Tensor, Tensor)>) -> Result<()> {
+        let layout = self.pool.layout().clone();
+        if layers.len() != layout.num_layers {
+            candle_core::bail!("expected {} layers, got {}", layout.num_layers, layers.len())
+        }
+        let Some((first, _)) = layers.first() else {
+            return Ok(());
+        };
+        let len = first.dim(2)?;
+        for (k, v) in layers.iter() {
+            if k.dims4()? != (1, layout.num_kv_heads, len, layout.head_dim) || k.dims() != v.dims() {
+                candle_core::bail!("unexpected kv cache shape {:?}", k.dims())
+            }
+        }
+        self.truncate(0);
+        self.reserve(len)?;
+        for (layer, (k, v)) in layers.iter().enumerate() {
+            let k = tokens_first(&k.to_device(&layout.device)?)?;
+            let v = tokens_first(&v.to_device(&layout.device)?)?;
+            self.write(layer, &k, &v)?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    // 保证块表放得下 len 个 token: 先复制将要写入的共享块, 再分配新的块
+    // 需要的块一次分配, 池不够时不改变块表
+    pub fn reserve(&mut self, len: usize) -> Result<()> {
+        let count = self.growth_blocks(len);
+        if count == 0 {
+            return Ok(());
+        }
+        let mut fresh = self.pool.allocate(count)?.into_iter();
+        let block_size = self.pool.block_size();
+        let end = len.div_ceil(block_size).min(self.blocks.len());
+        for index in (self.len / block_size).min(end)..end {
+            if Arc::strong_count(&self.blocks[index]) > 1 {
+                let block = fresh.next().expect("allocated blocks for copy-on-write");
+                let rows = self.len.saturating_sub(index * block_size).min(block_size);
+                self.pool.copy_block(&self.blocks[index], &block, rows)?;
+                self.blocks[index] = block;
+            }
+        }
+        self.blocks.extend(fresh);
+        Ok(())
+    }
+
+    // 位置 0..len 在池中的行号
+    fn slots(&self, len: usize) -> Result<Tensor> {
+        let block_size = self.pool.block_size();
+        let slots: Vec<u32> = (0..len)
+            .map(|pos| self.blocks[pos / block_size].id() * block_size as u32 + (pos % block_size) as u32)
+            .collect();
+        Tensor::new(slots, &self.pool.layout().device)
+    }
+
+    // 为接下来 len - self.len 个 token 的前向分配块并计算行号
+    fn begin(&mut self, len: usize) -> Result<()> {
+        self.reserve(len)?;
+        self.slots = Some(self.slots(len)?);
+        Ok(())
+    }
+
+    // 从 self.len 开始写入一层的 (k, v), shape 为 (rows, num_kv_heads, head_dim), 按块切开写入
+    fn write(&self, layer: usize, k: &Tensor, v: &Tensor) -> Result<()> {
+        let block_size = self.pool.block_size();
+        let rows = k.dim(0)?;
+        let mut written = 0;
+        while written < rows {
+            let pos = self.len + written;
+            let offset = pos % block_size;
+            let count = (block_size - offset).min(rows - written);
+            self.pool.write(
+                layer,
+                &self.blocks[pos / block_size],
+                offset,
+                &k.narrow(0, written, count)?.contiguous()?,
+                &v.narrow(0, written, count)?.contiguous()?,
+            )?;
+            written += count;
+        }
+        Ok(())
+    }
+
+    // 写入一层新 token 的 k/v, 返回包括它们在内的全部 (k, v), shape 为 (1, num_kv_heads, len, head_dim)
+    // 所有层都写完后由 commit 确认
+    fn append(&mut self, layer: usize, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        self.write(layer, &tokens_first(k)?, &tokens_first(v)?)?;
+        let slots = self
+            .slots
+            .as_ref()
+            .ok_or_else(|| candle_core::Error::Msg("kv cache append without begin".to_string()))?;
+        let (k, v) = self.pool.gather(layer, slots)?;
+        Ok((heads_first(&k)?, heads_first(&v)?))
+    }
+
+    fn commit(&mut self, len: usize) {
+        self.len += len;
+        self.slots = None;
+    }
+}
+
+// (1, num_kv_heads, len, head_dim) 与池中按行存放的 (len, num_kv_heads, head_dim) 互相转换
+fn tokens_first(x: &Tensor) -> Result<Tensor> {
+    x.squeeze(0)?.transpose(0, 1)?.contiguous()
+}
+
+fn heads_first(x: &Tensor) -> Result<Tensor> {
+    x.transpose(0, 1)?.unsqueeze(0)?.contiguous()
+}
+
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
+        let dim = cfg.head_dim;
+        let max_seq_len = cfg.max_position_embeddings;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    fn apply(&self, q: &Tensor, k: &Tensor, offset: usize) -> Result<(Tensor, Tensor)> {
+        let (_, _, seq_len, _) = q.dims4()?;
+        let cos = self.cos.narrow(0, offset, seq_len)?;
+        let sin = self.sin.narrow(0, offset, seq_len)?;
+        let q_embed = candle_nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+struct Mlp {
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            gate_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(cfg.hidden_size, cfg.intermediate_size, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(cfg.intermediate_size, cfg.hidden_size, vb.pp("down_proj"))?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let lhs = x.apply(&self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = x.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    q_norm: RmsNorm,
+    k_norm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    hidden_size: usize,
+    rotary_emb: Arc<RotaryEmbedding>,
+}
+
+impl Attention {
+    fn new(cfg: &Config, rotary_emb: Arc<RotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        if cfg.use_sliding_window {
+            candle_core::bail!("sliding window is not supported")
+        }
+        let head_dim = cfg.head_dim;
+        let num_heads = cfg.num_attention_heads;
+        let num_kv_heads = cfg.num_key_value_heads;
+        let bias = cfg.attention_bias;
+        Ok(Self {
+            q_proj: linear_b(cfg.hidden_size, num_heads * head_dim, bias, vb.pp("q_proj"))?,
+            k_proj: linear_b(cfg.hidden_size, num_kv_heads * head_dim, bias, vb.pp("k_proj"))?,
+            v_proj: linear_b(cfg.hidden_size, num_kv_heads * head_dim, bias, vb.pp("v_proj"))?,
+            o_proj: linear_b(num_heads * head_dim, cfg.hidden_size, bias, vb.pp("o_proj"))?,
+            q_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("q_norm"))?,
+            k_norm: RmsNorm::new(head_dim, cfg.rms_norm_eps, vb.pp("k_norm"))?,
+            num_heads,
+            num_kv_heads,
+            num_kv_groups: num_heads / num_kv_heads,
+            head_dim,
+            // config 中的 hidden_size 不一定等于 head_dim * num_heads
+            hidden_size: head_dim * num_heads,
+            rotary_emb,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        offset: usize,
+        cache: &mut KvCache,
+        layer: usize,
+    ) -> Result<Tensor> {
+        let (b, l, _) = x.dims3()?;
+        let q = self
+            .q_proj
+            .forward(x)?
+            .reshape((b, l, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(x)?
+            .reshape((b, l, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        // 每个 head 单独做 RmsNorm
+        let q = self
+            .q_norm
+            .forward(&q.flatten(0, 2)?)?
+            .reshape((b, self.num_heads, l, self.head_dim))?;
+        let k = self
+            .k_norm
+            .forward(&k.flatten(0, 2)?)?
+            .reshape((b, self.num_kv_heads, l, self.head_dim))?;
+
+        let (q, k) = self.rotary_emb.apply(&q, &k, offset)?;
+        let (k, v) = cache.append(layer, &k, &v)?;
+
+        // 同一个 kv head 对应的 q head 合在一起计算, 不必把 k/v 复制 num_kv_groups 份
+        let rows = self.num_kv_groups * l;
+        let q = q.reshape((b, self.num_kv_heads, rows, self.head_dim))?;
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let scores = (q.matmul(&k.t()?)? * scale)?;
+        let total = scores.dim(3)?;
+        let mut scores = scores.reshape((b, self.num_heads, l, total))?;
+        if let Some(mask) = mask {
+            scores = scores.broadcast_add(mask)?;
+        }
+        let probs = candle_nn::ops::softmax_last_dim(&scores)?.reshape((b, self.num_kv_heads, rows, total))?;
+        let output = probs.matmul(&v)?;
+        output
+            .reshape((b, self.num_heads, l, self.head_dim))?
+            .transpose(1, 2)?
+            .reshape((b, l, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: Mlp,
+    ln1: RmsNorm,
+    ln2: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &Config, rotary: Arc<RotaryEmbedding>, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            self_attn: Attention::new(cfg, rotary, vb.pp("self_attn"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+            ln1: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("input_layernorm"))?,
+            ln2: RmsNorm::new(
+                cfg.hidden_size,
+                cfg.rms_norm_eps,
+                vb.pp("post_attention_layernorm"),
+            )?,
+        })
+    }
+
+    fn forward(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        offset: usize,
+        cache: &mut KvCache,
+        layer: usize,
+    ) -> Result<Tensor> {
+        let h = self.ln1.forward(x)?;
+        let h = self.self_attn.forward(&h, mask, offset, cache, layer)?;
+        let x = (x + h)?;
+        let h = self.mlp.forward(&self.ln2.forward(&x)?)?;
+        x + h
+    }
+}
+
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    // 新建的 KV cache 从这个池中分配块, 默认按需扩容
+    kv_pool: KvPool,
+    device: Device,
+    dtype: DType,
+}
+
+impl Model {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let lm_head = if cfg.tie_word_embeddings {
+            None
+        } else {
+            Some(linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?)
+        };
+        Self::load(cfg, vb.pp("model"), lm_head)
+    }
+
+    // Qwen3-Embedding 的权重一般没有 model. 前缀, 也没有 lm_head, 只用来取 hidden states
+    pub fn new_embedding(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let vb = if vb.contains_tensor("model.embed_tokens.weight") {
+            vb.pp("model")
+        } else {
+            vb
+        };
+        Self::load(cfg, vb, None)
+    }
+
+    // lm_head 为 None 时与 embed_tokens 共享权重
+    fn load(cfg: &Config, vb: VarBuilder, lm_head: Option<Linear>) -> Result<Self> {
+        let embed_tokens = candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("embed_tokens"))?;
+        let rotary = Arc::new(RotaryEmbedding::new(vb.dtype(), cfg, vb.device())?);
+        let vb_l = vb.pp("layers");
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| DecoderLayer::new(cfg, rotary.clone(), vb_l.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let lm_head =
+            lm_head.unwrap_or_else(|| Linear::from_weights(embed_tokens.embeddings().clone(), None));
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm: RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("norm"))?,
+            lm_head,
+            kv_pool: KvPool::growable(
+                KvLayout {
+                    num_layers: cfg.num_hidden_layers,
+                    num_kv_heads: cfg.num_key_value_heads,
+                    head_dim: cfg.head_dim,
+                    dtype: vb.dtype(),
+                    device: vb.device().clone(),
+                },
+                KV_BLOCK_SIZE,
+            ),
+            device: vb.device().clone(),
+            dtype: vb.dtype(),
+        })
+    }
+
+    pub fn new_cache(&self) -> KvCache {
+        KvCache::new(self.kv_pool.clone())
+    }
+
+    pub fn kv_layout(&self) -> &KvLayout {
+        self.kv_pool.layout()
+    }
+
+    pub fn kv_pool(&self) -> &KvPool {
+        &self.kv_pool
+    }
+
+    // 换成另一个池, 已有的 cache 仍然使用原来的池
+    pub fn set_kv_pool(&mut self, pool: KvPool) -> Result<()> {
+        let (layout, expected) = (pool.layout(), self.kv_layout());
+        if (layout.num_layers, layout.num_kv_heads, layout.head_dim, layout.dtype)
+            != (expected.num_layers, expected.num_kv_heads, expected.head_dim, expected.dtype)
+        {
+            candle_core::bail!("kv pool layout {:?} does not match the model", layout)
+        }
+        self.kv_pool = pool;
+        Ok(())
+    }
+
+    // 一个 token 在所有层的 K 和 V 占用的字节数, 按实际加载的 dtype 计算
+    pub fn kv_bytes_per_token(&self) -> u64 {
+        self.kv_layout().bytes_per_token()
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    // input 接在 cache 之后, 返回最后一个位置的 logits, shape 为 (vocab_size,)
+    pub fn forward(&self, input: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let hidden = self.forward_hidden(input, cache)?;
+        let l = hidden.dim(1)?;
+        hidden
+            .narrow(1, l - 1, 1)?
+            .apply(&self.lm_head)?
+            .squeeze(0)?
+            .squeeze(0)?
+            .to_dtype(DType::F32)
+    }
+
+    // 返回 input 每个位置的 logits, shape 为 (input_len, vocab_size)
+    pub fn forward_all(&self, input: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        self.forward_hidden(input, cache)?
+            .apply(&self.lm_head)?
+            .squeeze(0)?
+            .to_dtype(DType::F32)
+    }
+
+    // 最后一个位置经过最终 RMSNorm 的 hidden state, shape 为 (hidden_size,), 用于 embedding
+    pub fn last_hidden_state(&self, input: &[u32]) -> Result<Tensor> {
+        let hidden = self.forward_hidden(input, &mut self.new_cache())?;
+        let l = hidden.dim(1)?;
+        hidden.narrow(1, l - 1, 1)?.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)
+    }
+
+    fn forward_hidden(&self, input: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        if input.is_empty() {
+            candle_core::bail!("empty model input")
+        }
+        let offset = cache.len();
+        cache.begin(offset + input.len())?;
+        let input = Tensor::new(input, &self.device)?.unsqueeze(0)?;
+        let (b, l) = input.dims2()?;
+        let mut h = self.embed_tokens.forward(&input)?;
+        let mask = if l == 1 {
+            None
+        } else {
+            Some(self.causal_mask(b, l, offset)?)
+        };
+        for (index, layer) in self.layers.iter().enumerate() {
+            h = layer.forward(&h, mask.as_ref(), offset, cache, index)?;
+        }
+        cache.commit(l);
+        self.norm.forward(&h)
+    }
+
+    fn causal_mask(&self, b: usize, tgt: usize, offset: usize) -> Result<Tensor> {
+        let mask: Vec<f32> = (0..tgt)
+            .flat_map(|i| {
+                (0..(tgt + offset)).map(move |j| if j <= i + offset { 0. } else { f32::NEG_INFINITY })
+            })
+            .collect();
+        Tensor::from_slice(&mask, (b, 1, tgt, tgt + offset), &self.device)?.to_dtype(self.dtype)
+    }
+}
@@ -0,0 +1,508 @@
+use crate::ChatRequest;
+use crate::error::ApiError;
+use crate::guided::JSON_GRAMMAR;
+use crate::qwen3::{Qwen3, StopReason};
+use crate::segment::{BlockParser, Segment};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Ollama 兼容层: /api/chat 和 /api/generate 转换成 ChatRequest, 流式输出为每行一个 JSON
+// 不支持 images; format 只支持 "json", 用 JSON 文法约束输出; 只有一个模型, model 和 keep_alive 不生效
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct OllamaOptions {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<i64>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub seed: Option<u64>,
+    // -1 表示不限制, -2 表示填满上下文, 都按不限制处理
+    pub num_predict: Option<i64>,
+    pub stop: Option<Vec<String>>,
+    pub repeat_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaChatRequest {
+    pub model: Option<String>,
+    pub messages: Vec<OllamaMessage>,
+    pub tools: Option<Vec<OllamaTool>>,
+    pub format: Option<Value>,
+    pub options: Option<OllamaOptions>,
+    pub stream: Option<bool>,
+    pub think: Option<bool>,
+    pub keep_alive: Option<Value>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    pub thinking: Option<String>,
+    pub images: Option<Vec<String>>,
+    pub tool_calls: Option<Vec<OllamaToolCall>>,
+    // 工具结果对应的工具名, 没有时按调用的顺序对应
+    pub tool_name: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaToolCall {
+    pub function: OllamaFunctionCall,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaFunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaTool {
+    pub function: OllamaFunction,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaFunction {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub parameters: Value,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OllamaGenerateRequest {
+    pub model: Option<String>,
+    #[serde(default)]
+    pub prompt: String,
+    pub suffix: Option<String>,
+    pub system: Option<String>,
+    pub template: Option<String>,
+    // 上一次返回的 context, 拼接在这次的 prompt 之前
+    pub context: Option<Vec<u32>>,
+    // 不套用对话模板, 直接使用 prompt
+    pub raw: Option<bool>,
+    pub format: Option<Value>,
+    pub options: Option<OllamaOptions>,
+    pub stream: Option<bool>,
+    pub think: Option<bool>,
+    pub images: Option<Vec<String>>,
+    pub keep_alive: Option<Value>,
+}
+
+// 与 Ollama 一致, 默认使用流式输出
+pub fn is_stream(stream: Option<bool>) -> bool {
+    stream != Some(false)
+}
+
+// 错误格式为 {"error": message}, 流式输出中途出错时也作为最后一行输出
+pub fn error_value(e: &ApiError) -> Value {
+    json!({"error": e.message})
+}
+
+pub fn error_json(e: &ApiError) -> String {
+    error_value(e).to_string()
+}
+
+fn guided_grammar(format: &Option<Value>) -> Result<Option<&'static str>, ApiError> {
+    match format {
+        None => Ok(None),
+        Some(Value::String(format)) if format.is_empty() => Ok(None),
+        Some(Value::String(format)) if format == "json" => Ok(Some(JSON_GRAMMAR)),
+        Some(_) => Err(ApiError::invalid_request("format only supports \"json\"", Some("format"))),
+    }
+}
+
+// 采样参数等公共字段转换成 OpenAI 格式, 剩下的校验交给 ChatRequest::validate
+fn chat_request(
+    messages: Vec<Value>,
+    tools: Option<Vec<Value>>,
+    options: &Option<OllamaOptions>,
+    format: &Option<Value>,
+    think: Option<bool>,
+) -> Result<ChatRequest, ApiError> {
+    let default = OllamaOptions::default();
+    let options = options.as_ref().unwrap_or(&default);
+    let max_tokens = options.num_predict.filter(|&num_predict| num_predict >= 0);
+    let request = json!({
+        "messages": messages,
+        "tools": tools,
+        "max_tokens": max_tokens,
+        "temperature": options.temperature,
+        "top_p": options.top_p,
+        "top_k": options.top_k,
+        "min_p": options.min_p,
+        "typical_p": options.typical_p,
+        "seed": options.seed,
+        "repetition_penalty": options.repeat_penalty,
+        "presence_penalty": options.presence_penalty,
+        "frequency_penalty": options.frequency_penalty,
+        "stop": options.stop,
+        "guided_grammar": guided_grammar(format)?,
+        "chat_template_kwargs": {"enable_thinking": think.unwrap_or(true)},
+    });
+    serde_json::from_value(request).map_err(|e| ApiError::invalid_request(e.to_string(), None))
+}
+
+fn check_images(images: &Option<Vec<String>>, param: &str) -> Result<(), ApiError> {
+    match images {
+        Some(images) if !images.is_empty() => Err(ApiError::invalid_request("images are not supported", Some(param))),
+        _ => Ok(()),
+    }
+}
+
+impl OllamaChatRequest {
+    // Ollama 的工具调用没有 id, 生成 id 后按工具名或顺序与工具结果对应
+    pub fn to_chat_request(&self) -> Result<ChatRequest, ApiError> {
+        if self.messages.is_empty() {
+            return Err(ApiError::invalid_request(
+                "messages must contain at least one message",
+                Some("messages"),
+            ));
+        }
+        let mut messages = Vec::new();
+        let mut pending: VecDeque<(String, String)> = VecDeque::new();
+        for (index, message) in self.messages.iter().enumerate() {
+            check_images(&message.images, &format!("messages[{}].images", index))?;
+            match message.role.as_str() {
+                "system" | "user" => messages.push(json!({"role": message.role, "content": message.content})),
+                "assistant" => {
+                    let content = match &message.thinking {
+                        Some(thinking) if !thinking.is_empty() => {
+                            format!("<think>\n{}\n</think>\n\n{}", thinking, message.content)
+                        }
+                        _ => message.content.clone(),
+                    };
+                    let mut assistant = json!({"role": "assistant", "content": content});
+                    if let Some(tool_calls) = &message.tool_calls {
+                        let tool_calls: Vec<Value> = tool_calls
+                            .iter()
+                            .enumerate()
+                            .map(|(call_index, tool_call)| {
+                                let id = format!("call_{}_{}", index, call_index);
+                                pending.push_back((id.clone(), tool_call.function.name.clone()));
+                                json!({
+                                    "id": id,
+                                    "type": "function",
+                                    "function": {"name": tool_call.function.name, "arguments": tool_call.function.arguments},
+                                })
+                            })
+                            .collect();
+                        assistant["tool_calls"] = Value::Array(tool_calls);
+                    }
+                    messages.push(assistant);
+                }
+                "tool" => {
+                    let position = match &message.tool_name {
+                        Some(name) => pending.iter().position(|(_, pending_name)| pending_name == name),
+                        None => (!pending.is_empty()).then_some(0),
+                    };
+                    let Some((id, _)) = position.and_then(|position| pending.remove(position)) else {
+                        return Err(ApiError::invalid_request(
+                            format!("messages[{}]: tool message does not match any previous tool call", index),
+                            Some(&format!("messages[{}]", index)),
+                        ));
+                    };
+                    messages.push(json!({"role": "tool", "tool_call_id": id, "content": message.content}));
+                }
+                role => {
+                    return Err(ApiError::invalid_request(
+                        format!("role must be system, user, assistant or tool, got {}", role),
+                        Some(&format!("messages[{}].role", index)),
+                    ));
+                }
+            }
+        }
+        let tools = self.tools.as_ref().map(|tools| {
+            tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "type": "function",
+                        "function": {
+                            "name": tool.function.name,
+                            "description": tool.function.description.clone().unwrap_or_default(),
+                            "parameters": tool.function.parameters,
+                        }
+                    })
+                })
+                .collect()
+        });
+        chat_request(messages, tools, &self.options, &self.format, self.think)
+    }
+}
+
+impl OllamaGenerateRequest {
+    pub fn is_raw(&self) -> bool {
+        self.raw == Some(true)
+    }
+
+    // prompt 和 context 都为空时只加载模型, 直接返回
+    pub fn is_load(&self) -> bool {
+        self.prompt.is_empty() && self.context.as_ref().is_none_or(Vec::is_empty)
+    }
+
+    // 非 raw 模式下 prompt 作为一条用户消息套用对话模板
+    pub fn to_chat_request(&self) -> Result<ChatRequest, ApiError> {
+        if self.suffix.as_ref().is_some_and(|suffix| !suffix.is_empty()) {
+            return Err(ApiError::invalid_request("suffix is not supported", Some("suffix")));
+        }
+        if self.template.as_ref().is_some_and(|template| !template.is_empty()) {
+            return Err(ApiError::invalid_request("template is not supported", Some("template")));
+        }
+        check_images(&self.images, "images")?;
+        let mut messages = Vec::new();
+        if let Some(system) = &self.system
+            && !self.is_raw()
+        {
+            messages.push(json!({"role": "system", "content": system}));
+        }
+        messages.push(json!({"role": "user", "content": self.prompt}));
+        chat_request(messages, None, &self.options, &self.format, self.think)
+    }
+}
+
+fn created_at() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+}
+
+// 生成结果转换成 Ollama 的输出, /api/chat 的输出放在 message 中, /api/generate 的输出放在 response 中
+#[derive(Debug)]
+pub struct OllamaReply {
+    model: String,
+    chat: bool,
+    // raw 模式不解析标签
+    parser: Option<BlockParser>,
+    prompt_tokens: usize,
+    started: Instant,
+    // 第一个 token 生成前的时间
+    prompt_eval: Option<Duration>,
+}
+
+impl OllamaReply {
+    pub fn chat(model: &str, prompt_tokens: usize) -> Self {
+        OllamaReply {
+            model: model.to_string(),
+            chat: true,
+            parser: Some(BlockParser::default()),
+            prompt_tokens,
+            started: Instant::now(),
+            prompt_eval: None,
+        }
+    }
+
+    pub fn generate(model: &str, prompt_tokens: usize, raw: bool) -> Self {
+        OllamaReply {
+            chat: false,
+            parser: (!raw).then(BlockParser::default),
+            ..OllamaReply::chat(model, prompt_tokens)
+        }
+    }
+
+    pub fn prompt_evaluated(&mut self) {
+        self.prompt_evaluated_at(Instant::now());
+    }
+
+    pub fn prompt_evaluated_at(&mut self, at: Instant) {
+        if self.prompt_eval.is_none() {
+            self.prompt_eval = Some(at.saturating_duration_since(self.started));
+        }
+    }
+
+    // 流式输出的一个 chunk, 没有可以输出的内容时返回 None
+    pub fn push(&mut self, text: &str) -> Option<Value> {
+        self.prompt_evaluated();
+        let output = match self.parser.as_mut() {
+            Some(parser) => {
+                let segments = parser.push(text);
+                self.output(segments)
+            }
+            None => self.output(vec![Segment::Text(text.to_string())]),
+        };
+        output.map(|mut output| {
+            output["done"] = json!(false);
+            output
+        })
+    }
+
+    // 流式输出结束时剩下的 chunk 和带统计信息的最后一个 chunk
+    pub fn finish(&mut self, finish_reason: StopReason, output_tokens: usize, context: Option<Vec<u32>>) -> Vec<Value> {
+        let segments = self.parser.as_mut().map(BlockParser::finish).unwrap_or_default();
+        let mut chunks: Vec<Value> = self.output(segments).into_iter().collect();
+        for chunk in chunks.iter_mut() {
+            chunk["done"] = json!(false);
+        }
+        chunks.push(self.done(self.empty(), finish_reason, output_tokens, context));
+        chunks
+    }
+
+    // 非流式输出, 全部内容放在一个对象中
+    pub fn response(
+        &mut self,
+        text: &str,
+        finish_reason: StopReason,
+        output_tokens: usize,
+        context: Option<Vec<u32>>,
+    ) -> Value {
+        let segments = match self.parser.as_mut() {
+            Some(parser) => {
+                let mut segments = parser.push(text);
+                segments.extend(parser.finish());
+                segments
+            }
+            None => vec![Segment::Text(text.to_string())],
+        };
+        let output = self.output(segments).unwrap_or_else(|| self.empty());
+        self.done(output, finish_reason, output_tokens, context)
+    }
+
+    // prompt 为空时只返回加载的结果
+    pub fn load(model: &str) -> Value {
+        json!({"model": model, "created_at": created_at(), "response": "", "done": true, "done_reason": "load"})
+    }
+
+    fn empty(&self) -> Value {
+        self.build(String::new(), String::new(), Vec::new())
+    }
+
+    fn output(&self, segments: Vec<Segment>) -> Option<Value> {
+        if segments.is_empty() {
+            return None;
+        }
+        let mut content = String::new();
+        let mut thinking = String::new();
+        let mut tool_calls = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => content.push_str(&text),
+                Segment::Thinking(text) => thinking.push_str(&text),
+                Segment::ToolCall { name, input } if self.chat => {
+                    tool_calls.push(json!({"function": {"name": name, "arguments": input}}))
+                }
+                // /api/generate 没有工具, 按模型的原始格式输出
+                Segment::ToolCall { name, input } => content.push_str(&format!(
+                    "<tool_call>\n{}\n</tool_call>",
+                    json!({"name": name, "arguments": input})
+                )),
+            }
+        }
+        Some(self.build(content, thinking, tool_calls))
+    }
+
+    fn build(&self, content: String, thinking: String, tool_calls: Vec<Value>) -> Value {
+        let mut output = json!({"model": self.model, "created_at": created_at()});
+        if self.chat {
+            let mut message = json!({"role": "assistant", "content": content});
+            if !thinking.is_empty() {
+                message["thinking"] = json!(thinking);
+            }
+            if !tool_calls.is_empty() {
+                message["tool_calls"] = json!(tool_calls);
+            }
+            output["message"] = message;
+        } else {
+            output["response"] = json!(content);
+            if !thinking.is_empty() {
+                output["thinking"] = json!(thinking);
+            }
+        }
+        output
+    }
+
+    // 时间的单位为纳秒
+    fn done(&self, mut output: Value, finish_reason: StopReason, output_tokens: usize, context: Option<Vec<u32>>) -> Value {
+        let total = self.started.elapsed();
+        let prompt_eval = self.prompt_eval.unwrap_or(total);
+        output["done"] = json!(true);
+        output["done_reason"] = json!(finish_reason.as_str());
+        output["total_duration"] = json!(total.as_nanos() as u64);
+        output["load_duration"] = json!(0);
+        output["prompt_eval_count"] = json!(self.prompt_tokens);
+        output["prompt_eval_duration"] = json!(prompt_eval.as_nanos() as u64);
+        output["eval_count"] = json!(output_tokens);
+        output["eval_duration"] = json!((total - prompt_eval).as_nanos() as u64);
+        if let Some(context) = context {
+            output["context"] = json!(context);
+        }
+        output
+    }
+}
+
+// /api/tags 中的一项, 大小为权重文件的总大小, digest 使用模型的指纹
+pub fn model_entry(name: &str, path: &str, fingerprint: u64) -> anyhow::Result<Value> {
+    let size = weights_size(path)?;
+    let config = read_config(path)?;
+    let modified_at = std::fs::metadata(format!("{}/config.json", path))
+        .and_then(|metadata| metadata.modified())
+        .map(chrono::DateTime::<chrono::Utc>::from)
+        .unwrap_or_else(|_| chrono::Utc::now());
+    Ok(json!({
+        "name": name,
+        "model": name,
+        "modified_at": modified_at.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+        "size": size,
+        "digest": format!("{:016x}", fingerprint),
+        "details": details(&config, size),
+    }))
+}
+
+// /api/show 的模型信息, model_info 的 key 与 GGUF 的元数据一致
+pub fn model_show(path: &str) -> anyhow::Result<Value> {
+    let config = read_config(path)?;
+    let size = weights_size(path)?;
+    Ok(json!({
+        "modelfile": "",
+        "parameters": "",
+        "template": include_str!("chat_template.jinja"),
+        "details": details(&config, size),
+        "model_info": {
+            "general.architecture": "qwen3",
+            "qwen3.context_length": config["max_position_embeddings"],
+            "qwen3.embedding_length": config["hidden_size"],
+            "qwen3.feed_forward_length": config["intermediate_size"],
+            "qwen3.block_count": config["num_hidden_layers"],
+            "qwen3.attention.head_count": config["num_attention_heads"],
+            "qwen3.attention.head_count_kv": config["num_key_value_heads"],
+            "qwen3.attention.key_length": config["head_dim"],
+            "qwen3.rope.freq_base": config["rope_theta"],
+            "qwen3.vocab_size": config["vocab_size"],
+        },
+        "capabilities": ["completion", "tools", "thinking"],
+    }))
+}
+
+fn weights_size(path: &str) -> anyhow::Result<u64> {
+    Ok(Qwen3::find_safetensors_files(path)?
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum())
+}
+
+fn read_config(path: &str) -> anyhow::Result<Value> {
+    let config = std::fs::read_to_string(format!("{}/config.json", path))?;
+    Ok(serde_json::from_str(&config)?)
+}
+
+// 参数量按权重文件大小和数据类型估算
+fn details(config: &Value, size: u64) -> Value {
+    let dtype = config["torch_dtype"].as_str().unwrap_or("bfloat16");
+    let (quantization_level, bytes) = match dtype {
+        "float32" => ("F32", 4),
+        "float16" => ("F16", 2),
+        _ => ("BF16", 2),
+    };
+    json!({
+        "parent_model": "",
+        "format": "safetensors",
+        "family": "qwen3",
+        "families": ["qwen3"],
+        "parameter_size": format!("{:.1}B", size as f64 / bytes as f64 / 1e9),
+        "quantization_level": quantization_level,
+    })
+}
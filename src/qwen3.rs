@@ -1,29 +1,247 @@
-use crate::ChatRequest;
-use crate::utils::{get_device, str_startswith, str_endswith};
-use candle_core::{DType, Device, Tensor};
+use crate::{ChatRequest, Message, Tool};
+use crate::beam::{BeamHypotheses, BeamSearchParam, Hypothesis, top_candidates};
+use crate::kv_pool::{KV_BLOCK_SIZE, KvPool};
+use crate::detokenizer::IncrementalDecoder;
+use crate::embedding::{self, EmbeddingInput, EmbeddingModel};
+use crate::error::ApiError;
+use crate::guided::{Grammar, GuidedMatcher, TokenTrie};
+use crate::model::{KvCache, Model};
+use crate::rerank::{self, RerankModel};
+use crate::sampling::{Sampler, SamplingParam};
+use crate::session::{self, Session, common_prefix_len};
+use crate::speculative::{DraftModel, PromptLookup, Proposer, SpeculativeStats, verify};
+use crate::stop::StopChecker;
+use crate::utils::{byte_level_decode, get_device, str_startswith, str_endswith};
+use candle_core::{D, DType, Device, Tensor};
 use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::qwen3::{Config, ModelForCausalLM};
+use candle_transformers::models::qwen3::Config;
 use minijinja::{Environment, Value as MiniJinjaValue, context};
 use rocket::async_stream::stream;
-use rocket::futures::Stream;
+use rocket::futures::{Stream, StreamExt};
 
+use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Instant;
 use tokenizers::tokenizer::Tokenizer;
 
 
 
 pub struct Qwen3<'a> {
+    // 模型目录, 用于列出模型信息
+    path: String,
     tokenizer: Tokenizer,
-    model: ModelForCausalLM,
-    logits_processor: LogitsProcessor,
+    model: Model,
+    sampling: SamplingParam,
+    seed: u64,
+    request_count: u64,
     jinja_env: Environment<'a>,
-    device: Device,
     max_generate: usize,
-    repeat_penalty: f32,
     repeat_last_n: usize,
     eos_token1: Option<u32>,
     eos_token2: Option<u32>,
+    max_position_embeddings: usize,
+    vocab_size: usize,
+    token_trie: Option<Arc<TokenTrie>>,
+    device: Device,
+    proposer: Option<Proposer>,
+    speculative_stats: SpeculativeStats,
+    // 长 prompt 按块做 prefill, None 表示一次处理整个 prompt
+    prefill_chunk_size: Option<usize>,
+    // KV cache 的显存预算, None 表示不限制; 设置后目标模型和草稿模型的 KV 池按预算固定大小
+    kv_cache_budget: Option<u64>,
+    // KV 池每块的 token 数
+    kv_block_size: usize,
+    // 所有未结束的任务, 预算不够时从中挑选抢占对象
+    tickets: Vec<Weak<TaskTicket>>,
+    next_task_id: u64,
+    sessions: HashMap<String, Session>,
+    max_sessions: usize,
+    // 保存的会话只能恢复到指纹相同的模型
+    fingerprint: u64,
+    // 没有单独加载 embedding 模型时用对话模型计算 embedding
+    embedding_model: Option<EmbeddingModel>,
+    // 没有单独加载 reranker 模型时用对话模型打分
+    rerank_model: Option<RerankModel>,
+}
+
+// 单次请求的生成参数
+#[derive(Debug, Clone, Default)]
+pub struct GenerateParam {
+    pub guided: Option<Arc<Grammar>>,
+    pub max_tokens: Option<usize>,
+    pub sampling: SamplingParam,
+    // Some(n) 表示返回 logprobs 以及 n 个 top_logprobs
+    pub logprobs: Option<usize>,
+    // 同一个 prompt 独立生成的序列数, 默认为 1; beam search 时为返回的 beam 数
+    pub n: Option<usize>,
+    // 设置后使用 beam search, 忽略采样参数
+    pub beam: Option<BeamSearchParam>,
+    // 生成结束后把第一个序列的 KV cache 保存到该会话, 下次请求复用相同的前缀
+    pub session_id: Option<String>,
+    // 停止字符串, 输出中不包含
+    pub stop: Vec<String>,
+}
+
+// top_logprobs 最多返回的候选数, 与 OpenAI 一致
+pub const MAX_TOP_LOGPROBS: usize = 20;
+
+// 内存中最多保留的会话数
+pub const DEFAULT_MAX_SESSIONS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct TopLogprob {
+    pub token: String,
+    pub bytes: Vec<u8>,
+    pub logprob: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub bytes: Vec<u8>,
+    pub logprob: f32,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+// 序列结束的原因: 遇到 eos, 遇到第几个停止字符串, 或者达到长度上限
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Stop,
+    StopSequence(usize),
+    Length,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Stop | StopReason::StopSequence(_) => "stop",
+            StopReason::Length => "length",
+        }
+    }
+}
+
+// 流式输出的一段文本, 以及这段文本对应的 token logprobs, index 为所属的序列
+// 序列的最后一段带有 finish_reason, 文本可能为空
+#[derive(Debug, Clone)]
+pub struct GenerateToken {
+    pub index: usize,
+    pub text: String,
+    pub logprobs: Vec<TokenLogprob>,
+    pub finish_reason: Option<StopReason>,
+    // 该序列到目前为止生成的 token 数
+    pub completion_tokens: usize,
+}
+
+// 非流式生成的结果
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub text: String,
+    pub logprobs: Vec<TokenLogprob>,
+    // beam search 的累计 logprob 和长度惩罚后的得分
+    pub cumulative_logprob: Option<f32>,
+    pub score: Option<f32>,
+    pub finish_reason: StopReason,
+    pub completion_tokens: usize,
+}
+
+// beam search 中仍在搜索的一个 beam
+struct Beam {
+    tokens: Vec<u32>,
+    cache: KvCache,
+    logits: Tensor,
+    cumulative_logprob: f32,
+}
+
+// 进行中的 beam search, 每次 advance 推进一步, 步与步之间可以穿插其它请求
+struct BeamSearch {
+    param: BeamSearchParam,
+    beams: Vec<Beam>,
+    finished: BeamHypotheses,
+    step: usize,
+    max_new_tokens: usize,
+}
+
+// 一个生成序列的状态, n > 1 时各序列共享 prompt 的 KV cache, 各自写入生成的部分
+struct GenerateState {
+    index: usize,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    max_new_tokens: usize,
+    cache: KvCache,
+    // KV cache 预算不够时释放 cache, 之后预算足够时再重新计算
+    preempted: bool,
+    // prefill 得到的 logits, 第一个 token 直接用它采样
+    logits: Option<Tensor>,
+    // 草稿模型的 KV cache, 第一次投机时才做 prefill
+    draft_cache: Option<KvCache>,
+    sampler: Sampler,
+    matcher: Option<GuidedMatcher>,
+    top_logprobs: Option<usize>,
+    logprobs: Vec<TokenLogprob>,
+    // 已经随流式输出返回的 logprobs 数
+    emitted_logprobs: usize,
+    // 流式输出的增量解码进度
+    decoder: IncrementalDecoder,
+    // 设置了停止字符串时代替 decoder 决定输出的文本
+    stop: Option<StopChecker>,
+    finished: bool,
+    finish_reason: StopReason,
+}
+
+// 一次请求的生成任务, 所有状态都保存在任务中, 每次调用 Qwen3::advance 推进一步
+// 调用方可以在两步之间释放模型, 让其它请求的 prefill 和解码穿插执行
+pub struct GenerateTask {
+    prompt: Vec<u32>,
+    param: GenerateParam,
+    // 分块 prefill 的进度, 开始 prefill 时一次性为整个 prompt 分配块
+    cache: KvCache,
+    // prefill 完成后才创建
+    states: Vec<GenerateState>,
+    beam: Option<BeamSearch>,
+    // beam search 的结果
+    generations: Option<Vec<Generation>>,
+    // prompt 处理完成的时间
+    prefilled_at: Option<Instant>,
+    ticket: Arc<TaskTicket>,
+    // 上一步因为 KV cache 预算不够没有推进
+    waiting: bool,
+    finished: bool,
+}
+
+// 任务的调度信息, 任务持有 Arc, Qwen3 只保留 Weak, 任务 drop 后自动失效
+// id 按创建顺序递增, 预算不够时优先抢占最晚创建的任务, 先到的请求先完成
+#[derive(Debug)]
+struct TaskTicket {
+    id: u64,
+    // 当前占用着 KV cache
+    holding: AtomicBool,
+    // 被更早的任务选为抢占对象, 下次推进时释放 KV cache
+    preempt: AtomicBool,
+}
+
+impl GenerateTask {
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // 调用方看到任务在等待时应释放模型锁, 等其它请求归还 KV cache 预算后再推进
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    pub fn prefilled_at(&self) -> Option<Instant> {
+        self.prefilled_at
+    }
+}
+
+// 渲染并编码后的 prompt, truncated_messages 只在开启自动截断时返回
+pub struct PreparedPrompt {
+    pub tokens: Vec<u32>,
+    pub param: GenerateParam,
+    pub truncated_messages: Option<usize>,
 }
 
 impl<'a> Qwen3<'a> {
@@ -54,19 +272,14 @@ impl<'a> Qwen3<'a> {
         let eos_token1 = tokenizer.get_vocab(true).get("<|endoftext|>").copied();
         let eos_token2 = tokenizer.get_vocab(true).get("<|im_end|>").copied();
         let device = if is_cpu { Device::Cpu } else { get_device()? };
-        let weight_files = Self::find_safetensors_files(&path)?;
-        assert_ne!(weight_files.len(), 0, "no safetensors files found");
-        let vb =
-            unsafe { VarBuilder::from_mmaped_safetensors(&weight_files, DType::F16, &device)? };
-        let config_file = path.clone() + "/config.json";
-        assert!(
-            std::path::Path::new(&config_file).exists(),
-            "config.json not exists in model path"
-        );
-        let config: Config = serde_json::from_slice(&std::fs::read(config_file)?)
-            .map_err(|e| anyhow::anyhow!(format!("load config file error{}", e)))?;
-        let model = ModelForCausalLM::new(&config, vb)?;
-        let logits_processor = LogitsProcessor::new(seed, temperature, top_p);
+        let (model, config) = Self::load_model(&path, &device)?;
+        let fingerprint = session::model_fingerprint(&path, &Self::find_safetensors_files(&path)?)?;
+        let sampling = SamplingParam {
+            temperature,
+            top_p,
+            repetition_penalty: Some(repeat_penalty),
+            ..Default::default()
+        };
 
         let mut env = Environment::new();
 
@@ -98,20 +311,363 @@ impl<'a> Qwen3<'a> {
         let _ = env.add_template("chat", include_str!("chat_template.jinja"));
 
         Ok(Self {
+            path,
             tokenizer,
             model: model,
-            logits_processor: logits_processor,
+            sampling,
+            seed,
+            request_count: 0,
             jinja_env: env,
-            device,
             max_generate,
-            repeat_penalty,
             repeat_last_n,
             eos_token1,
             eos_token2,
+            max_position_embeddings: config.max_position_embeddings,
+            vocab_size: config.vocab_size,
+            token_trie: None,
+            device,
+            proposer: None,
+            speculative_stats: SpeculativeStats::default(),
+            prefill_chunk_size: None,
+            kv_cache_budget: None,
+            kv_block_size: KV_BLOCK_SIZE,
+            tickets: Vec::new(),
+            next_task_id: 0,
+            sessions: HashMap::new(),
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            fingerprint,
+            embedding_model: None,
+            rerank_model: None,
         })
     }
 
-    fn find_safetensors_files(path: &str) -> anyhow::Result<Vec<String>> {
+    fn load_model(path: &str, device: &Device) -> anyhow::Result<(Model, Config)> {
+        let (vb, config) = Self::load_weights(path, device)?;
+        let model = Model::new(&config, vb)?;
+        Ok((model, config))
+    }
+
+    fn load_weights<'v>(path: &str, device: &Device) -> anyhow::Result<(VarBuilder<'v>, Config)> {
+        let weight_files = Self::find_safetensors_files(path)?;
+        assert_ne!(weight_files.len(), 0, "no safetensors files found");
+        let vb =
+            unsafe { VarBuilder::from_mmaped_safetensors(&weight_files, DType::F16, device)? };
+        let config_file = path.to_string() + "/config.json";
+        assert!(
+            std::path::Path::new(&config_file).exists(),
+            "config.json not exists in model path"
+        );
+        let config: Config = serde_json::from_slice(&std::fs::read(config_file)?)
+            .map_err(|e| anyhow::anyhow!(format!("load config file error{}", e)))?;
+        Ok((vb, config))
+    }
+
+    // 加载 Qwen3-Embedding 模型, 之后的 embedding 请求都用它计算
+    pub fn load_embedding_model(&mut self, path: &str) -> anyhow::Result<()> {
+        let tokenizer = Tokenizer::from_file(path.to_string() + "/tokenizer.json")
+            .map_err(|e| anyhow::anyhow!(format!("tokenizer from file error{}", e)))?;
+        let (vb, config) = Self::load_weights(path, &self.device)?;
+        let model = Model::new_embedding(&config, vb)?;
+        let eos_token = tokenizer.get_vocab(true).get("<|endoftext|>").copied();
+        self.embedding_model = Some(EmbeddingModel {
+            tokenizer,
+            model,
+            eos_token,
+            max_position_embeddings: config.max_position_embeddings,
+        });
+        Ok(())
+    }
+
+    // 返回未归一化的向量和输入的 token 数
+    pub fn embed(&self, input: &EmbeddingInput) -> anyhow::Result<(Vec<f32>, usize)> {
+        match &self.embedding_model {
+            Some(embedding_model) => embedding::embed(
+                &embedding_model.tokenizer,
+                &embedding_model.model,
+                embedding_model.eos_token,
+                embedding_model.max_position_embeddings,
+                input,
+            ),
+            None => embedding::embed(
+                &self.tokenizer,
+                &self.model,
+                self.eos_token1,
+                self.max_position_embeddings,
+                input,
+            ),
+        }
+    }
+
+    // 加载 Qwen3-Reranker 模型, 它就是普通的 Qwen3 CausalLM 权重
+    pub fn load_rerank_model(&mut self, path: &str) -> anyhow::Result<()> {
+        let tokenizer = Tokenizer::from_file(path.to_string() + "/tokenizer.json")
+            .map_err(|e| anyhow::anyhow!(format!("tokenizer from file error{}", e)))?;
+        let (model, config) = Self::load_model(path, &self.device)?;
+        self.rerank_model = Some(RerankModel {
+            tokenizer,
+            model,
+            max_position_embeddings: config.max_position_embeddings,
+        });
+        Ok(())
+    }
+
+    // 返回每个文档的相关性(0~1)和处理的 token 总数
+    pub fn rerank(&self, instruction: &str, query: &str, documents: &[String]) -> anyhow::Result<(Vec<f32>, usize)> {
+        match &self.rerank_model {
+            Some(rerank_model) => rerank::rerank(
+                &rerank_model.tokenizer,
+                &rerank_model.model,
+                rerank_model.max_position_embeddings,
+                instruction,
+                query,
+                documents,
+            ),
+            None => rerank::rerank(
+                &self.tokenizer,
+                &self.model,
+                self.max_position_embeddings,
+                instruction,
+                query,
+                documents,
+            ),
+        }
+    }
+
+    // 加载与目标模型共用 tokenizer 的小模型作为投机解码的草稿模型
+    pub fn load_draft_model(&mut self, path: &str, num_speculative_tokens: usize) -> anyhow::Result<()> {
+        let (model, config) = Self::load_model(path, &self.device)?;
+        if config.vocab_size != self.vocab_size {
+            return Err(anyhow::anyhow!(
+                "draft model vocab_size {} does not match target model vocab_size {}",
+                config.vocab_size,
+                self.vocab_size
+            ));
+        }
+        self.proposer = Some(Proposer::Draft(DraftModel {
+            model,
+            num_speculative_tokens,
+        }));
+        self.speculative_stats = SpeculativeStats::default();
+        // 已经设置了预算时, 按两个模型重新划分池
+        self.allocate_kv_pools()
+    }
+
+    // 不使用草稿模型, 从 prompt 和已生成的内容中查找草稿, 适合大段复制输入的场景
+    pub fn enable_prompt_lookup(&mut self, max_ngram: usize, num_speculative_tokens: usize) {
+        self.proposer = Some(Proposer::PromptLookup(PromptLookup {
+            max_ngram,
+            min_ngram: 1,
+            num_speculative_tokens,
+        }));
+        self.speculative_stats = SpeculativeStats::default();
+    }
+
+    pub fn set_prefill_chunk_size(&mut self, chunk_size: Option<usize>) {
+        self.prefill_chunk_size = chunk_size.filter(|&chunk_size| chunk_size > 0);
+    }
+
+    // 至少保留一个会话
+    pub fn set_max_sessions(&mut self, max_sessions: usize) {
+        self.max_sessions = max_sessions.max(1);
+    }
+
+    pub fn model_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    // 按显存预算分配固定大小的 KV 池, 所有序列、会话和草稿模型的 KV cache 都从池中按块分配
+    // 块用完时请求等待、被抢占或被拒绝; block_size 为每块的 token 数
+    pub fn set_kv_cache_budget(&mut self, budget_bytes: u64, block_size: usize) -> anyhow::Result<()> {
+        self.kv_block_size = block_size.max(1);
+        self.kv_cache_budget = Some(budget_bytes);
+        self.allocate_kv_pools()?;
+        log::info!("kv cache budget: {} bytes, at most {} tokens", budget_bytes, self.max_context_len());
+        Ok(())
+    }
+
+    // 目标模型和草稿模型的池块数相同, 草稿模型的 cache 总能跟上目标模型
+    fn allocate_kv_pools(&mut self) -> anyhow::Result<()> {
+        let Some(budget_bytes) = self.kv_cache_budget else {
+            return Ok(());
+        };
+        let block_size = self.kv_block_size;
+        let mut block_bytes = self.model.kv_bytes_per_token() * block_size as u64;
+        if let Some(Proposer::Draft(draft)) = &self.proposer {
+            block_bytes += draft.model.kv_bytes_per_token() * block_size as u64;
+        }
+        let num_blocks = (budget_bytes / block_bytes) as usize;
+        if num_blocks == 0 {
+            return Err(anyhow::anyhow!(
+                "kv cache budget {} bytes is smaller than one block of {} bytes",
+                budget_bytes,
+                block_bytes
+            ));
+        }
+        let pool = KvPool::new(self.model.kv_layout().clone(), block_size, num_blocks)?;
+        self.model.set_kv_pool(pool)?;
+        if let Some(Proposer::Draft(draft)) = &mut self.proposer {
+            let pool = KvPool::new(draft.model.kv_layout().clone(), block_size, num_blocks)?;
+            draft.model.set_kv_pool(pool)?;
+        }
+        Ok(())
+    }
+
+    // 设置了显存预算时目标模型的 KV 池, 用于等待其它请求归还块
+    pub fn kv_pool(&self) -> Option<&KvPool> {
+        self.kv_cache_budget.map(|_| self.model.kv_pool())
+    }
+
+    fn new_cache(&self) -> KvCache {
+        self.model.new_cache()
+    }
+
+    // 目标模型的池放得下 blocks 个块、草稿模型的池放得下 draft_blocks 个块
+    fn has_room(&self, blocks: usize, draft_blocks: usize) -> bool {
+        let draft_room = match &self.proposer {
+            Some(Proposer::Draft(draft)) => draft.model.kv_pool().has_free(draft_blocks),
+            _ => true,
+        };
+        self.model.kv_pool().has_free(blocks) && draft_room
+    }
+
+    // 池中空闲的块不够时淘汰最久未使用的会话, 淘汰完仍然不够时返回 false
+    fn make_room(&mut self, blocks: usize, draft_blocks: usize, keep: Option<&str>) -> bool {
+        while !self.has_room(blocks, draft_blocks) {
+            if !self.evict_session(keep) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // 复制会话的 token 和 KV cache, 返回 token 数; 由调用方在模型锁外写文件
+    pub fn snapshot_session(&self, id: &str) -> anyhow::Result<(usize, HashMap<String, Tensor>)> {
+        let session = self
+            .sessions
+            .get(id)
+            .ok_or_else(|| ApiError::new(404, format!("session {} not found", id)))?;
+        let tensors = session::snapshot(&session.tokens, &session.cache, self.fingerprint)?;
+        Ok((session.tokens.len(), tensors))
+    }
+
+    // 从文件恢复会话, 覆盖同名的会话, 返回恢复的 token 数
+    // 读入之前按文件头的长度为 cache 腾出池中的块, 淘汰其它会话后仍然不够则放弃恢复
+    pub fn restore_session(&mut self, id: &str, path: &Path) -> anyhow::Result<usize> {
+        let cached = session::cached_len(path)?;
+        if cached > self.max_context_len() {
+            return Err(ApiError::context_length_exceeded(self.max_context_len(), cached, None).into());
+        }
+        let blocks = self.new_cache().growth_blocks(cached);
+        if !self.make_room(blocks, 0, Some(id)) {
+            return Err(kv_cache_exhausted().into());
+        }
+        let (tokens, cache) = session::load(path, &self.model, self.fingerprint)?;
+        if tokens.len() > self.max_context_len() {
+            return Err(ApiError::context_length_exceeded(self.max_context_len(), tokens.len(), None).into());
+        }
+        let len = tokens.len();
+        let session = Session {
+            tokens,
+            cache,
+            last_used: self.request_count,
+        };
+        self.insert_session(id, session);
+        Ok(len)
+    }
+
+    pub fn erase_session(&mut self, id: &str) -> bool {
+        self.sessions.remove(id).is_some()
+    }
+
+    // 复制会话的 cache 并截断到与 prompt 的公共前缀, 至少留一个 prompt token 用来计算 logits
+    fn reuse_session(
+        &mut self,
+        prompt: &[u32],
+        session_id: Option<&str>,
+    ) -> anyhow::Result<KvCache> {
+        let Some(session) = session_id.and_then(|id| self.sessions.get_mut(id)) else {
+            return Ok(self.new_cache());
+        };
+        session.last_used = self.request_count;
+        let reused = common_prefix_len(&session.tokens, prompt)
+            .min(session.cache.len())
+            .min(prompt.len().saturating_sub(1));
+        if reused == 0 {
+            return Ok(self.new_cache());
+        }
+        let cache = session.cache.fork(reused);
+        log::debug!("reusing {} of {} prompt tokens from session", reused, prompt.len());
+        Ok(cache)
+    }
+
+    // KV cache 预算不够时淘汰最久未使用的会话, 没有可淘汰的会话时返回 false
+    fn evict_session(&mut self, keep: Option<&str>) -> bool {
+        match self.oldest_session(keep) {
+            Some(id) => {
+                log::info!("kv cache budget exhausted, evicting session {}", id);
+                self.sessions.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn oldest_session(&self, keep: Option<&str>) -> Option<String> {
+        self.sessions
+            .iter()
+            .filter(|(id, _)| Some(id.as_str()) != keep)
+            .min_by_key(|(_, session)| session.last_used)
+            .map(|(id, _)| id.clone())
+    }
+
+    // 会话数超过上限时淘汰最久未使用的会话, 不开启 KV cache 预算时也不会无限增长
+    fn insert_session(&mut self, id: &str, session: Session) {
+        self.sessions.insert(id.to_string(), session);
+        while self.sessions.len() > self.max_sessions
+            && let Some(oldest) = self.oldest_session(Some(id))
+        {
+            log::info!("more than {} sessions, evicting session {}", self.max_sessions, oldest);
+            self.sessions.remove(&oldest);
+        }
+    }
+
+    // 结束的序列立即归还 KV cache, 不必等到整个任务结束
+    // 指定了会话时第一个序列的 cache 转存到会话中
+    fn finish_state(&mut self, state: &mut GenerateState, session_id: Option<&str>) {
+        let cache = std::mem::replace(&mut state.cache, self.new_cache());
+        state.draft_cache = None;
+        if let Some(id) = session_id
+            && state.index == 0
+        {
+            let session = Session {
+                tokens: state.tokens.clone(),
+                cache,
+                last_used: self.request_count,
+            };
+            self.insert_session(id, session);
+        }
+    }
+
+    // 没有开启投机解码时返回 None
+    pub fn speculative_stats(&self) -> Option<&SpeculativeStats> {
+        self.proposer.as_ref().map(|_| &self.speculative_stats)
+    }
+
+    fn log_speculative_stats(&self) {
+        if let Some(stats) = self.speculative_stats() {
+            log::info!(
+                "speculative decoding: acceptance rate {:.3}, {:.2} tokens per step",
+                stats.acceptance_rate(),
+                stats.tokens_per_step()
+            );
+        }
+    }
+
+    pub fn find_safetensors_files(path: &str) -> anyhow::Result<Vec<String>> {
         let mut files = Vec::new();
 
         for entry in fs::read_dir(path)? {
@@ -133,129 +689,1047 @@ impl<'a> Qwen3<'a> {
     pub fn infer_stream(
         &mut self,
         message_str: String,
-    ) -> anyhow::Result<impl Stream<Item = String>> {
-        let mut tokens = self
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<String>>> {
+        self.infer_stream_with_param(message_str, GenerateParam::default())
+            .map(|stream| stream.map(|token| token.map(|token| token.text)))
+    }
+
+    pub fn infer_stream_with_param(
+        &mut self,
+        message_str: String,
+        param: GenerateParam,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<GenerateToken>>> {
+        let tokens = self.encode_prompt(message_str)?;
+        Ok(self.infer_stream_tokens(tokens, param))
+    }
+
+    pub fn infer_stream_tokens(
+        &mut self,
+        tokens: Vec<u32>,
+        param: GenerateParam,
+    ) -> impl Stream<Item = anyhow::Result<GenerateToken>> {
+        stream! {
+            let mut task = self.new_task(tokens, param);
+            while !task.is_finished() {
+                match self.advance(&mut task) {
+                    Ok(tokens) => {
+                        for token in tokens {
+                            yield Ok(token);
+                        }
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn new_task(&mut self, tokens: Vec<u32>, param: GenerateParam) -> GenerateTask {
+        let ticket = Arc::new(TaskTicket {
+            id: self.next_task_id,
+            holding: AtomicBool::new(false),
+            preempt: AtomicBool::new(false),
+        });
+        self.next_task_id += 1;
+        self.tickets.retain(|ticket| ticket.strong_count() > 0);
+        self.tickets.push(Arc::downgrade(&ticket));
+        GenerateTask {
+            prompt: tokens,
+            param,
+            cache: self.new_cache(),
+            states: Vec::new(),
+            beam: None,
+            generations: None,
+            prefilled_at: None,
+            ticket,
+            waiting: false,
+            finished: false,
+        }
+    }
+
+    // 预算不够时抢占比 id 晚创建且占用 KV cache 的任务中最晚的一个, 没有这样的任务时返回 false
+    // 被选中的任务下次推进时才释放, 调用方在此之前继续等待
+    fn preempt_newer(&mut self, id: u64) -> bool {
+        self.tickets.retain(|ticket| ticket.strong_count() > 0);
+        let victim = self
+            .tickets
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|ticket| ticket.id > id && ticket.holding.load(Ordering::Relaxed))
+            .max_by_key(|ticket| ticket.id);
+        let Some(victim) = victim else {
+            return false;
+        };
+        if !victim.preempt.swap(true, Ordering::Relaxed) {
+            log::warn!("kv cache budget exhausted, preempting task {} for task {}", victim.id, id);
+        }
+        true
+    }
+
+    // 释放序列的 KV cache, 之后预算足够时重新计算
+    fn preempt_state(&self, state: &mut GenerateState) {
+        if state.preempted {
+            return;
+        }
+        log::warn!("kv cache budget exhausted, preempting sequence of {} tokens", state.tokens.len());
+        state.preempted = true;
+        state.cache = self.new_cache();
+        state.draft_cache = None;
+        state.logits = None;
+    }
+
+    // prefill 阶段每次处理一块 prompt, 之后每次为每个未结束的序列生成 token
+    // 出错时任务直接结束
+    pub fn advance(&mut self, task: &mut GenerateTask) -> anyhow::Result<Vec<GenerateToken>> {
+        if task.finished {
+            return Ok(Vec::new());
+        }
+        let result = self.try_advance(task);
+        if result.is_err() {
+            task.finished = true;
+        }
+        if task.finished {
+            self.log_speculative_stats();
+        }
+        result
+    }
+
+    fn try_advance(&mut self, task: &mut GenerateTask) -> anyhow::Result<Vec<GenerateToken>> {
+        task.waiting = false;
+        // 被更早的任务抢占时释放全部 KV cache, prefill 没有完成的从头开始
+        // beam search 的中间状态不能单独恢复, 也从头开始
+        if task.ticket.preempt.swap(false, Ordering::Relaxed) {
+            task.cache = self.new_cache();
+            task.beam = None;
+            for state in task.states.iter_mut().filter(|state| !state.finished) {
+                self.preempt_state(state);
+            }
+            task.ticket.holding.store(false, Ordering::Relaxed);
+            task.waiting = true;
+            return Ok(Vec::new());
+        }
+        // beam search 在 prefill 之后每次推进一步, 结束时一次性输出每个 beam 的结果
+        if let Some(search) = task.beam.as_mut() {
+            if !self.beam_step(search)? {
+                return Ok(Vec::new());
+            }
+            let Some(search) = task.beam.take() else {
+                return Ok(Vec::new());
+            };
+            let n = task.param.n.unwrap_or(search.param.beam_width);
+            let generations = self.beam_generations(search.finished.into_sorted(), n)?;
+            let tokens = generations
+                .iter()
+                .enumerate()
+                .map(|(index, generation)| GenerateToken {
+                    index,
+                    text: generation.text.clone(),
+                    logprobs: Vec::new(),
+                    finish_reason: Some(generation.finish_reason),
+                    completion_tokens: generation.completion_tokens,
+                })
+                .collect();
+            task.generations = Some(generations);
+            task.ticket.holding.store(false, Ordering::Relaxed);
+            task.finished = true;
+            return Ok(tokens);
+        }
+        if task.states.is_empty() {
+            if task.cache.is_empty() {
+                let session_id = task.param.session_id.clone();
+                let mut cache = self.reuse_session(&task.prompt, session_id.as_deref())?;
+                // 一次性为整个 prompt 分配块, 与会话共享的块在写入前复制
+                // 池不够时先淘汰不用的会话, 仍然不够则抢占更晚的任务或等待其它请求结束
+                let prompt_len = task.prompt.len();
+                if !self.make_room(cache.growth_blocks(prompt_len), 0, session_id.as_deref()) {
+                    self.preempt_newer(task.ticket.id);
+                    task.waiting = true;
+                    return Ok(Vec::new());
+                }
+                cache.reserve(prompt_len)?;
+                task.cache = cache;
+                task.ticket.holding.store(true, Ordering::Relaxed);
+            }
+            let start = task.cache.len();
+            let end = match self.prefill_chunk_size {
+                Some(chunk_size) => (start + chunk_size).min(task.prompt.len()),
+                None => task.prompt.len(),
+            };
+            let logits = self.model.forward(&task.prompt[start..end], &mut task.cache)?;
+            if end == task.prompt.len() {
+                let cache = std::mem::replace(&mut task.cache, self.new_cache());
+                task.prefilled_at = Some(Instant::now());
+                if let Some(param) = &task.param.beam {
+                    task.beam = Some(self.new_beam_search(task.prompt.len(), param, task.param.max_tokens, cache, logits)?);
+                    return Ok(Vec::new());
+                }
+                task.states = self.new_states(&task.prompt, &task.param, cache, logits)?;
+                task.finished = task.states.iter().all(|state| state.finished);
+                // max_tokens 为 0 时序列直接结束, 只输出 finish_reason
+                return Ok(task
+                    .states
+                    .iter()
+                    .filter(|state| state.finished)
+                    .map(|state| GenerateToken {
+                        index: state.index,
+                        text: String::new(),
+                        logprobs: Vec::new(),
+                        finish_reason: Some(state.finish_reason),
+                        completion_tokens: 0,
+                    })
+                    .collect());
+            }
+            return Ok(Vec::new());
+        }
+        // n 个序列轮流各生成一个 token
+        let mut tokens = Vec::new();
+        let mut progressed = false;
+        for state in task.states.iter_mut().filter(|state| !state.finished) {
+            // 遇到停止字符串时 stream_decode 会结束该序列
+            if !self.step(state, task.ticket.id)?.is_empty() {
+                progressed = true;
+                tokens.extend(self.stream_decode(state, false)?);
+            }
+            if state.finished {
+                tokens.extend(self.stream_decode(state, true)?);
+                self.finish_state(state, task.param.session_id.as_deref());
+            }
+        }
+        task.finished = task.states.iter().all(|state| state.finished);
+        let holding = task.states.iter().any(|state| !state.finished && !state.preempted);
+        task.ticket.holding.store(holding, Ordering::Relaxed);
+        task.waiting = !progressed && !task.finished;
+        Ok(tokens)
+    }
+
+    // 任务结束后取出 n 个生成结果, 顺序与 choice 的 index 一致
+    pub fn task_generations(&self, task: GenerateTask) -> anyhow::Result<Vec<Generation>> {
+        if let Some(generations) = task.generations {
+            return Ok(generations);
+        }
+        task.states
+            .into_iter()
+            .map(|state| {
+                let mut text = self.decode(&state.tokens[state.prompt_len..])?;
+                if let Some(stop) = &state.stop {
+                    stop.truncate(&mut text);
+                }
+                Ok(Generation {
+                    text,
+                    logprobs: state.logprobs,
+                    cumulative_logprob: None,
+                    score: None,
+                    finish_reason: state.finish_reason,
+                    completion_tokens: state.tokens.len() - state.prompt_len,
+                })
+            })
+            .collect()
+    }
+
+    // 增量解码新生成的 token, 序列结束时输出剩下的全部文本
+    // 拼接后的文本与非流式的 decode 结果完全一致
+    // 结束时总是输出一段带 finish_reason 的结果
+    fn stream_decode(&self, state: &mut GenerateState, finish: bool) -> anyhow::Result<Option<GenerateToken>> {
+        let text = if finish {
+            Some(state.decoder.flush(&self.tokenizer, &state.tokens)?)
+        } else {
+            state.decoder.step(&self.tokenizer, &state.tokens)?
+        };
+        let text = match state.stop.as_mut() {
+            // 停止字符串可能跨越多个 token, 增量解码出的文本交给 stop 检查, 可能是停止字符串开头的部分先不输出
+            Some(stop) => {
+                let generated = state.decoder.read_offset() - state.prompt_len;
+                let mut text = text.and_then(|text| stop.push(&text, generated));
+                if finish {
+                    text = Some(text.unwrap_or_default() + &stop.flush());
+                }
+                // 同一步里也达到长度上限或遇到 eos 时, 以停止字符串为准
+                // 投机解码一次接受多个 token 时, 停止字符串之后的 token 不计入结果
+                if let (Some(index), Some(matched_tokens)) = (stop.matched(), stop.matched_tokens()) {
+                    state.finished = true;
+                    state.finish_reason = StopReason::StopSequence(index);
+                    state.tokens.truncate(state.prompt_len + matched_tokens);
+                    state.logprobs.truncate(matched_tokens);
+                    state.cache.truncate(state.tokens.len());
+                }
+                text
+            }
+            None => text,
+        };
+        let Some(text) = text else {
+            return Ok(None);
+        };
+        // 投机解码一次生成多个 token 时, 只取这段文本对应的 logprobs
+        let start = state.emitted_logprobs;
+        let end = match &state.stop {
+            _ if finish => state.logprobs.len(),
+            Some(stop) => stop.emitted_tokens().min(state.logprobs.len()),
+            None => (state.decoder.read_offset() - state.prompt_len).min(state.logprobs.len()),
+        };
+        let logprobs = state.logprobs[start..end].to_vec();
+        state.emitted_logprobs = end;
+        if text.is_empty() && logprobs.is_empty() && !finish {
+            return Ok(None);
+        }
+        Ok(Some(GenerateToken {
+            index: state.index,
+            text,
+            logprobs,
+            finish_reason: finish.then_some(state.finish_reason),
+            completion_tokens: state.tokens.len() - state.prompt_len,
+        }))
+    }
+
+    pub fn encode(&self, message_str: String) -> anyhow::Result<Vec<u32>> {
+        let tokens = self
             .tokenizer
             .encode(message_str, true)
-            .map_err(|e| anyhow::anyhow!(format!("stream encode error{}", e)))?
+            .map_err(|e| anyhow::anyhow!(format!("tokenizer encode error{}", e)))?
             .get_ids()
-            .to_vec();        
-        let stream = stream! {
-            let mut error_tokens = Vec::new();
-            for index in 0..self.max_generate {
-                let next_token = self.next_token(index,&mut tokens);
-                if let Err(e) = next_token{
-                    log::error!("model error: {}", e);
-                    yield format!("model error: {}", e.to_string());
-                    break;
+            .to_vec();
+        Ok(tokens)
+    }
+
+    // 编码 prompt, 并检查是否超出模型的上下文长度
+    pub fn encode_prompt(&self, message_str: String) -> anyhow::Result<Vec<u32>> {
+        let tokens = self.encode(message_str)?;
+        self.check_context_length(tokens.len(), None)?;
+        Ok(tokens)
+    }
+
+    // 不套用模板的原始 prompt, 检查 token id 和长度并确定生成长度
+    pub fn prepare_raw(&self, tokens: Vec<u32>, mut param: GenerateParam) -> anyhow::Result<PreparedPrompt> {
+        let vocab_size = self.vocab_size;
+        if let Some(token) = tokens.iter().find(|&&token| token as usize >= vocab_size) {
+            return Err(ApiError::invalid_request(format!("invalid token id {} in prompt", token), Some("prompt")).into());
+        }
+        self.check_context_length(tokens.len(), param.max_tokens)
+            .map_err(|e| ApiError { param: Some("prompt".to_string()), ..e })?;
+        param.max_tokens = Some(self.max_new_tokens(tokens.len(), param.max_tokens));
+        Ok(PreparedPrompt {
+            tokens,
+            param,
+            truncated_messages: None,
+        })
+    }
+
+    // prompt 中除第一个 token 之外每个 token 的 logprob
+    pub fn prompt_logprobs(&self, tokens: &[u32], top_n: usize) -> anyhow::Result<Vec<TokenLogprob>> {
+        let Some((&first, rest)) = tokens.split_first() else {
+            return Ok(Vec::new());
+        };
+        let mut cache = self.model.new_cache();
+        self.check_kv(cache.growth_blocks(tokens.len()))?;
+        let logits = self.model.forward(&[first], &mut cache)?;
+        self.continuation_logprobs(&mut cache, logits, rest, top_n)
+    }
+
+    // 在 context 之后依次接上每个 continuation, 返回 continuation 每个 token 的 logprob
+    // context 只做一次 prefill, 每个 continuation 复制它的 KV cache 后做一次前向, 不采样
+    pub fn score(
+        &self,
+        context: &[u32],
+        continuations: &[Vec<u32>],
+        top_n: usize,
+    ) -> anyhow::Result<Vec<Vec<TokenLogprob>>> {
+        let longest = continuations.iter().map(Vec::len).max().unwrap_or(0);
+        if context.len() + longest > self.max_context_len() {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "This model's maximum context length is {} tokens, however the prompt and the longest continuation use {} tokens.",
+                    self.max_context_len(),
+                    context.len() + longest
+                ),
+                Some("continuation"),
+            )
+            .with_code("context_length_exceeded")
+            .into());
+        }
+        let mut cache = self.model.new_cache();
+        self.check_kv(cache.growth_blocks(context.len() + longest))?;
+        let logits = self.forward_chunked(&self.model, context, &mut cache)?;
+        continuations
+            .iter()
+            .map(|continuation| {
+                let mut cache = cache.fork(cache.len());
+                self.continuation_logprobs(&mut cache, logits.clone(), continuation, top_n)
+            })
+            .collect()
+    }
+
+    // logits 为 cache 最后一个位置的输出, 用于计算 tokens[0] 的 logprob, 之后分块前向
+    fn continuation_logprobs(
+        &self,
+        cache: &mut KvCache,
+        logits: Tensor,
+        tokens: &[u32],
+        top_n: usize,
+    ) -> anyhow::Result<Vec<TokenLogprob>> {
+        let top_n = top_n.min(MAX_TOP_LOGPROBS);
+        let chunk_size = self.prefill_chunk_size.unwrap_or(tokens.len()).max(1);
+        let mut logprobs = Vec::with_capacity(tokens.len());
+        let mut last_logits = logits;
+        for (chunk_index, chunk) in tokens.chunks(chunk_size).enumerate() {
+            logprobs.push(self.token_logprob(&last_logits, chunk[0], top_n)?);
+            // 最后一个 token 之后的输出不再需要
+            let input = match tokens.get((chunk_index + 1) * chunk_size) {
+                Some(_) => chunk,
+                None => &chunk[..chunk.len() - 1],
+            };
+            if input.is_empty() {
+                break;
+            }
+            let all_logits = self.model.forward_all(input, cache)?;
+            for offset in 1..chunk.len() {
+                logprobs.push(self.token_logprob(&all_logits.get(offset - 1)?, chunk[offset], top_n)?);
+            }
+            if input.len() == chunk.len() {
+                last_logits = all_logits.get(chunk.len() - 1)?;
+            }
+        }
+        Ok(logprobs)
+    }
+
+    // 单个序列的最大长度, 设置了 KV cache 预算时不超过整个池能容纳的 token 数
+    fn max_context_len(&self) -> usize {
+        let pool = self.model.kv_pool();
+        match pool.max_blocks() {
+            Some(blocks) => self.max_position_embeddings.min(blocks * pool.block_size()),
+            None => self.max_position_embeddings,
+        }
+    }
+
+    // prompt 加上显式请求的生成长度不能超过 max_context_len
+    fn check_context_length(&self, prompt_len: usize, max_tokens: Option<usize>) -> Result<(), ApiError> {
+        let total = prompt_len + max_tokens.unwrap_or(1);
+        if total > self.max_context_len() {
+            return Err(ApiError::context_length_exceeded(
+                self.max_context_len(),
+                prompt_len,
+                max_tokens,
+            ));
+        }
+        Ok(())
+    }
+
+    // 未指定 max_tokens 时用 max_generate, 但不会超出剩余的上下文
+    fn max_new_tokens(&self, prompt_len: usize, max_tokens: Option<usize>) -> usize {
+        max_tokens
+            .unwrap_or(self.max_generate)
+            .min(self.max_context_len().saturating_sub(prompt_len))
+    }
+
+    // 渲染模板并编码, 生成前可能出现的请求错误都在这里返回
+    // truncation 为 auto 时保留 system 消息和工具定义, 按轮次从最早的对话开始丢弃直到放得下
+    pub fn prepare(&mut self, request: &ChatRequest) -> anyhow::Result<PreparedPrompt> {
+        let mut param = request.generate_param()?;
+        let system_len = request.system_prefix_len();
+        let starts = if request.truncation_auto() {
+            request.truncation_starts()
+        } else {
+            vec![system_len]
+        };
+        let mut last_error = None;
+        for start in starts {
+            let messages: Vec<&Message> = request.messages[..system_len]
+                .iter()
+                .chain(request.messages[start..].iter())
+                .collect();
+            let message_str = self.render_template(request, &messages)?;
+            let tokens = self.encode(message_str)?;
+            match self.check_context_length(tokens.len(), param.max_tokens) {
+                Ok(()) => {
+                    param.max_tokens = Some(self.max_new_tokens(tokens.len(), param.max_tokens));
+                    return Ok(PreparedPrompt {
+                        tokens,
+                        param,
+                        truncated_messages: request
+                            .truncation_auto()
+                            .then_some(start - system_len),
+                    });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| ApiError::server_error("no messages to render"))
+            .into())
+    }
+
+    // prompt 只做一次 prefill, n 个序列共享 prefill 得到的 KV cache 和 logits
+    // n > 1 时各序列共享 prompt 的块, 只为自己生成的 token 分配块, 最后一个未写满的块写入前复制
+    fn new_states(
+        &mut self,
+        tokens: &[u32],
+        param: &GenerateParam,
+        cache: KvCache,
+        logits: Tensor,
+    ) -> anyhow::Result<Vec<GenerateState>> {
+        let max_new_tokens = self.max_new_tokens(tokens.len(), param.max_tokens);
+        let sampling = param.sampling.merge(&self.sampling);
+        let seed = self.request_seed(&sampling);
+        let n = param.n.unwrap_or(1).max(1);
+        let mut caches: Vec<KvCache> = (1..n).map(|_| cache.fork(cache.len())).collect();
+        caches.insert(0, cache);
+        let states = caches
+            .into_iter()
+            .enumerate()
+            .map(|(index, cache)| GenerateState {
+                index,
+                tokens: tokens.to_vec(),
+                prompt_len: tokens.len(),
+                max_new_tokens,
+                cache,
+                preempted: false,
+                logits: Some(logits.clone()),
+                draft_cache: None,
+                // 每个序列使用不同的种子, 保证 n 个结果相互独立
+                sampler: Sampler::new(sampling.clone(), seed.wrapping_add(index as u64)),
+                matcher: param.guided.clone().map(GuidedMatcher::new),
+                top_logprobs: param.logprobs.map(|top_n| top_n.min(MAX_TOP_LOGPROBS)),
+                logprobs: Vec::new(),
+                emitted_logprobs: 0,
+                decoder: IncrementalDecoder::new(tokens.len()),
+                stop: (!param.stop.is_empty()).then(|| StopChecker::new(param.stop.clone())),
+                finished: max_new_tokens == 0,
+                finish_reason: StopReason::Length,
+            })
+            .collect();
+        Ok(states)
+    }
+
+    // 分块把 tokens 接到 cache 之后, 返回最后一个位置的 logits
+    fn forward_chunked(&self, model: &Model, tokens: &[u32], cache: &mut KvCache) -> anyhow::Result<Tensor> {
+        let chunk_size = self.prefill_chunk_size.unwrap_or(tokens.len()).max(1);
+        let mut logits = None;
+        for chunk in tokens.chunks(chunk_size) {
+            logits = Some(model.forward(chunk, cache)?);
+        }
+        logits.ok_or_else(|| anyhow::anyhow!("empty model input"))
+    }
+
+    // 生成一个 token, 投机解码时可能生成多个, 遇到 eos 或达到长度上限时结束该序列
+    // KV cache 预算不够、这一步没有推进时返回空
+    fn step(&mut self, state: &mut GenerateState, task_id: u64) -> anyhow::Result<Vec<u32>> {
+        let remaining = state.max_new_tokens - (state.tokens.len() - state.prompt_len);
+        // 约束解码需要逐个 token 推进文法状态, 不做投机
+        let mut speculate = match &self.proposer {
+            Some(proposer) if state.matcher.is_none() && remaining > 1 => {
+                proposer.num_speculative_tokens().min(remaining - 1)
+            }
+            _ => 0,
+        };
+        // 这一步目标模型和草稿模型的 cache 分配块前检查池, 不够投机时退化为普通解码, 仍然不够则先淘汰会话
+        // 再不够时抢占更晚创建的任务, 没有更晚的任务时释放自己的 cache, 让更早的任务先完成
+        if self.kv_cache_budget.is_some() {
+            let (blocks, draft_blocks) = self.step_blocks(state, speculate);
+            if speculate > 0 && !self.has_room(blocks, draft_blocks) {
+                speculate = 0;
+            }
+            let (blocks, draft_blocks) = self.step_blocks(state, speculate);
+            if !self.make_room(blocks, draft_blocks, None) {
+                if !self.preempt_newer(task_id) {
+                    self.preempt_state(state);
                 }
+                return Ok(Vec::new());
+            }
+            // 被抢占的序列重新计算整个序列的 KV cache
+            if state.preempted {
+                state.preempted = false;
+                state.logits = Some(self.forward_chunked(&self.model, &state.tokens, &mut state.cache)?);
+            }
+        }
+        let new_tokens = if speculate > 0 {
+            self.speculative_step(state, speculate)?
+        } else {
+            vec![self.next_token(state)?]
+        };
+        let mut tokens = Vec::with_capacity(new_tokens.len());
+        for (next_token, logprob) in new_tokens {
+            state.tokens.push(next_token);
+            state.logprobs.extend(logprob);
+            tokens.push(next_token);
+            if self.is_eos(next_token) {
+                state.finished = true;
+                state.finish_reason = StopReason::Stop;
+                break;
+            }
+            if state.tokens.len() - state.prompt_len >= state.max_new_tokens {
+                state.finished = true;
+                break;
+            }
+        }
+        Ok(tokens)
+    }
+
+    // 生成 speculate 个草稿 token 的这一步, 目标模型和草稿模型的 cache 需要新分配的块数
+    fn step_blocks(&self, state: &GenerateState, speculate: usize) -> (usize, usize) {
+        let len = state.tokens.len() + speculate;
+        let draft_blocks = match &self.proposer {
+            Some(Proposer::Draft(draft)) if speculate > 0 => match &state.draft_cache {
+                Some(cache) => cache.growth_blocks(len),
+                None => draft.model.new_cache().growth_blocks(len),
+            },
+            _ => 0,
+        };
+        (state.cache.growth_blocks(len), draft_blocks)
+    }
 
-                let next_token = next_token.unwrap();
-                tokens.push(next_token);
+    // 不经过调度的计算在分配前检查池, 不淘汰会话, 不够时直接拒绝
+    fn check_kv(&self, blocks: usize) -> Result<(), ApiError> {
+        if self.has_room(blocks, 0) {
+            Ok(())
+        } else {
+            Err(kv_cache_exhausted())
+        }
+    }
 
-                let mut decode_ids = Vec::new();
-                if error_tokens.len() > 0 {
-                    decode_ids.extend_from_slice(&error_tokens);
+    // beam search 扩展 beam 前检查池, 不够时先淘汰会话, 仍然不够则直接拒绝
+    fn reserve_kv(&mut self, blocks: usize) -> Result<(), ApiError> {
+        if self.make_room(blocks, 0, None) {
+            Ok(())
+        } else {
+            Err(kv_cache_exhausted())
+        }
+    }
+
+    fn penalty_context<'t>(&self, tokens: &'t [u32]) -> &'t [u32] {
+        &tokens[tokens.len().saturating_sub(self.repeat_last_n)..]
+    }
+
+    // 提出最多 k 个草稿 token, 目标模型一次前向得到每个位置的分布并做拒绝采样
+    fn speculative_step(
+        &mut self,
+        state: &mut GenerateState,
+        k: usize,
+    ) -> anyhow::Result<Vec<(u32, Option<TokenLogprob>)>> {
+        let prefix_len = state.tokens.len();
+        let lookup_tokens = match &self.proposer {
+            Some(Proposer::PromptLookup(lookup)) => lookup.propose(&state.tokens, k),
+            _ => Vec::new(),
+        };
+        // 查找不到可用的草稿时退化为普通解码
+        let proposer = match &self.proposer {
+            Some(Proposer::PromptLookup(_)) if lookup_tokens.is_empty() => None,
+            proposer => proposer.as_ref(),
+        };
+        let Some(proposer) = proposer else {
+            return Ok(vec![self.next_token(state)?]);
+        };
+        let first_logits = match state.logits.take() {
+            Some(logits) => logits,
+            None => self.model.forward(&state.tokens[state.cache.len()..], &mut state.cache)?,
+        };
+        let mut sequence = state.tokens.clone();
+        let mut draft_tokens = Vec::with_capacity(k);
+        let mut draft_probs = Vec::with_capacity(k);
+        let mut draft_cache = None;
+        match proposer {
+            Proposer::Draft(draft) => {
+                let mut cache = match state.draft_cache.take() {
+                    Some(cache) => cache,
+                    None => draft.model.new_cache(),
+                };
+                for _ in 0..k {
+                    let logits = self.forward_chunked(&draft.model, &sequence[cache.len()..], &mut cache)?;
+                    let probs = state
+                        .sampler
+                        .probs(&logits, self.penalty_context(&sequence), &draft_tokens)?;
+                    let token = state.sampler.sample_probs(&probs);
+                    sequence.push(token);
+                    draft_tokens.push(token);
+                    draft_probs.push(probs);
+                    if self.is_eos(token) {
+                        break;
+                    }
                 }
-                decode_ids.push(next_token);
-                let decoded_token = self
-                    .tokenizer
-                    .decode(&decode_ids, true)
-                    .map_err(|e| anyhow::anyhow!(format!("stream decode error{}", e))).unwrap();
-                if decoded_token.contains("�") {
-                    error_tokens.push(next_token);
-                    if error_tokens.len() > 3 {
-                        error_tokens.clear();
+                draft_cache = Some(cache);
+            }
+            // 查找得到的草稿是确定的, 草稿分布为 one-hot
+            Proposer::PromptLookup(_) => {
+                let vocab_size = first_logits.dim(0)?;
+                for token in lookup_tokens {
+                    let mut probs = vec![0.0; vocab_size];
+                    if let Some(prob) = probs.get_mut(token as usize) {
+                        *prob = 1.0;
                     }
-                    continue;
+                    sequence.push(token);
+                    draft_tokens.push(token);
+                    draft_probs.push(probs);
                 }
-                error_tokens.clear();
-                yield decoded_token.clone();
+            }
+        }
 
-                if matches!(self.eos_token1, Some(eos_token) if eos_token == next_token)
-                    || matches!(self.eos_token2, Some(eos_token) if eos_token == next_token)
-                {
-                    break;
+        let verify_logits = self.model.forward_all(&draft_tokens, &mut state.cache)?;
+        let mut target_logits = vec![first_logits];
+        for index in 0..draft_tokens.len() {
+            target_logits.push(verify_logits.get(index)?);
+        }
+        let target_probs = target_logits
+            .iter()
+            .enumerate()
+            .map(|(index, logits)| {
+                state.sampler.probs(
+                    logits,
+                    self.penalty_context(&sequence[..prefix_len + index]),
+                    &draft_tokens[..index],
+                )
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let (accepted, next_token) = verify(&target_probs, &draft_probs, &draft_tokens, &mut state.sampler);
+
+        // 只保留已确认 token 的缓存, next_token 还没有经过模型
+        state.cache.truncate(prefix_len + accepted);
+        if let Some(mut draft_cache) = draft_cache {
+            draft_cache.truncate(prefix_len + accepted);
+            state.draft_cache = Some(draft_cache);
+        }
+        self.speculative_stats.add(draft_tokens.len(), accepted);
+
+        let mut tokens = draft_tokens[..accepted].to_vec();
+        tokens.push(next_token);
+        tokens
+            .into_iter()
+            .enumerate()
+            .map(|(index, token)| {
+                state.sampler.record(token);
+                let logprob = match state.top_logprobs {
+                    Some(top_n) => Some(self.token_logprob(&target_logits[index], token, top_n)?),
+                    None => None,
+                };
+                Ok((token, logprob))
+            })
+            .collect()
+    }
+
+    fn next_token(&mut self, state: &mut GenerateState) -> anyhow::Result<(u32, Option<TokenLogprob>)> {
+        let logits = match state.logits.take() {
+            Some(logits) => logits,
+            None => {
+                let input = &state.tokens[state.cache.len()..];
+                self.model.forward(input, &mut state.cache)?
+            }
+        };
+        let context = self.penalty_context(&state.tokens);
+        let next_token = match state.matcher.as_mut() {
+            Some(matcher) => {
+                let logits = self.apply_guided_mask(&logits, matcher)?;
+                let next_token = state.sampler.sample(&logits, context)?;
+                if !self.is_eos(next_token) {
+                    let (_, bytes) = self.token_text(next_token);
+                    if !matcher.accept_bytes(&bytes) {
+                        return Err(anyhow::anyhow!("guided decoding rejected token {}", next_token));
+                    }
                 }
+                next_token
             }
-            self.model.clear_kv_cache();
+            None => state.sampler.sample(&logits, context)?,
+        };
+        // logprobs 基于模型原始输出计算, 不受惩罚项和采样参数影响
+        let logprob = match state.top_logprobs {
+            Some(top_n) => Some(self.token_logprob(&logits, next_token, top_n)?),
+            None => None,
         };
+        Ok((next_token, logprob))
+    }
 
-        Ok(stream)
+    fn token_logprob(&self, logits: &Tensor, token: u32, top_n: usize) -> anyhow::Result<TokenLogprob> {
+        let log_probs = candle_nn::ops::log_softmax(logits, D::Minus1)?.to_vec1::<f32>()?;
+        let logprob = log_probs[token as usize];
+        let mut top: Vec<(u32, f32)> = Vec::new();
+        let top_n = top_n.min(log_probs.len());
+        if top_n > 0 {
+            top = log_probs
+                .iter()
+                .enumerate()
+                .map(|(id, &logprob)| (id as u32, logprob))
+                .collect();
+            top.select_nth_unstable_by(top_n - 1, |a, b| b.1.total_cmp(&a.1));
+            top.truncate(top_n);
+            top.sort_by(|a, b| b.1.total_cmp(&a.1));
+        }
+        let (token, bytes) = self.token_text(token);
+        let top_logprobs = top
+            .into_iter()
+            .map(|(id, logprob)| {
+                let (token, bytes) = self.token_text(id);
+                TopLogprob {
+                    token,
+                    bytes,
+                    logprob,
+                }
+            })
+            .collect();
+        Ok(TokenLogprob {
+            token,
+            bytes,
+            logprob,
+            top_logprobs,
+        })
     }
 
-    fn next_token(&mut self, index: usize, tokens: &mut Vec<u32>) -> anyhow::Result<u32> {
-        let context_size = if index > 0 { 1 } else { tokens.len() };
-        let start_pos = tokens.len().saturating_sub(context_size);
-        let ctxt = &tokens[start_pos..];
-        let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-        let logits = self.model.forward(&input, start_pos)?;
-        let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-        let logits = if self.repeat_penalty == 1. {
-            logits
-        } else {
-            let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-            candle_transformers::utils::apply_repeat_penalty(
-                &logits,
-                self.repeat_penalty,
-                &tokens[start_at..],
-            )?
+    // token 的原始字节以及展示用的字符串
+    pub fn token_text(&self, token: u32) -> (String, Vec<u8>) {
+        let bytes = match self.tokenizer.id_to_token(token) {
+            Some(piece) => byte_level_decode(&piece),
+            None => Vec::new(),
         };
-        let next_token = self.logits_processor.sample(&logits)?;
-        Ok(next_token)
+        (String::from_utf8_lossy(&bytes).to_string(), bytes)
+    }
+
+    // 请求没有指定 seed 时, 每个请求使用不同的种子
+    fn request_seed(&mut self, param: &SamplingParam) -> u64 {
+        let seed = param
+            .seed
+            .unwrap_or_else(|| self.seed.wrapping_add(self.request_count));
+        self.request_count += 1;
+        seed
+    }
+
+    fn is_eos(&self, token: u32) -> bool {
+        matches!(self.eos_token1, Some(eos_token) if eos_token == token)
+            || matches!(self.eos_token2, Some(eos_token) if eos_token == token)
+    }
+
+    // 约束解码: 不满足文法的 token 的 logits 置为 -inf, 只有完整匹配后才允许 eos
+    fn apply_guided_mask(
+        &mut self,
+        logits: &Tensor,
+        matcher: &mut GuidedMatcher,
+    ) -> anyhow::Result<Tensor> {
+        let trie = self.token_trie();
+        let mut allowed = matcher.allowed_tokens(&trie);
+        if matcher.is_accepting() {
+            allowed.extend(self.eos_token1);
+            allowed.extend(self.eos_token2);
+        }
+        if allowed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "guided decoding: no token can continue the constrained output"
+            ));
+        }
+        let vocab_size = logits.dim(0)?;
+        let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+        for id in allowed {
+            if let Some(value) = mask.get_mut(id as usize) {
+                *value = 0.0;
+            }
+        }
+        let mask = Tensor::from_vec(mask, vocab_size, logits.device())?;
+        Ok((logits + mask)?)
+    }
+
+    // 第一次使用约束解码时再构建词表前缀树, 特殊 token 不参与匹配
+    // 按原始字节建立, 只含部分 utf-8 字节的 token 也可以用来拼出字符
+    fn token_trie(&mut self) -> Arc<TokenTrie> {
+        if let Some(trie) = &self.token_trie {
+            return trie.clone();
+        }
+        let added_tokens = self.tokenizer.get_added_tokens_decoder();
+        let vocab_size = self.tokenizer.get_vocab_size(true) as u32;
+        let tokens = (0..vocab_size)
+            .filter(|id| !added_tokens.contains_key(id))
+            .map(|id| (id, self.token_text(id).1));
+        let trie = Arc::new(TokenTrie::from_bytes(tokens));
+        self.token_trie = Some(trie.clone());
+        trie
     }
 
-    fn render_template(&mut self, request: &ChatRequest) -> anyhow::Result<String> {
+    fn render_template(&self, request: &ChatRequest, messages: &[&Message]) -> anyhow::Result<String> {
+        self.render_messages(messages, request.tools.as_ref(), request.enable_thinking())
+    }
+
+    // 渲染到 assistant 回复开始之前, 关闭思考时带上空的 <think></think>
+    pub fn render_messages(
+        &self,
+        messages: &[&Message],
+        tools: Option<&Vec<Tool>>,
+        enable_thinking: bool,
+    ) -> anyhow::Result<String> {
         let context = context! {
-            messages => &request.messages,
-            tools => &request.tools.as_ref(),
+            messages => messages,
+            tools => &tools,
             add_generation_prompt => true,
-            enable_thinking => true
+            enable_thinking => enable_thinking
         };
         let template = self.jinja_env.get_template("chat")?;
-        let message_str = template
-            .render(context)
-            .map_err(|e| anyhow::anyhow!(format!("render template  error{}", e)))?;
+        let message_str = template.render(context).map_err(|e| {
+            ApiError::invalid_request(format!("render template error: {}", e), Some("messages"))
+        })?;
         Ok(message_str)
     }
 
     pub fn generate_stream(
         &mut self,
         request: &ChatRequest,
-    ) -> anyhow::Result<impl Stream<Item = String>> {
-        let message_str = self.render_template(request)?;
-        let stream = self.infer_stream(message_str);
-        stream
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<String>>> {
+        let prompt = self.prepare(request)?;
+        Ok(self
+            .infer_stream_tokens(prompt.tokens, prompt.param)
+            .map(|token| token.map(|token| token.text)))
     }
 
     pub fn infer(&mut self, message_str: String) -> anyhow::Result<String> {
-        let mut tokens = self
-            .tokenizer
-            .encode(message_str, true)
-            .map_err(|e| anyhow::anyhow!(format!("tokenizer encode error{}", e)))?
-            .get_ids()
-            .to_vec();
-        let input_len = tokens.len();
-        for index in 0..self.max_generate {
-            let next_token = self.next_token(index, &mut tokens)?;
-            tokens.push(next_token);
-            if matches!(self.eos_token1, Some(eos_token) if eos_token == next_token)
-                || matches!(self.eos_token2, Some(eos_token) if eos_token == next_token)
-            {
-                break;
+        self.infer_with_param(message_str, GenerateParam::default())
+    }
+
+    pub fn infer_with_param(
+        &mut self,
+        message_str: String,
+        param: GenerateParam,
+    ) -> anyhow::Result<String> {
+        let tokens = self.encode_prompt(message_str)?;
+        Ok(self.infer_tokens(tokens, param)?.remove(0).text)
+    }
+
+    // 返回 n 个生成结果, 顺序与 choice 的 index 一致
+    pub fn infer_tokens(&mut self, tokens: Vec<u32>, param: GenerateParam) -> anyhow::Result<Vec<Generation>> {
+        let mut task = self.new_task(tokens, param);
+        while !task.is_finished() {
+            self.advance(&mut task)?;
+        }
+        self.task_generations(task)
+    }
+
+    // 得分最高的 n 个结果
+    fn beam_generations(&self, hypotheses: Vec<Hypothesis>, n: usize) -> anyhow::Result<Vec<Generation>> {
+        hypotheses
+            .into_iter()
+            .take(n)
+            .map(|hypothesis| {
+                Ok(Generation {
+                    text: self.decode(&hypothesis.tokens)?,
+                    logprobs: Vec::new(),
+                    cumulative_logprob: Some(hypothesis.cumulative_logprob),
+                    score: Some(hypothesis.score),
+                    finish_reason: hypothesis.finish_reason,
+                    completion_tokens: hypothesis.tokens.len(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn decode(&self, tokens: &[u32]) -> anyhow::Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(|e| anyhow::anyhow!(format!("tokenizer decode error{}", e)))
+    }
+
+    // 确定性的 beam search, 返回按得分从高到低排列的 beam_width 个结果
+    // prompt 只做一次 prefill, 每个 beam 复制父 beam 的 KV cache 后继续解码
+    pub fn beam_search(
+        &mut self,
+        tokens: Vec<u32>,
+        param: &BeamSearchParam,
+        max_tokens: Option<usize>,
+    ) -> anyhow::Result<Vec<Hypothesis>> {
+        let mut cache = self.new_cache();
+        self.reserve_kv(cache.growth_blocks(tokens.len()))?;
+        let logits = self.forward_chunked(&self.model, &tokens, &mut cache)?;
+        let mut search = self.new_beam_search(tokens.len(), param, max_tokens, cache, logits)?;
+        while !self.beam_step(&mut search)? {}
+        Ok(search.finished.into_sorted())
+    }
+
+    // 所有 beam 共享 prompt 的块, 每步只为新 token 分配块, 写入共享的块前复制
+    fn new_beam_search(
+        &self,
+        prompt_len: usize,
+        param: &BeamSearchParam,
+        max_tokens: Option<usize>,
+        cache: KvCache,
+        logits: Tensor,
+    ) -> anyhow::Result<BeamSearch> {
+        Ok(BeamSearch {
+            param: param.clone(),
+            beams: vec![Beam {
+                tokens: Vec::new(),
+                cache,
+                logits,
+                cumulative_logprob: 0.0,
+            }],
+            finished: BeamHypotheses::new(param.clone()),
+            step: 0,
+            max_new_tokens: self.max_new_tokens(prompt_len, max_tokens),
+        })
+    }
+
+    // 每个 beam 扩展一个 token, 搜索结束时返回 true, 结果在 search.finished 中
+    // 这一步的 KV cache 超出预算时直接返回错误
+    fn beam_step(&mut self, search: &mut BeamSearch) -> anyhow::Result<bool> {
+        let beam_width = search.param.beam_width.max(1);
+        if search.step < search.max_new_tokens {
+            let step = search.step;
+            let log_probs = search
+                .beams
+                .iter()
+                .map(|beam| {
+                    let log_probs = candle_nn::ops::log_softmax(&beam.logits, D::Minus1)?.to_vec1::<f32>()?;
+                    Ok((beam.cumulative_logprob, log_probs))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            // 每步取 2 * beam_width 个候选, 保证去掉 eos 后仍有足够的 beam
+            let candidates = top_candidates(&log_probs, 2 * beam_width);
+            let mut next_beams = Vec::with_capacity(beam_width);
+            for (rank, candidate) in candidates.into_iter().enumerate() {
+                let parent = &search.beams[candidate.beam];
+                let mut tokens = parent.tokens.clone();
+                tokens.push(candidate.token);
+                if self.is_eos(candidate.token) {
+                    // 排在 beam_width 之后的 eos 候选不采用
+                    if rank < beam_width {
+                        search.finished.add(tokens, candidate.cumulative_logprob, StopReason::Stop);
+                    }
+                    continue;
+                }
+                // 达到长度上限时不再需要下一步的 logits 和 cache
+                let (cache, logits) = if step + 1 < search.max_new_tokens {
+                    let mut cache = parent.cache.fork(parent.cache.len());
+                    self.reserve_kv(cache.growth_blocks(cache.len() + 1))?;
+                    let logits = self.model.forward(&[candidate.token], &mut cache)?;
+                    (cache, logits)
+                } else {
+                    (self.new_cache(), parent.logits.clone())
+                };
+                next_beams.push(Beam {
+                    tokens,
+                    cache,
+                    logits,
+                    cumulative_logprob: candidate.cumulative_logprob,
+                });
+                if next_beams.len() == beam_width {
+                    break;
+                }
+            }
+            search.beams = next_beams;
+            search.step += 1;
+            let best_logprob = search
+                .beams
+                .iter()
+                .map(|beam| beam.cumulative_logprob)
+                .fold(f32::NEG_INFINITY, f32::max);
+            if search.beams.is_empty() || search.finished.is_done(best_logprob, search.step) {
+                return Ok(true);
+            }
+            if search.step < search.max_new_tokens {
+                return Ok(false);
             }
         }
-        let all_tokens = tokens.len();
-        let decode = self
-            .tokenizer
-            .decode(&tokens[input_len..all_tokens], true)
-            .map_err(|e| anyhow::anyhow!(format!("tokenizer decode error{}", e)))?;
-        self.model.clear_kv_cache();
-        Ok(decode)
+        // 达到长度上限时, 仍在搜索的 beam 也作为候选
+        for beam in std::mem::take(&mut search.beams) {
+            search.finished.add(beam.tokens, beam.cumulative_logprob, StopReason::Length);
+        }
+        Ok(true)
     }
 
     pub fn generate(&mut self, request: &ChatRequest) -> anyhow::Result<String> {
-        let message_str = self.render_template(request)?;
-        self.infer(message_str)
+        let prompt = self.prepare(request)?;
+        Ok(self.infer_tokens(prompt.tokens, prompt.param)?.remove(0).text)
     }
 }
+
+fn kv_cache_exhausted() -> ApiError {
+    ApiError::new(503, "kv cache budget exhausted, please retry later").with_code("kv_cache_exhausted")
+}
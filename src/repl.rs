@@ -0,0 +1,254 @@
+use crate::ChatRequest;
+use crate::error::ApiError;
+use crate::segment::{BlockParser, Segment};
+use serde_json::{Value, json};
+
+// 终端对话: 历史和采样参数以 ChatRequest 的 JSON 格式保存, 可以直接保存成文件或发给 /chat/completions
+
+pub const SAMPLING_PARAMS: [&str; 9] = [
+    "temperature",
+    "top_p",
+    "top_k",
+    "min_p",
+    "max_tokens",
+    "seed",
+    "presence_penalty",
+    "frequency_penalty",
+    "repetition_penalty",
+];
+
+pub const HELP: &str = "\
+/set <param> [value]   set a sampling parameter, unset it without value
+                       params: temperature, top_p, top_k, min_p, max_tokens, seed,
+                       presence_penalty, frequency_penalty, repetition_penalty
+/think [on|off]        toggle thinking
+/system <file>         load the system prompt from a file
+/save <file>           save the transcript as a ChatRequest JSON
+/load <file>           load a transcript saved by /save or any ChatRequest JSON
+/show                  show the sampling parameters and history length
+/clear                 clear the history, keeping the system prompt and parameters
+/exit                  quit";
+
+const DIM: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandResult {
+    Reply(String),
+    Exit,
+}
+
+#[derive(Debug)]
+pub struct ChatSession {
+    request: Value,
+}
+
+impl Default for ChatSession {
+    fn default() -> Self {
+        ChatSession {
+            request: json!({"messages": []}),
+        }
+    }
+}
+
+fn parse_request(request: Value) -> Result<ChatRequest, ApiError> {
+    serde_json::from_value(request).map_err(|e| ApiError::invalid_request(e.to_string(), None))
+}
+
+impl ChatSession {
+    pub fn request(&self) -> &Value {
+        &self.request
+    }
+
+    fn messages(&mut self) -> &mut Vec<Value> {
+        if !self.request["messages"].is_array() {
+            self.request["messages"] = json!([]);
+        }
+        self.request["messages"].as_array_mut().unwrap()
+    }
+
+    pub fn push_user(&mut self, content: &str) {
+        self.messages().push(json!({"role": "user", "content": content}));
+    }
+
+    // 生成的原始文本, 思考内容留在 <think> 中由模板处理
+    pub fn push_assistant(&mut self, content: &str) {
+        self.messages().push(json!({"role": "assistant", "content": content}));
+    }
+
+    // 生成失败时撤回用户消息
+    pub fn pop(&mut self) {
+        self.messages().pop();
+    }
+
+    pub fn chat_request(&self) -> Result<ChatRequest, ApiError> {
+        let mut request = self.request.clone();
+        if let Some(object) = request.as_object_mut() {
+            object.remove("stream");
+        }
+        let chat = parse_request(request)?;
+        chat.validate()?;
+        Ok(chat)
+    }
+
+    pub fn enable_thinking(&self) -> bool {
+        self.request["chat_template_kwargs"]["enable_thinking"] != false
+    }
+
+    pub fn set_system(&mut self, content: &str) {
+        let messages = self.messages();
+        let message = json!({"role": "system", "content": content});
+        match messages.first_mut() {
+            Some(first) if first["role"] == "system" => *first = message,
+            _ => messages.insert(0, message),
+        }
+    }
+
+    // 不带值时取消设置, 使用模型的默认值
+    pub fn set_param(&mut self, name: &str, value: Option<&str>) -> Result<String, ApiError> {
+        if !SAMPLING_PARAMS.contains(&name) {
+            return Err(ApiError::invalid_request(format!("unknown parameter {}", name), Some(name)));
+        }
+        let mut request = self.request.clone();
+        match value {
+            Some(value) => {
+                let value: Value = serde_json::from_str(value)
+                    .map_err(|_| ApiError::invalid_request(format!("{} must be a number", name), Some(name)))?;
+                request[name] = value;
+            }
+            None => {
+                if let Some(object) = request.as_object_mut() {
+                    object.remove(name);
+                }
+            }
+        }
+        // 只校验参数, 历史为空时也可以设置
+        let mut check = request.clone();
+        check["messages"] = json!([{"role": "user", "content": ""}]);
+        parse_request(check)?.validate()?;
+        self.request = request;
+        Ok(match value {
+            Some(value) => format!("{} = {}", name, value),
+            None => format!("{} unset", name),
+        })
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(&self.request)?)?;
+        Ok(())
+    }
+
+    // 文件中其它的请求字段原样保留, 之后的请求也会带上
+    pub fn load(&mut self, path: &str) -> anyhow::Result<()> {
+        let request: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        parse_request(request.clone())?;
+        if !request["messages"].is_array() {
+            anyhow::bail!("{} has no messages", path);
+        }
+        self.request = request;
+        Ok(())
+    }
+
+    pub fn command(&mut self, line: &str) -> Result<CommandResult, ApiError> {
+        let mut parts = line.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let arg = parts.next();
+        let io_error = |e: anyhow::Error| ApiError::invalid_request(e.to_string(), None);
+        let reply = match (command, arg) {
+            ("/exit" | "/quit" | "/bye", _) => return Ok(CommandResult::Exit),
+            ("/help" | "/?", _) => HELP.to_string(),
+            ("/set", Some(name)) => self.set_param(name, parts.next())?,
+            ("/think", None) => self.set_thinking(!self.enable_thinking()),
+            ("/think", Some("on")) => self.set_thinking(true),
+            ("/think", Some("off")) => self.set_thinking(false),
+            ("/system", Some(_)) => {
+                let path = line["/system".len()..].trim();
+                let content = std::fs::read_to_string(path).map_err(|e| io_error(e.into()))?;
+                self.set_system(content.trim());
+                format!("system prompt loaded from {}", path)
+            }
+            ("/save", Some(_)) => {
+                let path = line["/save".len()..].trim();
+                self.save(path).map_err(io_error)?;
+                format!("transcript saved to {}", path)
+            }
+            ("/load", Some(_)) => {
+                let path = line["/load".len()..].trim();
+                self.load(path).map_err(io_error)?;
+                format!("loaded {} messages from {}", self.messages().len(), path)
+            }
+            ("/show", _) => self.show(),
+            ("/clear", _) => {
+                self.messages().retain(|message| message["role"] == "system");
+                "history cleared".to_string()
+            }
+            _ => {
+                return Err(ApiError::invalid_request(
+                    format!("unknown command {}, type /help for commands", line),
+                    None,
+                ));
+            }
+        };
+        Ok(CommandResult::Reply(reply))
+    }
+
+    fn set_thinking(&mut self, enable: bool) -> String {
+        self.request["chat_template_kwargs"]["enable_thinking"] = json!(enable);
+        format!("thinking {}", if enable { "on" } else { "off" })
+    }
+
+    fn show(&mut self) -> String {
+        let mut lines: Vec<String> = SAMPLING_PARAMS
+            .iter()
+            .filter_map(|name| self.request.get(*name).map(|value| format!("{} = {}", name, value)))
+            .collect();
+        lines.push(format!("thinking {}", if self.enable_thinking() { "on" } else { "off" }));
+        lines.push(format!("{} messages", self.messages().len()));
+        lines.join("\n")
+    }
+}
+
+// 思考内容暗色显示, 工具调用单独一行
+#[derive(Debug, Default)]
+pub struct Renderer {
+    parser: BlockParser,
+    thinking: bool,
+    started: bool,
+}
+
+impl Renderer {
+    pub fn push(&mut self, text: &str) -> String {
+        let segments = self.parser.push(text);
+        self.render(segments)
+    }
+
+    pub fn finish(&mut self) -> String {
+        let segments = self.parser.finish();
+        let mut output = self.render(segments);
+        if self.thinking {
+            output.push_str(RESET);
+            self.thinking = false;
+        }
+        output
+    }
+
+    fn render(&mut self, segments: Vec<Segment>) -> String {
+        let mut output = String::new();
+        for segment in segments {
+            let thinking = matches!(segment, Segment::Thinking(_));
+            if thinking != self.thinking {
+                output.push_str(if thinking { DIM } else { RESET });
+                if self.started {
+                    output.push_str("\n\n");
+                }
+                self.thinking = thinking;
+            }
+            match segment {
+                Segment::Thinking(text) | Segment::Text(text) => output.push_str(&text),
+                Segment::ToolCall { name, input } => output.push_str(&format!("\n[tool call] {}({})\n", name, input)),
+            }
+            self.started = true;
+        }
+        output
+    }
+}
@@ -0,0 +1,168 @@
+use crate::error::ApiError;
+use crate::model::Model;
+use candle_core::Tensor;
+use tokenizers::Tokenizer;
+
+// Qwen3-Reranker: 把 (query, document) 放进固定的 prompt, 相关性为下一个 token 是 "yes" 的概率
+// 只在 "yes" 和 "no" 两个 token 之间做 softmax
+// prompt 中 document 之前的部分对所有文档相同, 只做一次 prefill, 每个文档复制它的 KV cache
+
+pub const DEFAULT_INSTRUCTION: &str =
+    "Given a web search query, retrieve relevant passages that answer the query";
+
+const SYSTEM_PROMPT: &str = "<|im_start|>system\nJudge whether the Document meets the requirements based on the Query and the Instruct provided. Note that the answer can only be \"yes\" or \"no\".<|im_end|>\n<|im_start|>user\n";
+const ASSISTANT_PREFIX: &str = "<|im_end|>\n<|im_start|>assistant\n<think>\n\n</think>\n\n";
+
+// 单独加载的 reranker 模型, 与对话模型分开
+pub struct RerankModel {
+    pub tokenizer: Tokenizer,
+    pub model: Model,
+    pub max_position_embeddings: usize,
+}
+
+// 所有文档共享的前缀, 在 "<Document>:" 后断开, 文档以空格开头, 分词结果与整体编码一致
+pub fn shared_prefix(instruction: &str, query: &str) -> String {
+    format!(
+        "{}<Instruct>: {}\n<Query>: {}\n<Document>:",
+        SYSTEM_PROMPT, instruction, query
+    )
+}
+
+pub fn document_suffix() -> &'static str {
+    ASSISTANT_PREFIX
+}
+
+// logits 为最后一个位置的输出, 返回 P(yes)
+pub fn yes_probability(logits: &Tensor, yes_token: u32, no_token: u32) -> anyhow::Result<f32> {
+    let yes = logits.get(yes_token as usize)?.to_scalar::<f32>()?;
+    let no = logits.get(no_token as usize)?.to_scalar::<f32>()?;
+    // 1 / (1 + e^(no - yes)) 等价于两者 softmax 后 yes 的概率
+    Ok(1.0 / (1.0 + (no - yes).exp()))
+}
+
+fn encode(tokenizer: &Tokenizer, text: &str) -> anyhow::Result<Vec<u32>> {
+    Ok(tokenizer
+        .encode(text, false)
+        .map_err(|e| anyhow::anyhow!(format!("tokenizer encode error{}", e)))?
+        .get_ids()
+        .to_vec())
+}
+
+fn token_id(tokenizer: &Tokenizer, token: &str) -> anyhow::Result<u32> {
+    tokenizer
+        .token_to_id(token)
+        .ok_or_else(|| anyhow::anyhow!("token {:?} not found in tokenizer", token))
+}
+
+// 返回每个文档的相关性以及处理的 token 总数, 超长的文档从末尾截断
+pub fn rerank(
+    tokenizer: &Tokenizer,
+    model: &Model,
+    max_len: usize,
+    instruction: &str,
+    query: &str,
+    documents: &[String],
+) -> anyhow::Result<(Vec<f32>, usize)> {
+    let yes_token = token_id(tokenizer, "yes")?;
+    let no_token = token_id(tokenizer, "no")?;
+    let prefix = encode(tokenizer, &shared_prefix(instruction, query))?;
+    let suffix = encode(tokenizer, document_suffix())?;
+    if prefix.len() + suffix.len() >= max_len {
+        return Err(ApiError::invalid_request(
+            format!(
+                "This model's maximum context length is {} tokens, however the query uses {} tokens.",
+                max_len,
+                prefix.len() + suffix.len()
+            ),
+            Some("query"),
+        )
+        .with_code("context_length_exceeded")
+        .into());
+    }
+    let mut cache = model.new_cache();
+    model.forward(&prefix, &mut cache)?;
+    let mut total_tokens = prefix.len();
+    let mut scores = Vec::with_capacity(documents.len());
+    for document in documents {
+        let mut tokens = encode(tokenizer, &format!(" {}", document))?;
+        tokens.truncate(max_len - prefix.len() - suffix.len());
+        tokens.extend(&suffix);
+        total_tokens += tokens.len();
+        let logits = model.forward(&tokens, &mut cache.fork(cache.len()))?;
+        scores.push(yes_probability(&logits, yes_token, no_token)?);
+    }
+    Ok((scores, total_tokens))
+}
+
+// Cohere 最多 1000 个文档
+pub const MAX_RERANK_DOCUMENTS: usize = 1000;
+
+// Cohere / Jina 兼容的请求
+#[derive(Debug, serde::Deserialize)]
+pub struct RerankRequest {
+    pub model: Option<String>,
+    pub query: String,
+    pub documents: Vec<RerankDocument>,
+    pub top_n: Option<usize>,
+    pub return_documents: Option<bool>,
+    // Qwen3-Reranker 的任务说明扩展字段
+    pub instruction: Option<String>,
+}
+
+// 文档可以是字符串, 也可以是带 text 字段的对象
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum RerankDocument {
+    Text(String),
+    Object { text: String },
+}
+
+impl RerankDocument {
+    pub fn text(&self) -> &str {
+        match self {
+            RerankDocument::Text(text) => text,
+            RerankDocument::Object { text } => text,
+        }
+    }
+}
+
+impl RerankRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        if self.query.is_empty() {
+            return Err(ApiError::invalid_request("query cannot be empty", Some("query")));
+        }
+        if self.documents.is_empty() {
+            return Err(ApiError::invalid_request("documents cannot be empty", Some("documents")));
+        }
+        if self.documents.len() > MAX_RERANK_DOCUMENTS {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "documents can contain at most {} items, got {}",
+                    MAX_RERANK_DOCUMENTS,
+                    self.documents.len()
+                ),
+                Some("documents"),
+            ));
+        }
+        if self.top_n == Some(0) {
+            return Err(ApiError::invalid_request("top_n must be at least 1", Some("top_n")));
+        }
+        Ok(())
+    }
+
+    pub fn instruction(&self) -> &str {
+        self.instruction.as_deref().unwrap_or(DEFAULT_INSTRUCTION)
+    }
+
+    pub fn documents(&self) -> Vec<String> {
+        self.documents.iter().map(|document| document.text().to_string()).collect()
+    }
+}
+
+// 按相关性从高到低排列, 返回 (文档下标, 相关性), 最多 top_n 个
+pub fn ranked(scores: &[f32], top_n: Option<usize>) -> Vec<(usize, f32)> {
+    let mut results: Vec<(usize, f32)> = scores.iter().copied().enumerate().collect();
+    results.sort_by(|a, b| b.1.total_cmp(&a.1));
+    results.truncate(top_n.unwrap_or(results.len()));
+    results
+}
@@ -0,0 +1,668 @@
+use crate::error::ApiError;
+use crate::guided::JSON_GRAMMAR;
+use crate::qwen3::StopReason;
+use crate::segment::{BlockParser, Segment};
+use crate::{ChatRequest, new_tool_call_id};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+
+// OpenAI Responses API: input 的各种 item 转换成对话消息, 输出按 <think> 和 <tool_call> 切分成 output item
+// 只支持 function 工具; tool_choice 为 required 或指定函数时不强制调用; text.format 只支持 text 和 json_object
+
+// 没有设置保存目录时内存中最多保存的响应数, 超过后丢弃最早的
+pub const MAX_STORED_RESPONSES: usize = 1000;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ResponsesRequest {
+    pub model: Option<String>,
+    pub input: Option<ResponseInput>,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub tools: Option<Vec<Value>>,
+    pub tool_choice: Option<Value>,
+    pub parallel_tool_calls: Option<bool>,
+    pub max_output_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub stream: Option<bool>,
+    // 默认保存, 之后可以用 previous_response_id 接着对话
+    pub store: Option<bool>,
+    pub reasoning: Option<ReasoningConfig>,
+    pub text: Option<Value>,
+    // "disabled"(默认) 或 "auto", 与 chat 的 truncation 相同
+    pub truncation: Option<String>,
+    pub metadata: Option<Value>,
+    pub user: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ResponseInput {
+    Text(String),
+    Items(Vec<Value>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ReasoningConfig {
+    pub effort: Option<String>,
+    pub summary: Option<String>,
+}
+
+// 输入的 item, 之前响应的 output item 也按它解析
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputItem {
+    Message {
+        role: String,
+        content: InputContent,
+    },
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+    Reasoning {
+        #[serde(default)]
+        summary: Vec<ContentPart>,
+        #[serde(default)]
+        content: Vec<ContentPart>,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum InputContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub part_type: String,
+    pub text: Option<String>,
+}
+
+impl InputContent {
+    fn text(&self, param: &str) -> Result<String, ApiError> {
+        match self {
+            InputContent::Text(text) => Ok(text.clone()),
+            InputContent::Parts(parts) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match (part.part_type.as_str(), &part.text) {
+                        ("input_text" | "output_text" | "text" | "refusal", Some(text)) => texts.push(text.as_str()),
+                        (part_type, _) => {
+                            return Err(ApiError::invalid_request(
+                                format!("{} content is not supported", part_type),
+                                Some(param),
+                            ));
+                        }
+                    }
+                }
+                Ok(texts.join("\n"))
+            }
+        }
+    }
+}
+
+// 连续的 reasoning、message 和 function_call 合并成一条 assistant 消息
+#[derive(Debug, Default)]
+struct AssistantTurn {
+    reasoning: Vec<String>,
+    texts: Vec<String>,
+    tool_calls: Vec<Value>,
+}
+
+impl AssistantTurn {
+    fn flush(&mut self, messages: &mut Vec<Value>) {
+        if self.reasoning.is_empty() && self.texts.is_empty() && self.tool_calls.is_empty() {
+            return;
+        }
+        let text = self.texts.join("\n");
+        let content = if self.reasoning.is_empty() {
+            text
+        } else {
+            format!("<think>\n{}\n</think>\n\n{}", self.reasoning.join("\n"), text)
+        };
+        let mut message = json!({"role": "assistant", "content": content});
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = Value::Array(std::mem::take(&mut self.tool_calls));
+        }
+        messages.push(message);
+        *self = AssistantTurn::default();
+    }
+}
+
+// 没有 type 的 item 为 message
+pub fn normalize_item(mut item: Value, param: &str) -> Result<Value, ApiError> {
+    let Some(object) = item.as_object_mut() else {
+        return Err(ApiError::invalid_request("input item must be an object", Some(param)));
+    };
+    object.entry("type").or_insert_with(|| json!("message"));
+    Ok(item)
+}
+
+fn parse_item(item: &Value, param: &str) -> Result<InputItem, ApiError> {
+    serde_json::from_value(item.clone())
+        .map_err(|e| ApiError::invalid_request(format!("invalid input item: {}", e), Some(param)))
+}
+
+// 对话历史转换成 OpenAI chat 格式的消息, instructions 作为第一条 system 消息
+pub fn to_messages(instructions: Option<&str>, items: &[Value]) -> Result<Vec<Value>, ApiError> {
+    let mut messages = Vec::new();
+    if let Some(instructions) = instructions {
+        messages.push(json!({"role": "system", "content": instructions}));
+    }
+    let mut assistant = AssistantTurn::default();
+    for (index, item) in items.iter().enumerate() {
+        let param = format!("input[{}]", index);
+        match parse_item(item, &param)? {
+            InputItem::Message { role, content } => {
+                let text = content.text(&format!("{}.content", param))?;
+                match role.as_str() {
+                    "assistant" => assistant.texts.push(text),
+                    "user" | "system" | "developer" => {
+                        assistant.flush(&mut messages);
+                        let role = if role == "developer" { "system" } else { role.as_str() };
+                        messages.push(json!({"role": role, "content": text}));
+                    }
+                    role => {
+                        return Err(ApiError::invalid_request(
+                            format!("role must be user, assistant, system or developer, got {}", role),
+                            Some(&format!("{}.role", param)),
+                        ));
+                    }
+                }
+            }
+            InputItem::FunctionCall {
+                call_id,
+                name,
+                arguments,
+            } => assistant.tool_calls.push(json!({
+                "id": call_id,
+                "type": "function",
+                "function": {"name": name, "arguments": arguments},
+            })),
+            InputItem::FunctionCallOutput { call_id, output } => {
+                assistant.flush(&mut messages);
+                messages.push(json!({"role": "tool", "tool_call_id": call_id, "content": output}));
+            }
+            InputItem::Reasoning { summary, content } => {
+                let parts = if content.is_empty() { summary } else { content };
+                let text: Vec<String> = parts.into_iter().filter_map(|part| part.text).collect();
+                assistant.reasoning.push(text.join("\n"));
+            }
+        }
+    }
+    assistant.flush(&mut messages);
+    Ok(messages)
+}
+
+impl ResponsesRequest {
+    pub fn is_stream(&self) -> bool {
+        self.stream == Some(true)
+    }
+
+    pub fn store(&self) -> bool {
+        self.store != Some(false)
+    }
+
+    // 这次请求新增的 item, 字符串输入为一条用户消息
+    pub fn input_items(&self) -> Result<Vec<Value>, ApiError> {
+        match &self.input {
+            None => Err(ApiError::invalid_request("input is required", Some("input"))),
+            Some(ResponseInput::Text(text)) => Ok(vec![json!({"type": "message", "role": "user", "content": text})]),
+            Some(ResponseInput::Items(items)) => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| normalize_item(item.clone(), &format!("input[{}]", index)))
+                .collect(),
+        }
+    }
+
+    fn enable_thinking(&self) -> bool {
+        let effort = self.reasoning.as_ref().and_then(|reasoning| reasoning.effort.as_deref());
+        !matches!(effort, Some("none" | "minimal"))
+    }
+
+    fn guided_grammar(&self) -> Result<Option<&'static str>, ApiError> {
+        let format_type = self
+            .text
+            .as_ref()
+            .and_then(|text| text.get("format"))
+            .and_then(|format| format.get("type"))
+            .and_then(Value::as_str);
+        match format_type {
+            None | Some("text") => Ok(None),
+            Some("json_object") => Ok(Some(JSON_GRAMMAR)),
+            Some(other) => Err(ApiError::invalid_request(
+                format!("text.format type {} is not supported", other),
+                Some("text.format"),
+            )),
+        }
+    }
+
+    fn chat_tools(&self) -> Result<Option<Vec<Value>>, ApiError> {
+        if self.tool_choice.as_ref().and_then(Value::as_str) == Some("none") {
+            return Ok(None);
+        }
+        let Some(tools) = &self.tools else {
+            return Ok(None);
+        };
+        let mut chat_tools = Vec::new();
+        for (index, tool) in tools.iter().enumerate() {
+            if tool.get("type").and_then(Value::as_str) != Some("function") {
+                return Err(ApiError::invalid_request(
+                    "only function tools are supported",
+                    Some(&format!("tools[{}].type", index)),
+                ));
+            }
+            chat_tools.push(json!({
+                "type": "function",
+                "function": {
+                    "name": tool.get("name").cloned().unwrap_or_default(),
+                    "description": tool.get("description").and_then(Value::as_str).unwrap_or_default(),
+                    "parameters": tool.get("parameters").cloned().unwrap_or_else(|| json!({})),
+                }
+            }));
+        }
+        Ok(Some(chat_tools))
+    }
+
+    // items 为包括之前响应在内的完整对话历史, 剩下的校验交给 ChatRequest::validate
+    pub fn to_chat_request(&self, items: &[Value]) -> Result<ChatRequest, ApiError> {
+        let messages = to_messages(self.instructions.as_deref(), items)?;
+        let request = json!({
+            "messages": messages,
+            "tools": self.chat_tools()?,
+            "max_tokens": self.max_output_tokens,
+            "temperature": self.temperature,
+            "top_p": self.top_p,
+            "truncation": self.truncation,
+            "guided_grammar": self.guided_grammar()?,
+            "chat_template_kwargs": {"enable_thinking": self.enable_thinking()},
+        });
+        serde_json::from_value(request).map_err(|e| ApiError::invalid_request(e.to_string(), None))
+    }
+
+    // 状态为 in_progress 的响应对象, 请求中的参数原样带上
+    pub fn response_object(&self, model: &str) -> Value {
+        json!({
+            "id": format!("resp_{}", uuid::Uuid::new_v4().simple()),
+            "object": "response",
+            "created_at": chrono::Utc::now().timestamp(),
+            "status": "in_progress",
+            "error": null,
+            "incomplete_details": null,
+            "instructions": self.instructions,
+            "max_output_tokens": self.max_output_tokens,
+            "model": model,
+            "output": [],
+            "parallel_tool_calls": self.parallel_tool_calls.unwrap_or(true),
+            "previous_response_id": self.previous_response_id,
+            "reasoning": {
+                "effort": self.reasoning.as_ref().and_then(|reasoning| reasoning.effort.clone()),
+                "summary": self.reasoning.as_ref().and_then(|reasoning| reasoning.summary.clone()),
+            },
+            "store": self.store(),
+            "temperature": self.temperature.unwrap_or(1.0),
+            "text": self.text.clone().unwrap_or_else(|| json!({"format": {"type": "text"}})),
+            "tool_choice": self.tool_choice.clone().unwrap_or_else(|| json!("auto")),
+            "tools": self.tools.clone().unwrap_or_default(),
+            "top_p": self.top_p.unwrap_or(1.0),
+            "truncation": self.truncation.clone().unwrap_or_else(|| "disabled".to_string()),
+            "usage": null,
+            "user": self.user,
+            "metadata": self.metadata.clone().unwrap_or_else(|| json!({})),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ItemKind {
+    Reasoning,
+    Message,
+}
+
+// 正在输出的 reasoning 或 message item
+#[derive(Debug)]
+struct OpenItem {
+    kind: ItemKind,
+    id: String,
+    text: String,
+}
+
+// 生成结果转换成流式事件, 非流式时只取最后的响应对象
+#[derive(Debug)]
+pub struct ResponseStream {
+    response: Value,
+    parser: BlockParser,
+    output: Vec<Value>,
+    open: Option<OpenItem>,
+    sequence_number: usize,
+    reasoning: String,
+}
+
+impl ResponseStream {
+    pub fn new(response: Value) -> Self {
+        ResponseStream {
+            response,
+            parser: BlockParser::default(),
+            output: Vec::new(),
+            open: None,
+            sequence_number: 0,
+            reasoning: String::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        self.response["id"].as_str().unwrap_or_default()
+    }
+
+    // 目前输出的思考内容, 用于统计 reasoning_tokens
+    pub fn reasoning(&self) -> &str {
+        &self.reasoning
+    }
+
+    pub fn start(&mut self) -> Vec<Value> {
+        let response = self.response.clone();
+        vec![
+            self.event("response.created", json!({"response": response})),
+            self.event("response.in_progress", json!({"response": response})),
+        ]
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<Value> {
+        let segments = self.parser.push(text);
+        self.events(segments)
+    }
+
+    // 输出剩下的内容并结束所有 item
+    pub fn flush(&mut self) -> Vec<Value> {
+        let segments = self.parser.finish();
+        let mut events = self.events(segments);
+        events.extend(self.close());
+        events
+    }
+
+    // 最后的 response.completed 或 response.incomplete 事件, 带上完整的响应对象
+    pub fn complete(
+        &mut self,
+        finish_reason: StopReason,
+        input_tokens: usize,
+        output_tokens: usize,
+        reasoning_tokens: usize,
+    ) -> Value {
+        let mut response = self.response.clone();
+        response["output"] = json!(self.output);
+        response["usage"] = json!({
+            "input_tokens": input_tokens,
+            "input_tokens_details": {"cached_tokens": 0},
+            "output_tokens": output_tokens,
+            "output_tokens_details": {"reasoning_tokens": reasoning_tokens},
+            "total_tokens": input_tokens + output_tokens,
+        });
+        let event_type = if finish_reason == StopReason::Length {
+            response["status"] = json!("incomplete");
+            response["incomplete_details"] = json!({"reason": "max_output_tokens"});
+            "response.incomplete"
+        } else {
+            response["status"] = json!("completed");
+            "response.completed"
+        };
+        self.response = response.clone();
+        self.event(event_type, json!({"response": response}))
+    }
+
+    // 生成过程中出错: error 事件后接 response.failed, 之后不再有其它事件
+    pub fn fail(&mut self, e: &ApiError) -> Vec<Value> {
+        let mut response = self.response.clone();
+        response["output"] = json!(self.output);
+        response["status"] = json!("failed");
+        response["error"] = json!({"code": e.code.as_deref().unwrap_or(&e.error_type), "message": e.message});
+        self.response = response.clone();
+        vec![
+            self.event("error", json!({"code": e.code, "message": e.message, "param": e.param})),
+            self.event("response.failed", json!({"response": response})),
+        ]
+    }
+
+    pub fn response(&self) -> &Value {
+        &self.response
+    }
+
+    fn event(&mut self, event_type: &str, mut event: Value) -> Value {
+        event["type"] = json!(event_type);
+        event["sequence_number"] = json!(self.sequence_number);
+        self.sequence_number += 1;
+        event
+    }
+
+    fn events(&mut self, segments: Vec<Segment>) -> Vec<Value> {
+        let mut events = Vec::new();
+        for segment in segments {
+            let (kind, text) = match segment {
+                Segment::Thinking(text) => (ItemKind::Reasoning, text),
+                Segment::Text(text) => (ItemKind::Message, text),
+                Segment::ToolCall { name, input } => {
+                    events.extend(self.close());
+                    events.extend(self.function_call(name, input));
+                    continue;
+                }
+            };
+            if self.open.as_ref().map(|open| open.kind) != Some(kind) {
+                events.extend(self.close());
+                events.extend(self.open_item(kind));
+            }
+            let output_index = self.output.len();
+            let open = self.open.as_mut().unwrap();
+            open.text.push_str(&text);
+            let item_id = open.id.clone();
+            let event = match kind {
+                ItemKind::Reasoning => {
+                    self.reasoning.push_str(&text);
+                    self.event(
+                        "response.reasoning_text.delta",
+                        json!({"item_id": item_id, "output_index": output_index, "content_index": 0, "delta": text}),
+                    )
+                }
+                ItemKind::Message => self.event(
+                    "response.output_text.delta",
+                    json!({"item_id": item_id, "output_index": output_index, "content_index": 0, "delta": text, "logprobs": []}),
+                ),
+            };
+            events.push(event);
+        }
+        events
+    }
+
+    fn open_item(&mut self, kind: ItemKind) -> Vec<Value> {
+        let output_index = self.output.len();
+        let (id, item, part) = match kind {
+            ItemKind::Reasoning => {
+                let id = format!("rs_{}", uuid::Uuid::new_v4().simple());
+                let item = json!({"type": "reasoning", "id": id, "summary": [], "content": []});
+                (id, item, json!({"type": "reasoning_text", "text": ""}))
+            }
+            ItemKind::Message => {
+                let id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+                let item = json!({"type": "message", "id": id, "status": "in_progress", "role": "assistant", "content": []});
+                (id, item, json!({"type": "output_text", "text": "", "annotations": []}))
+            }
+        };
+        let events = vec![
+            self.event("response.output_item.added", json!({"output_index": output_index, "item": item})),
+            self.event(
+                "response.content_part.added",
+                json!({"item_id": id, "output_index": output_index, "content_index": 0, "part": part}),
+            ),
+        ];
+        self.open = Some(OpenItem {
+            kind,
+            id,
+            text: String::new(),
+        });
+        events
+    }
+
+    fn close(&mut self) -> Vec<Value> {
+        let Some(open) = self.open.take() else {
+            return Vec::new();
+        };
+        let output_index = self.output.len();
+        let (done_type, done, part, item) = match open.kind {
+            ItemKind::Reasoning => {
+                let part = json!({"type": "reasoning_text", "text": open.text});
+                let item = json!({"type": "reasoning", "id": open.id, "summary": [], "content": [part]});
+                ("response.reasoning_text.done", json!({"text": open.text}), part, item)
+            }
+            ItemKind::Message => {
+                let part = json!({"type": "output_text", "text": open.text, "annotations": []});
+                let item = json!({"type": "message", "id": open.id, "status": "completed", "role": "assistant", "content": [part]});
+                ("response.output_text.done", json!({"text": open.text, "logprobs": []}), part, item)
+            }
+        };
+        let mut done = done;
+        done["item_id"] = json!(open.id);
+        done["output_index"] = json!(output_index);
+        done["content_index"] = json!(0);
+        let events = vec![
+            self.event(done_type, done),
+            self.event(
+                "response.content_part.done",
+                json!({"item_id": open.id, "output_index": output_index, "content_index": 0, "part": part}),
+            ),
+            self.event("response.output_item.done", json!({"output_index": output_index, "item": item})),
+        ];
+        self.output.push(item);
+        events
+    }
+
+    // 工具调用解析完整后一次输出参数
+    fn function_call(&mut self, name: String, input: Value) -> Vec<Value> {
+        let output_index = self.output.len();
+        let id = format!("fc_{}", uuid::Uuid::new_v4().simple());
+        let arguments = input.to_string();
+        let mut item = json!({
+            "type": "function_call",
+            "id": id,
+            "call_id": new_tool_call_id(),
+            "name": name,
+            "arguments": "",
+            "status": "in_progress",
+        });
+        let added = self.event("response.output_item.added", json!({"output_index": output_index, "item": item}));
+        item["arguments"] = json!(arguments);
+        item["status"] = json!("completed");
+        let events = vec![
+            added,
+            self.event(
+                "response.function_call_arguments.delta",
+                json!({"item_id": id, "output_index": output_index, "delta": arguments}),
+            ),
+            self.event(
+                "response.function_call_arguments.done",
+                json!({"item_id": id, "output_index": output_index, "arguments": arguments}),
+            ),
+            self.event("response.output_item.done", json!({"output_index": output_index, "item": item})),
+        ];
+        self.output.push(item);
+        events
+    }
+}
+
+// 保存的响应和它的输入, 输入包括之前响应的对话历史
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StoredResponse {
+    pub input: Vec<Value>,
+    pub response: Value,
+}
+
+impl StoredResponse {
+    // 接着这个响应对话时的历史
+    pub fn history(&self) -> Vec<Value> {
+        let mut history = self.input.clone();
+        if let Some(output) = self.response["output"].as_array() {
+            history.extend(output.iter().cloned());
+        }
+        history
+    }
+}
+
+// 设置了目录时每个响应保存为一个 JSON 文件, 重启后仍然可以接着对话; 否则保存在内存中
+#[derive(Debug, Default)]
+pub struct ResponseStore {
+    dir: Option<PathBuf>,
+    memory: HashMap<String, StoredResponse>,
+    order: VecDeque<String>,
+}
+
+impl ResponseStore {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        ResponseStore {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    // id 只能包含字母、数字和下划线, 不能指向目录之外的文件
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        valid.then(|| dir.join(format!("{}.json", id)))
+    }
+
+    pub fn insert(&mut self, id: &str, stored: StoredResponse) -> anyhow::Result<()> {
+        if self.dir.is_some() {
+            let path = self.path(id).ok_or_else(|| anyhow::anyhow!("invalid response id {}", id))?;
+            std::fs::write(path, serde_json::to_string(&stored)?)?;
+            return Ok(());
+        }
+        if self.memory.insert(id.to_string(), stored).is_none() {
+            self.order.push_back(id.to_string());
+        }
+        while self.order.len() > MAX_STORED_RESPONSES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.memory.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<StoredResponse>> {
+        if self.dir.is_none() {
+            return Ok(self.memory.get(id).cloned());
+        }
+        match self.path(id) {
+            Some(path) if path.exists() => Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) -> anyhow::Result<bool> {
+        if self.dir.is_none() {
+            self.order.retain(|stored_id| stored_id != id);
+            return Ok(self.memory.remove(id).is_some());
+        }
+        match self.path(id) {
+            Some(path) if path.exists() => {
+                std::fs::remove_file(path)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+pub fn not_found(id: &str, param: Option<&str>) -> ApiError {
+    ApiError {
+        param: param.map(|param| param.to_string()),
+        ..ApiError::new(404, format!("Response with id '{}' not found.", id))
+    }
+}
@@ -0,0 +1,309 @@
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+
+// 单次请求的采样参数, 未设置的字段使用服务端默认值
+#[derive(Debug, Clone, Default)]
+pub struct SamplingParam {
+    pub temperature: Option<f64>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub seed: Option<u64>,
+    // OpenAI 的加性惩罚, 只统计本次生成的 token
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    // vLLM 风格的乘性惩罚, 作用于最近 repeat_last_n 个 token(含 prompt)
+    pub repetition_penalty: Option<f32>,
+    // token id -> 偏置, 小于等于 -100 视为禁止
+    pub logit_bias: Option<HashMap<u32, f32>>,
+}
+
+impl SamplingParam {
+    // 请求里显式设置的字段优先
+    // 请求设置了截断参数, 而请求和默认配置都没有 temperature 时按 temperature 1.0 采样, 否则这些参数不起作用
+    // seed 只让采样可以复现, 不会把贪心解码变成采样
+    pub fn merge(&self, default: &SamplingParam) -> SamplingParam {
+        let mut merged = SamplingParam {
+            temperature: self.temperature.or(default.temperature),
+            top_k: self.top_k.or(default.top_k),
+            top_p: self.top_p.or(default.top_p),
+            min_p: self.min_p.or(default.min_p),
+            typical_p: self.typical_p.or(default.typical_p),
+            seed: self.seed.or(default.seed),
+            presence_penalty: self.presence_penalty.or(default.presence_penalty),
+            frequency_penalty: self.frequency_penalty.or(default.frequency_penalty),
+            repetition_penalty: self.repetition_penalty.or(default.repetition_penalty),
+            logit_bias: self.logit_bias.clone().or_else(|| default.logit_bias.clone()),
+        };
+        let truncation =
+            self.top_k.is_some() || self.top_p.is_some() || self.min_p.is_some() || self.typical_p.is_some();
+        if merged.temperature.is_none() && truncation {
+            merged.temperature = Some(1.0);
+        }
+        merged
+    }
+
+    // 没有 temperature 或 temperature 为 0 时退化为贪心解码
+    pub fn is_greedy(&self) -> bool {
+        !matches!(self.temperature, Some(temperature) if temperature > 1e-7)
+    }
+}
+
+pub struct Sampler {
+    param: SamplingParam,
+    logits_processor: LogitsProcessor,
+    // 已生成 token 的出现次数, 用于 presence/frequency penalty
+    generated: HashMap<u32, usize>,
+    // 投机解码的接受判断和重采样使用
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(param: SamplingParam, seed: u64) -> Self {
+        // 过滤和温度都在 process_logits 中完成, 这里只按概率做一次多项式采样
+        let sampling = if param.is_greedy() {
+            Sampling::ArgMax
+        } else {
+            Sampling::All { temperature: 1.0 }
+        };
+        Sampler {
+            logits_processor: LogitsProcessor::from_sampling(seed, sampling),
+            param,
+            generated: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // context 为参与 repetition_penalty 的最近 token
+    pub fn sample(&mut self, logits: &Tensor, context: &[u32]) -> candle_core::Result<u32> {
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        apply_penalties(&mut logits, &self.param, context, &self.generated);
+        if !self.param.is_greedy() {
+            process_logits(&mut logits, &self.param);
+        }
+        let len = logits.len();
+        let logits = Tensor::from_vec(logits, len, &Device::Cpu)?;
+        let token = self.logits_processor.sample(&logits)?;
+        self.record(token);
+        Ok(token)
+    }
+
+    // 与 sample 使用同样的处理得到的概率分布, 贪心解码时为 one-hot
+    // proposed 为尚未确认的草稿 token, 计算惩罚时视为已生成
+    pub fn probs(&self, logits: &Tensor, context: &[u32], proposed: &[u32]) -> candle_core::Result<Vec<f32>> {
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        if proposed.is_empty() {
+            apply_penalties(&mut logits, &self.param, context, &self.generated);
+        } else {
+            let mut generated = self.generated.clone();
+            for &token in proposed {
+                *generated.entry(token).or_insert(0) += 1;
+            }
+            apply_penalties(&mut logits, &self.param, context, &generated);
+        }
+        if self.param.is_greedy() {
+            let mut probs = vec![0.0; logits.len()];
+            let argmax = logits
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(index, _)| index);
+            if let Some(argmax) = argmax {
+                probs[argmax] = 1.0;
+            }
+            return Ok(probs);
+        }
+        process_logits(&mut logits, &self.param);
+        Ok(softmax(&logits))
+    }
+
+    // 按给定的概率分布采样, 概率不必归一化
+    pub fn sample_probs(&mut self, probs: &[f32]) -> u32 {
+        let total: f32 = probs.iter().sum();
+        if total <= 0.0 {
+            return 0;
+        }
+        let mut threshold = self.rng.random::<f32>() * total;
+        let mut last = 0;
+        for (token, &prob) in probs.iter().enumerate() {
+            if prob <= 0.0 {
+                continue;
+            }
+            if threshold < prob {
+                return token as u32;
+            }
+            threshold -= prob;
+            last = token;
+        }
+        last as u32
+    }
+
+    // [0, 1) 均匀分布
+    pub fn uniform(&mut self) -> f32 {
+        self.rng.random::<f32>()
+    }
+
+    // 记录最终确认输出的 token
+    pub fn record(&mut self, token: u32) {
+        *self.generated.entry(token).or_insert(0) += 1;
+    }
+}
+
+// logit_bias -> repetition_penalty -> presence/frequency penalty
+pub fn apply_penalties(
+    logits: &mut [f32],
+    param: &SamplingParam,
+    context: &[u32],
+    generated: &HashMap<u32, usize>,
+) {
+    if let Some(logit_bias) = &param.logit_bias {
+        for (&token, &bias) in logit_bias {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                if bias <= -100.0 {
+                    *logit = f32::NEG_INFINITY;
+                } else {
+                    *logit += bias;
+                }
+            }
+        }
+    }
+    if let Some(penalty) = param.repetition_penalty
+        && penalty != 1.0
+    {
+        let context: HashSet<u32> = context.iter().copied().collect();
+        for token in context {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                if *logit >= 0.0 {
+                    *logit /= penalty;
+                } else {
+                    *logit *= penalty;
+                }
+            }
+        }
+    }
+    let presence_penalty = param.presence_penalty.unwrap_or(0.0);
+    let frequency_penalty = param.frequency_penalty.unwrap_or(0.0);
+    if presence_penalty != 0.0 || frequency_penalty != 0.0 {
+        for (&token, &count) in generated {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= frequency_penalty * count as f32 + presence_penalty;
+            }
+        }
+    }
+}
+
+// 与 HF transformers 的 logits warper 顺序一致: temperature -> top_k -> top_p -> min_p -> typical_p
+// 被过滤的 token 置为 -inf, 每一步至少保留一个 token
+pub fn process_logits(logits: &mut [f32], param: &SamplingParam) {
+    if let Some(temperature) = param.temperature
+        && temperature > 1e-7
+        && temperature != 1.0
+    {
+        let temperature = temperature as f32;
+        logits.iter_mut().for_each(|logit| *logit /= temperature);
+    }
+    if let Some(top_k) = param.top_k {
+        apply_top_k(logits, top_k);
+    }
+    if let Some(top_p) = param.top_p {
+        apply_top_p(logits, top_p as f32);
+    }
+    if let Some(min_p) = param.min_p {
+        apply_min_p(logits, min_p as f32);
+    }
+    if let Some(typical_p) = param.typical_p {
+        apply_typical_p(logits, typical_p as f32);
+    }
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = logits.iter().map(|&logit| (logit - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|value| value / sum).collect()
+}
+
+fn apply_top_k(logits: &mut [f32], top_k: usize) {
+    if top_k == 0 || top_k >= logits.len() {
+        return;
+    }
+    let mut sorted = logits.to_vec();
+    sorted.sort_by(|a, b| b.total_cmp(a));
+    let threshold = sorted[top_k - 1];
+    logits
+        .iter_mut()
+        .filter(|logit| **logit < threshold)
+        .for_each(|logit| *logit = f32::NEG_INFINITY);
+}
+
+fn apply_top_p(logits: &mut [f32], top_p: f32) {
+    if top_p >= 1.0 {
+        return;
+    }
+    let probs = softmax(logits);
+    let mut indices: Vec<usize> = (0..logits.len()).collect();
+    indices.sort_by(|&a, &b| logits[a].total_cmp(&logits[b]));
+    // 从概率最小的一端累加, 累计概率不超过 1 - top_p 的部分被过滤, 最大的一个始终保留
+    let mut cumulative = 0.0;
+    for &index in &indices[..indices.len() - 1] {
+        cumulative += probs[index];
+        if cumulative > 1.0 - top_p {
+            break;
+        }
+        logits[index] = f32::NEG_INFINITY;
+    }
+}
+
+fn apply_min_p(logits: &mut [f32], min_p: f32) {
+    if min_p <= 0.0 {
+        return;
+    }
+    let probs = softmax(logits);
+    let top_prob = probs.iter().copied().fold(0.0, f32::max);
+    let threshold = min_p * top_prob;
+    for (logit, prob) in logits.iter_mut().zip(probs) {
+        if prob < threshold {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+fn apply_typical_p(logits: &mut [f32], mass: f32) {
+    if mass >= 1.0 {
+        return;
+    }
+    let probs = softmax(logits);
+    let log_probs: Vec<f32> = probs.iter().map(|prob| prob.ln()).collect();
+    let entropy: f32 = -log_probs
+        .iter()
+        .zip(&probs)
+        .map(|(log_prob, prob)| log_prob * prob)
+        .filter(|value| !value.is_nan())
+        .sum::<f32>();
+    let shifted: Vec<f32> = log_probs
+        .iter()
+        .map(|log_prob| (-log_prob - entropy).abs())
+        .collect();
+    let mut indices: Vec<usize> = (0..logits.len()).collect();
+    indices.sort_by(|&a, &b| shifted[a].total_cmp(&shifted[b]));
+    // 按与熵的距离从小到大累加概率, 直到达到 typical_p
+    let mut cumulative = 0.0;
+    let mut last = 0;
+    for &index in &indices {
+        cumulative += probs[index];
+        if cumulative < mass {
+            last += 1;
+        }
+    }
+    let last = last.min(indices.len() - 1);
+    let threshold = shifted[indices[last]];
+    for &index in &indices[1..] {
+        if shifted[index] > threshold {
+            logits[index] = f32::NEG_INFINITY;
+        }
+    }
+}
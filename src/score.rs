@@ -0,0 +1,101 @@
+use crate::error::ApiError;
+use crate::qwen3::{MAX_TOP_LOGPROBS, TokenLogprob};
+use crate::{Message, Tool};
+
+// 给候选的续写打分: 返回每个 token 的 logprob 和总的对数似然, 不做采样
+// prompt 模式直接拼接 prompt 和 continuation 的 token
+// messages 模式按对话模板渲染到 assistant 回复开始处, continuation 为候选回复, 末尾计入 <|im_end|>
+
+pub const MAX_SCORE_CONTINUATIONS: usize = 1000;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ScoreRequest {
+    pub model: Option<String>,
+    pub prompt: Option<String>,
+    pub messages: Option<Vec<Message>>,
+    pub tools: Option<Vec<Tool>>,
+    pub continuation: ScoreContinuation,
+    pub top_logprobs: Option<usize>,
+}
+
+// 单个候选或多个候选, 多个候选共享 prompt 的 prefill
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ScoreContinuation {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ScoreRequest {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        match (&self.prompt, &self.messages) {
+            (Some(_), Some(_)) | (None, None) => {
+                return Err(ApiError::invalid_request(
+                    "exactly one of prompt and messages must be set",
+                    Some("prompt"),
+                ));
+            }
+            (Some(prompt), None) if prompt.is_empty() => {
+                return Err(ApiError::invalid_request("prompt cannot be empty", Some("prompt")));
+            }
+            (None, Some(messages)) if messages.is_empty() => {
+                return Err(ApiError::invalid_request(
+                    "messages must contain at least one message",
+                    Some("messages"),
+                ));
+            }
+            _ => {}
+        }
+        if self.tools.is_some() && self.messages.is_none() {
+            return Err(ApiError::invalid_request("tools can only be used with messages", Some("tools")));
+        }
+        let continuations = match &self.continuation {
+            ScoreContinuation::One(continuation) => std::slice::from_ref(continuation),
+            ScoreContinuation::Many(continuations) => continuations.as_slice(),
+        };
+        if continuations.is_empty() || continuations.iter().any(String::is_empty) {
+            return Err(ApiError::invalid_request("continuation cannot be empty", Some("continuation")));
+        }
+        if continuations.len() > MAX_SCORE_CONTINUATIONS {
+            return Err(ApiError::invalid_request(
+                format!(
+                    "continuation can contain at most {} items, got {}",
+                    MAX_SCORE_CONTINUATIONS,
+                    continuations.len()
+                ),
+                Some("continuation"),
+            ));
+        }
+        if let Some(top_logprobs) = self.top_logprobs
+            && top_logprobs > MAX_TOP_LOGPROBS
+        {
+            return Err(ApiError::invalid_request(
+                format!("top_logprobs must be in [0, {}], got {}", MAX_TOP_LOGPROBS, top_logprobs),
+                Some("top_logprobs"),
+            ));
+        }
+        Ok(())
+    }
+
+    // messages 模式下候选回复以 <|im_end|> 结束
+    pub fn continuations(&self) -> Vec<String> {
+        let continuations = match &self.continuation {
+            ScoreContinuation::One(continuation) => vec![continuation.clone()],
+            ScoreContinuation::Many(continuations) => continuations.clone(),
+        };
+        match &self.messages {
+            Some(_) => continuations
+                .into_iter()
+                .map(|continuation| continuation + "<|im_end|>")
+                .collect(),
+            None => continuations,
+        }
+    }
+}
+
+// 总的对数似然和困惑度
+pub fn log_likelihood(logprobs: &[TokenLogprob]) -> (f32, f32) {
+    let total: f32 = logprobs.iter().map(|logprob| logprob.logprob).sum();
+    let perplexity = (-total / logprobs.len().max(1) as f32).exp();
+    (total, perplexity)
+}
@@ -0,0 +1,144 @@
+use crate::stop::partial_stop_len;
+use serde_json::{Value, json};
+
+// 模型的输出按 <think> 和 <tool_call> 标签切分, 供各个兼容层转换成自己的格式
+
+// 输出的片段, 无法解析的 tool_call 按原文作为文本
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Thinking(String),
+    Text(String),
+    ToolCall { name: String, input: Value },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Text,
+    Thinking,
+    ToolCall,
+}
+
+// 流式的标签解析, 结尾可能是标签开头的部分先不输出
+// 每个块开头的换行不输出, 结尾的换行等到后面有内容时再输出
+#[derive(Debug, Default)]
+pub struct BlockParser {
+    // 只切分 tool_call, <think> 标签和换行原样作为文本, 与非流式的 chat 结果一致
+    tool_calls_only: bool,
+    mode: Mode,
+    buffer: String,
+    started: bool,
+    newlines: String,
+    tool_call: String,
+}
+
+impl BlockParser {
+    pub fn tool_calls_only() -> Self {
+        BlockParser {
+            tool_calls_only: true,
+            ..Default::default()
+        }
+    }
+
+    fn tags(&self) -> Vec<String> {
+        let tags: &[&str] = match self.mode {
+            Mode::Text if self.tool_calls_only => &["<tool_call>"],
+            Mode::Text => &["<think>", "<tool_call>"],
+            Mode::Thinking => &["</think>"],
+            Mode::ToolCall => &["</tool_call>"],
+        };
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<Segment> {
+        self.buffer.push_str(text);
+        let mut segments = Vec::new();
+        loop {
+            let tags = self.tags();
+            let found = tags
+                .iter()
+                .filter_map(|tag| self.buffer.find(tag.as_str()).map(|position| (position, tag)))
+                .min();
+            match found {
+                Some((position, tag)) => {
+                    let content: String = self.buffer.drain(..position + tag.len()).collect();
+                    self.content(&content[..position], &mut segments);
+                    self.switch(tag, &mut segments);
+                }
+                None => {
+                    let end = self.buffer.len() - partial_stop_len(&self.buffer, &tags);
+                    let content: String = self.buffer.drain(..end).collect();
+                    self.content(&content, &mut segments);
+                    break;
+                }
+            }
+        }
+        segments
+    }
+
+    // 输出结束时剩下的文本, 未闭合的 tool_call 按文本输出
+    pub fn finish(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let rest = std::mem::take(&mut self.buffer);
+        self.content(&rest, &mut segments);
+        if self.mode == Mode::ToolCall {
+            segments.push(Segment::Text(format!("<tool_call>{}", std::mem::take(&mut self.tool_call))));
+            self.mode = Mode::Text;
+        }
+        segments
+    }
+
+    fn content(&mut self, content: &str, segments: &mut Vec<Segment>) {
+        if self.mode == Mode::ToolCall {
+            self.tool_call.push_str(content);
+            return;
+        }
+        if self.tool_calls_only {
+            if !content.is_empty() {
+                segments.push(Segment::Text(content.to_string()));
+            }
+            return;
+        }
+        let content = if self.started {
+            content
+        } else {
+            content.trim_start_matches('\n')
+        };
+        let trimmed = content.trim_end_matches('\n');
+        if trimmed.is_empty() {
+            if self.started {
+                self.newlines.push_str(content);
+            }
+            return;
+        }
+        let text = std::mem::take(&mut self.newlines) + trimmed;
+        self.newlines = content[trimmed.len()..].to_string();
+        self.started = true;
+        segments.push(match self.mode {
+            Mode::Thinking => Segment::Thinking(text),
+            _ => Segment::Text(text),
+        });
+    }
+
+    fn switch(&mut self, tag: &str, segments: &mut Vec<Segment>) {
+        if self.mode == Mode::ToolCall {
+            let raw = std::mem::take(&mut self.tool_call);
+            segments.push(parse_tool_call(&raw).unwrap_or_else(|| Segment::Text(format!("<tool_call>{}</tool_call>", raw))));
+        }
+        self.mode = match tag {
+            "<think>" => Mode::Thinking,
+            "<tool_call>" => Mode::ToolCall,
+            _ => Mode::Text,
+        };
+        self.started = false;
+        self.newlines.clear();
+    }
+}
+
+// 模型输出的工具调用格式为 {"name": ..., "arguments": {...}}
+fn parse_tool_call(raw: &str) -> Option<Segment> {
+    let value: Value = serde_json::from_str(raw.trim()).ok()?;
+    let name = value.get("name")?.as_str()?.to_string();
+    let input = value.get("arguments").cloned().unwrap_or_else(|| json!({}));
+    Some(Segment::ToolCall { name, input })
+}
@@ -0,0 +1,209 @@
+use crate::error::ApiError;
+use crate::model::{KvCache, Model};
+use candle_core::{Device, Tensor};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 会话: 一个序列的 token 和 KV cache, 后续请求与其有相同前缀时跳过这部分的 prefill
+// 可以保存到文件, 服务重启后再恢复, 类似 llama.cpp 的 slot save/restore
+// 文件为 safetensors 格式, 除了每层的 k/v 外还保存格式版本、模型指纹和 token
+
+// 文件格式变化时加一, 旧版本的文件不再加载
+pub const SESSION_VERSION: u32 = 1;
+
+pub struct Session {
+    // 最后一个 token 可能还没经过模型, tokens 可能比 cache 多一个
+    pub tokens: Vec<u32>,
+    pub cache: KvCache,
+    // 最近一次使用时的请求序号, KV cache 预算不够时先淘汰最久未使用的会话
+    pub last_used: u64,
+}
+
+// 模型指纹: config.json、tokenizer.json, 以及每个权重文件的大小和开头的 1MiB
+// 不读取全部权重, 大模型也能很快算出, 同时能区分结构相同但权重不同的模型
+pub fn model_fingerprint(path: &str, weight_files: &[String]) -> anyhow::Result<u64> {
+    let mut hash = fnv1a(FNV_OFFSET, &std::fs::read(Path::new(path).join("config.json"))?);
+    hash = fnv1a(hash, &std::fs::read(Path::new(path).join("tokenizer.json"))?);
+    let mut weight_files = weight_files.to_vec();
+    weight_files.sort();
+    for file in weight_files {
+        let file = std::fs::File::open(file)?;
+        hash = fnv1a(hash, &file.metadata()?.len().to_le_bytes());
+        let mut head = Vec::new();
+        file.take(1 << 20).read_to_end(&mut head)?;
+        hash = fnv1a(hash, &head);
+    }
+    Ok(hash)
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// 跨版本稳定的哈希, 保存到文件的指纹不能用 DefaultHasher
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// 只允许目录内的普通文件名, 防止通过文件名读写会话目录以外的文件
+pub fn validate_filename(filename: &str) -> Result<(), ApiError> {
+    let valid = !filename.is_empty()
+        && filename.len() <= 255
+        && !filename.starts_with('.')
+        && filename
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(ApiError::invalid_request(
+            format!("invalid session filename: {:?}", filename),
+            Some("filename"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn save(path: &Path, tokens: &[u32], cache: &KvCache, fingerprint: u64) -> anyhow::Result<()> {
+    write(path, &snapshot(tokens, cache, fingerprint)?)
+}
+
+// 复制到 CPU 的会话内容, 之后写文件不再引用模型中的 cache, 可以在模型锁外进行
+pub fn snapshot(tokens: &[u32], cache: &KvCache, fingerprint: u64) -> anyhow::Result<HashMap<String, Tensor>> {
+    let mut tensors = HashMap::new();
+    tensors.insert(
+        "session.version".to_string(),
+        Tensor::new(&[SESSION_VERSION], &Device::Cpu)?,
+    );
+    // safetensors 没有 u64, 按位存成 i64
+    tensors.insert(
+        "session.fingerprint".to_string(),
+        Tensor::new(&[fingerprint as i64], &Device::Cpu)?,
+    );
+    tensors.insert("session.tokens".to_string(), Tensor::new(tokens, &Device::Cpu)?);
+    for (index, (k, v)) in cache.layers()?.iter().enumerate() {
+        tensors.insert(format!("layers.{}.k", index), to_cpu(k)?);
+        tensors.insert(format!("layers.{}.v", index), to_cpu(v)?);
+    }
+    Ok(tensors)
+}
+
+// 复制出独立的连续内存, 不与 KV 池共享
+fn to_cpu(tensor: &Tensor) -> candle_core::Result<Tensor> {
+    if !tensor.device().is_cpu() {
+        return tensor.to_device(&Device::Cpu)?.contiguous();
+    }
+    if tensor.is_contiguous() {
+        tensor.copy()
+    } else {
+        tensor.contiguous()
+    }
+}
+
+// 临时文件的序号, 同时保存的多个会话各自写不同的临时文件
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// 先写临时文件再改名, 写入过程中崩溃不会留下不完整的会话文件
+// 临时文件名带上完整的文件名、进程号和序号, 不同的会话文件不会共用同一个临时文件
+pub fn write(path: &Path, tensors: &HashMap<String, Tensor>) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid session path {}", path.display()))?
+        .to_string_lossy();
+    let tmp_name = format!(
+        "{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = candle_core::safetensors::save(tensors, &tmp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| std::fs::rename(&tmp_path, path).map_err(anyhow::Error::from));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// 只读文件头, 返回保存的 KV cache 的 token 数, 恢复前据此为会话腾出 KV 池中的块
+pub fn cached_len(path: &Path) -> anyhow::Result<usize> {
+    if !path.is_file() {
+        return Err(ApiError::new(404, format!("session file {} not found", path.display())).into());
+    }
+    let invalid = |message: String| ApiError::invalid_request(format!("invalid session file: {}", message), Some("filename"));
+    // safetensors 文件以 8 字节小端的头长度开始, 之后是 JSON 格式的头
+    let mut file = std::fs::File::open(path)?;
+    let mut size = [0u8; 8];
+    file.read_exact(&mut size).map_err(|e| invalid(e.to_string()))?;
+    let size = u64::from_le_bytes(size);
+    if size > file.metadata()?.len() {
+        return Err(invalid(format!("header size {} exceeds the file size", size)).into());
+    }
+    let mut header = vec![0u8; size as usize];
+    file.read_exact(&mut header).map_err(|e| invalid(e.to_string()))?;
+    let header: serde_json::Value = serde_json::from_slice(&header).map_err(|e| invalid(e.to_string()))?;
+    // 空的会话没有保存任何层, k 的 shape 为 (1, num_kv_heads, len, head_dim)
+    match header.get("layers.0.k") {
+        Some(k) => k["shape"]
+            .get(2)
+            .and_then(|len| len.as_u64())
+            .map(|len| len as usize)
+            .ok_or_else(|| invalid("unexpected kv cache shape".to_string()).into()),
+        None => Ok(0),
+    }
+}
+
+// 版本或指纹不一致时拒绝加载, 不会把别的模型的 KV cache 用在当前模型上
+// 先读到内存中, 写入 KV 池时才逐层复制到模型所在的设备
+pub fn load(path: &Path, model: &Model, fingerprint: u64) -> anyhow::Result<(Vec<u32>, KvCache)> {
+    if !path.is_file() {
+        return Err(ApiError::new(404, format!("session file {} not found", path.display())).into());
+    }
+    let mut tensors = candle_core::safetensors::load(path, &Device::Cpu)
+        .map_err(|e| ApiError::invalid_request(format!("invalid session file: {}", e), Some("filename")))?;
+    let mut take = |name: &str| {
+        tensors
+            .remove(name)
+            .ok_or_else(|| ApiError::invalid_request(format!("session file is missing {}", name), Some("filename")))
+    };
+    let version = take("session.version")?.to_vec1::<u32>()?;
+    if version != [SESSION_VERSION] {
+        return Err(ApiError::invalid_request(
+            format!("session file version {:?} is not supported, expected {}", version, SESSION_VERSION),
+            Some("filename"),
+        )
+        .into());
+    }
+    let saved_fingerprint = take("session.fingerprint")?.to_vec1::<i64>()?;
+    if saved_fingerprint != [fingerprint as i64] {
+        return Err(ApiError::invalid_request("session file was saved by a different model", Some("filename"))
+            .with_code("model_mismatch")
+            .into());
+    }
+    let tokens = take("session.tokens")?.to_vec1::<u32>()?;
+    // 空的会话没有保存任何层
+    let mut layers = Vec::with_capacity(model.num_layers());
+    for index in 0..model.num_layers() {
+        if let (Some(k), Some(v)) = (tensors.remove(&format!("layers.{}.k", index)), tensors.remove(&format!("layers.{}.v", index))) {
+            layers.push((k.to_dtype(model.dtype())?, v.to_dtype(model.dtype())?));
+        }
+    }
+    let mut cache = model.new_cache();
+    if !layers.is_empty() {
+        cache
+            .restore(layers)
+            .map_err(|e| ApiError::invalid_request(format!("invalid session file: {}", e), Some("filename")))?;
+    }
+    if cache.len() > tokens.len() {
+        return Err(ApiError::invalid_request("session file has more cache than tokens", Some("filename")).into());
+    }
+    Ok((tokens, cache))
+}
+
+// 会话与 prompt 的公共前缀长度
+pub fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
@@ -0,0 +1,125 @@
+use crate::model::Model;
+use crate::sampling::Sampler;
+
+// 投机解码: 先提出 k 个草稿 token, 目标模型一次前向验证
+// 接受规则按 Leviathan et al. 2023 的拒绝采样, 输出分布与只用目标模型完全一致
+
+// 草稿 token 的来源
+pub enum Proposer {
+    // 与目标模型共用 tokenizer 的小模型
+    Draft(DraftModel),
+    // 不需要草稿模型, 在已有 token 中查找与末尾 n-gram 相同的片段, 把它后面的 token 作为草稿
+    PromptLookup(PromptLookup),
+}
+
+impl Proposer {
+    pub fn num_speculative_tokens(&self) -> usize {
+        match self {
+            Proposer::Draft(draft) => draft.num_speculative_tokens,
+            Proposer::PromptLookup(lookup) => lookup.num_speculative_tokens,
+        }
+    }
+}
+
+pub struct DraftModel {
+    pub model: Model,
+    pub num_speculative_tokens: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct PromptLookup {
+    // 从 max_ngram 开始逐渐缩短 n-gram 查找, 最短为 min_ngram
+    pub max_ngram: usize,
+    pub min_ngram: usize,
+    pub num_speculative_tokens: usize,
+}
+
+impl PromptLookup {
+    // 返回最多 k 个草稿 token, 找不到匹配时为空
+    pub fn propose(&self, tokens: &[u32], k: usize) -> Vec<u32> {
+        let min_ngram = self.min_ngram.max(1);
+        for n in (min_ngram..=self.max_ngram).rev() {
+            if tokens.len() <= n {
+                continue;
+            }
+            let ngram = &tokens[tokens.len() - n..];
+            // 不与末尾的 n-gram 自身匹配, 取最早出现的位置
+            let matched = tokens[..tokens.len() - 1]
+                .windows(n)
+                .position(|window| window == ngram);
+            if let Some(start) = matched {
+                let begin = start + n;
+                let end = (begin + k).min(tokens.len());
+                if begin < end {
+                    return tokens[begin..end].to_vec();
+                }
+            }
+        }
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SpeculativeStats {
+    // 目标模型的验证次数
+    pub steps: u64,
+    pub proposed_tokens: u64,
+    pub accepted_tokens: u64,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.proposed_tokens == 0 {
+            0.0
+        } else {
+            self.accepted_tokens as f64 / self.proposed_tokens as f64
+        }
+    }
+
+    // 每次验证平均输出的 token 数, 包括拒绝后重采样或全部接受后多出的一个
+    pub fn tokens_per_step(&self) -> f64 {
+        if self.steps == 0 {
+            0.0
+        } else {
+            (self.accepted_tokens + self.steps) as f64 / self.steps as f64
+        }
+    }
+
+    pub fn add(&mut self, proposed: usize, accepted: usize) {
+        self.steps += 1;
+        self.proposed_tokens += proposed as u64;
+        self.accepted_tokens += accepted as u64;
+    }
+}
+
+// target_probs 比 draft_tokens 多一个, 最后一个用于全部接受后再采样一个 token
+// 返回接受的草稿 token 数, 以及之后由目标分布确定的下一个 token
+pub fn verify(
+    target_probs: &[Vec<f32>],
+    draft_probs: &[Vec<f32>],
+    draft_tokens: &[u32],
+    sampler: &mut Sampler,
+) -> (usize, u32) {
+    for (index, &token) in draft_tokens.iter().enumerate() {
+        let p = target_probs[index][token as usize];
+        let q = draft_probs[index][token as usize];
+        // 以 min(1, p / q) 的概率接受
+        if q > 0.0 && (p >= q || sampler.uniform() * q < p) {
+            continue;
+        }
+        // 拒绝后从 max(0, p - q) 归一化后的分布中重新采样
+        let residual: Vec<f32> = target_probs[index]
+            .iter()
+            .zip(&draft_probs[index])
+            .map(|(p, q)| (p - q).max(0.0))
+            .collect();
+        let next = if residual.iter().sum::<f32>() > 0.0 {
+            sampler.sample_probs(&residual)
+        } else {
+            sampler.sample_probs(&target_probs[index])
+        };
+        return (index, next);
+    }
+    let next = sampler.sample_probs(&target_probs[draft_tokens.len()]);
+    (draft_tokens.len(), next)
+}
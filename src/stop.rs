@@ -0,0 +1,148 @@
+// 停止字符串: 生成的文本中出现任意一个时结束该序列, 输出不包含停止字符串本身
+// 流式输出时, 结尾可能是停止字符串开头的部分先不输出, 等后面的 token 确认
+
+// 请求中的 stop 可以是单个字符串或字符串数组
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            StopSequences::One(stop) => vec![stop.clone()],
+            StopSequences::Many(stop) => stop.clone(),
+        }
+    }
+}
+
+// 输入流式增量解码的结果, 只在还没输出的部分中查找, 每步的开销与已生成的长度无关
+// 同时记录每段文本对应的 token 数, 用来把 logprobs 和 token 数对齐到输出的文本
+#[derive(Debug, Clone)]
+pub struct StopChecker {
+    stop: Vec<String>,
+    // 目前解码出的全部文本
+    text: String,
+    // 每段文本结束时的字节数和累计 token 数
+    boundaries: Vec<(usize, usize)>,
+    // 已经输出的字节数
+    emitted: usize,
+    // 遇到的停止字符串的下标
+    matched: Option<usize>,
+    // 生成到停止字符串结尾所用的 token 数
+    matched_tokens: Option<usize>,
+}
+
+// 最早出现的停止字符串的位置
+pub fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    find_stop_index(text, stop).map(|(position, _)| position)
+}
+
+// 最早出现的停止字符串的位置和下标
+fn find_stop_index(text: &str, stop: &[String]) -> Option<(usize, usize)> {
+    stop.iter()
+        .enumerate()
+        .filter_map(|(index, stop)| text.find(stop.as_str()).map(|position| (position, index)))
+        .min()
+}
+
+// text 结尾最长的、是某个停止字符串真前缀的部分的长度
+pub fn partial_stop_len(text: &str, stop: &[String]) -> usize {
+    stop.iter()
+        .flat_map(|stop| {
+            stop.char_indices()
+                .skip(1)
+                .map(|(end, _)| &stop[..end])
+                .filter(|prefix| text.ends_with(prefix))
+                .map(str::len)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+impl StopChecker {
+    pub fn new(stop: Vec<String>) -> Self {
+        StopChecker {
+            stop,
+            text: String::new(),
+            boundaries: Vec::new(),
+            emitted: 0,
+            matched: None,
+            matched_tokens: None,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.matched.is_some()
+    }
+
+    pub fn matched(&self) -> Option<usize> {
+        self.matched
+    }
+
+    // 遇到停止字符串时, 包含停止字符串结尾的那段文本及之前的 token 数, 之后的 token 不计入结果
+    pub fn matched_tokens(&self) -> Option<usize> {
+        self.matched_tokens
+    }
+
+    // 文本已经全部输出的 token 数, 这些 token 的 logprobs 可以随文本一起输出
+    pub fn emitted_tokens(&self) -> usize {
+        self.boundaries
+            .iter()
+            .take_while(|&&(end, _)| end <= self.emitted)
+            .last()
+            .map_or(0, |&(_, tokens)| tokens)
+    }
+
+    // text 为新解码出的完整 utf-8 文本, tokens 为到这段文本为止的 token 数, 返回可以输出的新文本
+    // 遇到停止字符串时只输出它之前的部分, 之后不再输出
+    pub fn push(&mut self, text: &str, tokens: usize) -> Option<String> {
+        if self.is_stopped() {
+            return None;
+        }
+        self.text.push_str(text);
+        self.boundaries.push((self.text.len(), tokens));
+        // 已输出的部分不含停止字符串, 也不以停止字符串的开头结尾, 匹配只可能从未输出的部分开始
+        let pending = &self.text[self.emitted..];
+        let end = match find_stop_index(pending, &self.stop) {
+            Some((position, index)) => {
+                let stop_end = self.emitted + position + self.stop[index].len();
+                self.matched = Some(index);
+                self.matched_tokens = self
+                    .boundaries
+                    .iter()
+                    .find(|&&(end, _)| end >= stop_end)
+                    .map(|&(_, tokens)| tokens);
+                self.emitted + position
+            }
+            None => self.text.len() - partial_stop_len(pending, &self.stop),
+        };
+        self.emit(end)
+    }
+
+    // 序列结束时输出剩下的文本
+    pub fn flush(&mut self) -> String {
+        if self.is_stopped() {
+            return String::new();
+        }
+        self.emit(self.text.len()).unwrap_or_default()
+    }
+
+    // 非流式的结果截断到停止字符串之前
+    pub fn truncate(&self, text: &mut String) {
+        if let Some(position) = find_stop(text, &self.stop) {
+            text.truncate(position);
+        }
+    }
+
+    fn emit(&mut self, end: usize) -> Option<String> {
+        if end <= self.emitted {
+            return None;
+        }
+        let new_text = self.text[self.emitted..end].to_string();
+        self.emitted = end;
+        Some(new_text)
+    }
+}
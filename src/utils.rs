@@ -116,3 +116,60 @@ pub fn str_startswith(s: &str, prefix: &str) -> bool {
 pub fn str_endswith(s: &str, suffix: &str) -> bool {
     s.ends_with(suffix)
 }
+
+// GPT-2 byte-level BPE 把每个字节映射成一个可见字符, 这里做逆映射还原 token 的原始字节
+pub fn byte_level_decode(piece: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(piece.len());
+    for c in piece.chars() {
+        match byte_level_char_to_byte(c) {
+            Some(byte) => bytes.push(byte),
+            // 不在映射表中的字符(如 added token)按 utf-8 原样输出
+            None => {
+                let mut buf = [0u8; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    bytes
+}
+
+fn byte_level_char_to_byte(c: char) -> Option<u8> {
+    let code = c as u32;
+    match code {
+        0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF => Some(code as u8),
+        // 其余 68 个字节按顺序映射到 U+0100 之后
+        0x100..=0x143 => {
+            let offset = (code - 0x100) as u8;
+            let mut n = 0u8;
+            for byte in 0..=255u8 {
+                if !matches!(byte, 0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF) {
+                    if n == offset {
+                        return Some(byte);
+                    }
+                    n += 1;
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+// 标准 base64 编码(带 = 填充)
+pub fn base64_encode(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
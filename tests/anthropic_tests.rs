@@ -0,0 +1,158 @@
+use qwen3_deploy::anthropic::{MessageStream, MessagesRequest, error_event, message_response};
+use qwen3_deploy::error::ApiError;
+use qwen3_deploy::qwen3::StopReason;
+use qwen3_deploy::segment::{BlockParser, Segment};
+use serde_json::json;
+
+fn request(value: serde_json::Value) -> MessagesRequest {
+    serde_json::from_value(value).unwrap()
+}
+
+#[test]
+fn test_to_chat_request() {
+    let req = request(json!({
+        "model": "qwen3",
+        "max_tokens": 64,
+        "system": [{"type": "text", "text": "be brief"}],
+        "stop_sequences": ["###"],
+        "thinking": {"type": "enabled", "budget_tokens": 1024},
+        "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+        "messages": [
+            {"role": "user", "content": "weather in Paris?"},
+            {"role": "assistant", "content": [
+                {"type": "thinking", "thinking": "need the tool", "signature": "sig"},
+                {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+            ]},
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "toolu_1", "content": [{"type": "text", "text": "sunny"}]},
+                {"type": "text", "text": "and tomorrow?"}
+            ]}
+        ]
+    }));
+    let chat = req.to_chat_request().unwrap();
+    assert!(chat.validate().is_ok());
+    assert!(chat.enable_thinking());
+    assert_eq!(chat.stop(), vec!["###"]);
+    assert_eq!(chat.max_tokens, Some(64));
+    let messages = serde_json::to_value(&chat.messages).unwrap();
+    assert_eq!(messages[0], json!({"role": "system", "content": "be brief"}));
+    assert_eq!(messages[2]["content"], "<think>\nneed the tool\n</think>\n\n");
+    assert_eq!(messages[2]["tool_calls"][0]["function"]["arguments"], json!({"city": "Paris"}));
+    assert_eq!(messages[3], json!({"role": "tool", "content": "sunny", "tool_call_id": "toolu_1"}));
+    assert_eq!(messages[4], json!({"role": "user", "content": "and tomorrow?"}));
+
+    // 默认关闭思考, tool_choice 为 none 时不带工具
+    let req = request(json!({
+        "max_tokens": 16,
+        "tools": [{"name": "f", "input_schema": {}}],
+        "tool_choice": {"type": "none"},
+        "messages": [{"role": "user", "content": "hi"}]
+    }));
+    let chat = req.to_chat_request().unwrap();
+    assert!(!chat.enable_thinking());
+    assert!(chat.tools.is_none());
+
+    let param = |value: serde_json::Value| request(value).to_chat_request().unwrap_err().param;
+    assert_eq!(param(json!({"max_tokens": 0, "messages": [{"role": "user", "content": "hi"}]})), Some("max_tokens".to_string()));
+    assert_eq!(param(json!({"max_tokens": 1, "messages": [{"role": "system", "content": "hi"}]})), Some("messages[0].role".to_string()));
+    assert_eq!(
+        param(json!({"max_tokens": 1, "messages": [{"role": "user", "content": [{"type": "image", "source": {}}]}]})),
+        Some("messages[0].content".to_string())
+    );
+}
+
+#[test]
+fn test_block_parser() {
+    // 标签和换行被切分在任意位置
+    let output = "<think>\nlet me think\n</think>\n\nHello\n\nworld\n<tool_call>\n{\"name\": \"f\", \"arguments\": {\"x\": 1}}\n</tool_call>";
+    let mut parser = BlockParser::default();
+    let mut segments = Vec::new();
+    for c in output.chars() {
+        segments.extend(parser.push(&c.to_string()));
+    }
+    segments.extend(parser.finish());
+    let thinking: String = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Thinking(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    let text: String = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(thinking, "let me think");
+    assert_eq!(text, "Hello\n\nworld");
+    assert_eq!(
+        segments.last(),
+        Some(&Segment::ToolCall {
+            name: "f".to_string(),
+            input: json!({"x": 1})
+        })
+    );
+
+    // 未闭合或无法解析的 tool_call 按文本输出
+    let mut parser = BlockParser::default();
+    let mut segments = parser.push("a<tool_call>oops</tool_call><tool_call>{\"name\"");
+    segments.extend(parser.finish());
+    assert_eq!(
+        segments,
+        vec![
+            Segment::Text("a".to_string()),
+            Segment::Text("<tool_call>oops</tool_call>".to_string()),
+            Segment::Text("<tool_call>{\"name\"".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_message_response_and_stream() {
+    let output = "<think>\nhmm\n</think>\n\n<tool_call>\n{\"name\": \"f\", \"arguments\": {}}\n</tool_call>";
+    let response = message_response("qwen3", output, StopReason::Stop, &[], 10, 5);
+    assert_eq!(response["stop_reason"], "tool_use");
+    assert_eq!(response["content"][0], json!({"type": "thinking", "thinking": "hmm", "signature": ""}));
+    assert_eq!(response["content"][1]["type"], "tool_use");
+    assert_eq!(response["content"][1]["name"], "f");
+    assert_eq!(response["usage"], json!({"input_tokens": 10, "output_tokens": 5}));
+
+    let stop = vec!["END".to_string()];
+    let response = message_response("qwen3", "done", StopReason::StopSequence(0), &stop, 1, 1);
+    assert_eq!(response["stop_reason"], "stop_sequence");
+    assert_eq!(response["stop_sequence"], "END");
+
+    let mut message = MessageStream::new("qwen3", 3);
+    let mut events = vec![message.start()];
+    for piece in ["<think>", "\nhmm", "\n</think>\n\n", "Hi", " there"] {
+        events.extend(message.push(piece));
+    }
+    events.extend(message.finish(StopReason::Length, &[], 7));
+    let types: Vec<&str> = events.iter().map(|event| event["type"].as_str().unwrap()).collect();
+    assert_eq!(
+        types,
+        vec![
+            "message_start",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_stop",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_delta",
+            "content_block_stop",
+            "message_delta",
+            "message_stop",
+        ]
+    );
+    assert_eq!(events[2]["delta"], json!({"type": "thinking_delta", "thinking": "hmm"}));
+    assert_eq!(events[4]["index"], 1);
+    assert_eq!(events[6]["delta"], json!({"type": "text_delta", "text": " there"}));
+    assert_eq!(events[8]["delta"]["stop_reason"], "max_tokens");
+    assert_eq!(events[8]["usage"]["output_tokens"], 7);
+
+    // 生成中途出错时以 error 事件结束流
+    let event = error_event(&ApiError::server_error("boom"));
+    assert_eq!(event, json!({"type": "error", "error": {"type": "api_error", "message": "boom"}}));
+}
@@ -0,0 +1,138 @@
+use qwen3_deploy::batch::{BatchRequest, BatchStore, is_failed, parse_input, read_results, result_line, run_batch};
+use qwen3_deploy::error::ApiError;
+use serde_json::json;
+use std::path::PathBuf;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("batch_tests_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_parse_input() {
+    let input = concat!(
+        r#"{"custom_id": "a", "method": "POST", "url": "/v1/chat/completions", "body": {"messages": []}}"#,
+        "\n\n",
+        r#"{"custom_id": "b", "method": "POST", "url": "/v1/embeddings", "body": {"input": "x"}}"#,
+        "\n"
+    );
+    let lines = parse_input(input).unwrap();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1].custom_id, "b");
+    assert_eq!(lines[1].body, json!({"input": "x"}));
+
+    // 错误中带上行号, 空行也计入行号
+    let duplicate = format!("{}\n\n{}", input.lines().next().unwrap(), input.lines().next().unwrap());
+    assert_eq!(parse_input(&duplicate).unwrap_err().param, Some("line 3".to_string()));
+    assert_eq!(parse_input("{\"custom_id\": \"a\"}").unwrap_err().param, Some("line 1".to_string()));
+    assert!(parse_input("\n\n").is_err());
+
+    let ok = result_line("a", Ok(r#"{"object": "chat.completion"}"#.to_string()));
+    assert!(!is_failed(&ok));
+    assert_eq!(ok["response"]["status_code"], 200);
+    assert_eq!(ok["response"]["body"]["object"], "chat.completion");
+    let failed = result_line("b", Err(ApiError::invalid_request("bad", Some("messages"))));
+    assert!(is_failed(&failed));
+    assert_eq!(failed["response"]["status_code"], 400);
+    assert_eq!(failed["response"]["body"]["error"]["param"], "messages");
+}
+
+#[tokio::test]
+async fn test_run_batch_resume() {
+    let dir = temp_dir("resume");
+    let output = dir.join("output.jsonl");
+    let errors = dir.join("errors.jsonl");
+    // 上次运行完成了 a, 崩溃时最后一行只写了一半
+    let done = result_line("a", Ok("{}".to_string()));
+    std::fs::write(&output, format!("{}\n{{\"id\": \"batch_req", done)).unwrap();
+    let input = [
+        json!({"custom_id": "a", "method": "POST", "url": "/v1/chat/completions", "body": {}}),
+        json!({"custom_id": "b", "method": "GET", "url": "/v1/chat/completions", "body": {}}),
+        json!({"custom_id": "c", "method": "POST", "url": "/v1/embeddings", "body": {}}),
+        json!({"custom_id": "d", "method": "POST", "url": "/v1/chat/completions", "body": {"stream": true}}),
+    ];
+    let input: Vec<String> = input.iter().map(|line| line.to_string()).collect();
+    let lines = parse_input(&input.join("\n")).unwrap();
+
+    let mut updates = Vec::new();
+    let counts = run_batch(lines.clone(), Some("/v1/chat/completions"), &output, Some(&errors), 2, |counts| {
+        updates.push(counts.clone());
+        true
+    })
+    .await
+    .unwrap();
+    assert_eq!((counts.total, counts.completed, counts.failed), (4, 1, 3));
+    assert_eq!((updates[0].completed, updates[0].failed), (1, 0));
+
+    // a 没有重新执行, 截掉的半行不影响后面追加的结果
+    let outputs = read_results(&output).unwrap();
+    assert_eq!(outputs, vec![done]);
+    let failures = read_results(&errors).unwrap();
+    let mut params: Vec<(String, String)> = failures
+        .iter()
+        .map(|result| {
+            let param = result["response"]["body"]["error"]["param"].as_str().unwrap().to_string();
+            (result["custom_id"].as_str().unwrap().to_string(), param)
+        })
+        .collect();
+    params.sort();
+    assert_eq!(
+        params,
+        vec![
+            ("b".to_string(), "method".to_string()),
+            ("c".to_string(), "url".to_string()),
+            ("d".to_string(), "stream".to_string()),
+        ]
+    );
+
+    // 全部完成后再次运行不执行任何请求
+    let counts = run_batch(lines, Some("/v1/chat/completions"), &output, Some(&errors), 2, |_| true)
+        .await
+        .unwrap();
+    assert_eq!((counts.completed, counts.failed), (1, 3));
+    assert_eq!(read_results(&errors).unwrap().len(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_batch_store() {
+    let dir = temp_dir("store");
+    let store = BatchStore::new(dir.to_str().unwrap()).unwrap();
+    let id = BatchStore::new_file_id();
+    std::fs::write(store.file_path(&id).unwrap(), "{}\n").unwrap();
+    let file = store.register_file(&id, "input.jsonl", "batch").unwrap();
+    assert_eq!(file["bytes"], 3);
+    assert_eq!(store.file_content(&id).unwrap(), "{}\n");
+    assert_eq!(store.list_files().unwrap()["data"].as_array().unwrap().len(), 1);
+    assert_eq!(store.file("../batches").unwrap_err().status, 404);
+
+    let request = |endpoint: &str, input_file_id: &str| BatchRequest {
+        input_file_id: input_file_id.to_string(),
+        endpoint: endpoint.to_string(),
+        completion_window: "24h".to_string(),
+        metadata: None,
+    };
+    assert_eq!(
+        store.create_batch(&request("/v1/rerank", &id)).unwrap_err().param,
+        Some("endpoint".to_string())
+    );
+    assert_eq!(
+        store.create_batch(&request("/v1/chat/completions", "file-missing")).unwrap_err().param,
+        Some("input_file_id".to_string())
+    );
+    let batch = store.create_batch(&request("/v1/chat/completions", &id)).unwrap();
+    let batch_id = batch["id"].as_str().unwrap();
+    assert_eq!(batch["status"], "validating");
+    assert_eq!(store.unfinished_batches().unwrap(), vec![batch_id.to_string()]);
+    let batch = store
+        .update_batch(batch_id, |batch| batch["status"] = json!("completed"))
+        .unwrap();
+    assert_eq!(store.batch(batch_id).unwrap(), batch);
+    assert!(store.unfinished_batches().unwrap().is_empty());
+
+    assert_eq!(store.delete_file(&id).unwrap()["deleted"], true);
+    assert_eq!(store.file(&id).unwrap_err().status, 404);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{remove_eos, tiny_model_dir};
+use qwen3_deploy::beam::{BeamHypotheses, BeamSearchParam, top_candidates};
+use qwen3_deploy::qwen3::{GenerateParam, Qwen3, StopReason};
+
+fn param(beam_width: usize, length_penalty: f32, early_stopping: bool) -> BeamSearchParam {
+    BeamSearchParam {
+        beam_width,
+        length_penalty,
+        early_stopping,
+    }
+}
+
+#[test]
+fn test_top_candidates() {
+    let beams = vec![
+        (-1.0, vec![-0.1, -2.0, -3.0, f32::NEG_INFINITY]),
+        (-0.5, vec![-1.0, -0.2, -4.0, -5.0]),
+    ];
+    let candidates = top_candidates(&beams, 3);
+    let picked: Vec<(usize, u32)> = candidates.iter().map(|c| (c.beam, c.token)).collect();
+    assert_eq!(picked, vec![(1, 1), (0, 0), (1, 0)]);
+    assert!((candidates[0].cumulative_logprob + 0.7).abs() < 1e-6);
+    assert!(top_candidates(&beams, 0).is_empty());
+}
+
+#[test]
+fn test_beam_hypotheses() {
+    // length_penalty = 1 时按平均 logprob 排序, 更长的结果可以胜出
+    let mut hypotheses = BeamHypotheses::new(param(2, 1.0, false));
+    hypotheses.add(vec![1, 2], -2.0, StopReason::Stop);
+    hypotheses.add(vec![1, 2, 3, 4], -3.0, StopReason::Stop);
+    hypotheses.add(vec![5], -4.0, StopReason::Stop);
+    assert_eq!(hypotheses.len(), 2);
+    // 剩余 beam 的最好得分 -1.0 / 3 仍可能超过最差的 -1.0
+    assert!(!hypotheses.is_done(-1.0, 3));
+    assert!(hypotheses.is_done(-4.0, 3));
+    let sorted = hypotheses.into_sorted();
+    assert_eq!(sorted[0].tokens, vec![1, 2, 3, 4]);
+    assert!((sorted[0].score + 0.75).abs() < 1e-6);
+    assert_eq!(sorted[1].tokens, vec![1, 2]);
+
+    // length_penalty = 0 时只看累计 logprob
+    let mut hypotheses = BeamHypotheses::new(param(2, 0.0, true));
+    hypotheses.add(vec![1, 2], -2.0, StopReason::Stop);
+    assert!(!hypotheses.is_done(0.0, 2));
+    hypotheses.add(vec![1, 2, 3, 4], -3.0, StopReason::Stop);
+    assert!(hypotheses.is_done(0.0, 4));
+    assert_eq!(hypotheses.into_sorted()[0].tokens, vec![1, 2]);
+}
+
+#[test]
+fn test_length_truncated_beams() {
+    // 去掉词表中的 eos, 所有 beam 都在达到 max_tokens 时被截断
+    let dir = tiny_model_dir("beam_length");
+    remove_eos(&dir);
+    let mut model =
+        Qwen3::new_with_param(dir.to_string_lossy().to_string(), 8, 1.0, 64, true, 1, None, None).unwrap();
+    let param = GenerateParam {
+        n: Some(2),
+        max_tokens: Some(2),
+        beam: Some(param(3, 1.0, false)),
+        ..Default::default()
+    };
+    let generations = model.infer_tokens(vec![1, 2, 3], param).unwrap();
+    assert_eq!(generations.len(), 2);
+    for generation in generations {
+        assert_eq!(generation.finish_reason, StopReason::Length);
+        assert_eq!(generation.completion_tokens, 2);
+    }
+}
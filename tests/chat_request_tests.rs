@@ -0,0 +1,179 @@
+use qwen3_deploy::ChatRequest;
+
+#[test]
+fn test_validate_tool_call_ids() {
+    let message = r#"
+    {
+        "messages": [
+            {
+                "role": "user",
+                "content": "现在几点了？"
+            },
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    {
+                        "id": "call_0f1e2d3c",
+                        "type": "function",
+                        "function": {"name": "get_current_time", "arguments": "{}"}
+                    }
+                ]
+            },
+            {
+                "role": "tool",
+                "content": "2025-07-24 10:00:00",
+                "tool_call_id": "call_0f1e2d3c"
+            }
+        ]
+    }
+    "#;
+    let request: ChatRequest = serde_json::from_str(message).unwrap();
+    assert!(request.validate_tool_call_ids().is_ok());
+
+    let dangling = message.replace(r#""tool_call_id": "call_0f1e2d3c""#, r#""tool_call_id": "0""#);
+    let request: ChatRequest = serde_json::from_str(&dangling).unwrap();
+    let err = request.validate_tool_call_ids().unwrap_err().to_string();
+    assert!(err.contains("messages[2]"), "{err}");
+    assert!(err.contains("`0`"), "{err}");
+}
+
+#[test]
+fn test_content_parts() {
+    let message = r#"
+    {
+        "messages": [
+            {
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "成都今天天气如何？"},
+                    {"type": "text", "text": "请简短回答。"}
+                ]
+            }
+        ]
+    }
+    "#;
+    let request: ChatRequest = serde_json::from_str(message).unwrap();
+    assert!(request.validate().is_ok());
+    let messages = serde_json::to_value(&request.messages).unwrap();
+    assert_eq!(messages[0]["content"], "成都今天天气如何？\n请简短回答。");
+
+    let message = r#"
+    {
+        "messages": [
+            {
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "图片里有什么？"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}}
+                ]
+            }
+        ]
+    }
+    "#;
+    let request: ChatRequest = serde_json::from_str(message).unwrap();
+    let err = request.validate().unwrap_err().to_string();
+    assert!(err.contains("messages[0].content[1]"), "{err}");
+    assert!(err.contains("image_url"), "{err}");
+}
+
+#[test]
+fn test_validate_request() {
+    let request: ChatRequest = serde_json::from_str(r#"{"messages": []}"#).unwrap();
+    let err = request.validate().unwrap_err();
+    assert_eq!(err.status, 400);
+    assert_eq!(err.param.as_deref(), Some("messages"));
+
+    let request: ChatRequest =
+        serde_json::from_str(r#"{"messages": [{"role": "robot", "content": "hi"}]}"#).unwrap();
+    let err = request.validate().unwrap_err();
+    assert_eq!(err.param.as_deref(), Some("messages[0].role"));
+    let body: serde_json::Value = serde_json::from_str(&err.to_json()).unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert!(body["error"]["message"].as_str().unwrap().contains("robot"));
+    assert!(body["error"]["code"].is_null());
+
+    let request: ChatRequest = serde_json::from_str(
+        r#"{"messages": [{"role": "user", "content": "hi"}], "guided_regex": "(a", "guided_choice": ["a"]}"#,
+    )
+    .unwrap();
+    let err = request.generate_param().unwrap_err();
+    assert_eq!(err.param.as_deref(), Some("guided_choice"));
+
+    let request: ChatRequest =
+        serde_json::from_str(r#"{"messages": [{"role": "user", "content": "hi"}], "n": 0}"#).unwrap();
+    assert_eq!(request.validate().unwrap_err().param.as_deref(), Some("n"));
+}
+
+#[test]
+fn test_beam_search_param() {
+    let request = |extra: &str| -> ChatRequest {
+        serde_json::from_str(&format!(r#"{{"messages": [{{"role": "user", "content": "hi"}}]{}}}"#, extra)).unwrap()
+    };
+    assert!(request(r#", "beam_width": 3"#).beam_search().is_none());
+    let beam = request(r#", "use_beam_search": true, "stream": false, "n": 3"#).beam_search().unwrap();
+    assert_eq!(beam.beam_width, 3);
+    assert!(!beam.early_stopping);
+
+    let err = request(r#", "use_beam_search": true, "stream": false, "beam_width": 2, "n": 3"#).validate().unwrap_err();
+    assert_eq!(err.param.as_deref(), Some("n"));
+    let err = request(r#", "use_beam_search": true, "stream": true"#).validate().unwrap_err();
+    assert!(err.message.contains("stream"), "{}", err.message);
+    // 没有指定 stream 时 /chat/completions 按流式处理
+    let err = request(r#", "use_beam_search": true"#).validate().unwrap_err();
+    assert!(err.message.contains("stream"), "{}", err.message);
+    assert!(request(r#", "use_beam_search": true, "stream": false, "length_penalty": 0.6"#).validate().is_ok());
+}
+
+#[test]
+fn test_truncation_starts() {
+    let message = r#"
+    {
+        "messages": [
+            {"role": "system", "content": "你是一个助手"},
+            {"role": "user", "content": "现在几点了？"},
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "get_current_time", "arguments": "{}"}}
+                ]
+            },
+            {"role": "tool", "content": "10:00", "tool_call_id": "call_1"},
+            {"role": "assistant", "content": "现在是10点。"},
+            {"role": "user", "content": "成都天气如何？"},
+            {"role": "assistant", "content": "晴。"},
+            {"role": "user", "content": "谢谢"}
+        ],
+        "truncation": "auto",
+        "max_tokens": 128
+    }
+    "#;
+    let request: ChatRequest = serde_json::from_str(message).unwrap();
+    assert!(request.validate().is_ok());
+    assert!(request.truncation_auto());
+    assert_eq!(request.max_tokens(), Some(128));
+    assert_eq!(request.system_prefix_len(), 1);
+    assert_eq!(request.truncation_starts(), vec![1, 5, 7]);
+
+    let request: ChatRequest = serde_json::from_str(
+        r#"{"messages": [{"role": "user", "content": "hi"}], "truncation": "middle"}"#,
+    )
+    .unwrap();
+    assert_eq!(request.validate().unwrap_err().param.as_deref(), Some("truncation"));
+}
+
+#[test]
+fn test_validate_logprobs() {
+    let request = |extra: &str| -> ChatRequest {
+        serde_json::from_str(&format!(r#"{{"messages": [{{"role": "user", "content": "hi"}}]{}}}"#, extra)).unwrap()
+    };
+    assert_eq!(request("").logprobs(), None);
+    assert_eq!(request(r#", "logprobs": true"#).logprobs(), Some(0));
+    assert_eq!(request(r#", "logprobs": true, "top_logprobs": 5"#).logprobs(), Some(5));
+
+    let err = request(r#", "logprobs": true, "top_logprobs": 21"#).validate().unwrap_err();
+    assert_eq!(err.param.as_deref(), Some("top_logprobs"));
+    let err = request(r#", "top_logprobs": 2"#).validate().unwrap_err();
+    assert!(err.message.contains("logprobs"), "{}", err.message);
+}
@@ -30,4 +30,30 @@ fn test_chat_response() {
     };
     println!("response: \n {:?}", response);
 
-}
\ No newline at end of file
+}
+#[test]
+fn test_tool_call_ids_unique() {
+    let message = r#"<tool_call>
+{"name": "get_current_time", "arguments": {}}
+</tool_call>
+<tool_call>
+{"name": "get_current_weather", "arguments": {"location": "成都"}}
+</tool_call>"#;
+    let first = serde_json::to_value(build_choice(message.to_string())).unwrap();
+    let second = serde_json::to_value(build_choice(message.to_string())).unwrap();
+    let ids = |choice: &serde_json::Value| {
+        choice["message"]["tool_calls"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool_call| tool_call["id"].as_str().unwrap().to_string())
+            .collect::<Vec<String>>()
+    };
+    let mut all_ids = ids(&first);
+    all_ids.extend(ids(&second));
+    assert_eq!(all_ids.len(), 4);
+    assert!(all_ids.iter().all(|id| id.starts_with("call_")));
+    all_ids.sort();
+    all_ids.dedup();
+    assert_eq!(all_ids.len(), 4);
+}
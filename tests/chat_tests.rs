@@ -112,9 +112,9 @@ async fn test_chat_stream() {
     let start = std::time::Instant::now();
     println!("开始");
     let request: ChatRequest = serde_json::from_str(&message).unwrap();
-    let mut stream = pin!(chat_stream(&request).unwrap());
+    let mut stream = pin!(chat_stream(&request).await.unwrap());
     while let Some(item) = stream.next().await {
-        println!("{}", item);
+        println!("{}", item.unwrap());
     }
     println!("耗时：{}ms", start.elapsed().as_millis());
 
@@ -0,0 +1,201 @@
+// 各测试共用的随机权重小模型, 每个测试文件只用到其中一部分
+#![allow(dead_code)]
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Activation, VarBuilder, VarMap};
+use candle_transformers::models::qwen3::Config;
+use qwen3_deploy::ChatChunkStream;
+use qwen3_deploy::model::Model;
+use qwen3_deploy::qwen3::{GenerateParam, Qwen3};
+use std::path::{Path, PathBuf};
+
+pub fn tiny_config(vocab_size: usize) -> Config {
+    Config {
+        vocab_size,
+        hidden_size: 32,
+        intermediate_size: 64,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        head_dim: 8,
+        attention_bias: false,
+        num_key_value_heads: 2,
+        max_position_embeddings: 128,
+        sliding_window: None,
+        max_window_layers: 2,
+        tie_word_embeddings: true,
+        rope_theta: 10000.0,
+        rms_norm_eps: 1e-6,
+        use_sliding_window: false,
+        hidden_act: Activation::Silu,
+    }
+}
+
+pub fn tiny_model(vocab_size: usize) -> Model {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    Model::new(&tiny_config(vocab_size), vb).unwrap()
+}
+
+// 可以用 Qwen3::new_with_param 加载的模型目录: 随机权重, 词表为 w0..w61 加两个特殊 token
+pub fn tiny_model_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tiny_model_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = tiny_config(64);
+    let config_json = serde_json::json!({
+        "vocab_size": config.vocab_size,
+        "hidden_size": config.hidden_size,
+        "intermediate_size": config.intermediate_size,
+        "num_hidden_layers": config.num_hidden_layers,
+        "num_attention_heads": config.num_attention_heads,
+        "head_dim": config.head_dim,
+        "attention_bias": config.attention_bias,
+        "num_key_value_heads": config.num_key_value_heads,
+        "max_position_embeddings": config.max_position_embeddings,
+        "sliding_window": null,
+        "max_window_layers": config.max_window_layers,
+        "tie_word_embeddings": config.tie_word_embeddings,
+        "rope_theta": config.rope_theta,
+        "rms_norm_eps": config.rms_norm_eps,
+        "use_sliding_window": false,
+        "hidden_act": "silu"
+    });
+    std::fs::write(dir.join("config.json"), config_json.to_string()).unwrap();
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    Model::new(&config, vb).unwrap();
+    varmap.save(dir.join("model.safetensors")).unwrap();
+
+    let mut vocab = serde_json::Map::new();
+    for i in 0..62 {
+        vocab.insert(format!("w{}", i), i.into());
+    }
+    vocab.insert("<|endoftext|>".into(), 62.into());
+    vocab.insert("<|im_end|>".into(), 63.into());
+    let special = |id: u32, content: &str| {
+        serde_json::json!({
+            "id": id, "content": content, "single_word": false, "lstrip": false,
+            "rstrip": false, "normalized": false, "special": true
+        })
+    };
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [special(62, "<|endoftext|>"), special(63, "<|im_end|>")],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "w0"}
+    });
+    std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+    dir
+}
+
+// 去掉词表中的 eos, 生成的序列总是达到 max_tokens
+pub fn remove_eos(dir: &Path) {
+    let tokenizer = std::fs::read_to_string(dir.join("tokenizer.json")).unwrap();
+    let tokenizer = tokenizer.replace("<|endoftext|>", "w62").replace("<|im_end|>", "w63");
+    std::fs::write(dir.join("tokenizer.json"), tokenizer).unwrap();
+}
+
+// 确定性输出的模型目录: 词表为 words 加两个特殊 token, 贪心解码时每个词的下一个词固定为 words 中的后一个
+// 最后一个词之后输出 <|im_end|>; 草稿模型用同一个目录时投机的 token 全部被接受
+// 各层的输出投影为 0, 隐状态就是 one-hot 的词向量, lm_head 把它映射到下一个词
+pub fn chain_model_dir(name: &str, words: &[&str]) -> PathBuf {
+    let dir = tiny_model_dir(name);
+    let vocab_size = words.len() + 2;
+    let mut config = tiny_config(vocab_size);
+    config.hidden_size = vocab_size;
+    config.tie_word_embeddings = false;
+    let mut config_json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("config.json")).unwrap()).unwrap();
+    config_json["vocab_size"] = vocab_size.into();
+    config_json["hidden_size"] = vocab_size.into();
+    config_json["tie_word_embeddings"] = false.into();
+    std::fs::write(dir.join("config.json"), config_json.to_string()).unwrap();
+
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    Model::new(&config, vb).unwrap();
+    let mut next = vec![0f32; vocab_size * vocab_size];
+    for token in 0..words.len() {
+        let successor = if token + 1 < words.len() { token + 1 } else { vocab_size - 1 };
+        next[successor * vocab_size + token] = 1.0;
+    }
+    let vars: Vec<(String, Vec<usize>)> = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (name.clone(), var.dims().to_vec()))
+        .collect();
+    for (name, dims) in vars {
+        let value = if name.ends_with("o_proj.weight") || name.ends_with("down_proj.weight") {
+            Tensor::zeros(dims, DType::F32, &Device::Cpu).unwrap()
+        } else if name == "model.embed_tokens.weight" {
+            Tensor::eye(vocab_size, DType::F32, &Device::Cpu).unwrap()
+        } else if name == "lm_head.weight" {
+            Tensor::from_vec(next.clone(), (vocab_size, vocab_size), &Device::Cpu).unwrap()
+        } else {
+            continue;
+        };
+        varmap.set_one(&name, value).unwrap();
+    }
+    varmap.save(dir.join("model.safetensors")).unwrap();
+
+    let mut tokenizer: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("tokenizer.json")).unwrap()).unwrap();
+    let mut vocab = serde_json::Map::new();
+    for (id, word) in words.iter().enumerate() {
+        vocab.insert(word.to_string(), id.into());
+    }
+    vocab.insert("<|endoftext|>".into(), (vocab_size - 2).into());
+    vocab.insert("<|im_end|>".into(), (vocab_size - 1).into());
+    tokenizer["model"]["vocab"] = vocab.into();
+    tokenizer["model"]["unk_token"] = words[0].into();
+    tokenizer["added_tokens"][0]["id"] = (vocab_size - 2).into();
+    tokenizer["added_tokens"][1]["id"] = (vocab_size - 1).into();
+    std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+    dir
+}
+
+// 输出一个 tool_call 的 chain_model_dir 词表, 从第一个词开始生成
+pub const TOOL_CALL_WORDS: [&str; 4] = [
+    "call",
+    "<tool_call>",
+    r#"{"name":"get_time","arguments":{"tz":"UTC"}}"#,
+    "</tool_call>",
+];
+
+// 逐步推进一个任务, 返回每次输出的文本和转换成的 chat 流式 chunk
+pub fn chat_chunks(model: &mut Qwen3, prompt: Vec<u32>, param: GenerateParam) -> (Vec<String>, Vec<serde_json::Value>) {
+    let mut task = model.new_task(prompt, param);
+    let mut chunks = ChatChunkStream::new(false);
+    let (mut texts, mut choices) = (Vec::new(), Vec::new());
+    while !task.is_finished() {
+        for token in model.advance(&mut task).unwrap() {
+            texts.push(token.text.clone());
+            choices.extend(chunks.push(token).iter().map(|choice| serde_json::to_value(choice).unwrap()));
+        }
+    }
+    (texts, choices)
+}
+
+// chunk 中的 tool_calls 增量 (name, arguments) 和拼接后的文本内容
+pub fn tool_calls_and_content(choices: &[serde_json::Value]) -> (Vec<(String, serde_json::Value)>, String) {
+    let mut tool_calls = Vec::new();
+    let mut content = String::new();
+    for choice in choices {
+        if let Some(calls) = choice["delta"]["tool_calls"].as_array() {
+            for call in calls {
+                let arguments = serde_json::from_str(call["function"]["arguments"].as_str().unwrap()).unwrap();
+                tool_calls.push((call["function"]["name"].as_str().unwrap().to_string(), arguments));
+            }
+        }
+        if let Some(text) = choice["delta"]["content"].as_str() {
+            content.push_str(text);
+        }
+    }
+    (tool_calls, content)
+}
@@ -0,0 +1,80 @@
+use qwen3_deploy::completion::{CompletionRequest, LegacyLogprobs, PromptInput};
+use qwen3_deploy::qwen3::{TokenLogprob, TopLogprob};
+
+fn request(json: &str) -> CompletionRequest {
+    serde_json::from_str(json).unwrap()
+}
+
+fn logprob(token: &str, logprob: f32) -> TokenLogprob {
+    TokenLogprob {
+        token: token.to_string(),
+        bytes: token.as_bytes().to_vec(),
+        logprob,
+        top_logprobs: vec![TopLogprob {
+            token: token.to_string(),
+            bytes: token.as_bytes().to_vec(),
+            logprob,
+        }],
+    }
+}
+
+#[test]
+fn test_completion_prompts() {
+    let prompts = |request: &CompletionRequest| -> Vec<String> {
+        request
+            .inputs()
+            .into_iter()
+            .map(|input| match input {
+                PromptInput::Text(text) => text,
+                PromptInput::Tokens(tokens) => format!("{:?}", tokens),
+            })
+            .collect()
+    };
+    assert_eq!(prompts(&request(r#"{"prompt": "hello"}"#)), vec!["hello"]);
+    assert_eq!(prompts(&request(r#"{"prompt": ["a", "b"]}"#)), vec!["a", "b"]);
+    assert_eq!(prompts(&request(r#"{"prompt": [1, 2, 3]}"#)), vec!["[1, 2, 3]"]);
+    assert_eq!(prompts(&request(r#"{"prompt": [[1], [2, 3]]}"#)), vec!["[1]", "[2, 3]"]);
+
+    let req = request(r#"{"prompt": "x", "stop": "\n"}"#);
+    assert_eq!(req.stop(), vec!["\n"]);
+    assert_eq!(req.max_tokens(), 16);
+    assert_eq!(request(r#"{"prompt": "x", "stop": ["a", "b"]}"#).stop(), vec!["a", "b"]);
+}
+
+#[test]
+fn test_validate_completion_request() {
+    let ok = r#"{"prompt": ["a", "b"], "n": 2, "logprobs": 5, "echo": true, "max_tokens": 0, "temperature": 0.7}"#;
+    assert!(request(ok).validate().is_ok());
+    let param = |json: &str| request(json).validate().unwrap_err().param;
+    assert_eq!(param(r#"{"prompt": ""}"#), Some("prompt".to_string()));
+    assert_eq!(param(r#"{"prompt": []}"#), Some("prompt".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "suffix": "y"}"#), Some("suffix".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "n": 0}"#), Some("n".to_string()));
+    assert_eq!(param(r#"{"prompt": ["a", "b"], "n": 100}"#), Some("n".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "best_of": 3}"#), Some("best_of".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "logprobs": 21}"#), Some("logprobs".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "stop": ["a", "b", "c", "d", "e"]}"#), Some("stop".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "stop": [""]}"#), Some("stop".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "temperature": 3}"#), Some("temperature".to_string()));
+    assert_eq!(param(r#"{"prompt": "x", "logit_bias": {"a": 1}}"#), Some("logit_bias".to_string()));
+}
+
+#[test]
+fn test_legacy_logprobs() {
+    let mut logprobs = LegacyLogprobs::default();
+    logprobs.push_first("The".to_string());
+    logprobs.extend(&[logprob(" cat", -1.0), logprob("你", -2.0)]);
+    let value = serde_json::to_value(&logprobs).unwrap();
+    assert_eq!(value["tokens"], serde_json::json!(["The", " cat", "你"]));
+    assert_eq!(value["token_logprobs"], serde_json::json!([null, -1.0, -2.0]));
+    assert_eq!(value["top_logprobs"][0], serde_json::Value::Null);
+    assert_eq!(value["top_logprobs"][1], serde_json::json!({" cat": -1.0}));
+    assert_eq!(value["text_offset"], serde_json::json!([0, 3, 7]));
+
+    // 流式输出时偏移接着上一个 chunk
+    let taken = logprobs.take();
+    assert_eq!(taken.tokens.len(), 3);
+    logprobs.push(&logprob("!", -0.5));
+    assert_eq!(logprobs.text_offset, vec![8]);
+    assert_eq!(logprobs.tokens, vec!["!"]);
+}
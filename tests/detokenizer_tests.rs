@@ -0,0 +1,104 @@
+use qwen3_deploy::detokenizer::IncrementalDecoder;
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+// GPT-2 的 bytes_to_unicode
+fn byte_char(byte: u8) -> char {
+    let visible = |b: u8| (33..=126).contains(&b) || (161..=172).contains(&b) || b >= 174;
+    if visible(byte) {
+        return byte as char;
+    }
+    let offset = (0..byte).filter(|&b| !visible(b)).count() as u32;
+    char::from_u32(256 + offset).unwrap()
+}
+
+fn piece(text: &str) -> String {
+    text.bytes().map(byte_char).collect()
+}
+
+// 0..256 为单字节 token, 之后是几个多字节 token, 最后是特殊 token
+fn tokenizer() -> (Tokenizer, HashMap<&'static str, u32>) {
+    let mut vocab: serde_json::Map<String, serde_json::Value> =
+        (0..=255u8).map(|b| (byte_char(b).to_string(), b.into())).collect();
+    let words = ["你", " world", "\u{FFFD}", "😀", "<|im_end|>"];
+    let mut ids = HashMap::new();
+    for (index, word) in words.iter().enumerate() {
+        let id = 256 + index as u32;
+        vocab.insert(piece(word), id.into());
+        ids.insert(*word, id);
+    }
+    let json = serde_json::json!({
+        "version": "1.0", "truncation": null, "padding": null,
+        "added_tokens": [{
+            "id": ids["<|im_end|>"], "content": "<|im_end|>", "single_word": false,
+            "lstrip": false, "rstrip": false, "normalized": false, "special": true
+        }],
+        "normalizer": null, "pre_tokenizer": null, "post_processor": null,
+        "decoder": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": false, "use_regex": false},
+        "model": {
+            "type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+            "end_of_word_suffix": null, "fuse_unk": false, "byte_fallback": false,
+            "vocab": vocab, "merges": []
+        }
+    });
+    let tokenizer = Tokenizer::from_bytes(json.to_string()).unwrap();
+    (tokenizer, ids)
+}
+
+fn bytes(text: &str) -> Vec<u32> {
+    text.bytes().map(|b| b as u32).collect()
+}
+
+// 逐个 token 流式解码, 返回每次输出的文本
+fn stream(tokenizer: &Tokenizer, prompt_len: usize, tokens: &[u32]) -> Vec<String> {
+    let mut decoder = IncrementalDecoder::new(prompt_len);
+    let mut chunks = Vec::new();
+    for end in prompt_len + 1..=tokens.len() {
+        chunks.extend(decoder.step(tokenizer, &tokens[..end]).unwrap());
+    }
+    chunks.push(decoder.flush(tokenizer, tokens).unwrap());
+    chunks
+}
+
+#[test]
+fn test_stream_matches_full_decode() {
+    let (tokenizer, ids) = tokenizer();
+    let prompt = bytes("hi");
+    let mut generated = bytes("a ");
+    // 4 字节的 emoji 拆成单字节 token, 3 字节的汉字既有整个 token 也有单字节 token
+    generated.extend(bytes("😀"));
+    generated.push(ids["你"]);
+    generated.extend(bytes("好"));
+    generated.push(ids[" world"]);
+    generated.push(ids["😀"]);
+    generated.push(ids["<|im_end|>"]);
+    let mut tokens = prompt.clone();
+    tokens.extend(&generated);
+
+    let chunks = stream(&tokenizer, prompt.len(), &tokens);
+    let expected = tokenizer.decode(&generated, true).unwrap();
+    assert_eq!(expected, "a 😀你好 world😀");
+    assert_eq!(chunks.concat(), expected);
+    // 不完整的 utf-8 不会单独输出
+    assert!(chunks.iter().all(|chunk| !chunk.contains('\u{FFFD}')));
+    assert!(chunks.contains(&"😀".to_string()));
+}
+
+#[test]
+fn test_legitimate_replacement_char() {
+    let (tokenizer, ids) = tokenizer();
+    let mut tokens = bytes("x");
+    tokens.push(ids["\u{FFFD}"]);
+    tokens.extend(bytes("y"));
+    tokens.push(ids["\u{FFFD}"]);
+    // 中间的 U+FFFD 随后面的 token 输出, 结尾的在 flush 时输出
+    let chunks = stream(&tokenizer, 1, &tokens);
+    assert_eq!(chunks.concat(), "\u{FFFD}y\u{FFFD}");
+    assert_eq!(chunks.last().unwrap(), "\u{FFFD}");
+
+    // 被截断的多字节字符在结束时按非流式解码的结果输出
+    let mut tokens = bytes("x");
+    tokens.extend(&bytes("你")[..2]);
+    let chunks = stream(&tokenizer, 1, &tokens);
+    assert_eq!(chunks.concat(), tokenizer.decode(&tokens[1..], true).unwrap());
+}
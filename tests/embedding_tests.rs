@@ -0,0 +1,59 @@
+use qwen3_deploy::embedding::{EmbeddingInput, EmbeddingRequest, encode_base64, postprocess};
+
+fn request(json: &str) -> EmbeddingRequest {
+    serde_json::from_str(json).unwrap()
+}
+
+#[test]
+fn test_postprocess() {
+    let embedding = postprocess(vec![3.0, 4.0, 12.0], None);
+    let norm: f32 = embedding.iter().map(|x| x * x).sum();
+    assert!((norm - 1.0).abs() < 1e-6);
+
+    // 截取前两维后重新归一化
+    assert_eq!(postprocess(vec![3.0, 4.0, 12.0], Some(2)), vec![0.6, 0.8]);
+    assert_eq!(postprocess(vec![0.0, 0.0], None), vec![0.0, 0.0]);
+}
+
+#[test]
+fn test_encode_base64() {
+    // 1.0f32 的小端字节为 00 00 80 3f
+    assert_eq!(encode_base64(&[1.0]), "AACAPw==");
+    assert_eq!(encode_base64(&[1.0, -2.0]), "AACAPwAAAMA=");
+}
+
+#[test]
+fn test_embedding_inputs() {
+    let texts = |request: &EmbeddingRequest| -> Vec<String> {
+        request
+            .inputs()
+            .into_iter()
+            .map(|input| match input {
+                EmbeddingInput::Text(text) => text,
+                EmbeddingInput::Tokens(tokens) => format!("{:?}", tokens),
+            })
+            .collect()
+    };
+    assert_eq!(texts(&request(r#"{"input": "hello"}"#)), vec!["hello"]);
+    assert_eq!(texts(&request(r#"{"input": ["a", "b"]}"#)), vec!["a", "b"]);
+    assert_eq!(texts(&request(r#"{"input": [1, 2, 3]}"#)), vec!["[1, 2, 3]"]);
+    assert_eq!(texts(&request(r#"{"input": [[1], [2, 3]]}"#)), vec!["[1]", "[2, 3]"]);
+
+    let with_instruction = request(r#"{"input": ["what is rust"], "instruction": "Retrieve relevant passages"}"#);
+    assert_eq!(
+        texts(&with_instruction),
+        vec!["Instruct: Retrieve relevant passages\nQuery:what is rust"]
+    );
+}
+
+#[test]
+fn test_validate_embedding_request() {
+    assert!(request(r#"{"input": "hi", "encoding_format": "base64", "dimensions": 32}"#).validate().is_ok());
+    let param = |json: &str| request(json).validate().unwrap_err().param;
+    assert_eq!(param(r#"{"input": "hi", "encoding_format": "int8"}"#), Some("encoding_format".to_string()));
+    assert_eq!(param(r#"{"input": "hi", "dimensions": 0}"#), Some("dimensions".to_string()));
+    assert_eq!(param(r#"{"input": ""}"#), Some("input".to_string()));
+    assert_eq!(param(r#"{"input": []}"#), Some("input".to_string()));
+    assert_eq!(param(r#"{"input": ["a", ""]}"#), Some("input".to_string()));
+    assert_eq!(param(r#"{"input": [1, 2], "instruction": "x"}"#), Some("instruction".to_string()));
+}
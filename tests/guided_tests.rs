@@ -0,0 +1,146 @@
+use qwen3_deploy::guided::{GuidedDecoding, GuidedMatcher, TokenTrie};
+use std::sync::Arc;
+
+fn matcher(guided: GuidedDecoding) -> GuidedMatcher {
+    GuidedMatcher::new(Arc::new(guided.compile().unwrap()))
+}
+
+fn full_match(guided: GuidedDecoding, text: &str) -> bool {
+    let mut matcher = matcher(guided);
+    matcher.accept_str(text) && matcher.is_accepting()
+}
+
+#[test]
+fn test_guided_regex() {
+    let date = || GuidedDecoding::Regex(r"\d{4}-\d{2}-\d{2}".to_string());
+    assert!(full_match(date(), "2025-07-24"));
+    assert!(!full_match(date(), "2025-7-24"));
+    assert!(!full_match(date(), "2025-07-2"));
+
+    let sql = || GuidedDecoding::Regex(r"SELECT [a-z_]+(, [a-z_]+)* FROM [a-z_]+;?".to_string());
+    assert!(full_match(sql(), "SELECT id, name FROM users;"));
+    assert!(full_match(sql(), "SELECT id FROM users"));
+    assert!(!full_match(sql(), "SELECT FROM users"));
+
+    let alt = || GuidedDecoding::Regex("(?:yes|no)[.!]?".to_string());
+    assert!(full_match(alt(), "yes"));
+    assert!(full_match(alt(), "no!"));
+    assert!(!full_match(alt(), "maybe"));
+
+    assert!(GuidedDecoding::Regex("(abc".to_string()).compile().is_err());
+    assert!(GuidedDecoding::Regex("*a".to_string()).compile().is_err());
+}
+
+#[test]
+fn test_guided_choice() {
+    let choice = || GuidedDecoding::Choice(vec!["positive".to_string(), "negative".to_string()]);
+    assert!(full_match(choice(), "positive"));
+    assert!(full_match(choice(), "negative"));
+    assert!(!full_match(choice(), "neutral"));
+    assert!(!full_match(choice(), "pos"));
+    assert!(GuidedDecoding::Choice(vec![]).compile().is_err());
+}
+
+#[test]
+fn test_guided_grammar() {
+    let grammar = r#"
+        # 简单的算术表达式
+        root   ::= expr
+        expr   ::= term (("+" | "-") term)*
+        term   ::= number | "(" expr ")"
+        number ::= [0-9]+
+    "#;
+    let gbnf = || GuidedDecoding::Grammar(grammar.to_string());
+    assert!(full_match(gbnf(), "1+2"));
+    assert!(full_match(gbnf(), "(10-2)+3"));
+    assert!(!full_match(gbnf(), "1+"));
+    assert!(!full_match(gbnf(), "a"));
+
+    let bounded = || GuidedDecoding::Grammar("root ::= \"ab\"{1,2} \"\\n\"?".to_string());
+    assert!(full_match(bounded(), "ab"));
+    assert!(full_match(bounded(), "abab\n"));
+    assert!(!full_match(bounded(), "ababab"));
+
+    assert!(GuidedDecoding::Grammar("expr ::= \"a\"".to_string()).compile().is_err());
+    assert!(GuidedDecoding::Grammar("root ::= missing".to_string()).compile().is_err());
+}
+
+#[test]
+fn test_guided_limits() {
+    let error = |guided: GuidedDecoding| guided.compile().unwrap_err().to_string();
+    // 过大的计数量词直接拒绝, 多个量词按展开后的总大小检查
+    assert!(error(GuidedDecoding::Regex("a{1000000}".to_string())).contains("exceeds the limit"));
+    assert!(error(GuidedDecoding::Regex("(x|y){0,500000}".to_string())).contains("exceeds the limit"));
+    assert!(error(GuidedDecoding::Regex("a{1000}".repeat(101))).contains("grammar elements"));
+    assert!(full_match(GuidedDecoding::Regex("a{3,1000}".to_string()), "aaaa"));
+
+    // 左递归返回错误, 而不是什么都不允许
+    let left = "root ::= root \"a\" | \"a\"";
+    assert!(error(GuidedDecoding::Grammar(left.to_string())).contains("`root` is left-recursive"));
+    let indirect = "root ::= expr\nexpr ::= term? expr \"+\" term\nterm ::= [0-9]";
+    assert!(error(GuidedDecoding::Grammar(indirect.to_string())).contains("left-recursive"));
+
+    // 可以匹配空串的重复不是左递归
+    assert!(full_match(GuidedDecoding::Regex("(a?)*b".to_string()), "aab"));
+    assert!(full_match(GuidedDecoding::Grammar("root ::= (\"a\"? \"b\"?)* \"c\"".to_string()), "abbc"));
+}
+
+#[test]
+fn test_guided_allowed_tokens() {
+    let trie = TokenTrie::new(vec![
+        (0, "pos".to_string()),
+        (1, "positive".to_string()),
+        (2, "itive".to_string()),
+        (3, "neg".to_string()),
+        (4, "negative".to_string()),
+        (5, "neutral".to_string()),
+        (6, " ".to_string()),
+    ]);
+    let mut matcher = matcher(GuidedDecoding::Choice(vec![
+        "positive".to_string(),
+        "negative".to_string(),
+    ]));
+    let mut allowed = matcher.allowed_tokens(&trie);
+    allowed.sort();
+    assert_eq!(allowed, vec![0, 1, 3, 4]);
+
+    assert!(matcher.accept_str("pos"));
+    assert_eq!(matcher.allowed_tokens(&trie), vec![2]);
+    assert!(!matcher.is_accepting());
+
+    assert!(matcher.accept_str("itive"));
+    assert!(matcher.is_accepting());
+    assert!(matcher.allowed_tokens(&trie).is_empty());
+}
+
+#[test]
+fn test_guided_byte_fragment_tokens() {
+    // "é" 为 C3 A9, "中" 为 E4 B8 AD, 字节级 BPE 中可能各自拆成多个 token
+    let trie = TokenTrie::from_bytes(vec![
+        (0, b"caf".to_vec()),
+        (1, vec![0xC3]),
+        (2, vec![0xA9]),
+        (3, vec![0xE4]),
+        (4, "é".as_bytes().to_vec()),
+        (5, vec![0xA9, b'!']),
+    ]);
+    let mut matcher = matcher(GuidedDecoding::Choice(vec!["café".to_string(), "café!".to_string()]));
+    assert_eq!(matcher.allowed_tokens(&trie), vec![0]);
+    assert!(matcher.accept_bytes(b"caf"));
+    let mut allowed = matcher.allowed_tokens(&trie);
+    allowed.sort();
+    assert_eq!(allowed, vec![1, 4]);
+
+    // 只接受了字符的前半部分时还不能结束, 也不能接上其它字符的字节
+    assert!(!matcher.accept_bytes(&[0xE4]));
+    assert!(matcher.accept_bytes(&[0xC3]));
+    assert!(!matcher.is_accepting());
+    let mut allowed = matcher.allowed_tokens(&trie);
+    allowed.sort();
+    assert_eq!(allowed, vec![2, 5]);
+    assert!(!matcher.accept_bytes(b"x"));
+    assert!(matcher.accept_bytes(&[0xA9]));
+    assert!(matcher.is_accepting());
+    assert!(matcher.accept_str("!"));
+    assert!(matcher.is_accepting());
+}
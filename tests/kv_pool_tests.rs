@@ -0,0 +1,124 @@
+mod common;
+
+use candle_core::{Device, Tensor};
+use common::tiny_model;
+use qwen3_deploy::kv_pool::KvPool;
+use qwen3_deploy::model::KvCache;
+use std::time::Duration;
+
+#[test]
+fn test_allocate_and_release() {
+    let model = tiny_model(64);
+    // 2 层, 2 个 kv head, head_dim 8, F32
+    assert_eq!(model.kv_bytes_per_token(), 2 * 2 * 2 * 8 * 4);
+    let pool = KvPool::new(model.kv_layout().clone(), 4, 4).unwrap();
+    assert_eq!(pool.block_bytes(), 4 * model.kv_bytes_per_token());
+    let first = pool.allocate(3).unwrap();
+    assert_eq!(first.iter().map(|block| block.id()).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert_eq!(pool.free_blocks(), 1);
+
+    // 不够时一个块也不分配
+    assert!(pool.allocate(2).is_err());
+    assert_eq!(pool.free_blocks(), 1);
+    drop(first);
+    assert_eq!(pool.used_blocks(), 0);
+    assert_eq!(pool.num_blocks(), 4);
+
+    // 按需扩容的池扩容后保留已写入的内容
+    let pool = KvPool::growable(model.kv_layout().clone(), 2);
+    let block = pool.allocate(1).unwrap().remove(0);
+    let rows = Tensor::arange(0f32, 32.0, &Device::Cpu).unwrap().reshape((2, 2, 8)).unwrap();
+    for layer in 0..2 {
+        pool.write(layer, &block, 0, &rows, &rows).unwrap();
+    }
+    let more = pool.allocate(3).unwrap();
+    assert_eq!(pool.num_blocks(), 4);
+    assert_eq!(more.len(), 3);
+    let slots = Tensor::new(&[block.id() * 2, block.id() * 2 + 1], &Device::Cpu).unwrap();
+    let (k, _) = pool.gather(1, &slots).unwrap();
+    assert_eq!(k.to_vec3::<f32>().unwrap(), rows.to_vec3::<f32>().unwrap());
+}
+
+#[test]
+fn test_forks_share_blocks_with_copy_on_write() {
+    let model = tiny_model(64);
+    let pool = KvPool::new(model.kv_layout().clone(), 4, 16).unwrap();
+    let mut cache = KvCache::new(pool.clone());
+    model.forward(&[1, 2, 3, 4, 5, 6], &mut cache).unwrap();
+    assert_eq!(pool.used_blocks(), 2);
+
+    // fork 只共享块表, 写入未写满的共享块时先复制这一块
+    let mut branches: Vec<_> = (0..3).map(|_| cache.fork(cache.len())).collect();
+    assert_eq!(pool.used_blocks(), 2);
+    assert_eq!(branches[0].growth_blocks(7), 1);
+    for branch in branches.iter_mut() {
+        model.forward(&[7], branch).unwrap();
+    }
+    assert_eq!(pool.used_blocks(), 2 + 3);
+
+    // 写满的块一直共享, 之后每个分支只为新的块分配
+    assert_eq!(branches[0].growth_blocks(9), 1);
+    model.forward(&[8, 9], &mut branches[0]).unwrap();
+    assert_eq!(pool.used_blocks(), 6);
+
+    // 块在最后一个引用它的序列释放时才归还
+    drop(cache);
+    assert_eq!(pool.used_blocks(), 5);
+    branches.truncate(1);
+    assert_eq!(pool.used_blocks(), 3);
+    drop(branches);
+    assert_eq!(pool.used_blocks(), 0);
+}
+
+#[test]
+fn test_predicted_blocks_match_allocations() {
+    let model = tiny_model(64);
+    let pool = KvPool::new(model.kv_layout().clone(), 4, 4).unwrap();
+    let mut cache = KvCache::new(pool.clone());
+    assert_eq!(cache.growth_blocks(9), 3);
+    model.forward(&[1, 2, 3, 4, 5, 6, 7, 8, 9], &mut cache).unwrap();
+    assert_eq!(pool.used_blocks(), 3);
+
+    // 池不够时前向失败, 块表和已缓存的内容不变
+    let mut fork = cache.fork(cache.len());
+    assert_eq!(fork.growth_blocks(13), 2);
+    assert!(model.forward(&[10, 11, 12, 13], &mut fork).is_err());
+    assert_eq!(fork.len(), 9);
+    assert_eq!(pool.used_blocks(), 3);
+
+    // 截断归还不再用到的块, 之后的写入重新分配
+    cache.truncate(4);
+    assert_eq!(cache.num_blocks(), 1);
+    assert_eq!(pool.used_blocks(), 3);
+    drop(fork);
+    assert_eq!(pool.used_blocks(), 1);
+    assert_eq!(cache.growth_blocks(13), 3);
+    model.forward(&[5, 6, 7, 8, 9, 10, 11, 12, 13], &mut cache).unwrap();
+    assert_eq!(pool.used_blocks(), 4);
+}
+
+#[tokio::test]
+async fn test_released_wakes_waiter() {
+    let model = tiny_model(64);
+    let pool = KvPool::new(model.kv_layout().clone(), 4, 2).unwrap();
+    let blocks = pool.allocate(2).unwrap();
+    let waiter = {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let start = std::time::Instant::now();
+            pool.released(Duration::from_secs(10)).await;
+            (start.elapsed(), pool.free_blocks())
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    drop(blocks);
+    // 归还时立即唤醒, 不必等到超时
+    let (elapsed, free) = waiter.await.unwrap();
+    assert!(elapsed < Duration::from_secs(5));
+    assert_eq!(free, 2);
+
+    // 没有归还时等到超时返回
+    let start = std::time::Instant::now();
+    pool.released(Duration::from_millis(10)).await;
+    assert!(start.elapsed() >= Duration::from_millis(10));
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Activation, VarBuilder, VarMap};
+use candle_transformers::models::qwen3::{Config, ModelForCausalLM};
+use common::{tiny_config, tiny_model};
+use qwen3_deploy::kv_pool::KvPool;
+use qwen3_deploy::model::{KvCache, Model};
+
+fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+    (a - b)
+        .unwrap()
+        .abs()
+        .unwrap()
+        .flatten_all()
+        .unwrap()
+        .max(0)
+        .unwrap()
+        .to_scalar::<f32>()
+        .unwrap()
+}
+
+#[test]
+fn test_model_matches_reference() {
+    let device = Device::Cpu;
+    let config = tiny_config(64);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    // 两个实现从同一个 VarMap 取权重
+    let model = Model::new(&config, vb.clone()).unwrap();
+    let mut reference = ModelForCausalLM::new(&config, vb).unwrap();
+
+    let prompt = [1u32, 5, 9, 13, 2, 7];
+    let mut cache = model.new_cache();
+    let logits = model.forward(&prompt, &mut cache).unwrap();
+    let input = Tensor::new(&prompt, &device).unwrap().unsqueeze(0).unwrap();
+    let expected = reference.forward(&input, 0).unwrap().flatten_all().unwrap();
+    assert!(max_diff(&logits, &expected) < 1e-4);
+    assert_eq!(cache.len(), prompt.len());
+
+    // fork 出的两个分支共享 prefill 的块, 互不影响
+    let mut branch = cache.fork(cache.len());
+    let logits = model.forward(&[11], &mut branch).unwrap();
+    let input = Tensor::new(&[11u32], &device).unwrap().unsqueeze(0).unwrap();
+    let expected = reference.forward(&input, prompt.len()).unwrap().flatten_all().unwrap();
+    assert!(max_diff(&logits, &expected) < 1e-4);
+    assert_eq!(branch.len(), prompt.len() + 1);
+    assert_eq!(cache.len(), prompt.len());
+    let mut other = cache.fork(cache.len());
+    model.forward(&[20], &mut other).unwrap();
+    let again = model.forward(&[11], &mut cache.fork(cache.len())).unwrap();
+    assert!(max_diff(&again, &logits) < 1e-6);
+
+    // forward_all 的最后一行与逐步解码一致, truncate 后可以重新生成
+    let mut full = model.new_cache();
+    let all = model.forward_all(&[1, 5, 9, 13, 2, 7, 11], &mut full).unwrap();
+    assert_eq!(all.dims(), &[7, 64]);
+    assert!(max_diff(&all.get(6).unwrap(), &logits) < 1e-4);
+    full.truncate(prompt.len());
+    let again = model.forward(&[11], &mut full).unwrap();
+    assert!(max_diff(&again, &logits) < 1e-4);
+}
+
+// Qwen3-0.6B 的形状 (head_dim * heads 不等于 hidden_size, GQA, rope_theta 1e6), 只减少层数和词表, 多层的 cache 由上面的小模型测试覆盖
+fn real_config(tie_word_embeddings: bool) -> Config {
+    Config {
+        vocab_size: 512,
+        hidden_size: 1024,
+        intermediate_size: 3072,
+        num_hidden_layers: 1,
+        num_attention_heads: 16,
+        head_dim: 128,
+        attention_bias: false,
+        num_key_value_heads: 8,
+        max_position_embeddings: 40960,
+        sliding_window: None,
+        max_window_layers: 28,
+        tie_word_embeddings,
+        rope_theta: 1000000.0,
+        rms_norm_eps: 1e-6,
+        use_sliding_window: false,
+        hidden_act: Activation::Silu,
+    }
+}
+
+#[test]
+fn test_model_matches_reference_on_real_shapes() {
+    let device = Device::Cpu;
+    // 共享和不共享 lm_head 两种权重
+    for config in [real_config(true), real_config(false)] {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let model = Model::new(&config, vb.clone()).unwrap();
+        let mut reference = ModelForCausalLM::new(&config, vb).unwrap();
+        let tolerance = |expected: &Tensor| {
+            let scale = expected.abs().unwrap().max(0).unwrap().to_scalar::<f32>().unwrap();
+            1e-4 * scale.max(1.0)
+        };
+
+        // 分块 prefill 与参考实现一次 prefill 的结果一致
+        let prompt = [17u32, 301, 5, 88, 460, 2, 511, 64, 9];
+        let mut cache = model.new_cache();
+        model.forward(&prompt[..4], &mut cache).unwrap();
+        let logits = model.forward(&prompt[4..], &mut cache).unwrap();
+        let input = Tensor::new(&prompt, &device).unwrap().unsqueeze(0).unwrap();
+        let expected = reference.forward(&input, 0).unwrap().flatten_all().unwrap();
+        assert!(max_diff(&logits, &expected) < tolerance(&expected));
+
+        // n > 1: prefill 后 fork, 每个分支逐个解码都与参考实现的增量解码一致
+        let mut branch = cache.fork(cache.len());
+        let mut other = cache.fork(cache.len());
+        model.forward(&[400, 401], &mut other).unwrap();
+        for (step, token) in [42u32, 7, 199].into_iter().enumerate() {
+            let logits = model.forward(&[token], &mut branch).unwrap();
+            let input = Tensor::new(&[token], &device).unwrap().unsqueeze(0).unwrap();
+            let expected = reference.forward(&input, prompt.len() + step).unwrap().flatten_all().unwrap();
+            assert!(max_diff(&logits, &expected) < tolerance(&expected));
+        }
+        assert_eq!(branch.len(), prompt.len() + 3);
+        assert_eq!(other.len(), prompt.len() + 2);
+    }
+}
+
+#[test]
+fn test_chunked_prefill_matches_single_forward() {
+    let model = tiny_model(64);
+
+    let prompt = [3u32, 8, 1, 40, 22, 7, 19, 5, 60, 2, 33];
+    let mut cache = model.new_cache();
+    let expected = model.forward(&prompt, &mut cache).unwrap();
+
+    // 分块 prefill, 最后一块不满
+    for chunk_size in [1, 3, 4] {
+        let mut chunked = model.new_cache();
+        let mut logits = None;
+        for chunk in prompt.chunks(chunk_size) {
+            logits = Some(model.forward(chunk, &mut chunked).unwrap());
+        }
+        assert_eq!(chunked.len(), prompt.len());
+        assert!(max_diff(&logits.unwrap(), &expected) < 1e-4);
+    }
+}
+
+#[test]
+fn test_kv_cache_growth_and_fork() {
+    let model = tiny_model(64);
+    let tokens = [3u32, 8, 1, 40, 22, 7, 19, 5, 60, 2, 33, 9, 14];
+    let mut full = model.new_cache();
+    let expected = model.forward_all(&tokens, &mut full).unwrap();
+
+    // 每块只有 3 个 token, 逐个解码时跨过多个块, fork 后写入共享的块前复制, 结果不变
+    let mut cache = KvCache::new(KvPool::growable(model.kv_layout().clone(), 3));
+    model.forward(&tokens[..5], &mut cache).unwrap();
+    let mut decoded = cache.fork(cache.len());
+    for (index, &token) in tokens.iter().enumerate().skip(5) {
+        let logits = model.forward(&[token], &mut decoded).unwrap();
+        assert!(max_diff(&logits, &expected.get(index).unwrap()) < 1e-4);
+    }
+    assert_eq!(decoded.len(), tokens.len());
+
+    // fork 截在自己写入的部分中间, 之后重新计算的结果与完整计算一致
+    let mut forked = decoded.fork(9);
+    assert_eq!(forked.len(), 9);
+    let logits = model.forward(&tokens[9..], &mut forked).unwrap();
+    assert!(max_diff(&logits, &expected.get(tokens.len() - 1).unwrap()) < 1e-4);
+
+    // 截断到共享的块之内, 重新写入后再 fork
+    decoded.truncate(3);
+    model.forward(&tokens[3..8], &mut decoded).unwrap();
+    let logits = model.forward(&tokens[8..], &mut decoded.fork(8)).unwrap();
+    assert!(max_diff(&logits, &expected.get(tokens.len() - 1).unwrap()) < 1e-4);
+    assert_eq!(decoded.layers().unwrap()[0].0.dims(), &[1, 2, 8, 8]);
+    // 原来的 cache 没有被分支的写入修改
+    let again = model.forward(&tokens[5..], &mut cache).unwrap();
+    assert!(max_diff(&again, &expected.get(tokens.len() - 1).unwrap()) < 1e-4);
+}
+
+#[test]
+fn test_embedding_model_weights() {
+    let device = Device::Cpu;
+    let config = tiny_config(64);
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let model = Model::new(&config, vb.clone()).unwrap();
+
+    // 带 model. 前缀的权重和 Qwen3-Embedding 不带前缀的权重得到相同的 hidden state
+    let input = [1u32, 5, 9, 13];
+    let with_prefix = Model::new_embedding(&config, vb.clone()).unwrap();
+    let without_prefix = Model::new_embedding(&config, vb.pp("model")).unwrap();
+    let expected = model.last_hidden_state(&input).unwrap();
+    assert_eq!(expected.dims(), &[32]);
+    assert!(max_diff(&with_prefix.last_hidden_state(&input).unwrap(), &expected) < 1e-6);
+    assert!(max_diff(&without_prefix.last_hidden_state(&input).unwrap(), &expected) < 1e-6);
+}
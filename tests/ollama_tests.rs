@@ -0,0 +1,113 @@
+use qwen3_deploy::guided::{GuidedDecoding, GuidedMatcher, JSON_GRAMMAR};
+use qwen3_deploy::ollama::{OllamaChatRequest, OllamaGenerateRequest, OllamaReply};
+use qwen3_deploy::qwen3::StopReason;
+use serde_json::json;
+use std::sync::Arc;
+
+fn chat_request(value: serde_json::Value) -> OllamaChatRequest {
+    serde_json::from_value(value).unwrap()
+}
+
+#[test]
+fn test_to_chat_request() {
+    let req = chat_request(json!({
+        "model": "qwen3:0.6b",
+        "messages": [
+            {"role": "user", "content": "weather?"},
+            {"role": "assistant", "content": "", "tool_calls": [
+                {"function": {"name": "a", "arguments": {}}},
+                {"function": {"name": "b", "arguments": {"x": 1}}}
+            ]},
+            {"role": "tool", "content": "from b", "tool_name": "b"},
+            {"role": "tool", "content": "from a"}
+        ],
+        "tools": [{"type": "function", "function": {"name": "a", "parameters": {"type": "object"}}}],
+        "options": {"temperature": 0.2, "num_predict": 32, "stop": ["\n\n"], "repeat_penalty": 1.1, "num_ctx": 4096},
+        "format": "json",
+        "think": false
+    }));
+    let chat = req.to_chat_request().unwrap();
+    assert!(chat.validate().is_ok());
+    assert_eq!(chat.max_tokens, Some(32));
+    assert_eq!(chat.temperature, Some(0.2));
+    assert_eq!(chat.repetition_penalty, Some(1.1));
+    assert_eq!(chat.stop(), vec!["\n\n"]);
+    assert!(chat.is_guided());
+    assert!(!chat.enable_thinking());
+    // 工具结果按工具名或顺序对应到生成的 id
+    let messages = serde_json::to_value(&chat.messages).unwrap();
+    assert_eq!(messages[2]["tool_call_id"], "call_1_1");
+    assert_eq!(messages[3]["tool_call_id"], "call_1_0");
+
+    // num_predict 为负数时不限制
+    let req = chat_request(json!({"messages": [{"role": "user", "content": "hi"}], "options": {"num_predict": -1}}));
+    let chat = req.to_chat_request().unwrap();
+    assert_eq!(chat.max_tokens, None);
+    assert!(chat.enable_thinking());
+
+    let param = |value: serde_json::Value| chat_request(value).to_chat_request().unwrap_err().param;
+    assert_eq!(
+        param(json!({"messages": [{"role": "user", "content": "hi", "images": ["aGk="]}]})),
+        Some("messages[0].images".to_string())
+    );
+    assert_eq!(
+        param(json!({"messages": [{"role": "tool", "content": "x"}]})),
+        Some("messages[0]".to_string())
+    );
+    assert_eq!(
+        param(json!({"messages": [{"role": "user", "content": "hi"}], "format": {"type": "object"}})),
+        Some("format".to_string())
+    );
+
+    let generate: OllamaGenerateRequest = serde_json::from_value(json!({"model": "qwen3"})).unwrap();
+    assert!(generate.is_load());
+    let generate: OllamaGenerateRequest = serde_json::from_value(json!({"prompt": "x", "suffix": "y"})).unwrap();
+    assert_eq!(generate.to_chat_request().unwrap_err().param, Some("suffix".to_string()));
+}
+
+#[test]
+fn test_json_grammar() {
+    let grammar = Arc::new(GuidedDecoding::Grammar(JSON_GRAMMAR.to_string()).compile().unwrap());
+    let full_match = |text: &str| {
+        let mut matcher = GuidedMatcher::new(grammar.clone());
+        matcher.accept_str(text) && matcher.is_accepting()
+    };
+    assert!(full_match(r#"{"a": [1, -2.5e3, true, null], "b": {"c": "é\n"}}"#));
+    assert!(full_match("{}"));
+    assert!(!full_match("[1, 2]"));
+    assert!(!full_match(r#"{"a": 01}"#));
+    assert!(!full_match(r#"{"a": 1,}"#));
+}
+
+#[test]
+fn test_reply() {
+    let mut reply = OllamaReply::chat("qwen3", 5);
+    let mut chunks = Vec::new();
+    for piece in ["<think>\nhm", "m\n</think>\n\nHel", "lo", "<tool_call>\n{\"name\": \"f\", \"arguments\": {\"x\": 1}}\n</tool_call>"] {
+        chunks.extend(reply.push(piece));
+    }
+    chunks.extend(reply.finish(StopReason::Stop, 9, None));
+    assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk["done"] == false));
+    let thinking: String = chunks.iter().filter_map(|chunk| chunk["message"]["thinking"].as_str()).collect();
+    let content: String = chunks.iter().filter_map(|chunk| chunk["message"]["content"].as_str()).collect();
+    assert_eq!(thinking, "hmm");
+    assert_eq!(content, "Hello");
+    let tool_calls: Vec<&serde_json::Value> = chunks.iter().filter_map(|chunk| chunk["message"].get("tool_calls")).collect();
+    assert_eq!(tool_calls, vec![&json!([{"function": {"name": "f", "arguments": {"x": 1}}}])]);
+    let last = chunks.last().unwrap();
+    assert_eq!(last["done"], true);
+    assert_eq!(last["done_reason"], "stop");
+    assert_eq!(last["prompt_eval_count"], 5);
+    assert_eq!(last["eval_count"], 9);
+
+    // /api/generate 的 raw 模式不解析标签, 非流式输出带上 context
+    // 非流式时按 prefill 完成的时间计算 prompt_eval_duration, 早于创建时间的记为 0
+    let mut reply = OllamaReply::generate("qwen3", 2, true);
+    reply.prompt_evaluated_at(std::time::Instant::now() - std::time::Duration::from_secs(1));
+    let response = reply.response("<think>x", StopReason::Length, 3, Some(vec![1, 2, 3]));
+    assert_eq!(response["prompt_eval_duration"], 0);
+    assert_eq!(response["response"], "<think>x");
+    assert_eq!(response["done_reason"], "length");
+    assert_eq!(response["context"], json!([1, 2, 3]));
+    assert!(response.get("message").is_none());
+}
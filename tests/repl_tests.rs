@@ -0,0 +1,100 @@
+use qwen3_deploy::repl::{ChatSession, CommandResult, Renderer};
+use serde_json::json;
+
+fn reply(session: &mut ChatSession, line: &str) -> String {
+    match session.command(line).unwrap() {
+        CommandResult::Reply(reply) => reply,
+        CommandResult::Exit => panic!("unexpected exit"),
+    }
+}
+
+#[test]
+fn test_commands() {
+    let mut session = ChatSession::default();
+    assert_eq!(reply(&mut session, "/set temperature 0.5"), "temperature = 0.5");
+    reply(&mut session, "/set max_tokens 64");
+    assert_eq!(reply(&mut session, "/think"), "thinking off");
+    assert_eq!(reply(&mut session, "/think on"), "thinking on");
+    reply(&mut session, "/think off");
+    assert_eq!(session.request()["temperature"], 0.5);
+
+    // 非法的值不修改当前参数
+    assert_eq!(session.command("/set temperature -1").unwrap_err().param, Some("temperature".to_string()));
+    assert_eq!(session.command("/set max_tokens 0").unwrap_err().param, Some("max_tokens".to_string()));
+    assert!(session.command("/set temperature hot").is_err());
+    assert!(session.command("/set n 2").is_err());
+    assert!(session.command("/unknown").is_err());
+    assert_eq!(session.request()["temperature"], 0.5);
+    assert_eq!(reply(&mut session, "/set temperature"), "temperature unset");
+    assert!(session.request().get("temperature").is_none());
+
+    session.push_user("hi");
+    let chat = session.chat_request().unwrap();
+    assert_eq!(chat.max_tokens, Some(64));
+    assert!(!chat.enable_thinking());
+    assert_eq!(session.command("/exit").unwrap(), CommandResult::Exit);
+}
+
+#[test]
+fn test_transcript() {
+    let dir = std::env::temp_dir().join(format!("repl_tests_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let system = dir.join("system.txt");
+    std::fs::write(&system, "be brief\n").unwrap();
+
+    let mut session = ChatSession::default();
+    session.push_user("hi");
+    session.push_assistant("<think>\nhmm\n</think>\n\nhello");
+    reply(&mut session, &format!("/system {}", system.display()));
+    reply(&mut session, "/set seed 7");
+    let transcript = dir.join("transcript.json");
+    reply(&mut session, &format!("/save {}", transcript.display()));
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&transcript).unwrap()).unwrap();
+    assert_eq!(
+        saved,
+        json!({
+            "messages": [
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "<think>\nhmm\n</think>\n\nhello"}
+            ],
+            "seed": 7
+        })
+    );
+
+    // 系统提示词替换原来的, /clear 保留系统提示词和参数
+    std::fs::write(&system, "be kind").unwrap();
+    reply(&mut session, &format!("/system {}", system.display()));
+    reply(&mut session, "/clear");
+    assert_eq!(session.request()["messages"], json!([{"role": "system", "content": "be kind"}]));
+    assert_eq!(session.request()["seed"], 7);
+
+    // 加载的文件原样保留其它字段, stream 不会带到请求中
+    std::fs::write(&transcript, r#"{"messages": [{"role": "user", "content": "x"}], "stream": true, "stop": "END"}"#).unwrap();
+    let mut session = ChatSession::default();
+    assert_eq!(reply(&mut session, &format!("/load {}", transcript.display())), format!("loaded 1 messages from {}", transcript.display()));
+    let chat = session.chat_request().unwrap();
+    assert_eq!(chat.stream, None);
+    assert_eq!(chat.stop(), vec!["END"]);
+    std::fs::write(&transcript, r#"{"messages": "x"}"#).unwrap();
+    assert!(session.command(&format!("/load {}", transcript.display())).is_err());
+    assert!(session.command("/load /nonexistent/transcript.json").is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_renderer() {
+    let mut renderer = Renderer::default();
+    let mut output = String::new();
+    for piece in ["<think>", "\nhm", "m\n</think>\n\nHel", "lo", "<tool_call>\n{\"name\": \"f\", \"arguments\": {}}\n</tool_call>"] {
+        output.push_str(&renderer.push(piece));
+    }
+    output.push_str(&renderer.finish());
+    assert_eq!(output, "\x1b[2mhmm\x1b[0m\n\nHello\n[tool call] f({})\n");
+
+    // 未结束的思考也恢复颜色
+    let mut renderer = Renderer::default();
+    let mut output = renderer.push("<think>\nabc");
+    output.push_str(&renderer.finish());
+    assert_eq!(output, "\x1b[2mabc\x1b[0m");
+}
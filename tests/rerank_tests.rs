@@ -0,0 +1,91 @@
+mod common;
+
+use candle_core::{Device, Tensor};
+use common::tiny_model;
+use qwen3_deploy::rerank::{
+    RerankRequest, document_suffix, ranked, rerank, shared_prefix, yes_probability,
+};
+use tokenizers::Tokenizer;
+
+fn request(json: &str) -> RerankRequest {
+    serde_json::from_str(json).unwrap()
+}
+
+// 按空白切分的词表, 不认识的词都映射到 <unk>, 与 Qwen 一样 <|im_start|> 和 <|im_end|> 是特殊 token
+fn tokenizer() -> Tokenizer {
+    let words = ["<unk>", "yes", "no", "rust", "is", "fast", "slow", "python", "<|im_start|>", "<|im_end|>"];
+    let vocab: serde_json::Map<String, serde_json::Value> = words
+        .iter()
+        .enumerate()
+        .map(|(id, word)| (word.to_string(), id.into()))
+        .collect();
+    let added_tokens = [8, 9].map(|id| {
+        serde_json::json!({
+            "id": id, "content": words[id], "single_word": false,
+            "lstrip": false, "rstrip": false, "normalized": false, "special": true
+        })
+    });
+    let json = serde_json::json!({
+        "version": "1.0", "truncation": null, "padding": null, "added_tokens": added_tokens,
+        "normalizer": null, "pre_tokenizer": {"type": "WhitespaceSplit"},
+        "post_processor": null, "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "<unk>"}
+    });
+    Tokenizer::from_bytes(json.to_string()).unwrap()
+}
+
+#[test]
+fn test_shared_prefix_matches_full_prompt() {
+    let model = tiny_model(16);
+    let tokenizer = tokenizer();
+    let documents = vec!["rust is fast".to_string(), "python is slow".to_string()];
+    let (scores, total_tokens) = rerank(&tokenizer, &model, 128, "x", "rust", &documents).unwrap();
+    assert_eq!(scores.len(), 2);
+
+    // 与每个文档单独从头计算的结果一致
+    let mut expected_tokens = 0;
+    for (document, score) in documents.iter().zip(&scores) {
+        let text = format!("{} {}{}", shared_prefix("x", "rust"), document, document_suffix());
+        let tokens = tokenizer.encode(text, false).unwrap().get_ids().to_vec();
+        expected_tokens += tokens.len();
+        let logits = model.forward(&tokens, &mut model.new_cache()).unwrap();
+        let expected = yes_probability(&logits, 1, 2).unwrap();
+        assert!((expected - score).abs() < 1e-5);
+        assert!((0.0..=1.0).contains(score));
+    }
+    // 共享的前缀只计算一次
+    let prefix_len = tokenizer.encode(shared_prefix("x", "rust"), false).unwrap().len();
+    assert_eq!(total_tokens, expected_tokens - prefix_len);
+
+    // 超长的文档从末尾截断, query 本身超长时报错
+    let long = vec!["fast ".repeat(200)];
+    assert!(rerank(&tokenizer, &model, 128, "x", "rust", &long).is_ok());
+    let e = rerank(&tokenizer, &model, 16, "x", "rust", &documents).unwrap_err();
+    let e = e.downcast_ref::<qwen3_deploy::error::ApiError>().unwrap();
+    assert_eq!(e.code.as_deref(), Some("context_length_exceeded"));
+}
+
+#[test]
+fn test_yes_probability_and_ranking() {
+    let logits = Tensor::new(&[0.0f32, 2.0, 2.0, -1.0], &Device::Cpu).unwrap();
+    assert!((yes_probability(&logits, 1, 2).unwrap() - 0.5).abs() < 1e-6);
+    let p = yes_probability(&logits, 1, 3).unwrap();
+    assert!((p - 1.0 / (1.0 + (-3.0f32).exp())).abs() < 1e-6);
+
+    assert_eq!(ranked(&[0.1, 0.9, 0.5], None), vec![(1, 0.9), (2, 0.5), (0, 0.1)]);
+    assert_eq!(ranked(&[0.1, 0.9, 0.5], Some(1)), vec![(1, 0.9)]);
+    assert_eq!(ranked(&[0.1], Some(5)), vec![(0, 0.1)]);
+}
+
+#[test]
+fn test_validate_rerank_request() {
+    let req = request(r#"{"query": "q", "documents": ["a", {"text": "b"}], "top_n": 1}"#);
+    assert!(req.validate().is_ok());
+    assert_eq!(req.documents(), vec!["a", "b"]);
+    assert!(req.instruction().starts_with("Given a web search query"));
+
+    let param = |json: &str| request(json).validate().unwrap_err().param;
+    assert_eq!(param(r#"{"query": "", "documents": ["a"]}"#), Some("query".to_string()));
+    assert_eq!(param(r#"{"query": "q", "documents": []}"#), Some("documents".to_string()));
+    assert_eq!(param(r#"{"query": "q", "documents": ["a"], "top_n": 0}"#), Some("top_n".to_string()));
+}
@@ -0,0 +1,176 @@
+use qwen3_deploy::error::ApiError;
+use qwen3_deploy::qwen3::StopReason;
+use qwen3_deploy::responses::{ResponseStore, ResponseStream, ResponsesRequest, StoredResponse};
+use serde_json::json;
+
+fn request(value: serde_json::Value) -> ResponsesRequest {
+    serde_json::from_value(value).unwrap()
+}
+
+#[test]
+fn test_to_chat_request() {
+    let req = request(json!({
+        "model": "qwen3",
+        "instructions": "be brief",
+        "input": [
+            {"role": "user", "content": "weather in Paris?"},
+            {"type": "reasoning", "summary": [], "content": [{"type": "reasoning_text", "text": "need the tool"}]},
+            {"type": "function_call", "call_id": "call_1", "name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+            {"type": "function_call_output", "call_id": "call_1", "output": "sunny"},
+            {"role": "developer", "content": [{"type": "input_text", "text": "answer in French"}]}
+        ],
+        "tools": [{"type": "function", "name": "get_weather", "parameters": {"type": "object"}}],
+        "max_output_tokens": 64,
+        "reasoning": {"effort": "minimal"},
+        "text": {"format": {"type": "json_object"}}
+    }));
+    let items = req.input_items().unwrap();
+    let chat = req.to_chat_request(&items).unwrap();
+    assert!(chat.validate().is_ok());
+    assert_eq!(chat.max_tokens, Some(64));
+    assert!(!chat.enable_thinking());
+    assert!(chat.is_guided());
+    assert_eq!(chat.tools.as_ref().map(Vec::len), Some(1));
+    let messages = serde_json::to_value(&chat.messages).unwrap();
+    assert_eq!(messages[0], json!({"role": "system", "content": "be brief"}));
+    assert_eq!(messages[1], json!({"role": "user", "content": "weather in Paris?"}));
+    assert_eq!(messages[2]["content"], "<think>\nneed the tool\n</think>\n\n");
+    assert_eq!(messages[2]["tool_calls"][0]["id"], "call_1");
+    assert_eq!(messages[3], json!({"role": "tool", "content": "sunny", "tool_call_id": "call_1"}));
+    assert_eq!(messages[4], json!({"role": "system", "content": "answer in French"}));
+
+    // 字符串输入为一条用户消息, 默认开启思考, tool_choice 为 none 时不带工具
+    let req = request(json!({"input": "hi", "tools": [{"type": "function", "name": "f"}], "tool_choice": "none"}));
+    let chat = req.to_chat_request(&req.input_items().unwrap()).unwrap();
+    assert!(chat.enable_thinking());
+    assert!(chat.tools.is_none());
+    assert_eq!(serde_json::to_value(&chat.messages).unwrap(), json!([{"role": "user", "content": "hi"}]));
+
+    let param = |value: serde_json::Value| {
+        let req = request(value);
+        req.input_items().and_then(|items| req.to_chat_request(&items)).unwrap_err().param
+    };
+    assert_eq!(param(json!({})), Some("input".to_string()));
+    assert_eq!(
+        param(json!({"input": "hi", "tools": [{"type": "web_search"}]})),
+        Some("tools[0].type".to_string())
+    );
+    assert_eq!(
+        param(json!({"input": [{"role": "user", "content": [{"type": "input_image", "image_url": "x"}]}]})),
+        Some("input[0].content".to_string())
+    );
+    assert_eq!(param(json!({"input": [{"role": "tool", "content": "x"}]})), Some("input[0].role".to_string()));
+    assert_eq!(
+        param(json!({"input": "hi", "text": {"format": {"type": "json_schema"}}})),
+        Some("text.format".to_string())
+    );
+}
+
+#[test]
+fn test_response_stream() {
+    let req = request(json!({"input": "hi", "max_output_tokens": 16}));
+    let mut stream = ResponseStream::new(req.response_object("qwen3"));
+    let mut events = stream.start();
+    for piece in ["<think>\nhm", "m\n</think>\n\nHel", "lo", "<tool_call>\n{\"name\": \"f\", \"arguments\": {\"x\": 1}}\n</tool_call>"] {
+        events.extend(stream.push(piece));
+    }
+    events.extend(stream.flush());
+    assert_eq!(stream.reasoning(), "hmm");
+    events.push(stream.complete(StopReason::Length, 5, 20, 2));
+    let types: Vec<&str> = events.iter().map(|event| event["type"].as_str().unwrap()).collect();
+    assert_eq!(
+        types,
+        vec![
+            "response.created",
+            "response.in_progress",
+            "response.output_item.added",
+            "response.content_part.added",
+            "response.reasoning_text.delta",
+            "response.reasoning_text.delta",
+            "response.reasoning_text.done",
+            "response.content_part.done",
+            "response.output_item.done",
+            "response.output_item.added",
+            "response.content_part.added",
+            "response.output_text.delta",
+            "response.output_text.delta",
+            "response.output_text.done",
+            "response.content_part.done",
+            "response.output_item.done",
+            "response.output_item.added",
+            "response.function_call_arguments.delta",
+            "response.function_call_arguments.done",
+            "response.output_item.done",
+            "response.incomplete",
+        ]
+    );
+    let sequence: Vec<u64> = events.iter().map(|event| event["sequence_number"].as_u64().unwrap()).collect();
+    assert_eq!(sequence, (0..events.len() as u64).collect::<Vec<_>>());
+    assert_eq!(events[13]["text"], "Hello");
+
+    let response = stream.response();
+    assert_eq!(response["status"], "incomplete");
+    assert_eq!(response["incomplete_details"], json!({"reason": "max_output_tokens"}));
+    assert_eq!(response["max_output_tokens"], 16);
+    assert_eq!(response["usage"]["total_tokens"], 25);
+    assert_eq!(response["usage"]["output_tokens_details"]["reasoning_tokens"], 2);
+    let output = response["output"].as_array().unwrap();
+    assert_eq!(output[0]["content"][0]["text"], "hmm");
+    assert_eq!(output[1]["content"][0]["text"], "Hello");
+    assert_eq!(output[2]["type"], "function_call");
+    assert_eq!(output[2]["arguments"], "{\"x\":1}");
+    assert!(output[2]["call_id"].as_str().unwrap().starts_with("call_"));
+
+    // 生成中途出错时输出 error 和 response.failed, 已生成的内容保留在 output 中
+    let mut stream = ResponseStream::new(req.response_object("qwen3"));
+    let mut events = stream.start();
+    events.extend(stream.push("Hel"));
+    events.extend(stream.fail(&ApiError::server_error("boom")));
+    let last = &events[events.len() - 2..];
+    assert_eq!(last[0]["type"], "error");
+    assert_eq!(last[0]["message"], "boom");
+    assert_eq!(last[1]["type"], "response.failed");
+    assert_eq!(last[1]["response"]["status"], "failed");
+    assert_eq!(last[1]["response"]["error"], json!({"code": "server_error", "message": "boom"}));
+    assert_eq!(stream.response()["status"], "failed");
+}
+
+#[test]
+fn test_response_store() {
+    let response = json!({
+        "id": "resp_1",
+        "output": [
+            {"type": "reasoning", "id": "rs_1", "summary": [], "content": [{"type": "reasoning_text", "text": "hmm"}]},
+            {"type": "message", "id": "msg_1", "role": "assistant", "content": [{"type": "output_text", "text": "Hello"}]}
+        ]
+    });
+    let stored = StoredResponse {
+        input: vec![json!({"type": "message", "role": "user", "content": "hi"})],
+        response,
+    };
+    // 之前的输入和输出接上新的输入, 重新得到完整的对话
+    let req = request(json!({"input": "again", "previous_response_id": "resp_1"}));
+    let mut items = stored.history();
+    items.extend(req.input_items().unwrap());
+    let chat = req.to_chat_request(&items).unwrap();
+    assert_eq!(
+        serde_json::to_value(&chat.messages).unwrap(),
+        json!([
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "<think>\nhmm\n</think>\n\nHello"},
+            {"role": "user", "content": "again"}
+        ])
+    );
+
+    let dir = std::env::temp_dir().join(format!("responses_tests_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for mut store in [ResponseStore::new(None), ResponseStore::new(Some(dir.clone()))] {
+        store.insert("resp_1", stored.clone()).unwrap();
+        assert_eq!(store.get("resp_1").unwrap().unwrap().response["id"], "resp_1");
+        assert!(store.get("resp_2").unwrap().is_none());
+        assert!(store.get("../resp_1").unwrap().is_none());
+        assert!(store.remove("resp_1").unwrap());
+        assert!(!store.remove("resp_1").unwrap());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
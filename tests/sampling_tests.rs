@@ -0,0 +1,127 @@
+use candle_core::{Device, Tensor};
+use qwen3_deploy::sampling::{Sampler, SamplingParam, process_logits};
+
+const NEG_INF: f32 = f32::NEG_INFINITY;
+
+fn processed(param: SamplingParam) -> Vec<f32> {
+    let mut logits = vec![1.0, 2.0, 3.0, 4.0];
+    process_logits(&mut logits, &param);
+    logits
+}
+
+#[test]
+fn test_sampling_warpers() {
+    // 期望值按 HF transformers 的 TopK/TopP/MinP/TypicalLogitsWarper 计算
+    let top_k = processed(SamplingParam {
+        top_k: Some(2),
+        ..Default::default()
+    });
+    assert_eq!(top_k, vec![NEG_INF, NEG_INF, 3.0, 4.0]);
+
+    let top_p = processed(SamplingParam {
+        top_p: Some(0.8),
+        ..Default::default()
+    });
+    assert_eq!(top_p, vec![NEG_INF, NEG_INF, 3.0, 4.0]);
+
+    let top_p = processed(SamplingParam {
+        top_p: Some(0.5),
+        ..Default::default()
+    });
+    assert_eq!(top_p, vec![NEG_INF, NEG_INF, NEG_INF, 4.0]);
+
+    let min_p = processed(SamplingParam {
+        min_p: Some(0.2),
+        ..Default::default()
+    });
+    assert_eq!(min_p, vec![NEG_INF, NEG_INF, 3.0, 4.0]);
+
+    let min_p = processed(SamplingParam {
+        min_p: Some(0.0),
+        ..Default::default()
+    });
+    assert_eq!(min_p, vec![1.0, 2.0, 3.0, 4.0]);
+
+    let typical = processed(SamplingParam {
+        typical_p: Some(0.5),
+        ..Default::default()
+    });
+    assert_eq!(typical, vec![NEG_INF, NEG_INF, 3.0, 4.0]);
+
+    // 最 "典型" 的 token 不一定是概率最大的 token
+    let typical = processed(SamplingParam {
+        typical_p: Some(0.2),
+        ..Default::default()
+    });
+    assert_eq!(typical, vec![NEG_INF, NEG_INF, 3.0, NEG_INF]);
+
+    let combined = processed(SamplingParam {
+        temperature: Some(2.0),
+        top_k: Some(3),
+        top_p: Some(0.5),
+        ..Default::default()
+    });
+    assert_eq!(combined, vec![NEG_INF, NEG_INF, NEG_INF, 2.0]);
+}
+
+#[test]
+fn test_sampler_seed() {
+    let logits = Tensor::new(&[1.0f32, 1.2, 0.8, 1.1, 0.9], &Device::Cpu).unwrap();
+    let param = SamplingParam {
+        temperature: Some(1.0),
+        top_k: Some(4),
+        ..Default::default()
+    };
+    let draw = |seed: u64| {
+        let mut sampler = Sampler::new(param.clone(), seed);
+        (0..32)
+            .map(|_| sampler.sample(&logits, &[]).unwrap())
+            .collect::<Vec<u32>>()
+    };
+    let first = draw(42);
+    assert_eq!(first, draw(42));
+    assert!(first.iter().all(|&token| token != 2));
+
+    let mut greedy = Sampler::new(SamplingParam::default(), 42);
+    assert_eq!(greedy.sample(&logits, &[]).unwrap(), 1);
+
+    // 请求只设置了 top_k 时按 temperature 1.0 采样, 显式的 temperature 0 仍然是贪心
+    let merged = SamplingParam { top_k: Some(4), ..Default::default() }.merge(&SamplingParam::default());
+    assert_eq!(merged.temperature, Some(1.0));
+    let merged = SamplingParam { min_p: Some(0.1), ..Default::default() }.merge(&SamplingParam {
+        temperature: Some(0.0),
+        ..Default::default()
+    });
+    assert!(merged.is_greedy());
+    // 只设置 seed 不改变解码方式, 默认配置的 top_p 也不会打开采样
+    let merged = SamplingParam { seed: Some(1), ..Default::default() }.merge(&SamplingParam {
+        top_p: Some(0.9),
+        ..Default::default()
+    });
+    assert!(merged.is_greedy());
+    let merged = SamplingParam { seed: Some(1), ..Default::default() }.merge(&SamplingParam {
+        temperature: Some(0.7),
+        ..Default::default()
+    });
+    assert_eq!(merged.temperature, Some(0.7));
+    assert!(SamplingParam::default().merge(&SamplingParam::default()).is_greedy());
+}
+
+#[test]
+fn test_penalties() {
+    use qwen3_deploy::sampling::apply_penalties;
+    use std::collections::HashMap;
+
+    let param = SamplingParam {
+        presence_penalty: Some(0.5),
+        frequency_penalty: Some(0.25),
+        repetition_penalty: Some(2.0),
+        logit_bias: Some(HashMap::from([(0, -100.0), (3, 1.5)])),
+        ..Default::default()
+    };
+    let generated = HashMap::from([(1, 2), (2, 1)]);
+    let mut logits = vec![1.0, 2.0, -1.0, 0.5];
+    apply_penalties(&mut logits, &param, &[1, 2], &generated);
+    // token 0 被禁止; token 1: 2.0 / 2 - (0.25 * 2 + 0.5); token 2: -1.0 * 2 - (0.25 + 0.5); token 3: 0.5 + 1.5
+    assert_eq!(logits, vec![NEG_INF, 0.0, -2.75, 2.0]);
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use common::{remove_eos, tiny_model_dir};
+use qwen3_deploy::beam::BeamSearchParam;
+use qwen3_deploy::qwen3::{GenerateParam, GenerateTask, Qwen3};
+
+fn load(name: &str) -> Qwen3<'static> {
+    let dir = tiny_model_dir(name);
+    // 随机权重可能很早生成 eos, 去掉后每个序列的长度是确定的
+    remove_eos(&dir);
+    Qwen3::new_with_param(dir.to_string_lossy().to_string(), 8, 1.0, 64, true, 1, None, None).unwrap()
+}
+
+fn texts(model: &Qwen3, task: GenerateTask) -> Vec<String> {
+    model
+        .task_generations(task)
+        .unwrap()
+        .into_iter()
+        .map(|generation| generation.text)
+        .collect()
+}
+
+#[test]
+fn test_chunked_prefill_interleaves() {
+    let mut model = load("interleave");
+    let first = vec![1u32, 2, 3, 4, 5, 6, 7];
+    let second = vec![9u32, 8, 7, 6, 5];
+    let param = GenerateParam {
+        n: Some(2),
+        ..Default::default()
+    };
+    let expected_first = model.infer_tokens(first.clone(), param.clone()).unwrap();
+    let expected_second = model.infer_tokens(second.clone(), GenerateParam::default()).unwrap();
+
+    // 每块 2 个 token, 两个任务轮流推进, 一个任务的 prefill 没有完成时另一个也在推进
+    model.set_prefill_chunk_size(Some(2));
+    let mut a = model.new_task(first, param);
+    let mut b = model.new_task(second, GenerateParam::default());
+    model.advance(&mut a).unwrap();
+    model.advance(&mut b).unwrap();
+    assert!(a.prefilled_at().is_none() && b.prefilled_at().is_none());
+    let mut rounds = 0;
+    while !(a.is_finished() && b.is_finished()) {
+        model.advance(&mut a).unwrap();
+        model.advance(&mut b).unwrap();
+        rounds += 1;
+    }
+    assert!(rounds > 2);
+
+    let expected_first: Vec<String> = expected_first.into_iter().map(|generation| generation.text).collect();
+    assert_eq!(texts(&model, a), expected_first);
+    assert_eq!(texts(&model, b), vec![expected_second[0].text.clone()]);
+}
+
+#[test]
+fn test_beam_search_advances_per_step() {
+    let mut model = load("beam");
+    let prompt = vec![1u32, 2, 3, 4];
+    let param = GenerateParam {
+        n: Some(2),
+        beam: Some(BeamSearchParam {
+            beam_width: 3,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let expected: Vec<String> = model
+        .infer_tokens(prompt.clone(), param.clone())
+        .unwrap()
+        .into_iter()
+        .map(|generation| generation.text)
+        .collect();
+
+    // beam search 每次 advance 只扩展一步, 其间可以穿插其它请求的解码
+    let mut beam = model.new_task(prompt, param);
+    let mut other = model.new_task(vec![5, 6, 7], GenerateParam::default());
+    let mut beam_steps = 0;
+    while !beam.is_finished() {
+        let tokens = model.advance(&mut beam).unwrap();
+        assert!(tokens.is_empty() || beam.is_finished());
+        model.advance(&mut other).unwrap();
+        beam_steps += 1;
+    }
+    assert!(beam_steps > 2);
+    assert_eq!(texts(&model, beam), expected);
+}
+
+#[test]
+fn test_kv_budget_preempts_newest_task() {
+    let mut model = load("preempt");
+    let first = vec![1u32, 2, 3, 4, 5, 6];
+    let second = vec![9u32, 8, 7, 6, 5];
+    let param = GenerateParam {
+        max_tokens: Some(8),
+        ..Default::default()
+    };
+    let expected_first = model.infer_tokens(first.clone(), param.clone()).unwrap().remove(0).text;
+    let expected_second = model.infer_tokens(second.clone(), param.clone()).unwrap().remove(0).text;
+
+    // 权重为 F16, 每个 token 2 层 * 2 个 kv head * 8 维 * 2 (k 和 v) * 2 字节 = 128 字节
+    // 预算 20 个 token, 即 5 个 4 token 的块, 两个 14 个 token 的序列放不下, 后创建的任务等待
+    model.set_kv_cache_budget(20 * 128, 4).unwrap();
+    let mut a = model.new_task(first, param.clone());
+    let mut b = model.new_task(second, param);
+    let (mut a_waits, mut b_waits) = (0, 0);
+    while !(a.is_finished() && b.is_finished()) {
+        model.advance(&mut a).unwrap();
+        a_waits += a.is_waiting() as usize;
+        model.advance(&mut b).unwrap();
+        b_waits += b.is_waiting() as usize;
+    }
+    assert_eq!(a_waits, 0);
+    assert!(b_waits > 0);
+    assert_eq!(texts(&model, a), vec![expected_first]);
+    assert_eq!(texts(&model, b), vec![expected_second]);
+    assert_eq!(model.kv_pool().unwrap().num_blocks(), 5);
+    assert_eq!(model.kv_pool().unwrap().used_blocks(), 0);
+}
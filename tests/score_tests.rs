@@ -0,0 +1,50 @@
+use qwen3_deploy::qwen3::TokenLogprob;
+use qwen3_deploy::score::{ScoreRequest, log_likelihood};
+
+fn request(json: &str) -> ScoreRequest {
+    serde_json::from_str(json).unwrap()
+}
+
+#[test]
+fn test_continuations() {
+    let req = request(r#"{"prompt": "The capital of France is", "continuation": " Paris"}"#);
+    assert!(req.validate().is_ok());
+    assert_eq!(req.continuations(), vec![" Paris"]);
+
+    // 候选回复以 <|im_end|> 结束
+    let req = request(r#"{"messages": [{"role": "user", "content": "hi"}], "continuation": ["Hello!", "Bye."]}"#);
+    assert!(req.validate().is_ok());
+    assert_eq!(req.continuations(), vec!["Hello!<|im_end|>", "Bye.<|im_end|>"]);
+}
+
+#[test]
+fn test_validate_score_request() {
+    let param = |json: &str| request(json).validate().unwrap_err().param;
+    assert_eq!(param(r#"{"continuation": "a"}"#), Some("prompt".to_string()));
+    assert_eq!(
+        param(r#"{"prompt": "p", "messages": [{"role": "user", "content": "hi"}], "continuation": "a"}"#),
+        Some("prompt".to_string())
+    );
+    assert_eq!(param(r#"{"prompt": "", "continuation": "a"}"#), Some("prompt".to_string()));
+    assert_eq!(param(r#"{"messages": [], "continuation": "a"}"#), Some("messages".to_string()));
+    assert_eq!(param(r#"{"prompt": "p", "continuation": []}"#), Some("continuation".to_string()));
+    assert_eq!(
+        param(r#"{"messages": [{"role": "user", "content": "hi"}], "continuation": ""}"#),
+        Some("continuation".to_string())
+    );
+    assert_eq!(param(r#"{"prompt": "p", "continuation": "a", "top_logprobs": 21}"#), Some("top_logprobs".to_string()));
+    assert_eq!(param(r#"{"prompt": "p", "continuation": "a", "tools": []}"#), Some("tools".to_string()));
+}
+
+#[test]
+fn test_log_likelihood() {
+    let logprob = |logprob: f32| TokenLogprob {
+        token: "x".to_string(),
+        bytes: b"x".to_vec(),
+        logprob,
+        top_logprobs: Vec::new(),
+    };
+    let (total, perplexity) = log_likelihood(&[logprob(-1.0), logprob(-3.0)]);
+    assert_eq!(total, -4.0);
+    assert!((perplexity - 2.0f32.exp()).abs() < 1e-5);
+}
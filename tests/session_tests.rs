@@ -0,0 +1,114 @@
+mod common;
+
+use common::{remove_eos, tiny_model, tiny_model_dir};
+use qwen3_deploy::error::ApiError;
+use qwen3_deploy::qwen3::{GenerateParam, Qwen3};
+use qwen3_deploy::session::{cached_len, common_prefix_len, load, save, validate_filename, write};
+
+#[test]
+fn test_save_and_restore() {
+    let model = tiny_model(64);
+    let dir = std::env::temp_dir().join(format!("session_tests_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("agent.safetensors");
+
+    let tokens = [1u32, 5, 9, 13, 2, 7];
+    let mut cache = model.new_cache();
+    model.forward(&tokens[..5], &mut cache).unwrap();
+    save(&path, &tokens, &cache, 42).unwrap();
+
+    // 恢复后接着解码, 与原来的 cache 结果一致
+    let (restored_tokens, mut restored) = load(&path, &model, 42).unwrap();
+    assert_eq!(restored_tokens, tokens);
+    assert_eq!(restored.len(), 5);
+    let expected = model.forward(&tokens[5..], &mut cache).unwrap();
+    let logits = model.forward(&tokens[5..], &mut restored).unwrap();
+    let diff = (expected - logits).unwrap().abs().unwrap().max_all().unwrap();
+    assert!(diff.to_scalar::<f32>().unwrap() < 1e-6);
+
+    // 指纹不同的模型拒绝加载
+    let e = load(&path, &model, 43).unwrap_err();
+    let e = e.downcast_ref::<ApiError>().unwrap();
+    assert_eq!(e.status, 400);
+    assert_eq!(e.code.as_deref(), Some("model_mismatch"));
+
+    // 主文件名相同的会话同时保存, 各自写自己的临时文件
+    let names = ["agent.bin", "agent.tmp", "other.safetensors"];
+    std::thread::scope(|scope| {
+        for (i, name) in names.iter().enumerate() {
+            let (dir, cache) = (&dir, &cache);
+            let tokens = [&tokens[..], &[i as u32]].concat();
+            scope.spawn(move || save(&dir.join(name), &tokens, cache, 42).unwrap());
+        }
+    });
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(load(&dir.join(name), &model, 42).unwrap().0, [&tokens[..], &[i as u32]].concat());
+    }
+    let files = std::fs::read_dir(&dir).unwrap().count();
+    assert_eq!(files, names.len() + 1);
+
+    let e = load(&dir.join("missing.safetensors"), &model, 42).unwrap_err();
+    assert_eq!(e.downcast_ref::<ApiError>().unwrap().status, 404);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_restore_session_makes_room() {
+    let dir = tiny_model_dir("restore");
+    remove_eos(&dir);
+    let mut model = Qwen3::new_with_param(dir.to_string_lossy().to_string(), 8, 1.0, 64, true, 1, None, None).unwrap();
+    // 8 个 4 token 的块, 权重为 F16, 每个 token 128 字节
+    model.set_kv_cache_budget(8 * 4 * 128, 4).unwrap();
+    let run = |model: &mut Qwen3, id: &str, prompt: Vec<u32>| {
+        let param = GenerateParam {
+            max_tokens: Some(2),
+            session_id: Some(id.to_string()),
+            ..Default::default()
+        };
+        model.infer_tokens(prompt, param).unwrap();
+    };
+    run(&mut model, "a", (1..11).collect());
+    let path = dir.join("a.safetensors");
+    write(&path, &model.snapshot_session("a").unwrap().1).unwrap();
+    let cached = cached_len(&path).unwrap();
+    assert!(cached >= 10);
+    model.erase_session("a");
+
+    // 读入前按文件头淘汰最久未使用的会话 b, 腾出 a 需要的块
+    run(&mut model, "b", (20..34).collect());
+    run(&mut model, "c", (40..50).collect());
+    let pool = model.kv_pool().unwrap().clone();
+    assert_eq!(pool.free_blocks(), 1);
+    assert_eq!(model.restore_session("a", &path).unwrap(), 12);
+    assert!(model.snapshot_session("b").is_err());
+    assert!(model.snapshot_session("c").is_ok());
+    assert_eq!(pool.used_blocks(), 3 + cached.div_ceil(4));
+
+    // 正在运行的任务占用的块不能淘汰, 放不下时不读入文件, 池不变
+    model.erase_session("a");
+    model.erase_session("c");
+    let mut task = model.new_task((1..25).collect(), GenerateParam::default());
+    model.advance(&mut task).unwrap();
+    assert_eq!(pool.used_blocks(), 6);
+    let e = model.restore_session("a", &path).unwrap_err();
+    let e = e.downcast_ref::<ApiError>().unwrap();
+    assert_eq!((e.status, e.code.as_deref()), (503, Some("kv_cache_exhausted")));
+    assert_eq!(pool.used_blocks(), 6);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_validate_filename() {
+    assert!(validate_filename("agent-1.safetensors").is_ok());
+    assert!(validate_filename("").is_err());
+    assert!(validate_filename("../model.safetensors").is_err());
+    assert!(validate_filename("a/b").is_err());
+    assert!(validate_filename(".hidden").is_err());
+}
+
+#[test]
+fn test_common_prefix_len() {
+    assert_eq!(common_prefix_len(&[1, 2, 3], &[1, 2, 4, 5]), 2);
+    assert_eq!(common_prefix_len(&[1, 2], &[1, 2, 3]), 2);
+    assert_eq!(common_prefix_len(&[], &[1]), 0);
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::{TOOL_CALL_WORDS, chain_model_dir, chat_chunks, tool_calls_and_content};
+use qwen3_deploy::qwen3::{GenerateParam, Qwen3};
+use qwen3_deploy::sampling::{Sampler, SamplingParam};
+use qwen3_deploy::speculative::{PromptLookup, SpeculativeStats, verify};
+
+fn sampler(seed: u64) -> Sampler {
+    let param = SamplingParam {
+        temperature: Some(1.0),
+        ..Default::default()
+    };
+    Sampler::new(param, seed)
+}
+
+#[test]
+fn test_verify_distribution() {
+    // 草稿分布与目标分布差别很大时, 输出的第一个 token 仍服从目标分布
+    let target = vec![0.5, 0.3, 0.2];
+    let draft = vec![0.1, 0.1, 0.8];
+    let mut sampler = sampler(7);
+    let trials = 40000;
+    let mut counts = [0usize; 3];
+    let mut accepted_total = 0;
+    for _ in 0..trials {
+        let token = sampler.sample_probs(&draft);
+        let (accepted, next) = verify(
+            &[target.clone(), target.clone()],
+            std::slice::from_ref(&draft),
+            &[token],
+            &mut sampler,
+        );
+        let first = if accepted == 1 { token } else { next };
+        counts[first as usize] += 1;
+        accepted_total += accepted;
+    }
+    for (count, expected) in counts.iter().zip(&target) {
+        let freq = *count as f32 / trials as f32;
+        assert!((freq - expected).abs() < 0.015, "{:?}", counts);
+    }
+    // 接受率的期望为 sum(min(p, q)) = 0.1 + 0.1 + 0.2
+    let rate = accepted_total as f32 / trials as f32;
+    assert!((rate - 0.4).abs() < 0.015, "{rate}");
+}
+
+#[test]
+fn test_verify_greedy() {
+    let mut sampler = sampler(0);
+    let one_hot = |token: usize| {
+        let mut probs = vec![0.0; 4];
+        probs[token] = 1.0;
+        probs
+    };
+    // 目标与草稿一致时全部接受, 并额外输出一个 token
+    let (accepted, next) = verify(
+        &[one_hot(1), one_hot(2), one_hot(3)],
+        &[one_hot(1), one_hot(2)],
+        &[1, 2],
+        &mut sampler,
+    );
+    assert_eq!((accepted, next), (2, 3));
+    // 第二个草稿 token 与目标不一致, 用目标的 argmax 替换
+    let (accepted, next) = verify(
+        &[one_hot(1), one_hot(0), one_hot(3)],
+        &[one_hot(1), one_hot(2)],
+        &[1, 2],
+        &mut sampler,
+    );
+    assert_eq!((accepted, next), (1, 0));
+
+    let mut stats = SpeculativeStats::default();
+    stats.add(2, 2);
+    stats.add(2, 1);
+    assert!((stats.acceptance_rate() - 0.75).abs() < 1e-9);
+    assert!((stats.tokens_per_step() - 2.5).abs() < 1e-9);
+}
+
+#[test]
+fn test_prompt_lookup() {
+    let lookup = PromptLookup {
+        max_ngram: 3,
+        min_ngram: 1,
+        num_speculative_tokens: 4,
+    };
+    // 末尾的 [7, 8] 在前面出现过, 取它后面的 token
+    let tokens = [1, 7, 8, 9, 10, 11, 12, 5, 7, 8];
+    assert_eq!(lookup.propose(&tokens, 3), vec![9, 10, 11]);
+    // 优先匹配更长的 n-gram
+    let tokens = [3, 8, 1, 5, 8, 2, 9, 5, 8];
+    assert_eq!(lookup.propose(&tokens, 2), vec![2, 9]);
+    // 续写不能超出已有的 token
+    let tokens = [4, 6, 4];
+    assert_eq!(lookup.propose(&tokens, 4), vec![6, 4]);
+    assert!(lookup.propose(&[1, 2, 3], 4).is_empty());
+}
+
+#[test]
+fn test_streamed_tool_call_with_draft_model() {
+    let dir = chain_model_dir("draft_tool_call", &TOOL_CALL_WORDS);
+    let path = dir.to_string_lossy().to_string();
+    let mut model = Qwen3::new_with_param(path.clone(), 16, 1.0, 64, true, 1, None, None).unwrap();
+    model.load_draft_model(&path, 4).unwrap();
+    let (texts, choices) = chat_chunks(&mut model, vec![0], GenerateParam::default());
+    // 一步接受多个 token, <tool_call> 标签与 JSON 在同一段输出中
+    assert!(texts.iter().any(|text| text.contains("<tool_call>") && text.contains("get_time")));
+    let (tool_calls, content) = tool_calls_and_content(&choices);
+    assert_eq!(tool_calls, vec![("get_time".to_string(), serde_json::json!({"tz": "UTC"}))]);
+    assert!(!content.contains("tool_call"));
+}
+
+#[test]
+fn test_streamed_tool_call_with_prompt_lookup() {
+    let dir = chain_model_dir("lookup_tool_call", &TOOL_CALL_WORDS);
+    let mut model = Qwen3::new_with_param(dir.to_string_lossy().to_string(), 16, 1.0, 64, true, 1, None, None).unwrap();
+    model.enable_prompt_lookup(2, 4);
+    // prompt 里已经有一次完整的 tool_call, 生成时整段从 prompt 中复制
+    let (texts, choices) = chat_chunks(&mut model, vec![0, 1, 2, 3, 0], GenerateParam::default());
+    assert!(texts.iter().any(|text| text.contains("<tool_call>") && text.contains("get_time")));
+    let (tool_calls, content) = tool_calls_and_content(&choices);
+    assert_eq!(tool_calls, vec![("get_time".to_string(), serde_json::json!({"tz": "UTC"}))]);
+    assert!(!content.contains("tool_call"));
+    assert!(model.speculative_stats().unwrap().accepted_tokens > 0);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use common::{TOOL_CALL_WORDS, chain_model_dir, chat_chunks, tool_calls_and_content};
+use qwen3_deploy::qwen3::{GenerateParam, Qwen3};
+use qwen3_deploy::stop::{StopChecker, find_stop};
+
+// 增量解码出的文本依次输入, 每段算一个 token, 返回每次输出的文本和最后 flush 的文本
+fn stream(stop: &[&str], steps: &[&str]) -> (Vec<String>, bool) {
+    let mut checker = StopChecker::new(stop.iter().map(|stop| stop.to_string()).collect());
+    let mut chunks = Vec::new();
+    for (i, text) in steps.iter().enumerate() {
+        chunks.extend(checker.push(text, i + 1));
+    }
+    chunks.push(checker.flush());
+    (chunks, checker.is_stopped())
+}
+
+#[test]
+fn test_stop_across_steps() {
+    // "\n\n" 跨越两次输出, 第一个换行先不输出
+    let (chunks, stopped) = stream(&["\n\n"], &["a", "\n", "\n", "b"]);
+    assert!(stopped);
+    assert_eq!(chunks.concat(), "a");
+    assert!(chunks.iter().all(|chunk| !chunk.contains('\n')));
+
+    // 看起来像停止字符串开头, 后面不匹配时补上
+    let (chunks, stopped) = stream(&["END"], &["x E", "N", "x", "y"]);
+    assert!(!stopped);
+    assert_eq!(chunks.concat(), "x ENxy");
+
+    // 多个停止字符串取最早出现的
+    let (chunks, _) = stream(&["b", "a"], &["xab"]);
+    assert_eq!(chunks.concat(), "x");
+
+    let (chunks, stopped) = stream(&["。"], &["你", "好", "。"]);
+    assert!(stopped);
+    assert_eq!(chunks, vec!["你", "好", ""]);
+}
+
+#[test]
+fn test_stop_token_counts() {
+    let mut checker = StopChecker::new(vec!["###".to_string()]);
+    assert_eq!(checker.push("ab", 1).as_deref(), Some("ab"));
+    assert_eq!(checker.emitted_tokens(), 1);
+    // 可能是停止字符串的开头, 文本和这个 token 的 logprobs 都先不输出
+    assert_eq!(checker.push("c#", 2).as_deref(), Some("c"));
+    assert_eq!(checker.emitted_tokens(), 1);
+
+    // 一次输入多个 token 的文本时, 停止字符串之后的 token 不计入
+    assert_eq!(checker.push("##", 4), None);
+    assert_eq!(checker.matched(), Some(0));
+    assert_eq!(checker.matched_tokens(), Some(4));
+
+    let mut checker = StopChecker::new(vec!["###".to_string()]);
+    checker.push("x", 1);
+    assert_eq!(checker.push("###", 2).as_deref(), None);
+    checker.push("tail", 5);
+    assert_eq!(checker.matched_tokens(), Some(2));
+    assert_eq!(checker.flush(), "");
+}
+
+#[test]
+fn test_truncate() {
+    let checker = StopChecker::new(vec!["###".to_string()]);
+    let mut text = "answer### next".to_string();
+    checker.truncate(&mut text);
+    assert_eq!(text, "answer");
+    assert_eq!(find_stop("no stop here", &["###".to_string()]), None);
+}
+
+#[test]
+fn test_stop_with_streamed_tool_call() {
+    let dir = chain_model_dir("stop_tool_call", &TOOL_CALL_WORDS);
+    let mut model = Qwen3::new_with_param(dir.to_string_lossy().to_string(), 16, 1.0, 64, true, 1, None, None).unwrap();
+    // <tool_call> 可能是停止字符串的开头, 先暂缓输出, 确认不匹配后与后面的 JSON 一起输出
+    let param = GenerateParam {
+        stop: vec![r#"<tool_call> {"name":"other""#.to_string()],
+        ..Default::default()
+    };
+    let (texts, choices) = chat_chunks(&mut model, vec![0], param);
+    assert!(texts.iter().any(|text| text.contains("<tool_call>") && text.contains("get_time")));
+    let (tool_calls, content) = tool_calls_and_content(&choices);
+    assert_eq!(tool_calls, vec![("get_time".to_string(), serde_json::json!({"tz": "UTC"}))]);
+    assert!(!content.contains("tool_call"));
+}
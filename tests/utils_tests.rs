@@ -0,0 +1,21 @@
+use qwen3_deploy::utils::{base64_encode, byte_level_decode};
+
+#[test]
+fn test_byte_level_decode() {
+    assert_eq!(byte_level_decode("hello"), b"hello");
+    // Ġ 表示空格, Ċ 表示换行
+    assert_eq!(byte_level_decode("Ġworld"), b" world");
+    assert_eq!(byte_level_decode("Ċ"), b"\n");
+    // "你" 的 utf-8 编码为 e4 bd a0
+    assert_eq!(byte_level_decode("ä½ł"), "你".as_bytes());
+    assert_eq!(byte_level_decode("<|im_end|>"), b"<|im_end|>");
+}
+
+#[test]
+fn test_base64_encode() {
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    assert_eq!(base64_encode(&[0xff, 0xfe, 0x00]), "//4A");
+}